@@ -0,0 +1,6 @@
+CREATE SCHEMA IF NOT EXISTS pasta;
+
+CREATE TABLE IF NOT EXISTS pasta.todo (
+  id BIGSERIAL PRIMARY KEY,
+  content VARCHAR(200) NOT NULL
+);
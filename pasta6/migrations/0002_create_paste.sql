@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS pasta.paste (
+  id VARCHAR(16) PRIMARY KEY,
+  title VARCHAR(200),
+  language VARCHAR(32),
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL
+);
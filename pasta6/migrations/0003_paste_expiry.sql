@@ -0,0 +1,7 @@
+ALTER TABLE pasta.paste
+  ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS burn_after_reading BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX IF NOT EXISTS paste_expires_at_idx
+  ON pasta.paste (expires_at)
+  WHERE expires_at IS NOT NULL;
@@ -0,0 +1,19 @@
+ALTER TABLE pasta.paste
+  ADD COLUMN IF NOT EXISTS revision INTEGER NOT NULL DEFAULT 1,
+  ADD COLUMN IF NOT EXISTS parent_id VARCHAR(16),
+  ADD COLUMN IF NOT EXISTS parent_revision INTEGER;
+
+CREATE TABLE IF NOT EXISTS pasta.paste_revision (
+  paste_id VARCHAR(16) NOT NULL REFERENCES pasta.paste (id) ON DELETE CASCADE,
+  revision INTEGER NOT NULL,
+  title VARCHAR(200),
+  language VARCHAR(32),
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY (paste_id, revision)
+);
+
+-- Existing pastes start out with their content as the first revision.
+INSERT INTO pasta.paste_revision (paste_id, revision, title, language, content, created_at)
+SELECT id, revision, title, language, content, created_at FROM pasta.paste
+ON CONFLICT DO NOTHING;
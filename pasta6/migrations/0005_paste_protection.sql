@@ -0,0 +1,2 @@
+ALTER TABLE pasta.paste
+  ADD COLUMN IF NOT EXISTS protected BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,17 @@
+-- Uploaded files, by the hex SHA-256 of their content.
+CREATE TABLE IF NOT EXISTS pasta.blob (
+  hash VARCHAR(64) PRIMARY KEY,
+  data BYTEA NOT NULL,
+  stored_at TIMESTAMPTZ NOT NULL
+);
+
+ALTER TABLE pasta.paste
+  ADD COLUMN IF NOT EXISTS file_name VARCHAR(255),
+  ADD COLUMN IF NOT EXISTS file_type VARCHAR(100),
+  ADD COLUMN IF NOT EXISTS file_size BIGINT,
+  ADD COLUMN IF NOT EXISTS file_hash VARCHAR(64) REFERENCES pasta.blob (hash);
+
+-- Lets the reaper find orphaned blobs.
+CREATE INDEX IF NOT EXISTS paste_file_hash_idx
+  ON pasta.paste (file_hash)
+  WHERE file_hash IS NOT NULL;
@@ -0,0 +1,9 @@
+-- Sessions by the hex SHA-256 of their ID, see `crate::session`.
+CREATE TABLE IF NOT EXISTS pasta.session (
+  id VARCHAR(64) PRIMARY KEY,
+  data TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+
+-- Lets the reaper find expired sessions.
+CREATE INDEX IF NOT EXISTS session_expires_at_idx ON pasta.session (expires_at);
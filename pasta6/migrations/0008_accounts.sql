@@ -0,0 +1,16 @@
+-- Accounts, which own the todos and pastes created while signed in.
+CREATE TABLE IF NOT EXISTS pasta.account (
+  id BIGSERIAL PRIMARY KEY,
+  name VARCHAR(32) NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL
+);
+
+ALTER TABLE pasta.todo
+  ADD COLUMN IF NOT EXISTS owner_id BIGINT REFERENCES pasta.account (id) ON DELETE CASCADE;
+
+ALTER TABLE pasta.paste
+  ADD COLUMN IF NOT EXISTS owner_id BIGINT REFERENCES pasta.account (id) ON DELETE CASCADE;
+
+-- Lets accounts list their todos.
+CREATE INDEX IF NOT EXISTS todo_owner_id_idx ON pasta.todo (owner_id);
@@ -0,0 +1,12 @@
+-- Personal API tokens, by the hex SHA-256 of the token.
+CREATE TABLE IF NOT EXISTS pasta.api_token (
+  id BIGSERIAL PRIMARY KEY,
+  account_id BIGINT NOT NULL REFERENCES pasta.account (id) ON DELETE CASCADE,
+  name VARCHAR(64) NOT NULL,
+  hash VARCHAR(64) NOT NULL UNIQUE,
+  scope VARCHAR(5) NOT NULL CHECK (scope IN ('read', 'write')),
+  created_at TIMESTAMPTZ NOT NULL
+);
+
+-- Lets accounts list their tokens.
+CREATE INDEX IF NOT EXISTS api_token_account_id_idx ON pasta.api_token (account_id);
@@ -1,7 +1,83 @@
+-- Resets the development database created by `make configure`. Production
+-- databases are managed by the migrations in `migrations/`, which this file
+-- must be kept in sync with.
 DROP SCHEMA IF EXISTS pasta CASCADE;
 CREATE SCHEMA pasta;
 
+CREATE TABLE pasta.account (
+  id BIGSERIAL PRIMARY KEY,
+  name VARCHAR(32) NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL
+);
+
 CREATE TABLE pasta.todo (
   id BIGSERIAL PRIMARY KEY,
-  content VARCHAR(200) NOT NULL
+  content VARCHAR(200) NOT NULL,
+  owner_id BIGINT REFERENCES pasta.account (id) ON DELETE CASCADE
+);
+
+CREATE INDEX todo_owner_id_idx ON pasta.todo (owner_id);
+
+-- Uploaded files, by the hex SHA-256 of their content.
+CREATE TABLE pasta.blob (
+  hash VARCHAR(64) PRIMARY KEY,
+  data BYTEA NOT NULL,
+  stored_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE pasta.paste (
+  id VARCHAR(16) PRIMARY KEY,
+  title VARCHAR(200),
+  language VARCHAR(32),
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  expires_at TIMESTAMPTZ,
+  burn_after_reading BOOLEAN NOT NULL DEFAULT false,
+  revision INTEGER NOT NULL DEFAULT 1,
+  parent_id VARCHAR(16),
+  parent_revision INTEGER,
+  protected BOOLEAN NOT NULL DEFAULT false,
+  file_name VARCHAR(255),
+  file_type VARCHAR(100),
+  file_size BIGINT,
+  file_hash VARCHAR(64) REFERENCES pasta.blob (hash),
+  owner_id BIGINT REFERENCES pasta.account (id) ON DELETE CASCADE
 );
+
+CREATE INDEX paste_expires_at_idx
+  ON pasta.paste (expires_at)
+  WHERE expires_at IS NOT NULL;
+
+CREATE INDEX paste_file_hash_idx
+  ON pasta.paste (file_hash)
+  WHERE file_hash IS NOT NULL;
+
+CREATE TABLE pasta.paste_revision (
+  paste_id VARCHAR(16) NOT NULL REFERENCES pasta.paste (id) ON DELETE CASCADE,
+  revision INTEGER NOT NULL,
+  title VARCHAR(200),
+  language VARCHAR(32),
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY (paste_id, revision)
+);
+
+CREATE TABLE pasta.session (
+  id VARCHAR(64) PRIMARY KEY,
+  data TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX session_expires_at_idx ON pasta.session (expires_at);
+
+CREATE TABLE pasta.api_token (
+  id BIGSERIAL PRIMARY KEY,
+  account_id BIGINT NOT NULL REFERENCES pasta.account (id) ON DELETE CASCADE,
+  name VARCHAR(64) NOT NULL,
+  hash VARCHAR(64) NOT NULL UNIQUE,
+  scope VARCHAR(5) NOT NULL CHECK (scope IN ('read', 'write')),
+  created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX api_token_account_id_idx ON pasta.api_token (account_id);
@@ -1,77 +1,1818 @@
-use lunatic::{
-    process::{self, Process},
-    Mailbox,
+#[cfg(target_arch = "wasm32")]
+use lunatic::{process, Mailbox};
+use serde::{Deserialize, Serialize};
+#[cfg(target_arch = "wasm32")]
+use std::net::SocketAddr;
+
+use crate::crypto;
+use crate::highlight::{self, HighlightCache};
+#[cfg(target_arch = "wasm32")]
+use crate::http::BindAddrs;
+use crate::http::{
+    CookieKey, Cors, Form, Handler, Method, Request, Response, SecurityHeaders, Timeouts,
+    TrustedProxies,
 };
+use crate::diff::{self, Hunk};
+use crate::markdown;
+use crate::mime;
+use crate::ratelimit::{Quota, RateLimiter, RouteQuotas, Status};
+use crate::session::{Session, Sessions};
+use crate::store::{
+    Account, ApiToken, NewPaste, NewRevision, Paste, PasteFile, Revision, RevisionRef, Store,
+    StoreError, StoreHandle, Todo, TokenScope,
+};
+use crate::templates::{
+    self, DiffCell, DiffPage, DiffRow, EditPastePage, FilePage, HistoryPage, IndexPage,
+    LoginPage, NewPastePage, PasteContent, PasteCreatedPage, PastePage, RegisterPage, TokensPage,
+    UnlockPage,
+};
+use std::time::Duration;
+
+/// Maximum length of a todo, matching the `pasta.todo.content` column.
+const MAX_TODO_LENGTH: usize = 200;
+/// Maximum length of a paste title, matching the `pasta.paste.title` column.
+const MAX_PASTE_TITLE_LENGTH: usize = 200;
+/// Maximum length of a paste language, matching the `pasta.paste.language`
+/// column.
+const MAX_PASTE_LANGUAGE_LENGTH: usize = 32;
+/// Longest lifetime a paste can be created with.
+const MAX_PASTE_LIFETIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Unchanged lines shown around changes in diffs.
+const DIFF_CONTEXT: usize = 3;
+/// Password attempts allowed per protected paste: a burst of 5, then one
+/// every 12 seconds.
+const UNLOCK_QUOTA: Quota = Quota {
+    capacity: 5,
+    refill: Duration::from_secs(12),
+};
+/// Login attempts allowed per account name, as for [`UNLOCK_QUOTA`].
+const LOGIN_QUOTA: Quota = Quota {
+    capacity: 5,
+    refill: Duration::from_secs(12),
+};
+/// Invalid API tokens allowed per client, as for [`UNLOCK_QUOTA`]. Requests
+/// with a token are rate limited by the token, so that without this, a
+/// client could evade its quota by sending random tokens.
+const INVALID_TOKEN_QUOTA: Quota = Quota {
+    capacity: 5,
+    refill: Duration::from_secs(12),
+};
+/// Shortest password an account can be registered with.
+pub(crate) const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longest password accepted, so that hashing stays cheap.
+pub(crate) const MAX_PASSWORD_LENGTH: usize = 1024;
+/// Session key of the ID of the signed-in account.
+const ACCOUNT_KEY: &str = "account";
+/// Maximum length of an API token name, matching the `pasta.api_token.name`
+/// column.
+const MAX_TOKEN_NAME_LENGTH: usize = 64;
+/// Sent with `401` and `403` API responses, see RFC 6750 section 3.
+const BEARER_CHALLENGE: &str = "Bearer realm=\"pasta6\"";
+
+pub(crate) struct App {
+    store_handle: StoreHandle,
+    // Opened on first use, so that requests which don't need the store
+    // don't pay for e.g. a database connection.
+    store: Option<Box<dyn Store>>,
+    highlight_cache: HighlightCache,
+    rate_limiter: RateLimiter,
+    route_quotas: RouteQuotas,
+    trusted_proxies: TrustedProxies,
+    proxy_protocol: bool,
+    cookie_key: CookieKey,
+    sessions: Sessions,
+    secure_cookies: bool,
+    security_headers: SecurityHeaders,
+    cors: Cors,
+    max_body_size: usize,
+    timeouts: Timeouts,
+    // Loaded on first use, and saved once the response is ready.
+    session: Option<Session>,
+}
+
+/// A todo, as returned by the JSON API.
+#[derive(Serialize)]
+struct ApiTodo<'a> {
+    id: i64,
+    content: &'a str,
+}
+
+/// A paste, as returned by the JSON API.
+#[derive(Serialize)]
+struct ApiPaste<'a> {
+    id: &'a str,
+    title: Option<&'a str>,
+    language: Option<&'a str>,
+    /// `None` for protected pastes, which the API can't decrypt, and files.
+    content: Option<&'a str>,
+    revision: i32,
+    created_at: i64,
+    expires_at: Option<i64>,
+    burn_after_reading: bool,
+    protected: bool,
+    file: Option<&'a PasteFile>,
+}
+
+/// The body of `POST /api/todos`.
+#[derive(Deserialize)]
+struct NewApiTodo {
+    content: String,
+}
+
+/// The body of `POST /api/pastes`, with the fields of the paste form.
+#[derive(Deserialize)]
+struct NewApiPaste {
+    content: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    language: Option<String>,
+    /// A lifetime such as `1h`, see [`parse_lifetime`].
+    #[serde(default)]
+    expires: Option<String>,
+    #[serde(default)]
+    burn_after_reading: bool,
+}
+
+enum DiffView {
+    Unified,
+    SideBySide,
+    /// A unified diff as plain text.
+    Raw,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Config {
+    pub(crate) store: StoreHandle,
+    pub(crate) highlight_cache: HighlightCache,
+    pub(crate) rate_limiter: RateLimiter,
+    /// How often each client may request some routes.
+    pub(crate) route_quotas: RouteQuotas,
+    /// The proxies trusted to tell the addresses of clients.
+    pub(crate) trusted_proxies: TrustedProxies,
+    /// Takes the addresses of connections from PROXY protocol headers. The
+    /// test server ignores this.
+    pub(crate) proxy_protocol: bool,
+    pub(crate) cookie_key: CookieKey,
+    pub(crate) sessions: Sessions,
+    /// Only send the session cookie over HTTPS.
+    pub(crate) secure_cookies: bool,
+    pub(crate) security_headers: SecurityHeaders,
+    /// The other origins allowed to use the JSON API.
+    pub(crate) cors: Cors,
+    /// The largest request body accepted, which bounds uploads.
+    pub(crate) max_body_size: usize,
+    /// How long clients may take to send requests.
+    pub(crate) timeouts: Timeouts,
+}
+
+impl Handler for App {
+    type Config = Config;
+
+    #[inline]
+    fn new(config: Config) -> Self {
+        Self {
+            store_handle: config.store,
+            store: None,
+            highlight_cache: config.highlight_cache,
+            rate_limiter: config.rate_limiter,
+            route_quotas: config.route_quotas,
+            trusted_proxies: config.trusted_proxies,
+            proxy_protocol: config.proxy_protocol,
+            cookie_key: config.cookie_key,
+            sessions: config.sessions,
+            secure_cookies: config.secure_cookies,
+            security_headers: config.security_headers,
+            cors: config.cors,
+            max_body_size: config.max_body_size,
+            timeouts: config.timeouts,
+            session: None,
+        }
+    }
+
+    #[inline]
+    fn handle<'request, 'response>(
+        &mut self,
+        request: &'request Request<'request>,
+    ) -> Response<'response> {
+        tracing::trace!("App server handling request");
+        let rate_limit = self.check_route_quota(request);
+        let result = match (request.method(), request.path()) {
+            (Method::Options, path) if is_api_path(path) => Ok(self.cors.preflight(request)),
+            _ if rate_limit.is_some_and(|(_, status)| status.retry_after.is_some()) => {
+                Ok(self.too_many_requests(request))
+            }
+            (method, path) if is_api_path(path) => {
+                let result = self.api(method, path, request);
+                result.map(|response| self.cors.apply(request, response))
+            }
+            (Method::Post | Method::Delete, _) if !self.verify_csrf(request) => {
+                Ok(Response::from_static(403, "Cross-site request refused.\n"))
+            }
+            (Method::Get, "/") => {
+                // FIXME: uncomment
+                //assert_eq!(request.body(), b"");
+                self.index(request)
+            }
+            (Method::Post, "/") => self.create_todo(request),
+            (Method::Get, "/register") => {
+                let csrf_token = self.csrf_token(request);
+                Ok(register_form(200, "", None, csrf_token.as_deref()))
+            }
+            (Method::Post, "/register") => self.register(request),
+            (Method::Get, "/login") => {
+                let csrf_token = self.csrf_token(request);
+                Ok(login_form(200, "", None, csrf_token.as_deref()))
+            }
+            (Method::Post, "/login") => self.login(request),
+            (Method::Post, "/logout") => Ok(self.logout(request)),
+            (Method::Get, "/tokens") => self.tokens(request),
+            (Method::Post, "/tokens") => self.create_token(request),
+            (Method::Get, "/p") => {
+                let csrf_token = self.csrf_token(request);
+                Ok(new_paste(csrf_token.as_deref()))
+            }
+            (Method::Post, "/p") => self.create_paste(request),
+            (Method::Get, path) => match paste_route(path) {
+                Some((id, view)) => match view.as_slice() {
+                    [] => self.show_paste(id, request),
+                    ["raw"] => self.raw_paste(id),
+                    ["edit"] => self.edit_paste_form(id, request),
+                    ["history"] => self.paste_history(id),
+                    ["rev", revision] => self.show_revision(id, revision, request),
+                    ["rev", revision, "raw"] => self.raw_revision(id, revision),
+                    ["diff", old, new] => self.diff_paste(id, old, new, DiffView::Unified),
+                    ["diff", old, new, "split"] => {
+                        self.diff_paste(id, old, new, DiffView::SideBySide)
+                    }
+                    ["diff", old, new, "raw"] => self.diff_paste(id, old, new, DiffView::Raw),
+                    _ => Ok(Response::from_static(404, "")),
+                },
+                None => Ok(Response::from_static(404, "")),
+            },
+            (Method::Post, path) => match (
+                item_action("/todo/", path),
+                item_action("/tokens/", path),
+                paste_route(path),
+            ) {
+                (Some((id, "delete")), _, _) => self.delete_todo(id, request),
+                (_, Some((id, "delete")), _) => self.revoke_token(id, request),
+                (_, _, Some((id, view))) => match view.as_slice() {
+                    ["edit"] => self.edit_paste(id, request),
+                    ["fork"] => self.fork_paste(id, request),
+                    ["unlock"] => self.unlock_paste(id, request, false),
+                    ["raw"] => self.unlock_paste(id, request, true),
+                    _ => Ok(Response::from_static(404, "")),
+                },
+                _ => Ok(Response::from_static(404, "")),
+            },
+            (Method::Delete | Method::Options, _) => Ok(Response::from_static(404, "")),
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::error!("store error: {}", e);
+                // Reconnect on the next request in case the store went away.
+                self.store = None;
+                Response::from_static(500, "")
+            }
+        };
+        let cookie = self.session.take().and_then(|session| {
+            session.save(&self.sessions, &self.cookie_key, self.secure_cookies)
+        });
+        let response = match cookie {
+            Some(cookie) => response.with_cookie(&cookie),
+            None => response,
+        };
+        match rate_limit {
+            Some((quota, status)) => with_rate_limit(response, quota, status),
+            None => response,
+        }
+    }
+
+    #[inline]
+    fn security_headers(&self) -> Option<&SecurityHeaders> {
+        Some(&self.security_headers)
+    }
+
+    #[inline]
+    fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    #[inline]
+    fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    #[inline]
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+}
+
+impl App {
+    #[inline]
+    fn store(&mut self) -> Result<&mut dyn Store, StoreError> {
+        if self.store.is_none() {
+            self.store = Some(self.store_handle.open()?);
+        }
+        Ok(self.store.as_deref_mut().unwrap())
+    }
+
+    #[inline]
+    fn session(&mut self, request: &Request) -> &mut Session {
+        if self.session.is_none() {
+            self.session = Some(Session::load(&self.sessions, &self.cookie_key, request));
+        }
+        self.session.as_mut().unwrap()
+    }
+
+    /// Returns the CSRF token of the session, for the forms of a page.
+    #[inline]
+    fn csrf_token(&mut self, request: &Request) -> Option<String> {
+        let id = self.session(request).id()?.to_string();
+        Some(crate::csrf::token(&self.cookie_key, &id))
+    }
+
+    /// Returns `true` unless `request` may have been forged by another site,
+    /// see [`crate::csrf`].
+    #[inline]
+    fn verify_csrf(&mut self, request: &Request) -> bool {
+        let id = self.session(request).id().map(str::to_string);
+        crate::csrf::verify(&self.cookie_key, id.as_deref(), request)
+    }
+
+    /// Takes a token from the bucket of the client for the route of
+    /// `request`, if it has a quota, see [`RouteQuotas`]. Clients are told
+    /// apart by their API token, account or IP address, in that order.
+    #[inline]
+    fn check_route_quota(&mut self, request: &Request) -> Option<(Quota, Status)> {
+        // Preflights are sent by browsers on their own.
+        if request.method() == Method::Options {
+            return None;
+        }
+        let route = self
+            .route_quotas
+            .find(request.method().as_ref(), request.path())?
+            .clone();
+        let bearer_token = request.headers().get("authorization").and_then(bearer_token);
+        let client = match bearer_token {
+            // Only the API accepts tokens, and it never uses the session.
+            Some(token) if is_api_path(request.path()) => {
+                format!("token:{}", crate::store::hash_api_token(token))
+            }
+            _ if is_api_path(request.path()) => self.client_ip(request),
+            _ => match self.account_id(request) {
+                Some(account_id) => format!("account:{}", account_id),
+                None => self.client_ip(request),
+            },
+        };
+        let status = self.rate_limiter.check(&route.key(&client), route.quota);
+        if status.retry_after.is_some() {
+            tracing::debug!("rate limited {} on {} {}", client, route.method, route.path);
+        }
+        Some((route.quota, status))
+    }
+
+    /// Returns the rate limiter name of the client which sent `request`, by its
+    /// IP address, see [`TrustedProxies::client_addr`] and
+    /// [`crate::ratelimit::client_key`].
+    #[inline]
+    fn client_ip(&self, request: &Request) -> String {
+        match self.trusted_proxies.client_addr(request) {
+            Some(addr) => crate::ratelimit::client_key(addr),
+            None => "ip:unknown".to_string(),
+        }
+    }
+
+    /// Returns the `429 Too Many Requests` response to `request`.
+    #[inline]
+    fn too_many_requests<'response>(&self, request: &Request) -> Response<'response> {
+        if is_api_path(request.path()) {
+            self.cors
+                .apply(request, api_error(429, "too many requests, try again later"))
+        } else {
+            Response::from_static(429, "Too many requests, try again later.\n")
+        }
+    }
+
+    /// Returns the ID of the account signed in to the session, if any.
+    #[inline]
+    fn account_id(&mut self, request: &Request) -> Option<i64> {
+        self.session(request).get(ACCOUNT_KEY)?.parse().ok()
+    }
+
+    /// Returns the account signed in to the session, if any.
+    #[inline]
+    fn account(&mut self, request: &Request) -> Result<Option<Account>, StoreError> {
+        match self.account_id(request) {
+            Some(id) => self.store()?.get_account(id),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whether the requester may change an item owned by `owner`.
+    /// Items without an owner were created anonymously, and anyone may
+    /// change them.
+    #[inline]
+    fn may_change(&mut self, request: &Request, owner: Option<i64>) -> bool {
+        let account_id = self.account_id(request);
+        may_change(account_id, owner)
+    }
+
+    #[inline]
+    fn index<'response>(&mut self, request: &Request) -> Result<Response<'response>, StoreError> {
+        let account = self.account(request)?;
+        let owner = account.as_ref().map(|account| account.id);
+        let todos = self.store()?.list_todos(owner)?;
+        let flash = self.session(request).take_flash();
+        let csrf_token = self.csrf_token(request);
+        Ok(templates::render(
+            200,
+            &IndexPage {
+                todos: &todos,
+                account: account.as_ref(),
+                flash: &flash,
+                csrf_token: csrf_token.as_deref(),
+            },
+        ))
+    }
+
+    #[inline]
+    fn register<'response>(&mut self, request: &Request) -> Result<Response<'response>, StoreError> {
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let name = form.get("name").map(str::trim).unwrap_or_default();
+        let csrf_token = self.csrf_token(request);
+        let csrf_token = csrf_token.as_deref();
+        if !crate::store::is_valid_account_name(name) {
+            let message = "Names are 3 to 32 lowercase letters, digits, dashes or underscores.";
+            return Ok(register_form(400, name, Some(message), csrf_token));
+        }
+        let password = form.get("password").unwrap_or_default();
+        if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password.chars().count()) {
+            let message = "Passwords are 8 to 1024 characters long.";
+            return Ok(register_form(400, name, Some(message), csrf_token));
+        }
+        let password_hash = match crypto::hash_password(password) {
+            Ok(password_hash) => password_hash,
+            Err(e) => {
+                tracing::error!("failed to hash password: {}", e);
+                return Ok(Response::from_static(500, ""));
+            }
+        };
+        let account = match self.store()?.create_account(name, &password_hash)? {
+            Some(account) => account,
+            None => return Ok(register_form(409, name, Some("That name is taken."), csrf_token)),
+        };
+        tracing::debug!("created account: {}", account.id);
+        self.sign_in(request, &account);
+        Ok(Response::redirect("/"))
+    }
+
+    /// Checks the submitted name and password. Attempts are rate limited per
+    /// name, so that passwords can't be guessed online.
+    #[inline]
+    fn login<'response>(&mut self, request: &Request) -> Result<Response<'response>, StoreError> {
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let name = form.get("name").map(str::trim).unwrap_or_default();
+        let password = form.get("password").unwrap_or_default();
+        let csrf_token = self.csrf_token(request);
+        let csrf_token = csrf_token.as_deref();
+        let key = format!("login:{}", name);
+        if let Err(retry_after) = self.rate_limiter.acquire(&key, LOGIN_QUOTA) {
+            tracing::debug!("too many login attempts for {}", name);
+            let retry_after = retry_after.as_millis().div_ceil(1000);
+            let message = "Too many attempts, try again later.";
+            return Ok(login_form(429, name, Some(message), csrf_token)
+                .with_header("retry-after", retry_after.to_string()));
+        }
+        let account = match self.store()?.get_account_by_name(name)? {
+            Some(account) => account,
+            None => {
+                // Hash anyway, so that how long this takes doesn't tell which
+                // names are registered.
+                let _hash = crypto::hash_password(password);
+                return Ok(login_form(403, name, Some("Wrong name or password."), csrf_token));
+            }
+        };
+        match crypto::verify_password(password, &account.password_hash) {
+            Ok(()) => {
+                self.sign_in(request, &account);
+                Ok(Response::redirect("/"))
+            }
+            Err(e) if e.is_wrong_password() => {
+                Ok(login_form(403, name, Some("Wrong name or password."), csrf_token))
+            }
+            Err(e) => {
+                tracing::error!("failed to verify password of account {}: {}", account.id, e);
+                Ok(Response::from_static(500, ""))
+            }
+        }
+    }
+
+    /// Signs `account` in to the session, under a new ID so that an ID set
+    /// by someone else before doesn't become signed in.
+    #[inline]
+    fn sign_in(&mut self, request: &Request, account: &Account) {
+        let session = self.session(request);
+        session.rotate();
+        session.insert(ACCOUNT_KEY, account.id.to_string());
+        session.push_flash(format!("Signed in as {}.", account.name));
+    }
+
+    #[inline]
+    fn logout<'response>(&mut self, request: &Request) -> Response<'response> {
+        let session = self.session(request);
+        session.destroy();
+        session.push_flash("Signed out.");
+        Response::redirect("/")
+    }
+
+    #[inline]
+    fn tokens<'response>(&mut self, request: &Request) -> Result<Response<'response>, StoreError> {
+        let account = match self.account(request)? {
+            Some(account) => account,
+            None => return Ok(Response::redirect("/login")),
+        };
+        self.tokens_page(request, &account, 200, None, None)
+    }
+
+    /// Creates an API token, which is only shown this once: only its hash is
+    /// stored.
+    #[inline]
+    fn create_token<'response>(
+        &mut self,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let account = match self.account(request)? {
+            Some(account) => account,
+            None => return Ok(Response::from_static(403, "Log in to create API tokens.\n")),
+        };
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let name = match form.get("name").map(str::trim) {
+            Some(name) if !name.is_empty() && name.chars().count() <= MAX_TOKEN_NAME_LENGTH => name,
+            _ => {
+                let message = "Token names are 1 to 64 characters long.";
+                return self.tokens_page(request, &account, 400, None, Some(message));
+            }
+        };
+        let scope = match form.get("scope").and_then(TokenScope::from_name) {
+            Some(scope) => scope,
+            None => return Ok(Response::from_static(400, "")),
+        };
+        let token = crate::store::generate_api_token();
+        let hash = crate::store::hash_api_token(&token);
+        let created = self
+            .store()?
+            .create_api_token(account.id, name, &hash, scope)?;
+        tracing::debug!("created API token {} of account {}", created.id, account.id);
+        let response = self.tokens_page(request, &account, 201, Some(&token), None)?;
+        Ok(response.with_header("cache-control", "no-store"))
+    }
+
+    #[inline]
+    fn revoke_token<'response>(
+        &mut self,
+        id: i64,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let account_id = match self.account_id(request) {
+            Some(account_id) => account_id,
+            None => return Ok(Response::from_static(403, "Log in to revoke API tokens.\n")),
+        };
+        if self.store()?.delete_api_token(account_id, id)? {
+            self.session(request).push_flash("Revoked an API token.");
+            Ok(Response::redirect("/tokens"))
+        } else {
+            Ok(Response::from_static(404, ""))
+        }
+    }
+
+    #[inline]
+    fn tokens_page<'response>(
+        &mut self,
+        request: &Request,
+        account: &Account,
+        code: u16,
+        created: Option<&str>,
+        message: Option<&str>,
+    ) -> Result<Response<'response>, StoreError> {
+        let tokens = self.store()?.list_api_tokens(account.id)?;
+        let flash = self.session(request).take_flash();
+        let csrf_token = self.csrf_token(request);
+        let page = TokensPage {
+            tokens: &tokens,
+            created,
+            message,
+            flash: &flash,
+            csrf_token: csrf_token.as_deref(),
+        };
+        Ok(templates::render(code, &page))
+    }
+
+    /// Handles a request to the JSON API, once authenticated by its bearer
+    /// token. Unlike the pages, the API never uses the session.
+    #[inline]
+    fn api<'response>(
+        &mut self,
+        method: Method,
+        path: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let scope = match method {
+            // Preflights are answered by `Cors::preflight` before, see
+            // `handle`, so other `OPTIONS` requests are just reads.
+            Method::Get | Method::Options => TokenScope::Read,
+            Method::Post | Method::Delete => TokenScope::Write,
+        };
+        let token = match self.authenticate(request, scope)? {
+            Ok(token) => token,
+            Err(response) => return Ok(response),
+        };
+        let route: Vec<&str> = path.trim_start_matches("/api").split('/').skip(1).collect();
+        match (method, route.as_slice()) {
+            (Method::Get, ["todos"]) => self.api_list_todos(&token),
+            (Method::Post, ["todos"]) => self.api_create_todo(&token, request),
+            (Method::Delete, ["todos", id]) => match id.parse() {
+                Ok(id) => self.api_delete_todo(&token, id),
+                Err(_e) => Ok(api_error(404, "Not Found")),
+            },
+            (Method::Post, ["pastes"]) => self.api_create_paste(&token, request),
+            (Method::Get, ["pastes", id]) => self.api_get_paste(id),
+            _ => Ok(api_error(404, "Not Found")),
+        }
+    }
+
+    /// Returns the API token of a request if it allows `scope`, or else the
+    /// `401` or `403` response, see RFC 6750.
+    #[inline]
+    fn authenticate<'response>(
+        &mut self,
+        request: &Request,
+        scope: TokenScope,
+    ) -> Result<Result<ApiToken, Response<'response>>, StoreError> {
+        let token = match request.headers().get("authorization").and_then(bearer_token) {
+            Some(token) => token,
+            None => {
+                return Ok(Err(api_error(401, "missing bearer token")
+                    .with_header("www-authenticate", BEARER_CHALLENGE)))
+            }
+        };
+        // Don't query the store for tokens which can't exist.
+        let found = if crate::store::is_valid_api_token(token) {
+            let hash = crate::store::hash_api_token(token);
+            self.store()?.get_api_token(&hash)?
+        } else {
+            None
+        };
+        let found = match found {
+            Some(found) => found,
+            None => {
+                let client = self.client_ip(request);
+                let key = format!("invalid-token:{}", client);
+                if let Err(retry_after) = self.rate_limiter.acquire(&key, INVALID_TOKEN_QUOTA) {
+                    tracing::debug!("too many invalid tokens from {}", client);
+                    let retry_after = retry_after.as_millis().div_ceil(1000);
+                    return Ok(Err(api_error(429, "too many invalid tokens, try again later")
+                        .with_header("retry-after", retry_after.to_string())));
+                }
+                let challenge = format!("{}, error=\"invalid_token\"", BEARER_CHALLENGE);
+                return Ok(Err(api_error(401, "invalid bearer token")
+                    .with_header("www-authenticate", challenge)));
+            }
+        };
+        if !found.scope.allows(scope) {
+            let challenge = format!(
+                "{}, error=\"insufficient_scope\", scope=\"{}\"",
+                BEARER_CHALLENGE,
+                scope.as_str()
+            );
+            return Ok(Err(api_error(403, "insufficient scope")
+                .with_header("www-authenticate", challenge)));
+        }
+        Ok(Ok(found))
+    }
+
+    #[inline]
+    fn api_list_todos<'response>(
+        &mut self,
+        token: &ApiToken,
+    ) -> Result<Response<'response>, StoreError> {
+        let todos = self.store()?.list_todos(Some(token.account_id))?;
+        let todos: Vec<ApiTodo> = todos.iter().map(api_todo).collect();
+        Ok(json_response(200, &todos))
+    }
+
+    #[inline]
+    fn api_create_todo<'response>(
+        &mut self,
+        token: &ApiToken,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let new_todo: NewApiTodo = match read_json(request) {
+            Ok(new_todo) => new_todo,
+            Err(response) => return Ok(response),
+        };
+        let content = new_todo.content.trim();
+        if content.is_empty() || content.chars().count() > MAX_TODO_LENGTH {
+            return Ok(api_error(400, "todos are 1 to 200 characters long"));
+        }
+        let todo = self
+            .store()?
+            .create_todo(content, Some(token.account_id))?;
+        tracing::debug!("created todo {} with API token {}", todo.id, token.id);
+        Ok(json_response(201, &api_todo(&todo)))
+    }
+
+    #[inline]
+    fn api_delete_todo<'response>(
+        &mut self,
+        token: &ApiToken,
+        id: i64,
+    ) -> Result<Response<'response>, StoreError> {
+        let todo = match self.store()?.get_todo(id)? {
+            Some(todo) => todo,
+            None => return Ok(api_error(404, "Not Found")),
+        };
+        if !may_change(Some(token.account_id), todo.owner) {
+            return Ok(api_error(403, "this todo isn't yours"));
+        }
+        if self.store()?.delete_todo(id)? {
+            Ok(Response::from_static(204, ""))
+        } else {
+            Ok(api_error(404, "Not Found"))
+        }
+    }
+
+    #[inline]
+    fn api_create_paste<'response>(
+        &mut self,
+        token: &ApiToken,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let new_paste: NewApiPaste = match read_json(request) {
+            Ok(new_paste) => new_paste,
+            Err(response) => return Ok(response),
+        };
+        let title = new_paste.title.as_deref().filter(|title| !title.is_empty());
+        let language = new_paste
+            .language
+            .as_deref()
+            .filter(|language| !language.is_empty());
+        if new_paste.content.is_empty() || !is_valid_paste_metadata(title, language) {
+            return Ok(api_error(400, "invalid paste"));
+        }
+        let expires_in = match new_paste.expires.as_deref().map(parse_lifetime) {
+            Some(Some(expires_in)) => Some(expires_in),
+            Some(None) => return Ok(api_error(400, "invalid lifetime")),
+            None => None,
+        };
+        let paste = self.store()?.create_paste(NewPaste {
+            title,
+            language,
+            content: &new_paste.content,
+            expires_in,
+            burn_after_reading: new_paste.burn_after_reading,
+            parent: None,
+            protected: false,
+            file: None,
+            owner: Some(token.account_id),
+        })?;
+        tracing::debug!("created paste {} with API token {}", paste.id, token.id);
+        Ok(json_response(201, &api_paste(&paste))
+            .with_header("location", format!("/p/{}", paste.id)))
+    }
+
+    #[inline]
+    fn api_get_paste<'response>(&mut self, id: &str) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(api_error(response.code(), response.reason())),
+        };
+        // Reading a protected paste or a file takes a password or a
+        // download, so only those burn them.
+        let paste = if paste.protected || paste.file.is_some() {
+            paste
+        } else {
+            match self.burn_paste(paste)? {
+                Ok(paste) => paste,
+                Err(response) => return Ok(api_error(response.code(), response.reason())),
+            }
+        };
+        Ok(json_response(200, &api_paste(&paste)))
+    }
+
+    #[inline]
+    fn create_todo<'response>(
+        &mut self,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let content = match form.get("content").map(str::trim) {
+            Some(content) if !content.is_empty() && content.chars().count() <= MAX_TODO_LENGTH => {
+                content
+            }
+            _ => return Ok(Response::from_static(400, "")),
+        };
+        let owner = self.account_id(request);
+        let todo = self.store()?.create_todo(content, owner)?;
+        tracing::debug!("created todo: {}", todo.id);
+        self.session(request).push_flash("Added a todo.");
+        Ok(Response::redirect("/"))
+    }
+
+    #[inline]
+    fn delete_todo<'response>(
+        &mut self,
+        id: i64,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let todo = match self.store()?.get_todo(id)? {
+            Some(todo) => todo,
+            None => return Ok(Response::from_static(404, "")),
+        };
+        if !self.may_change(request, todo.owner) {
+            return Ok(Response::from_static(403, "This todo isn't yours.\n"));
+        }
+        if self.store()?.delete_todo(id)? {
+            self.session(request).push_flash("Deleted a todo.");
+            Ok(Response::redirect("/"))
+        } else {
+            Ok(Response::from_static(404, ""))
+        }
+    }
+
+    #[inline]
+    fn create_paste<'response>(
+        &mut self,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let (mut title, language) = match paste_metadata(&form) {
+            Some(metadata) => metadata,
+            None => return Ok(Response::from_static(400, "")),
+        };
+        let upload = form.field("file").filter(|field| !field.value().is_empty());
+        let upload_name = upload.map(|upload| file_name(upload.filename()));
+        let mut file = None;
+        let content = match upload {
+            Some(upload) => match mime::sniff(upload.value()) {
+                // Text files are pasted as text, so that they're highlighted
+                // and can be edited.
+                mime::TEXT => {
+                    title = title.or(upload_name.as_deref());
+                    std::str::from_utf8(upload.value()).unwrap_or_default()
+                }
+                media_type => {
+                    file = Some(PasteFile {
+                        name: upload_name.clone().unwrap_or_default(),
+                        media_type: media_type.to_string(),
+                        size: upload.value().len() as i64,
+                        hash: crate::store::blob_hash(upload.value()),
+                    });
+                    ""
+                }
+            },
+            None => match form.get("content").filter(|content| !content.is_empty()) {
+                Some(content) => content,
+                None => return Ok(Response::from_static(400, "")),
+            },
+        };
+        let expires_in = match optional_field(&form, "expires").map(parse_lifetime) {
+            Some(Some(expires_in)) => Some(expires_in),
+            Some(None) => return Ok(Response::from_static(400, "")),
+            None => None,
+        };
+        let burn_after_reading = optional_field(&form, "burn").is_some();
+        // Passwords aren't trimmed, as spaces are as good as any character.
+        let password = form.get("password").filter(|password| !password.is_empty());
+        let sealed = match password {
+            Some(_password) if file.is_some() => {
+                return Ok(Response::from_static(
+                    400,
+                    "Files can't be protected by a password.\n",
+                ));
+            }
+            Some(password) => match crypto::seal(password, content) {
+                Ok(sealed) => Some(sealed),
+                Err(e) => {
+                    tracing::error!("failed to encrypt paste: {}", e);
+                    return Ok(Response::from_static(500, ""));
+                }
+            },
+            None => None,
+        };
+        if let (Some(file), Some(upload)) = (&file, upload) {
+            // Stored first, so that pastes never refer to a missing blob.
+            self.store()?.put_blob(&file.hash, upload.value())?;
+        }
+        let owner = self.account_id(request);
+        let paste = self.store()?.create_paste(NewPaste {
+            title,
+            language,
+            content: sealed.as_deref().unwrap_or(content),
+            expires_in,
+            burn_after_reading,
+            parent: None,
+            protected: sealed.is_some(),
+            file,
+            owner,
+        })?;
+        tracing::debug!("created paste: {}", paste.id);
+        let location = format!("/p/{}", paste.id);
+        if !paste.burn_after_reading {
+            return Ok(Response::redirect(&location));
+        }
+        // Redirecting would burn the paste straight away, so just show the
+        // link instead.
+        let page = PasteCreatedPage {
+            location: &location,
+        };
+        Ok(templates::render(201, &page).with_header("location", location))
+    }
+
+    #[inline]
+    fn show_paste<'response>(
+        &mut self,
+        id: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        // Protected pastes are only viewed, and burned, once unlocked.
+        if paste.protected {
+            let csrf_token = self.csrf_token(request);
+            return Ok(unlock_form(&paste, 200, None, csrf_token.as_deref()));
+        }
+        // Files are only burned once downloaded.
+        if let Some(file) = &paste.file {
+            return Ok(file_page(&paste, file));
+        }
+        match self.burn_paste(paste)? {
+            Ok(paste) => {
+                let revision = paste.revision;
+                Ok(self.render_paste(paste, revision, request))
+            }
+            Err(response) => Ok(response),
+        }
+    }
+
+    /// Decrypts a protected paste with the submitted password, and shows it
+    /// as HTML or, if `raw`, as plain text. Attempts are rate limited per
+    /// paste, so that passwords can't be guessed online.
+    #[inline]
+    fn unlock_paste<'response>(
+        &mut self,
+        id: &str,
+        request: &Request,
+        raw: bool,
+    ) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_paste(id)? {
+            Ok(paste) if paste.protected => paste,
+            Ok(_paste) => return Ok(Response::from_static(404, "")),
+            Err(response) => return Ok(response),
+        };
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let password = form.get("password").unwrap_or_default();
+        let key = format!("unlock:{}", paste.id);
+        if let Err(retry_after) = self.rate_limiter.acquire(&key, UNLOCK_QUOTA) {
+            tracing::debug!("too many unlock attempts for paste {}", paste.id);
+            // Round up, so that retrying on time succeeds.
+            let retry_after = retry_after.as_millis().div_ceil(1000);
+            return Ok(Response::from_static(429, "Too many attempts, try again later.\n")
+                .with_header("retry-after", retry_after.to_string()));
+        }
+        let content = match crypto::open(password, &paste.content) {
+            Ok(content) => content,
+            Err(e) if e.is_wrong_password() => {
+                return Ok(if raw {
+                    Response::from_static(403, "Wrong password.\n")
+                } else {
+                    let csrf_token = self.csrf_token(request);
+                    unlock_form(&paste, 403, Some("Wrong password."), csrf_token.as_deref())
+                });
+            }
+            Err(e) => {
+                tracing::error!("failed to decrypt paste {}: {}", paste.id, e);
+                return Ok(Response::from_static(500, ""));
+            }
+        };
+        let mut paste = match self.burn_paste(paste)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        paste.content = content;
+        if raw {
+            return Ok(Response::text(200, paste.content));
+        }
+        let revision = paste.revision;
+        Ok(self.render_paste(paste, revision, request))
+    }
+
+    #[inline]
+    fn show_revision<'response>(
+        &mut self,
+        id: &str,
+        revision: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let (mut paste, revision) = match self.find_revision(id, revision)? {
+            Ok(found) => found,
+            Err(response) => return Ok(response),
+        };
+        paste.title = revision.title;
+        paste.language = revision.language;
+        paste.content = revision.content;
+        Ok(self.render_paste(paste, revision.revision, request))
+    }
+
+    /// Renders `paste` as HTML, with the title, language and content of
+    /// `revision`.
+    #[inline]
+    fn render_paste<'response>(
+        &mut self,
+        paste: Paste,
+        revision: i32,
+        request: &Request,
+    ) -> Response<'response> {
+        let markdown = markdown::is_markdown(paste.language.as_deref(), paste.title.as_deref());
+        let language = if markdown {
+            None
+        } else {
+            highlight::detect(
+                paste.language.as_deref(),
+                paste.title.as_deref(),
+                &paste.content,
+            )
+        };
+        let mut stylesheet = highlight::STYLESHEET.to_string();
+        if markdown {
+            stylesheet.push_str(markdown::STYLESHEET);
+        }
+        let content = match language {
+            _ if markdown => {
+                let render = || markdown::render(&paste.content);
+                PasteContent::Markdown(self.render_cached(&paste, revision, render))
+            }
+            Some(language) => {
+                let highlight = || highlight::highlight(language, &paste.content);
+                PasteContent::Code(
+                    language.name(),
+                    self.render_cached(&paste, revision, highlight),
+                )
+            }
+            None => PasteContent::Text(&paste.content),
+        };
+        let language_name = language.map(|l| l.name()).or(markdown.then_some("markdown"));
+        let raw = if revision == paste.revision {
+            format!("/p/{}/raw", paste.id)
+        } else {
+            format!("/p/{}/rev/{}/raw", paste.id, revision)
+        };
+        let csrf_token = self.csrf_token(request);
+        let nonce = crate::http::generate_nonce();
+        let page = PastePage {
+            paste: &paste,
+            title: paste.title.as_deref().unwrap_or(&paste.id),
+            language: paste.language.as_deref().or(language_name),
+            stylesheet,
+            expires_in: expires_in(&paste),
+            revision,
+            raw,
+            content,
+            csrf_token: csrf_token.as_deref(),
+            nonce: &nonce,
+        };
+        templates::render(200, &page).with_nonce(nonce)
+    }
+
+    /// Returns the HTML of `revision` of `paste` from the cache, or renders
+    /// it with `render`.
+    #[inline]
+    fn render_cached(
+        &self,
+        paste: &Paste,
+        revision: i32,
+        render: impl FnOnce() -> String,
+    ) -> String {
+        if paste.burn_after_reading || paste.protected {
+            // Either it's gone after this view, or it mustn't be kept in
+            // plain text, so don't cache it.
+            render()
+        } else {
+            // Revisions never change, unlike the paste itself.
+            let key = format!("{}/{}", paste.id, revision);
+            self.highlight_cache.get_or_insert_with(&key, render)
+        }
+    }
+
+    #[inline]
+    fn raw_paste<'response>(&mut self, id: &str) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        if paste.protected {
+            return Ok(Response::from_static(
+                403,
+                "This paste is protected, POST its password as `password` to view it.\n",
+            ));
+        }
+        // Read the blob before burning the paste, after which it could be
+        // purged.
+        let blob = match &paste.file {
+            Some(file) => match self.store()?.get_blob(&file.hash)? {
+                Some(data) => Some(data),
+                None => {
+                    tracing::error!("missing blob {} of paste {}", file.hash, paste.id);
+                    return Ok(Response::from_static(500, ""));
+                }
+            },
+            None => None,
+        };
+        match (self.burn_paste(paste)?, blob) {
+            (Ok(paste), Some(data)) => Ok(file_response(paste.file.as_ref().unwrap(), data)),
+            (Ok(paste), None) => Ok(Response::text(200, paste.content)),
+            (Err(response), _) => Ok(response),
+        }
+    }
+
+    #[inline]
+    fn raw_revision<'response>(
+        &mut self,
+        id: &str,
+        revision: &str,
+    ) -> Result<Response<'response>, StoreError> {
+        match self.find_revision(id, revision)? {
+            Ok((_paste, revision)) => Ok(Response::text(200, revision.content)),
+            Err(response) => Ok(response),
+        }
+    }
+
+    #[inline]
+    fn edit_paste_form<'response>(
+        &mut self,
+        id: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_live_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        if !self.may_change(request, paste.owner) {
+            return Ok(Response::from_static(403, "This paste isn't yours.\n"));
+        }
+        let csrf_token = self.csrf_token(request);
+        let page = EditPastePage {
+            paste: &paste,
+            csrf_token: csrf_token.as_deref(),
+        };
+        Ok(templates::render(200, &page))
+    }
+
+    #[inline]
+    fn edit_paste<'response>(
+        &mut self,
+        id: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_live_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        if !self.may_change(request, paste.owner) {
+            return Ok(Response::from_static(403, "This paste isn't yours.\n"));
+        }
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let fields = match paste_fields(&form) {
+            Some(fields) => fields,
+            None => return Ok(Response::from_static(400, "")),
+        };
+        let location = format!("/p/{}", paste.id);
+        // Saving without changes doesn't make a revision.
+        if fields.title == paste.title.as_deref()
+            && fields.language == paste.language.as_deref()
+            && fields.content == paste.content
+        {
+            return Ok(Response::redirect(&location));
+        }
+        match self.store()?.add_revision(&paste.id, fields)? {
+            Some(revision) => {
+                tracing::debug!("added revision {} to paste {}", revision.revision, paste.id);
+                Ok(Response::redirect(&location))
+            }
+            // Deleted in the meantime.
+            None => Ok(Response::from_static(404, "")),
+        }
+    }
+
+    #[inline]
+    fn fork_paste<'response>(
+        &mut self,
+        id: &str,
+        request: &Request,
+    ) -> Result<Response<'response>, StoreError> {
+        let form = match read_form(request) {
+            Ok(form) => form,
+            Err(response) => return Ok(response),
+        };
+        let revision = match optional_field(&form, "revision") {
+            Some(revision) => revision.to_string(),
+            // Fork the latest revision by default.
+            None => match self.find_live_paste(id)? {
+                Ok(paste) => paste.revision.to_string(),
+                Err(response) => return Ok(response),
+            },
+        };
+        let (_paste, revision) = match self.find_revision(id, &revision)? {
+            Ok(found) => found,
+            Err(response) => return Ok(response),
+        };
+        // Forks belong to whoever forked them.
+        let owner = self.account_id(request);
+        let fork = self.store()?.create_paste(NewPaste {
+            title: revision.title.as_deref(),
+            language: revision.language.as_deref(),
+            content: &revision.content,
+            expires_in: None,
+            burn_after_reading: false,
+            parent: Some(RevisionRef {
+                paste_id: id.to_string(),
+                revision: revision.revision,
+            }),
+            protected: false,
+            file: None,
+            owner,
+        })?;
+        tracing::debug!("forked paste {} into {}", id, fork.id);
+        Ok(Response::redirect(&format!("/p/{}/edit", fork.id)))
+    }
+
+    #[inline]
+    fn paste_history<'response>(&mut self, id: &str) -> Result<Response<'response>, StoreError> {
+        let paste = match self.find_live_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(response),
+        };
+        let revisions = self.store()?.list_revisions(&paste.id)?;
+        let now = crate::store::unix_time();
+        let page = HistoryPage {
+            paste: &paste,
+            revisions: revisions
+                .iter()
+                .rev()
+                .map(|revision| (revision, format_lifetime(now - revision.created_at)))
+                .collect(),
+        };
+        Ok(templates::render(200, &page))
+    }
+
+    #[inline]
+    fn diff_paste<'response>(
+        &mut self,
+        id: &str,
+        old: &str,
+        new: &str,
+        view: DiffView,
+    ) -> Result<Response<'response>, StoreError> {
+        let (paste, old) = match self.find_revision(id, old)? {
+            Ok(found) => found,
+            Err(response) => return Ok(response),
+        };
+        let new = match self.find_revision(id, new)? {
+            Ok((_paste, new)) => new,
+            Err(response) => return Ok(response),
+        };
+        let changes = diff::diff_lines(&old.content, &new.content);
+        let old_path = format!("/p/{}/rev/{}", paste.id, old.revision);
+        let new_path = format!("/p/{}/rev/{}", paste.id, new.revision);
+        if let DiffView::Raw = view {
+            let diff = diff::unified(&old_path, &new_path, &changes, DIFF_CONTEXT);
+            return Ok(Response::text(200, diff));
+        }
+
+        let diff_path = format!("/p/{}/diff/{}/{}", paste.id, old.revision, new.revision);
+        let hunks = diff::hunks(&changes, DIFF_CONTEXT);
+        let side_by_side = match view {
+            DiffView::SideBySide => Some(hunks.iter().map(diff_rows).collect()),
+            _ => None,
+        };
+        let page = DiffPage {
+            paste_id: &paste.id,
+            old_path: &old_path,
+            old_revision: old.revision,
+            new_path: &new_path,
+            new_revision: new.revision,
+            diff_path: &diff_path,
+            hunks,
+            side_by_side,
+        };
+        Ok(templates::render(200, &page))
+    }
+
+    /// Lets a paste be shown to the client, returning the error response if
+    /// it can't be. Burn-after-reading pastes are deleted, and only the
+    /// request which deleted one gets to see it.
+    #[inline]
+    fn burn_paste<'response>(
+        &mut self,
+        paste: Paste,
+    ) -> Result<Result<Paste, Response<'response>>, StoreError> {
+        if paste.burn_after_reading && !self.store()?.delete_paste(&paste.id)? {
+            // Someone else read it first.
+            return Ok(Err(Response::from_static(404, "")));
+        }
+        Ok(Ok(paste))
+    }
+
+    /// Looks up a paste whose history can be seen and changed, which
+    /// burn-after-reading, protected and file pastes can't be.
+    #[inline]
+    fn find_live_paste<'response>(
+        &mut self,
+        id: &str,
+    ) -> Result<Result<Paste, Response<'response>>, StoreError> {
+        Ok(match self.find_paste(id)? {
+            Ok(paste) if paste.burn_after_reading || paste.protected || paste.file.is_some() => {
+                Err(Response::from_static(404, ""))
+            }
+            result => result,
+        })
+    }
+
+    /// Looks up a revision of a paste, see [`App::find_live_paste`].
+    #[inline]
+    fn find_revision<'response>(
+        &mut self,
+        id: &str,
+        revision: &str,
+    ) -> Result<Result<(Paste, Revision), Response<'response>>, StoreError> {
+        let revision = match parse_revision(revision) {
+            Some(revision) => revision,
+            None => return Ok(Err(Response::from_static(404, ""))),
+        };
+        let paste = match self.find_live_paste(id)? {
+            Ok(paste) => paste,
+            Err(response) => return Ok(Err(response)),
+        };
+        Ok(match self.store()?.get_revision(&paste.id, revision)? {
+            Some(revision) => Ok((paste, revision)),
+            None => Err(Response::from_static(404, "")),
+        })
+    }
+
+    /// Looks up a paste which hasn't expired.
+    #[inline]
+    fn find_paste<'response>(
+        &mut self,
+        id: &str,
+    ) -> Result<Result<Paste, Response<'response>>, StoreError> {
+        // Don't query the store for IDs which can't exist.
+        if !crate::store::is_valid_paste_id(id) {
+            return Ok(Err(Response::from_static(404, "")));
+        }
+        let paste = match self.store()?.get_paste(id)? {
+            Some(paste) => paste,
+            None => return Ok(Err(Response::from_static(404, ""))),
+        };
+        // Expired pastes are kept until the reaper purges them.
+        if paste.is_expired(crate::store::unix_time()) {
+            return Ok(Err(Response::from_static(410, "")));
+        }
+        Ok(Ok(paste))
+    }
+}
+
+#[inline]
+fn new_paste<'response>(csrf_token: Option<&str>) -> Response<'response> {
+    templates::render(200, &NewPastePage { csrf_token })
+}
+
+/// Returns the page of a file paste, which links to the file rather than
+/// showing it, except for images.
+#[inline]
+fn file_page<'response>(paste: &Paste, file: &PasteFile) -> Response<'response> {
+    let page = FilePage {
+        paste,
+        file,
+        title: paste.title.as_deref().unwrap_or(&file.name),
+        expires_in: expires_in(paste),
+        is_image: mime::is_image(&file.media_type),
+    };
+    templates::render(200, &page)
+}
+
+/// Returns the content of a file paste. Only types which are safe to show
+/// are shown by the browser, others are downloaded.
+#[inline]
+fn file_response<'response>(file: &PasteFile, data: Vec<u8>) -> Response<'response> {
+    let disposition = if mime::is_inline(&file.media_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    Response::from_bytes(200, data)
+        .with_header("content-type", &file.media_type)
+        .with_header(
+            "content-disposition",
+            content_disposition(disposition, &file.name),
+        )
+        // Browsers mustn't second-guess the type, e.g. run a file as HTML.
+        .with_header("x-content-type-options", "nosniff")
+}
+
+/// Formats a `content-disposition` header with a file name, both as ASCII
+/// for old clients and UTF-8 (RFC 6266).
+#[inline]
+fn content_disposition(disposition: &str, name: &str) -> String {
+    let ascii: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-'
+            | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, ascii, encoded
+    )
+}
+
+/// Returns whether an account, or `None` if signed out, may change an item
+/// owned by `owner`. Items without an owner were created anonymously, and
+/// anyone may change them.
+#[inline]
+fn may_change(account_id: Option<i64>, owner: Option<i64>) -> bool {
+    owner.is_none() || account_id == owner
+}
+
+/// Tells the client about its quota with the `ratelimit-*` headers of the
+/// IETF draft, and when to retry if it was refused.
+#[inline]
+fn with_rate_limit(response: Response, quota: Quota, status: Status) -> Response {
+    // Round up, so that retrying on time succeeds.
+    let seconds = |duration: Duration| duration.as_millis().div_ceil(1000).to_string();
+    let response = response
+        .with_header("ratelimit-limit", quota.capacity.to_string())
+        .with_header("ratelimit-remaining", status.remaining.to_string())
+        .with_header("ratelimit-reset", seconds(status.reset));
+    match status.retry_after {
+        Some(retry_after) => response.with_header("retry-after", seconds(retry_after)),
+        None => response,
+    }
+}
+
+/// Returns the token of an `Authorization: Bearer` header.
+#[inline]
+fn bearer_token(authorization: &[u8]) -> Option<&str> {
+    let authorization = std::str::from_utf8(authorization).ok()?;
+    let (scheme, token) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+/// Reads the JSON body of an API request, or returns the error response.
+#[inline]
+fn read_json<'response, T: serde::de::DeserializeOwned>(
+    request: &Request,
+) -> Result<T, Response<'response>> {
+    let content_type = request.headers().get("content-type").unwrap_or_default();
+    let media_type = content_type.split(|b| *b == b';').next().unwrap_or_default();
+    if !media_type.trim_ascii().eq_ignore_ascii_case(b"application/json") {
+        return Err(api_error(415, "expected application/json"));
+    }
+    serde_json::from_slice(request.body().as_bytes()).map_err(|e| {
+        tracing::debug!("JSON error: {}", e);
+        api_error(400, "invalid JSON")
+    })
+}
+
+#[inline]
+fn json_response<'response>(code: u16, value: &impl Serialize) -> Response<'response> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::json(code, json),
+        Err(e) => {
+            tracing::error!("JSON error: {}", e);
+            Response::from_static(500, "")
+        }
+    }
+}
+
+/// Returns an API error, with a JSON body such as `{"error": "Not Found"}`.
+#[inline]
+fn api_error<'response>(code: u16, message: &str) -> Response<'response> {
+    json_response(code, &serde_json::json!({ "error": message }))
+}
+
+#[inline]
+fn api_todo(todo: &Todo) -> ApiTodo<'_> {
+    ApiTodo {
+        id: todo.id,
+        content: &todo.content,
+    }
+}
+
+#[inline]
+fn api_paste(paste: &Paste) -> ApiPaste<'_> {
+    let readable = !paste.protected && paste.file.is_none();
+    ApiPaste {
+        id: &paste.id,
+        title: paste.title.as_deref(),
+        language: paste.language.as_deref(),
+        content: readable.then_some(paste.content.as_str()),
+        revision: paste.revision,
+        created_at: paste.created_at,
+        expires_at: paste.expires_at,
+        burn_after_reading: paste.burn_after_reading,
+        protected: paste.protected,
+        file: paste.file.as_ref(),
+    }
+}
+
+/// Returns the registration form, showing `message` about the submitted
+/// `name`.
+#[inline]
+fn register_form<'response>(
+    code: u16,
+    name: &str,
+    message: Option<&str>,
+    csrf_token: Option<&str>,
+) -> Response<'response> {
+    let page = RegisterPage {
+        name,
+        message,
+        csrf_token,
+    };
+    templates::render(code, &page)
+}
+
+/// Returns the login form, showing `message` about the submitted `name`.
+#[inline]
+fn login_form<'response>(
+    code: u16,
+    name: &str,
+    message: Option<&str>,
+    csrf_token: Option<&str>,
+) -> Response<'response> {
+    let page = LoginPage {
+        name,
+        message,
+        csrf_token,
+    };
+    templates::render(code, &page)
+}
+
+/// Returns the page asking for the password of a protected paste.
+#[inline]
+fn unlock_form<'response>(
+    paste: &Paste,
+    code: u16,
+    message: Option<&str>,
+    csrf_token: Option<&str>,
+) -> Response<'response> {
+    let page = UnlockPage {
+        paste,
+        title: paste.title.as_deref().unwrap_or(&paste.id),
+        message,
+        csrf_token,
+    };
+    templates::render(code, &page)
+}
+
+/// Returns how long until `paste` expires, if it does.
+#[inline]
+fn expires_in(paste: &Paste) -> Option<String> {
+    let expires_at = paste.expires_at?;
+    Some(format_lifetime(expires_at - crate::store::unix_time()))
+}
+
+/// Lays out a hunk side by side.
+#[inline]
+fn diff_rows<'a>(hunk: &Hunk<'_, 'a>) -> Vec<DiffRow<'a>> {
+    let cell = |(number, line): (usize, &'a str)| DiffCell {
+        number,
+        line: line.strip_suffix('\n').unwrap_or(line),
+    };
+    diff::side_by_side(hunk)
+        .into_iter()
+        .map(|(left, right)| DiffRow {
+            changed: left.map(|(_, line)| line) != right.map(|(_, line)| line),
+            left: left.map(cell),
+            right: right.map(cell),
+        })
+        .collect()
+}
+
+/// Reads the form submitted with a request, or returns the error response.
+#[inline]
+fn read_form<'response>(request: &Request) -> Result<Form, Response<'response>> {
+    Form::from_request(request).map_err(|e| {
+        if e.is_unsupported_media_type() {
+            return Response::from_static(415, "");
+        }
+        tracing::debug!("form error: {}", e);
+        Response::from_static(400, "")
+    })
+}
+
+/// Validates the fields shared by new pastes and their revisions.
+#[inline]
+fn paste_fields<'form>(form: &'form Form) -> Option<NewRevision<'form>> {
+    let content = form.get("content").filter(|content| !content.is_empty())?;
+    let (title, language) = paste_metadata(form)?;
+    Some(NewRevision {
+        title,
+        language,
+        content,
+    })
+}
 
-use crate::http::{Handler, Method, Request, Response};
+/// Validates the optional title and language of a paste.
+#[inline]
+fn paste_metadata(form: &Form) -> Option<(Option<&str>, Option<&str>)> {
+    let title = optional_field(form, "title");
+    let language = optional_field(form, "language");
+    is_valid_paste_metadata(title, language).then_some((title, language))
+}
 
-pub(crate) struct App;
+/// Validates the optional title and language of a paste.
+#[inline]
+fn is_valid_paste_metadata(title: Option<&str>, language: Option<&str>) -> bool {
+    if title.map_or(false, |title| title.chars().count() > MAX_PASTE_TITLE_LENGTH) {
+        return false;
+    }
+    if language.map_or(false, |language| !is_valid_language(language)) {
+        return false;
+    }
+    true
+}
 
-impl Handler for App {
-    #[inline]
-    fn handle<'request, 'response>(request: &'request Request<'request>) -> Response<'response> {
-        tracing::trace!("App server handling request");
-        match (request.method(), request.path()) {
-            (Method::Get, "/") => {
-                // FIXME: uncomment
-                //assert_eq!(request.body(), b"");
-                const BODY: &str = "<html>\
-                  <head>\
-                    <title>Home</title>\
-                  </head>\
-                  <body>\
-                  <form method=\"post\" action\"/\" enctype=\"multipart/form-data\">\
-                    <label for=\"content\">TODO:</label>\
-                    <input type=\"text\" name=\"content\" id=\"content\" required>\
-                  </form>\
-                  </body>\
-                  </html>";
-                Response::from_static(200, BODY)
-            }
-            (Method::Post, "/") => {
-                // TODO: remove this log statement.
-                tracing::info!(
-                    "headers: {:?}",
-                    request
-                        .headers()
-                        .iter()
-                        .map(|(n, v)| (n, String::from_utf8_lossy(v)))
-                        .collect::<Vec<_>>()
-                );
-                tracing::info!("body: {}", request.body().to_string().unwrap());
-                unimplemented!();
-            }
-            (_method, _path) => Response::from_static(404, ""),
-        }
+/// Returns the name of an uploaded file, without the directories some
+/// browsers send or control characters, and short enough to be a title.
+#[inline]
+fn file_name(filename: Option<&str>) -> String {
+    let name = filename
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_PASTE_TITLE_LENGTH)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        name => name.to_string(),
     }
 }
 
-#[cfg(target_arch = "wasm32")]
+/// Returns the trimmed value of a form field, or `None` if it's missing or
+/// blank.
 #[inline]
-pub(crate) fn server(handler: for<'r, 's> fn(&'r Request<'s>) -> Response<'r>) {
-    let handler_int = crate::http::handler_as_int(handler);
-    fn server_((parent, handler_int): (Process<()>, usize), mailbox: Mailbox<()>) {
-        let handler = crate::http::handler_from_int(handler_int);
-        crate::http::server((parent, handler, ([0, 0, 0, 0], 3000)), mailbox)
+fn optional_field<'form>(form: &'form Form, name: &str) -> Option<&'form str> {
+    form.get(name).map(str::trim).filter(|value| !value.is_empty())
+}
+
+/// Parses a paste lifetime like `30s`, `10m`, `1h`, `1d` or `1w`.
+#[inline]
+fn parse_lifetime(lifetime: &str) -> Option<Duration> {
+    let unit = match lifetime.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = lifetime[..lifetime.len() - 1].parse().ok()?;
+    let lifetime = Duration::from_secs(count.checked_mul(unit)?);
+    Some(lifetime).filter(|lifetime| !lifetime.is_zero() && *lifetime <= MAX_PASTE_LIFETIME)
+}
+
+/// Formats a number of seconds in the largest whole unit, e.g. `3 hours`.
+#[inline]
+fn format_lifetime(seconds: i64) -> String {
+    let (count, unit) = match seconds.max(0) {
+        s if s >= 24 * 60 * 60 => (s / (24 * 60 * 60), "day"),
+        s if s >= 60 * 60 => (s / (60 * 60), "hour"),
+        s if s >= 60 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    if count == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", count, unit)
     }
+}
+
+/// Languages are identifiers like `rust`, `c++` or `objective-c`.
+#[inline]
+fn is_valid_language(language: &str) -> bool {
+    language.len() <= MAX_PASTE_LANGUAGE_LENGTH
+        && language
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c))
+}
+
+/// Splits a path of the form `/p/:id/...` into the ID and the remaining
+/// segments.
+#[inline]
+fn paste_route(path: &str) -> Option<(&str, Vec<&str>)> {
+    let mut segments = path.strip_prefix("/p/")?.split('/');
+    let id = segments.next()?;
+    Some((id, segments.collect()))
+}
+
+/// Parses a revision number, which starts at 1.
+#[inline]
+fn parse_revision(revision: &str) -> Option<i32> {
+    revision.parse().ok().filter(|revision| *revision > 0)
+}
+
+/// Returns `true` if `path` is part of the JSON API.
+#[inline]
+fn is_api_path(path: &str) -> bool {
+    path == "/api" || path.starts_with("/api/")
+}
+
+/// Splits a path of the form `<prefix>:id/:action`, e.g. `/todo/:id/:action`.
+#[inline]
+fn item_action<'a>(prefix: &str, path: &'a str) -> Option<(i64, &'a str)> {
+    let (id, action) = path.strip_prefix(prefix)?.split_once('/')?;
+    Some((id.parse().ok()?, action))
+}
+
+/// Starts a server process listening on each of `bind_addrs`, returning the
+/// addresses they're bound to, e.g. to learn the port picked for port 0.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+pub(crate) fn server(config: Config, bind_addrs: &BindAddrs) -> Vec<SocketAddr> {
     tracing::info!("starting application");
-    let mailbox = unsafe { Mailbox::new() };
+    let mailbox = unsafe { Mailbox::<Result<SocketAddr, String>>::new() };
     let this = process::this(&mailbox);
-    // Run the entire application in a lunatic process because `println!`
-    // doesn't work outside of one.
-    tracing::info!("spawning server process");
-    crate::spawn_with!((this, handler_int), server_).unwrap();
-    // Wait for the server to initialize.
-    mailbox.receive().unwrap();
+    bind_addrs
+        .addrs()
+        .iter()
+        .map(|addr| {
+            // Run the entire application in lunatic processes because
+            // `println!` doesn't work outside of one.
+            tracing::info!("spawning server process for {}", addr);
+            let context = (this.clone(), config.clone(), *addr);
+            crate::spawn_with!(context, crate::http::server::<App>).unwrap();
+            // Wait for the server to initialize.
+            match mailbox.receive().unwrap() {
+                Ok(local_addr) => {
+                    tracing::info!("listening on {}", local_addr);
+                    local_addr
+                }
+                Err(e) => panic!("failed to bind {}: {}", addr, e),
+            }
+        })
+        .collect()
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 #[inline]
-pub(crate) fn server(
-    handler: for<'r, 's> fn(&'r Request<'s>) -> Response<'r>,
-    callback: fn(u16),
-    port: u16,
-) {
+pub(crate) fn server<H>(config: H::Config, callback: fn(u16))
+where
+    H: Handler,
+    H::Config: Send + Sync + 'static,
+{
     use std::sync::Arc;
 
     let rt = tokio::runtime::Builder::new_current_thread()
@@ -79,8 +1820,9 @@ pub(crate) fn server(
         .build()
         .unwrap();
     rt.block_on(async {
-        async fn handle(
-            handler: Arc<for<'r, 's> fn(&'r Request<'s>) -> Response<'r>>,
+        async fn handle<H: Handler>(
+            config: Arc<H::Config>,
+            (peer, local_addr): (std::net::SocketAddr, std::net::SocketAddr),
             hyper_request: hyper::Request<hyper::Body>,
         ) -> hyper::Result<hyper::Response<hyper::Body>> {
             let (parts, body) = hyper_request.into_parts();
@@ -93,25 +1835,37 @@ pub(crate) fn server(
             };
             let uri = parts.uri.to_string();
             let headers = parts.headers.into();
-            let request = crate::http::from_parts(uri, parts.method.as_str(), headers, &body);
-            let response = handler(&request);
+            let request = crate::http::from_parts(uri, parts.method.as_str(), headers, &body)
+                .with_addrs(peer, local_addr);
+            // Like a connection process, construct the handler from a copy
+            // of the configuration.
+            let mut handler = H::new((*config).clone());
+            let mut response = handler.handle(&request);
+            if let Some(security_headers) = handler.security_headers() {
+                security_headers.apply(&mut response);
+            }
             let hyper_response = response.into();
             Ok(hyper_response)
         }
 
-        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        // Port 0, so that tests can run in parallel.
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
 
-        let handler = Arc::new(handler);
-        let make_svc = hyper::service::make_service_fn(move |_conn| {
-            let handler = handler.clone();
-            async move {
-                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
-                    let handler = handler.clone();
-                    async move { handle(handler, req).await }
-                }))
-            }
-        });
+        let config = Arc::new(config);
+        let make_svc = hyper::service::make_service_fn(
+            move |conn: &hyper::server::conn::AddrStream| {
+                let config = config.clone();
+                let addrs = (conn.remote_addr(), conn.local_addr());
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                        let config = config.clone();
+                        async move { handle::<H>(config, addrs, req).await }
+                    }))
+                }
+            },
+        );
         let server = hyper::Server::bind(&addr).serve(make_svc);
+        let port = server.local_addr().port();
         tracing::info!("server listening on 127.0.0.1:{}", port);
 
         let (tx, rx) = tokio::sync::oneshot::channel::<()>();
@@ -134,42 +1888,1421 @@ pub(crate) fn server(
 
 #[cfg(all(test, target_arch = "wasm32"))]
 mod test {
-    use crate::{
-        app::App,
-        http::{Client, Handler, Method},
+    use crate::app::Config;
+    use crate::highlight::HighlightCache;
+    use crate::http::{
+        BindAddrs, Client, CookieKey, Cors, Method, SecurityHeaders, Timeouts, TrustedProxies,
+        MAX_REQUEST_BODY_SIZE,
     };
+    use crate::postgres::PoolConfig;
+    use crate::ratelimit::{RateLimiter, RouteQuotas};
+    use crate::session::Sessions;
+    use crate::store::{StoreConfig, StoreHandle};
 
     #[test]
     fn test_get() {
-        crate::app::server(App::handle);
+        let bind_addrs = BindAddrs::parse("127.0.0.1:0").unwrap();
+        let addrs = crate::app::server(
+            Config {
+                store: StoreHandle::spawn(StoreConfig::Memory, PoolConfig::default()).unwrap(),
+                highlight_cache: HighlightCache::Disabled,
+                rate_limiter: RateLimiter::Disabled,
+                cookie_key: CookieKey::generate(),
+                sessions: Sessions::Disabled,
+                secure_cookies: false,
+                security_headers: SecurityHeaders::default(),
+                cors: Cors::default(),
+                route_quotas: RouteQuotas::none(),
+                trusted_proxies: TrustedProxies::default(),
+                proxy_protocol: false,
+                max_body_size: MAX_REQUEST_BODY_SIZE,
+                timeouts: Timeouts::default(),
+            },
+            &bind_addrs,
+        );
 
-        let tcp_stream = lunatic::net::TcpStream::connect("127.0.0.1:3000")
-            .unwrap()
-            .into();
+        let tcp_stream = lunatic::net::TcpStream::connect(addrs[0]).unwrap().into();
         let mut client = Client::new(tcp_stream).unwrap();
         let response = client.request(Method::Get, "/").unwrap();
         assert_eq!(response.code(), 200);
         assert_eq!(response.reason(), "OK");
-        assert_eq!(response.headers().len(), 2);
+        // `content-length`, `date`, `content-type` and the security headers.
+        assert_eq!(response.headers().len(), 7);
+        assert_eq!(
+            response.headers().get("content-type"),
+            Some(&b"text/html; charset=utf-8"[..])
+        );
+        let csp = response.headers().get("content-security-policy").unwrap();
+        assert!(csp.starts_with(b"default-src 'self'"));
+        assert_eq!(
+            response.headers().get("strict-transport-security"),
+            Some(&b"max-age=31536000"[..])
+        );
+        assert_eq!(
+            response.headers().get("x-content-type-options"),
+            Some(&b"nosniff"[..])
+        );
+        assert_eq!(
+            response.headers().get("referrer-policy"),
+            Some(&b"strict-origin-when-cross-origin"[..])
+        );
     }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod test {
-    use crate::app::App;
-    use crate::http::{Client, Handler, Method};
+    use crate::app::{App, Config};
+    use crate::highlight::HighlightCache;
+    use crate::http::{
+        Client, CookieKey, Cors, Method, SecurityHeaders, Timeouts, TrustedProxies,
+        MAX_REQUEST_BODY_SIZE,
+    };
+    use crate::ratelimit::{Buckets, RateLimiter, RouteQuotas};
+    use crate::session::{SessionTable, Sessions};
+    use crate::store::{Paste, StoreConfig, StoreHandle};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Returns a configuration with a file store, which unlike the in-memory
+    /// store persists across handlers.
+    fn config() -> Config {
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        Config {
+            store: StoreHandle::Local(StoreConfig::File { path }),
+            highlight_cache: HighlightCache::Disabled,
+            rate_limiter: RateLimiter::Local(Arc::new(Mutex::new(Buckets::new(100)))),
+            cookie_key: CookieKey::generate(),
+            sessions: Sessions::Local(Arc::new(Mutex::new(SessionTable::new(100, None)))),
+            secure_cookies: false,
+            security_headers: SecurityHeaders::default(),
+            cors: Cors::default(),
+            route_quotas: RouteQuotas::none(),
+            trusted_proxies: TrustedProxies::default(),
+            proxy_protocol: false,
+            max_body_size: MAX_REQUEST_BODY_SIZE,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Returns the CSRF token of the session `cookie`, from a form.
+    fn csrf_token(client: &mut Client, cookie: &str) -> String {
+        let response = client
+            .request_with_body(Method::Get, "/p", &[("cookie", cookie)], b"")
+            .unwrap();
+        let page = response.body().to_string().unwrap();
+        let field = "name=\"csrf_token\" value=\"";
+        let start = page.find(field).unwrap() + field.len();
+        let end = start + page[start..].find('"').unwrap();
+        page[start..end].to_string()
+    }
+
+    #[test]
+    fn test_parse_lifetime() {
+        use super::parse_lifetime;
+        assert_eq!(parse_lifetime("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_lifetime("1w"), Some(Duration::from_secs(604800)));
+        assert_eq!(parse_lifetime("0s"), None);
+        assert_eq!(parse_lifetime("366d"), None);
+        assert_eq!(parse_lifetime("10"), None);
+        assert_eq!(parse_lifetime("m"), None);
+        assert_eq!(parse_lifetime("1ü"), None);
+        assert_eq!(super::format_lifetime(7200), "2 hours");
+        assert_eq!(super::format_lifetime(60), "1 minute");
+    }
+
     #[test]
     fn test_get() {
         let callback = |port| {
-            let tcp_stream = std::net::TcpStream::connect("127.0.0.1:3000")
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
                 .unwrap()
                 .into();
             let mut client = Client::new(tcp_stream).unwrap();
             let response = client.request(Method::Get, "/").unwrap();
             assert_eq!(response.code(), 200);
             assert_eq!(response.reason(), "OK");
-            assert_eq!(response.headers().len(), 2);
+            // `content-length`, `date`, `content-type` and the security
+            // headers.
+            assert_eq!(response.headers().len(), 7);
+            assert_eq!(
+                response.headers().get("content-type"),
+                Some(&b"text/html; charset=utf-8"[..])
+            );
+            let csp = response.headers().get("content-security-policy").unwrap();
+            assert!(csp.starts_with(b"default-src 'self'"));
+            assert_eq!(
+                response.headers().get("strict-transport-security"),
+                Some(&b"max-age=31536000"[..])
+            );
+            assert_eq!(
+                response.headers().get("x-content-type-options"),
+                Some(&b"nosniff"[..])
+            );
+            assert_eq!(
+                response.headers().get("referrer-policy"),
+                Some(&b"strict-origin-when-cross-origin"[..])
+            );
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_create_todo() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=%3Cb%3Emilk%3C%2Fb%3E",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            assert_eq!(response.headers().get("location"), Some(&b"/"[..]));
+            let response = client.request(Method::Get, "/").unwrap();
+            assert_eq!(response.code(), 200);
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<li>&lt;b&gt;milk&lt;/b&gt;"), "{}", body);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=*oat*+milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let response = client.request(Method::Get, "/").unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<li><em>oat</em> milk"), "{}", body);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 400);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_create_paste() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"title=Hi&language=rust&content=fn+main%28%29+%7B%7D%0A%3C%2Fpre%3E",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            assert!(location.starts_with("/p/"), "{}", location);
+
+            let response = client.request(Method::Get, &location).unwrap();
+            assert_eq!(response.code(), 200);
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<h1>Hi</h1>"), "{}", body);
+            assert!(
+                body.contains(
+                    "<code class=\"language-rust\"><span class=\"hl-keyword\">fn</span> \
+                     <span class=\"hl-function\">main</span>() {}\n&lt;/pre&gt;"
+                ),
+                "{}",
+                body
+            );
+            // Only our own stylesheet may be applied.
+            let policy = response.headers().get("content-security-policy").unwrap();
+            let policy = std::str::from_utf8(policy).unwrap();
+            let start = policy.find("style-src 'self' 'nonce-").unwrap() + 24;
+            let nonce = &policy[start..start + policy[start..].find('\'').unwrap()];
+            assert!(body.contains(&format!("<style nonce=\"{}\">", nonce)), "{}", body);
+            assert_eq!(response.headers().get("x-content-type-options"), Some(&b"nosniff"[..]));
+
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            assert_eq!(response.code(), 200);
+            assert_eq!(
+                response.headers().get("content-type"),
+                Some(&b"text/plain; charset=utf-8"[..])
+            );
+            assert_eq!(
+                response.body().to_string().unwrap(),
+                "fn main() {}\n</pre>"
+            );
+
+            // Markdown is rendered, but the raw paste is still the source.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"title=README.md&content=%23+Hi%0A%0A%3Cscript%3E",
+                )
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client.request(Method::Get, &location).unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<p>Language: markdown</p>"), "{}", body);
+            assert!(
+                body.contains("<div class=\"markdown\"><h1>Hi</h1>\n&lt;script&gt;</div>"),
+                "{}",
+                body
+            );
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            assert_eq!(response.body().to_string().unwrap(), "# Hi\n\n<script>");
+
+            let response = client.request(Method::Get, "/p/00000000").unwrap();
+            assert_eq!(response.code(), 404);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"language=not+a+language&content=x",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 400);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_paste_expiry() {
+        let config = config();
+        // Add an expired paste which the reaper hasn't purged yet.
+        let mut store = config.store.open().unwrap();
+        let now = crate::store::unix_time();
+        let expired = Paste {
+            id: "Expired0".to_string(),
+            title: None,
+            language: None,
+            content: "old".to_string(),
+            created_at: now - 120,
+            expires_at: Some(now - 60),
+            burn_after_reading: false,
+            revision: 1,
+            parent: None,
+            protected: false,
+            file: None,
+            owner: None,
+        };
+        assert!(store.insert_paste(&expired).unwrap());
+        drop(store);
+
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let response = client.request(Method::Get, "/p/Expired0").unwrap();
+            assert_eq!(response.code(), 410);
+            assert_eq!(response.reason(), "Gone");
+            let response = client.request(Method::Get, "/p/Expired0/raw").unwrap();
+            assert_eq!(response.code(), 410);
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=soon&expires=1h",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client.request(Method::Get, &location).unwrap();
+            assert_eq!(response.code(), 200);
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("Expires in "), "{}", body);
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=x&expires=forever",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 400);
+        };
+        crate::app::server::<App>(config, callback);
+    }
+
+    #[test]
+    fn test_burn_after_reading() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[("content-type", "application/x-www-form-urlencoded")],
+                    b"content=secret&burn=on",
+                )
+                .unwrap();
+            // The paste isn't viewed by following a redirect.
+            assert_eq!(response.code(), 201);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            assert_eq!(response.code(), 200);
+            assert_eq!(response.body().to_string().unwrap(), "secret");
+            let response = client.request(Method::Get, &location).unwrap();
+            assert_eq!(response.code(), 404);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_paste_revisions() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = [("content-type", "application/x-www-form-urlencoded")];
+            let response = client
+                .request_with_body(Method::Post, "/p", &form, b"content=a%0Ab%0A")
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &format!("{}/edit", location),
+                    &form,
+                    b"title=Edited&content=a%0A%3Cc%3E%0A",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let response = client.request(Method::Get, &location).unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("Revision 2 of 2."), "{}", body);
+            let response = client
+                .request(Method::Get, &format!("{}/rev/1/raw", location))
+                .unwrap();
+            assert_eq!(response.body().to_string().unwrap(), "a\nb\n");
+            let response = client
+                .request(Method::Get, &format!("{}/rev/3", location))
+                .unwrap();
+            assert_eq!(response.code(), 404);
+
+            let response = client
+                .request(Method::Get, &format!("{}/history", location))
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains(&format!("{}/diff/1/2", location)), "{}", body);
+
+            let response = client
+                .request(Method::Get, &format!("{}/diff/1/2", location))
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<del>-b</del>\n<ins>+&lt;c&gt;</ins>"), "{}", body);
+            let response = client
+                .request(Method::Get, &format!("{}/diff/1/2/split", location))
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<td>2</td><td><pre><del>b</del>"), "{}", body);
+            let response = client
+                .request(Method::Get, &format!("{}/diff/1/2/raw", location))
+                .unwrap();
+            assert_eq!(
+                response.body().to_string().unwrap(),
+                format!(
+                    "--- {0}/rev/1\n+++ {0}/rev/2\n@@ -1,2 +1,2 @@\n a\n-b\n+<c>\n",
+                    location
+                )
+            );
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &format!("{}/fork", location),
+                    &form,
+                    b"revision=1",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let fork = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .strip_suffix("/edit")
+                .unwrap()
+                .to_string();
+            assert_ne!(fork, location);
+            let response = client.request(Method::Get, &fork).unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("Forked from"), "{}", body);
+            assert!(body.contains("Revision 1 of 1."), "{}", body);
+            let response = client
+                .request(Method::Get, &format!("{}/raw", fork))
+                .unwrap();
+            assert_eq!(response.body().to_string().unwrap(), "a\nb\n");
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_protected_paste() {
+        // The callback can't capture the store, so it's at a known path.
+        fn store() -> StoreHandle {
+            let path = std::env::temp_dir().join("pasta6-test-protected-paste.log");
+            StoreHandle::Local(StoreConfig::File { path })
+        }
+        let _ = std::fs::remove_file(std::env::temp_dir().join("pasta6-test-protected-paste.log"));
+        let config = Config {
+            store: store(),
+            ..config()
+        };
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = [("content-type", "application/x-www-form-urlencoded")];
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &form,
+                    b"title=Keys&content=s3cr3t&password=open+sesame&burn=on",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 201);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let id = location.strip_prefix("/p/").unwrap();
+
+            // The content is encrypted at rest, unlike the title.
+            let paste = store().open().unwrap().get_paste(id).unwrap().unwrap();
+            assert!(paste.protected);
+            assert!(!paste.content.contains("s3cr3t"), "{}", paste.content);
+            assert_eq!(paste.title.as_deref(), Some("Keys"));
+
+            // Asking for the password doesn't burn the paste.
+            for _ in 0..2 {
+                let response = client.request(Method::Get, &location).unwrap();
+                assert_eq!(response.code(), 200);
+                let body = response.body().to_string().unwrap();
+                assert!(body.contains(&format!("action=\"{}/unlock\"", location)), "{}", body);
+                assert!(!body.contains("s3cr3t"), "{}", body);
+            }
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request(Method::Get, &format!("{}/history", location))
+                .unwrap();
+            assert_eq!(response.code(), 404);
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &format!("{}/unlock", location),
+                    &form,
+                    b"password=guess",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            assert!(response.body().to_string().unwrap().contains("Wrong password."));
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &format!("{}/raw", location),
+                    &form,
+                    b"password=open+sesame",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 200);
+            assert_eq!(response.body().to_string().unwrap(), "s3cr3t");
+            // It was burned when unlocked.
+            let response = client.request(Method::Get, &location).unwrap();
+            assert_eq!(response.code(), 404);
+
+            let response = client
+                .request_with_body(Method::Post, "/p", &form, b"content=x&password=pw")
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let unlock = format!("{}/unlock", location);
+            for _ in 0..5 {
+                let response = client
+                    .request_with_body(Method::Post, &unlock, &form, b"password=wrong")
+                    .unwrap();
+                assert_eq!(response.code(), 403);
+            }
+            // Even the right password is refused once attempts run out.
+            let response = client
+                .request_with_body(Method::Post, &unlock, &form, b"password=pw")
+                .unwrap();
+            assert_eq!(response.code(), 429);
+            assert_eq!(response.reason(), "Too Many Requests");
+            let retry_after = response.headers().get("retry-after").unwrap();
+            let retry_after: u64 = std::str::from_utf8(retry_after).unwrap().parse().unwrap();
+            assert!((1..=12).contains(&retry_after), "{}", retry_after);
+        };
+        crate::app::server::<App>(config, callback);
+    }
+
+    #[test]
+    fn test_file_upload() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let multipart = [("content-type", "multipart/form-data; boundary=X")];
+            let body = |fields: &[(&str, Option<&str>, &[u8])]| {
+                let mut body = Vec::new();
+                for (name, filename, value) in fields {
+                    body.extend_from_slice(b"--X\r\ncontent-disposition: form-data; ");
+                    body.extend_from_slice(format!("name=\"{}\"", name).as_bytes());
+                    if let Some(filename) = filename {
+                        body.extend_from_slice(format!("; filename=\"{}\"", filename).as_bytes());
+                    }
+                    body.extend_from_slice(b"\r\n\r\n");
+                    body.extend_from_slice(value);
+                    body.extend_from_slice(b"\r\n");
+                }
+                body.extend_from_slice(b"--X--\r\n");
+                body
+            };
+            let png: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &multipart,
+                    &body(&[("file", Some("C:\\Photos\\café.png"), png)]),
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+
+            let response = client.request(Method::Get, &location).unwrap();
+            assert_eq!(response.code(), 200);
+            let page = response.body().to_string().unwrap();
+            assert!(page.contains("café.png (image/png, 16 bytes)"), "{}", page);
+            assert!(page.contains(&format!("<img src=\"{}/raw\"", location)), "{}", page);
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            assert_eq!(response.code(), 200);
+            assert_eq!(response.body().as_bytes(), png);
+            let header = |name| response.headers().get(name).unwrap().to_vec();
+            assert_eq!(header("content-type"), b"image/png");
+            assert_eq!(
+                header("content-disposition"),
+                b"inline; filename=\"caf_.png\"; filename*=UTF-8''caf%C3%A9.png"
+            );
+            assert_eq!(header("x-content-type-options"), b"nosniff");
+            let response = client
+                .request(Method::Get, &format!("{}/edit", location))
+                .unwrap();
+            assert_eq!(response.code(), 404);
+
+            // Other binary files are downloaded, whatever their name says.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &multipart,
+                    &body(&[("file", Some("page.html"), b"\x1f\x8b\x08\0<script>")]),
+                )
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client
+                .request(Method::Get, &format!("{}/raw", location))
+                .unwrap();
+            let header = |name| response.headers().get(name).unwrap().to_vec();
+            assert_eq!(header("content-type"), b"application/gzip");
+            assert!(header("content-disposition").starts_with(b"attachment;"));
+
+            // Text files become text pastes, titled after the file.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &multipart,
+                    &body(&[("content", None, b""), ("file", Some("main.rs"), b"fn main() {}\n")]),
+                )
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client.request(Method::Get, &location).unwrap();
+            let page = response.body().to_string().unwrap();
+            assert!(page.contains("<title>main.rs</title>"), "{}", page);
+            assert!(page.contains("<span class=\"hl-keyword\">fn</span>"), "{}", page);
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &multipart,
+                    &body(&[("password", None, b"pw"), ("file", Some("a.png"), png)]),
+                )
+                .unwrap();
+            assert_eq!(response.code(), 400);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = [("content-type", "application/x-www-form-urlencoded")];
+            let response = client
+                .request_with_body(Method::Post, "/", &form, b"content=milk")
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let set_cookie = response.headers().get("set-cookie").unwrap();
+            let set_cookie = std::str::from_utf8(set_cookie).unwrap().to_string();
+            assert!(
+                set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"),
+                "{}",
+                set_cookie
+            );
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            assert!(cookie.starts_with("session="), "{}", cookie);
+
+            // The flash message is shown once, after which the session is
+            // empty and removed.
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &cookie)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("<p class=\"flash\">Added a todo.</p>"), "{}", body);
+            let removal = response.headers().get("set-cookie").unwrap();
+            assert!(removal.starts_with(b"session=; Path=/; Max-Age=0"));
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &cookie)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(!body.contains("flash"), "{}", body);
+            assert_eq!(response.headers().get("set-cookie"), None);
+
+            // A session keeps its ID while it's in use.
+            let response = client
+                .request_with_body(Method::Post, "/", &form, b"content=eggs")
+                .unwrap();
+            let set_cookie = response.headers().get("set-cookie").unwrap();
+            let set_cookie = std::str::from_utf8(set_cookie).unwrap();
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            let token = csrf_token(&mut client, &cookie);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/todo/1/delete",
+                    &[("cookie", &cookie), ("x-csrf-token", &token)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            assert_eq!(response.headers().get("set-cookie"), None);
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &cookie)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(
+                body.contains("Added a todo.</p>\n<p class=\"flash\">Deleted a todo."),
+                "{}",
+                body
+            );
+
+            // Session IDs are signed, so they can't be made up.
+            let forged = format!("{}x", cookie);
+            let response = client
+                .request_with_body(Method::Post, "/todo/2/delete", &[("cookie", &forged)], b"")
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let set_cookie = response.headers().get("set-cookie").unwrap();
+            assert!(!set_cookie.starts_with(forged.as_bytes()));
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_accounts() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = "application/x-www-form-urlencoded";
+            // Returns the session cookie set by a response.
+            let cookie = |response: &crate::http::Response| {
+                let set_cookie = response.headers().get("set-cookie").unwrap();
+                let set_cookie = std::str::from_utf8(set_cookie).unwrap();
+                set_cookie.split(';').next().unwrap().to_string()
+            };
+
+            let response = client.request(Method::Get, "/register").unwrap();
+            assert_eq!(response.code(), 200);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/register",
+                    &[("content-type", form)],
+                    b"name=alice&password=short",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 400);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/register",
+                    &[("content-type", form)],
+                    b"name=alice&password=correct+horse",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let alice = cookie(&response);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/register",
+                    &[("content-type", form)],
+                    b"name=alice&password=battery+staple",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 409);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/register",
+                    &[("content-type", form)],
+                    b"name=bob&password=battery+staple",
+                )
+                .unwrap();
+            let bob = cookie(&response);
+            let alice_token = csrf_token(&mut client, &alice);
+            let bob_token = csrf_token(&mut client, &bob);
+
+            // Todos are listed only to their owner.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[
+                        ("content-type", form),
+                        ("cookie", &alice),
+                        ("x-csrf-token", &alice_token),
+                    ],
+                    b"content=milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &alice)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("Signed in as alice."), "{}", body);
+            assert!(body.contains("<li>milk"), "{}", body);
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &bob)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(!body.contains("<li>milk"), "{}", body);
+
+            // ... and only their owner may change them.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/todo/1/delete",
+                    &[("cookie", &bob), ("x-csrf-token", &bob_token)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(Method::Post, "/todo/1/delete", &[], b"")
+                .unwrap();
+            assert_eq!(response.code(), 403);
+
+            // The same goes for pastes.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/p",
+                    &[
+                        ("content-type", form),
+                        ("cookie", &alice),
+                        ("x-csrf-token", &alice_token),
+                    ],
+                    b"content=a",
+                )
+                .unwrap();
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let edit = format!("{}/edit", location);
+            let response = client
+                .request_with_body(Method::Get, &edit, &[("cookie", &bob)], b"")
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &edit,
+                    &[
+                        ("content-type", form),
+                        ("cookie", &bob),
+                        ("x-csrf-token", &bob_token),
+                    ],
+                    b"content=b",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    &edit,
+                    &[
+                        ("content-type", form),
+                        ("cookie", &alice),
+                        ("x-csrf-token", &alice_token),
+                    ],
+                    b"content=b",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+
+            // Logging out forgets the session, and logging in starts a new
+            // one.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/logout",
+                    &[("cookie", &alice), ("x-csrf-token", &alice_token)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &alice)], b"")
+                .unwrap();
+            let body = response.body().to_string().unwrap();
+            assert!(!body.contains("Signed in"), "{}", body);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/login",
+                    &[("content-type", form)],
+                    b"name=alice&password=battery+staple",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/login",
+                    &[("content-type", form)],
+                    b"name=carol&password=battery+staple",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/login",
+                    &[("content-type", form)],
+                    b"name=alice&password=correct+horse",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let again = cookie(&response);
+            assert_ne!(again, alice);
+            let token = csrf_token(&mut client, &again);
+            assert_ne!(token, alice_token);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/todo/1/delete",
+                    &[("cookie", &again), ("x-csrf-token", &token)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = "application/x-www-form-urlencoded";
+            let json = "application/json";
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/register",
+                    &[("content-type", form)],
+                    b"name=alice&password=correct+horse",
+                )
+                .unwrap();
+            let set_cookie = response.headers().get("set-cookie").unwrap();
+            let set_cookie = std::str::from_utf8(set_cookie).unwrap();
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            let csrf_token = csrf_token(&mut client, &cookie);
+
+            // Tokens are shown once, when they're created.
+            let mut create_token = |body: &[u8]| {
+                let response = client
+                    .request_with_body(
+                        Method::Post,
+                        "/tokens",
+                        &[
+                            ("content-type", form),
+                            ("cookie", &cookie),
+                            ("x-csrf-token", &csrf_token),
+                        ],
+                        body,
+                    )
+                    .unwrap();
+                assert_eq!(response.code(), 201);
+                assert_eq!(response.headers().get("cache-control"), Some(&b"no-store"[..]));
+                let page = response.body().to_string().unwrap();
+                let start = page.find("pasta6_").unwrap();
+                format!("Bearer {}", &page[start..start + 47])
+            };
+            let write = create_token(b"name=ci&scope=write");
+            let read = create_token(b"name=backup&scope=read");
+            let response = client
+                .request_with_body(Method::Get, "/tokens", &[("cookie", &cookie)], b"")
+                .unwrap();
+            let page = response.body().to_string().unwrap();
+            assert!(page.contains("<li>ci (write)"), "{}", page);
+            assert!(!page.contains("pasta6_"), "{}", page);
+
+            // Requests without a valid token are challenged.
+            let response = client.request(Method::Get, "/api/todos").unwrap();
+            assert_eq!(response.code(), 401);
+            assert_eq!(
+                response.headers().get("www-authenticate"),
+                Some(&b"Bearer realm=\"pasta6\""[..])
+            );
+            let response = client
+                .request_with_body(
+                    Method::Get,
+                    "/api/todos",
+                    &[("authorization", "Bearer pasta6_nope")],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 401);
+            assert_eq!(
+                response.headers().get("www-authenticate"),
+                Some(&b"Bearer realm=\"pasta6\", error=\"invalid_token\""[..])
+            );
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/api/todos",
+                    &[("authorization", &read), ("content-type", json)],
+                    b"{\"content\": \"milk\"}",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            assert!(response
+                .headers()
+                .get("www-authenticate")
+                .unwrap()
+                .ends_with(b"error=\"insufficient_scope\", scope=\"write\""));
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/api/todos",
+                    &[("authorization", &write), ("content-type", json)],
+                    b"{\"content\": \"milk\"}",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 201);
+            assert_eq!(
+                response.headers().get("content-type"),
+                Some(&b"application/json"[..])
+            );
+            assert_eq!(
+                response.body().to_string().unwrap(),
+                "{\"id\":1,\"content\":\"milk\"}"
+            );
+            assert_eq!(response.headers().get("set-cookie"), None);
+            let response = client
+                .request_with_body(Method::Get, "/api/todos", &[("authorization", &read)], b"")
+                .unwrap();
+            assert_eq!(
+                response.body().to_string().unwrap(),
+                "[{\"id\":1,\"content\":\"milk\"}]"
+            );
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/api/todos",
+                    &[("authorization", &write), ("content-type", form)],
+                    b"content=milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 415);
+
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/api/pastes",
+                    &[("authorization", &write), ("content-type", json)],
+                    b"{\"content\": \"fn main() {}\", \"language\": \"rust\"}",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 201);
+            let location = std::str::from_utf8(response.headers().get("location").unwrap())
+                .unwrap()
+                .to_string();
+            let response = client
+                .request_with_body(
+                    Method::Get,
+                    &format!("/api{}", location.replace("/p/", "/pastes/")),
+                    &[("authorization", &read)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 200);
+            let body = response.body().to_string().unwrap();
+            assert!(body.contains("\"content\":\"fn main() {}\""), "{}", body);
+
+            let response = client
+                .request_with_body(Method::Delete, "/api/todos/1", &[("authorization", &write)], b"")
+                .unwrap();
+            assert_eq!(response.code(), 204);
+
+            // Revoked tokens stop working straight away.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/tokens/1/delete",
+                    &[("cookie", &cookie), ("x-csrf-token", &csrf_token)],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let response = client
+                .request_with_body(Method::Get, "/api/todos", &[("authorization", &write)], b"")
+                .unwrap();
+            assert_eq!(response.code(), 401);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_csrf() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let form = "application/x-www-form-urlencoded";
+
+            // Browsers say where requests come from.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[("content-type", form), ("sec-fetch-site", "cross-site")],
+                    b"content=milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[
+                        ("content-type", form),
+                        ("host", "pasta6.test"),
+                        ("origin", "https://evil.test"),
+                    ],
+                    b"content=milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/",
+                    &[
+                        ("content-type", form),
+                        ("host", "pasta6.test"),
+                        ("origin", "https://pasta6.test"),
+                    ],
+                    b"content=milk",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+            let set_cookie = response.headers().get("set-cookie").unwrap();
+            let set_cookie = std::str::from_utf8(set_cookie).unwrap();
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+
+            // Requests in a session need its token, which forms include.
+            let response = client
+                .request_with_body(Method::Post, "/todo/1/delete", &[("cookie", &cookie)], b"")
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/todo/1/delete",
+                    &[("cookie", &cookie), ("x-csrf-token", "forged")],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+            let token = csrf_token(&mut client, &cookie);
+            let response = client
+                .request_with_body(Method::Get, "/", &[("cookie", &cookie)], b"")
+                .unwrap();
+            let page = response.body().to_string().unwrap();
+            let field = format!(
+                "<form method=\"post\" action=\"/todo/1/delete\">\n\
+                 <input type=\"hidden\" name=\"csrf_token\" value=\"{}\">",
+                token
+            );
+            assert!(page.contains(&field), "{}", page);
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/todo/1/delete",
+                    &[("content-type", form), ("cookie", &cookie)],
+                    format!("csrf_token={}", token).as_bytes(),
+                )
+                .unwrap();
+            assert_eq!(response.code(), 303);
+
+            // The API doesn't use cookies, so it isn't checked.
+            let response = client
+                .request_with_body(
+                    Method::Post,
+                    "/api/todos",
+                    &[("sec-fetch-site", "cross-site")],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 401);
+        };
+        crate::app::server::<App>(config(), callback);
+    }
+
+    #[test]
+    fn test_cors() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let origin = ("origin", "https://dashboard.test");
+
+            let response = client
+                .request_with_body(
+                    Method::Options,
+                    "/api/todos",
+                    &[
+                        origin,
+                        ("access-control-request-method", "POST"),
+                        ("access-control-request-headers", "authorization,content-type"),
+                    ],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 204);
+            assert_eq!(
+                response.headers().get("access-control-allow-origin"),
+                Some(&b"https://dashboard.test"[..])
+            );
+            assert_eq!(
+                response.headers().get("access-control-allow-methods"),
+                Some(&b"GET, POST, DELETE"[..])
+            );
+            let response = client
+                .request_with_body(
+                    Method::Options,
+                    "/api/todos",
+                    &[
+                        ("origin", "https://evil.test"),
+                        ("access-control-request-method", "POST"),
+                    ],
+                    b"",
+                )
+                .unwrap();
+            assert_eq!(response.code(), 403);
+
+            // Errors can be read too, e.g. to ask for a new token.
+            let response = client
+                .request_with_body(Method::Get, "/api/todos", &[origin], b"")
+                .unwrap();
+            assert_eq!(response.code(), 401);
+            assert_eq!(
+                response.headers().get("access-control-allow-origin"),
+                Some(&b"https://dashboard.test"[..])
+            );
+            assert_eq!(
+                response.headers().get("access-control-allow-credentials"),
+                Some(&b"true"[..])
+            );
+
+            // Pages are for this site only.
+            let response = client
+                .request_with_body(Method::Get, "/", &[origin], b"")
+                .unwrap();
+            assert_eq!(response.headers().get("access-control-allow-origin"), None);
+            let response = client
+                .request_with_body(Method::Options, "/", &[origin], b"")
+                .unwrap();
+            assert_eq!(response.code(), 404);
+        };
+        let config = Config {
+            cors: Cors {
+                allowed_origins: vec!["https://dashboard.test".to_string()],
+                allow_credentials: true,
+                ..Cors::default()
+            },
+            ..config()
+        };
+        crate::app::server::<App>(config, callback);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let header = |response: &crate::http::Response, name| {
+                String::from_utf8(response.headers().get(name).unwrap().to_vec()).unwrap()
+            };
+
+            let form = [("content-type", "application/x-www-form-urlencoded")];
+            for remaining in ["1", "0"] {
+                let response = client
+                    .request_with_body(Method::Post, "/p", &form, b"content=hi")
+                    .unwrap();
+                assert_eq!(response.code(), 303);
+                assert_eq!(header(&response, "ratelimit-limit"), "2");
+                assert_eq!(header(&response, "ratelimit-remaining"), remaining);
+            }
+            let response = client
+                .request_with_body(Method::Post, "/p", &form, b"content=hi")
+                .unwrap();
+            assert_eq!(response.code(), 429);
+            assert_eq!(header(&response, "ratelimit-remaining"), "0");
+            assert_eq!(header(&response, "ratelimit-reset"), "60");
+            assert_eq!(header(&response, "retry-after"), "30");
+            // Other routes aren't limited.
+            let response = client.request(Method::Get, "/").unwrap();
+            assert_eq!(response.code(), 200);
+            assert_eq!(response.headers().get("ratelimit-limit"), None);
+
+            // API clients are limited by their token, or address without one.
+            let response = client.request(Method::Get, "/api/todos").unwrap();
+            assert_eq!(response.code(), 401);
+            let response = client.request(Method::Get, "/api/todos").unwrap();
+            assert_eq!(response.code(), 429);
+            assert_eq!(
+                response.headers().get("content-type"),
+                Some(&b"application/json"[..])
+            );
+            // Each token has its own quota, so invalid ones are limited by
+            // address as well.
+            for (i, code) in [401, 401, 401, 401, 401, 429].into_iter().enumerate() {
+                let authorization = format!("Bearer invalid{}", i);
+                let response = client
+                    .request_with_body(
+                        Method::Get,
+                        "/api/todos",
+                        &[("authorization", &authorization)],
+                        b"",
+                    )
+                    .unwrap();
+                assert_eq!(response.code(), code);
+            }
+        };
+        let config = Config {
+            route_quotas: RouteQuotas::parse("POST /p=2/1m, * /api/*=1/1m").unwrap(),
+            ..config()
+        };
+        crate::app::server::<App>(config, callback);
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let callback = |port| {
+            let tcp_stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .unwrap()
+                .into();
+            let mut client = Client::new(tcp_stream).unwrap();
+            let request = |client: &mut Client, forwarded_for| {
+                client
+                    .request_with_body(
+                        Method::Get,
+                        "/api/todos",
+                        &[("x-forwarded-for", forwarded_for)],
+                        b"",
+                    )
+                    .unwrap()
+                    .code()
+            };
+            // Clients behind the proxy have their own quotas.
+            assert_eq!(request(&mut client, "203.0.113.1"), 401);
+            assert_eq!(request(&mut client, "203.0.113.1"), 429);
+            assert_eq!(request(&mut client, "203.0.113.2"), 401);
+            // Spoofed addresses before the client's are ignored.
+            assert_eq!(request(&mut client, "203.0.113.3, 203.0.113.2"), 429);
+        };
+        let config = Config {
+            route_quotas: RouteQuotas::parse("* /api/*=1/1m").unwrap(),
+            trusted_proxies: TrustedProxies::parse("127.0.0.1").unwrap(),
+            ..config()
         };
-        crate::app::server(App::handle, callback, 3000);
+        crate::app::server::<App>(config, callback);
     }
 }
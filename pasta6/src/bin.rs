@@ -1,5 +1,4 @@
 fn main() {
-    tracing_subscriber::fmt::init();
-    #[cfg(all(not(test), target_arch = "wasm32"))]
-    pasta6::run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(pasta6::main(&args));
 }
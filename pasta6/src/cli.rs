@@ -0,0 +1,847 @@
+//! The command line interface of the `pasta6` binary.
+//!
+//! Every invocation runs one of the [`COMMANDS`], e.g. `pasta6 serve` or
+//! `pasta6 export backup.jsonl`, after loading the configuration (see
+//! [`crate::config`]), which also sets the level of logs. `serve` and
+//! `migrate` need the lunatic runtime, while the others also run natively.
+//!
+//! `paste` sends the paste to a running server through the JSON API, while
+//! `import`, `export` and `create-user` open the store of the server straight
+//! from the current process. They refuse the in-memory store, which is only
+//! ever seen by one process, and with a log file the server must be stopped
+//! first. Only PostgreSQL can be shared with a running server.
+use crate::config::Config;
+use crate::http::Method;
+use crate::store::{Store, StoreConfig, StoreError};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// Environment variable holding the URL of the server `paste` sends to.
+const URL_ENV: &str = "PASTA6_URL";
+/// Environment variable holding the API token `paste` sends with.
+const TOKEN_ENV: &str = "PASTA6_TOKEN";
+/// The server `paste` sends to unless told otherwise.
+const DEFAULT_URL: &str = "http://localhost:3000";
+
+/// The commands, in the order of `pasta6 --help`.
+const COMMANDS: &[CommandHelp] = &[
+    CommandHelp {
+        name: "serve",
+        usage: "[--bind ADDR[,ADDR...]]",
+        options: &["bind"],
+        summary: "Run the server",
+        description: "Listens on the addresses given by `--bind`, or else by `server.bind`\n\
+                      (`PASTA6_BIND`), by default `0.0.0.0:3000`. Port 0 picks a free port.",
+    },
+    CommandHelp {
+        name: "migrate",
+        usage: "",
+        options: &[],
+        summary: "Apply pending database migrations",
+        description: "Only PostgreSQL has a schema, so this does nothing for other stores.",
+    },
+    CommandHelp {
+        name: "import",
+        usage: "[FILE]",
+        options: &[],
+        summary: "Import pastes from an export",
+        description: "Reads pastes written by `pasta6 export` from FILE, or else from stdin.\n\
+                      Pastes whose ID is taken already are skipped. Stop the server\n\
+                      first unless the store is PostgreSQL.",
+    },
+    CommandHelp {
+        name: "export",
+        usage: "[FILE]",
+        options: &[],
+        summary: "Export every paste as JSON lines",
+        description: "Writes every paste which hasn't expired to FILE, or else to stdout,\n\
+                      with its revisions and uploaded file. Accounts aren't exported.",
+    },
+    CommandHelp {
+        name: "create-user",
+        usage: "NAME",
+        options: &[],
+        summary: "Create an account",
+        description: "Reads the password from the first line of stdin, e.g.\n\
+                      `echo \"$PASSWORD\" | pasta6 create-user alice`. Stop the server\n\
+                      first unless the store is PostgreSQL.",
+    },
+    CommandHelp {
+        name: "config check",
+        usage: "",
+        options: &[],
+        summary: "Check the configuration",
+        description: "Reads the configuration file and the environment, and reports\n\
+                      every invalid setting.",
+    },
+    CommandHelp {
+        name: "paste",
+        usage: "[--url URL] [--token TOKEN] [--title T] [--language L] [--expires LIFETIME]",
+        options: &["url", "token", "title", "language", "expires"],
+        summary: "Create a paste from stdin on a server",
+        description: "Sends the text on stdin to the JSON API of the server at URL, or else\n\
+                      at `PASTA6_URL`, by default `http://localhost:3000`, with the API\n\
+                      token TOKEN, or else `PASTA6_TOKEN`. LIFETIME is e.g. `10m`, `1h`,\n\
+                      `1d` or `1w`. Prints the URL of the paste.",
+    },
+];
+
+/// How to use a command, for `--help`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct CommandHelp {
+    name: &'static str,
+    /// The arguments after the name.
+    usage: &'static str,
+    /// The long options it takes, without `--`, each with a value.
+    options: &'static [&'static str],
+    summary: &'static str,
+    description: &'static str,
+}
+
+/// Parsed command line arguments.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Args {
+    /// The configuration file given by `--config`.
+    config: Option<String>,
+    command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Serve {
+        bind: Option<String>,
+    },
+    Migrate,
+    Import {
+        path: Option<String>,
+    },
+    Export {
+        path: Option<String>,
+    },
+    CreateUser {
+        name: String,
+    },
+    CheckConfig,
+    Paste {
+        url: Option<String>,
+        token: Option<String>,
+        title: Option<String>,
+        language: Option<String>,
+        expires: Option<String>,
+    },
+    /// Prints how to use a command, or all of them.
+    Help(Option<&'static CommandHelp>),
+}
+
+/// A paste, as sent to `POST /api/pastes`.
+#[derive(Serialize)]
+struct NewPaste {
+    content: String,
+    title: Option<String>,
+    language: Option<String>,
+    /// A lifetime such as `1d`.
+    expires: Option<String>,
+}
+
+/// An error of the JSON API.
+#[derive(Deserialize)]
+struct ApiError {
+    error: String,
+}
+
+/// The part of the paste returned by `POST /api/pastes` which `paste` uses.
+#[derive(Deserialize)]
+struct CreatedPaste {
+    id: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct CliError {
+    kind: CliErrorKind,
+}
+
+#[derive(Debug)]
+enum CliErrorKind {
+    MissingCommand,
+    UnknownCommand(String),
+    UnknownOption(String),
+    MissingValue(String),
+    MissingArgument(&'static str, &'static str),
+    UnexpectedArgument(String),
+    /// The command can't run outside of the lunatic runtime.
+    Unsupported(&'static str),
+    /// The command would use the in-memory store, which only the current
+    /// process sees.
+    MemoryStore(&'static str),
+    Store(StoreError),
+    Io(io::Error),
+    /// Input the command refused, e.g. an invalid account name.
+    Invalid(String),
+    /// A failure which was already reported, e.g. by logging.
+    Failed,
+}
+
+/// Runs the command given by `args`, without the name of the binary, and
+/// returns the exit code: 0 on success, 1 if the command failed or 2 if it
+/// wasn't used correctly.
+#[inline]
+pub(crate) fn main(args: &[String]) -> i32 {
+    let args = match parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("Run `pasta6 --help` for usage.");
+            return 2;
+        }
+    };
+    let result = match args.command {
+        Command::Help(command) => {
+            print!("{}", help(command));
+            Ok(())
+        }
+        command => match Config::load(args.config.as_deref()) {
+            Ok(config) => run(command, &config),
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("error: {}", e);
+                }
+                Err(CliError::failed())
+            }
+        },
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            if !matches!(e.kind, CliErrorKind::Failed) {
+                eprintln!("error: {}", e);
+            }
+            1
+        }
+    }
+}
+
+/// Parses the command line, e.g. `--config pasta6.toml serve --bind 8080`.
+#[inline]
+pub(crate) fn parse(args: &[String]) -> Result<Args, CliError> {
+    let mut args = args.iter().map(String::as_str);
+    let mut config = None;
+    // Global options come before the command.
+    let name = loop {
+        match args.next() {
+            Some("-h" | "--help") => {
+                return Ok(Args {
+                    config,
+                    command: Command::Help(None),
+                })
+            }
+            Some("--config") => config = Some(option_value(&mut args, "--config")?),
+            Some(arg) => match arg.strip_prefix("--config=") {
+                Some(path) => config = Some(path.to_string()),
+                None if arg.starts_with('-') => return Err(CliError::unknown_option(arg)),
+                None => break arg,
+            },
+            None => return Err(CliError::missing_command()),
+        }
+    };
+    let command_help = match name {
+        "help" => {
+            let command = match args.next() {
+                Some("config") => find_command("config check"),
+                Some(name) => Some(find_command(name).ok_or_else(|| CliError::unknown(name))?),
+                None => None,
+            };
+            return Ok(Args {
+                config,
+                command: Command::Help(command),
+            });
+        }
+        "config" => match args.next() {
+            Some("check") => find_command("config check"),
+            Some(subcommand) => return Err(CliError::unknown(&format!("config {}", subcommand))),
+            None => return Err(CliError::missing_argument("config", "check")),
+        },
+        name => find_command(name),
+    };
+    let command_help = command_help.ok_or_else(|| CliError::unknown(name))?;
+
+    let mut options: Vec<(&str, String)> = Vec::new();
+    let mut arguments = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Args {
+                config,
+                command: Command::Help(Some(command_help)),
+            });
+        }
+        let option = match arg.strip_prefix("--") {
+            Some(option) => option,
+            None if arg.starts_with('-') && arg != "-" => return Err(CliError::unknown_option(arg)),
+            None => {
+                arguments.push(arg);
+                continue;
+            }
+        };
+        let (option, value) = match option.split_once('=') {
+            Some((option, value)) => (option, value.to_string()),
+            None => (option, option_value(&mut args, arg)?),
+        };
+        match command_help.options.iter().find(|name| **name == option) {
+            Some(option) => options.push((option, value)),
+            None => return Err(CliError::unknown_option(arg)),
+        }
+    }
+    // Later options override earlier ones.
+    let option = |name: &str| {
+        options.iter().rev().find(|(option, _)| *option == name).map(|(_, value)| value.clone())
+    };
+    let command = match (command_help.name, arguments.as_slice()) {
+        ("serve", []) => Command::Serve {
+            bind: option("bind"),
+        },
+        ("migrate", []) => Command::Migrate,
+        ("import", [] | [_]) => Command::Import {
+            path: arguments.first().map(|path| path.to_string()),
+        },
+        ("export", [] | [_]) => Command::Export {
+            path: arguments.first().map(|path| path.to_string()),
+        },
+        ("create-user", [name]) => Command::CreateUser {
+            name: name.to_string(),
+        },
+        ("create-user", []) => return Err(CliError::missing_argument("create-user", "NAME")),
+        ("config check", []) => Command::CheckConfig,
+        ("paste", []) => Command::Paste {
+            url: option("url"),
+            token: option("token"),
+            title: option("title"),
+            language: option("language"),
+            expires: option("expires"),
+        },
+        (_, arguments) => return Err(CliError::unexpected_argument(arguments.last().unwrap())),
+    };
+    Ok(Args { config, command })
+}
+
+/// Returns the value of `option`, the next argument.
+#[inline]
+fn option_value<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+    option: &str,
+) -> Result<String, CliError> {
+    args.next()
+        .map(str::to_string)
+        .ok_or_else(|| CliError::missing_value(option))
+}
+
+#[inline]
+fn find_command(name: &str) -> Option<&'static CommandHelp> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Returns the help of a command, or else the list of commands.
+#[inline]
+pub(crate) fn help(command: Option<&CommandHelp>) -> String {
+    match command {
+        Some(command) => format!(
+            "{}\n\nUsage: pasta6 [--config PATH] {}{}{}\n\n{}\n",
+            command.summary,
+            command.name,
+            if command.usage.is_empty() { "" } else { " " },
+            command.usage,
+            command.description
+        ),
+        None => {
+            let mut help = "A pastebin.\n\n\
+                            Usage: pasta6 [--config PATH] COMMAND [ARGS...]\n\n\
+                            Commands:\n"
+                .to_string();
+            for command in COMMANDS {
+                help.push_str(&format!("  {:<14}{}\n", command.name, command.summary));
+            }
+            help.push_str(
+                "\nOptions:\n  \
+                 --config PATH  Read settings from PATH instead of `pasta6.toml`\n  \
+                 -h, --help     Print help, also after a command\n",
+            );
+            help
+        }
+    }
+}
+
+/// Runs any command but `help`.
+#[inline]
+fn run(command: Command, config: &Config) -> Result<(), CliError> {
+    // Logs go to stderr, so that commands like `export` can write to stdout.
+    #[cfg(feature = "logging")]
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(config.log_level)
+        .init();
+    if let Some(path) = &config.path {
+        tracing::info!("read configuration from {}", path);
+    }
+    match command {
+        Command::Serve { bind } => serve(bind.as_deref(), config),
+        Command::Migrate => migrate(config),
+        Command::Import { path } => import(path.as_deref(), config),
+        Command::Export { path } => export(path.as_deref(), config),
+        Command::CreateUser { name } => create_user(&name, config),
+        Command::CheckConfig => check_config(config),
+        Command::Paste {
+            url,
+            token,
+            title,
+            language,
+            expires,
+        } => {
+            let url = url.or_else(|| std::env::var(URL_ENV).ok());
+            let token = token.or_else(|| std::env::var(TOKEN_ENV).ok());
+            let token = token.ok_or_else(|| {
+                CliError::invalid(format!("`paste` needs `--token` or {}", TOKEN_ENV))
+            })?;
+            let new_paste = NewPaste {
+                content: String::new(),
+                title,
+                language,
+                expires,
+            };
+            paste(url.as_deref().unwrap_or(DEFAULT_URL), &token, new_paste)
+        }
+        Command::Help(_) => unreachable!(),
+    }
+}
+
+/// Runs the server, listening on `bind`, a comma-separated list of
+/// addresses, or else on those of the configuration.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn serve(bind: Option<&str>, config: &Config) -> Result<(), CliError> {
+    let bind_addrs = match bind {
+        Some(bind) => crate::http::BindAddrs::parse(bind)
+            .map_err(|e| CliError::invalid(format!("--bind: {}", e)))?,
+        None => config.bind.clone(),
+    };
+    if config.migrate && !crate::store::migrate_in_process(config.store.clone()) {
+        return Err(CliError::failed());
+    }
+    let store = crate::store::StoreHandle::spawn(config.store.clone(), config.pool.clone())?;
+    crate::store::spawn_reaper(store.clone()).expect("failed to spawn reaper");
+    let highlight_cache =
+        crate::highlight::HighlightCache::spawn().expect("failed to spawn highlight cache");
+    let rate_limiter =
+        crate::ratelimit::RateLimiter::spawn().expect("failed to spawn rate limiter");
+    let cookie_key = match &config.cookie_key {
+        Some(cookie_key) => cookie_key.clone(),
+        None => {
+            tracing::warn!("no cookie secret is set, using a random cookie key");
+            crate::http::CookieKey::generate()
+        }
+    };
+    // Sessions are only kept in memory unless they're persisted.
+    let session_store = config.persist_sessions.then(|| store.clone());
+    let sessions = crate::session::Sessions::spawn(config.max_sessions, session_store)
+        .expect("failed to spawn session store");
+    let app_config = crate::app::Config {
+        store,
+        highlight_cache,
+        rate_limiter,
+        cookie_key,
+        sessions,
+        secure_cookies: config.secure_cookies,
+        security_headers: config.security_headers.clone(),
+        cors: config.cors.clone(),
+        route_quotas: config.route_quotas.clone(),
+        trusted_proxies: config.trusted_proxies.clone(),
+        proxy_protocol: config.proxy_protocol,
+        max_body_size: config.max_body_size,
+        timeouts: config.http_timeouts,
+    };
+    crate::app::server(app_config, &bind_addrs);
+    loop {
+        lunatic::process::sleep(u64::MAX);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[inline]
+fn serve(_bind: Option<&str>, _config: &Config) -> Result<(), CliError> {
+    Err(CliError::unsupported("serve"))
+}
+
+/// Applies pending database migrations.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn migrate(config: &Config) -> Result<(), CliError> {
+    match crate::store::migrate_in_process(config.store.clone()) {
+        true => Ok(()),
+        false => Err(CliError::failed()),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[inline]
+fn migrate(_config: &Config) -> Result<(), CliError> {
+    Err(CliError::unsupported("migrate"))
+}
+
+#[inline]
+fn import(path: Option<&str>, config: &Config) -> Result<(), CliError> {
+    let mut input: Box<dyn BufRead> = match path {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let mut store = open_store("import", config)?;
+    let summary = crate::store::import_pastes(&mut *store, &mut input)?;
+    eprintln!(
+        "imported {} paste(s), skipped {} which existed already",
+        summary.imported, summary.skipped
+    );
+    Ok(())
+}
+
+#[inline]
+fn export(path: Option<&str>, config: &Config) -> Result<(), CliError> {
+    let mut output: Box<dyn Write> = match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut store = open_store("export", config)?;
+    let exported = crate::store::export_pastes(&mut *store, &mut output)?;
+    eprintln!("exported {} paste(s)", exported);
+    Ok(())
+}
+
+#[inline]
+fn create_user(name: &str, config: &Config) -> Result<(), CliError> {
+    use crate::app::{MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH};
+
+    if !crate::store::is_valid_account_name(name) {
+        return Err(CliError::invalid(
+            "names are 3 to 32 lowercase letters, digits, dashes or underscores",
+        ));
+    }
+    let mut store = open_store("create-user", config)?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.strip_suffix('\n').unwrap_or(&password);
+    let password = password.strip_suffix('\r').unwrap_or(password);
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password.chars().count()) {
+        return Err(CliError::invalid(format!(
+            "passwords are {} to {} characters long",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        )));
+    }
+    let password_hash =
+        crate::crypto::hash_password(password).map_err(|e| CliError::invalid(e.to_string()))?;
+    match store.create_account(name, &password_hash)? {
+        Some(account) => {
+            println!("created account {} ({})", account.name, account.id);
+            Ok(())
+        }
+        None => Err(CliError::invalid(format!("the name {} is taken", name))),
+    }
+}
+
+/// Opens the store of the server for `command`, unless it's the in-memory
+/// store, which the server wouldn't see.
+#[inline]
+fn open_store(command: &'static str, config: &Config) -> Result<Box<dyn Store>, CliError> {
+    if config.store == StoreConfig::Memory {
+        return Err(CliError::new(CliErrorKind::MemoryStore(command)));
+    }
+    Ok(config.store.open()?)
+}
+
+/// Creates a paste of the text on stdin with the JSON API of the server at
+/// `url`, and prints its URL.
+#[inline]
+fn paste(url: &str, token: &str, mut new_paste: NewPaste) -> Result<(), CliError> {
+    let (authority, host, port, path) = match parse_url(url) {
+        Some(parts) => parts,
+        None => {
+            return Err(CliError::invalid(format!(
+                "invalid URL {:?}, expected e.g. `http://localhost:3000`",
+                url
+            )))
+        }
+    };
+    // Without HTTPS, anyone on the way could read the token.
+    if !is_loopback(host) {
+        return Err(CliError::invalid(format!(
+            "refusing to send the API token unencrypted to {}, only servers on this host can \
+             be reached over `http://`, e.g. through `fly proxy`",
+            host
+        )));
+    }
+    io::stdin().lock().read_to_string(&mut new_paste.content).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => CliError::invalid("only text can be pasted"),
+        _ => CliError::from(e),
+    })?;
+    if new_paste.content.is_empty() {
+        return Err(CliError::invalid("nothing to paste on stdin"));
+    }
+    let body = serde_json::to_vec(&new_paste).map_err(CliError::invalid)?;
+    let tcp_stream = crate::net::TcpStream::connect(host, port)?;
+    let mut client = crate::http::Client::new(tcp_stream)
+        .map_err(|_e| CliError::invalid(format!("couldn't connect to {}", url)))?;
+    let authorization = format!("Bearer {}", token);
+    let headers = [
+        ("host", authority),
+        ("authorization", authorization.as_str()),
+        ("content-type", "application/json"),
+    ];
+    let response = client
+        .request_with_body(Method::Post, &format!("{}/api/pastes", path), &headers, &body)
+        .map_err(|_e| CliError::invalid(format!("no response from {}", url)))?;
+    let location = response.headers().get("location");
+    match (response.code(), location.map(std::str::from_utf8)) {
+        (201, location) => {
+            // An empty or cut off body means the paste may not have been
+            // created as sent.
+            let created: CreatedPaste = serde_json::from_slice(response.body().as_bytes())
+                .map_err(|_e| CliError::invalid(format!("invalid response from {}", url)))?;
+            match location {
+                Some(Ok(location)) => println!("http://{}{}", authority, location),
+                _ => println!("http://{}/p/{}", authority, created.id),
+            }
+            Ok(())
+        }
+        (code, _) => {
+            // API errors are e.g. `{"error": "invalid lifetime"}`.
+            let error: Option<ApiError> = serde_json::from_slice(response.body().as_bytes()).ok();
+            let message = error.map_or_else(|| response.reason().to_string(), |e| e.error);
+            Err(CliError::invalid(format!("the server answered {}: {}", code, message)))
+        }
+    }
+}
+
+/// Splits an `http://` URL into its authority, host, port and path, without
+/// a trailing `/`. HTTPS isn't supported.
+#[inline]
+fn parse_url(url: &str) -> Option<(&str, &str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (host, port) = match authority.rsplit_once(':') {
+        // Not the end of an IPv6 address, e.g. `[::1]`.
+        Some((host, port)) if !port.ends_with(']') => (host, port.parse().ok()?),
+        _ => (authority, 80),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((authority, host, port, path.trim_end_matches('/')))
+}
+
+/// Returns `true` if `host` is this host, e.g. `localhost` or `127.0.0.1`.
+#[inline]
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|addr| addr.to_canonical().is_loopback())
+}
+
+/// Reports that the configuration is valid, which it is once loaded, since
+/// invalid settings are reported by [`main`].
+#[inline]
+fn check_config(config: &Config) -> Result<(), CliError> {
+    match &config.path {
+        Some(path) => println!("configuration OK ({})", path),
+        None => println!("configuration OK (environment only)"),
+    }
+    Ok(())
+}
+
+impl CliError {
+    #[inline]
+    fn new(kind: CliErrorKind) -> Self {
+        Self { kind }
+    }
+
+    #[inline]
+    fn missing_command() -> Self {
+        Self::new(CliErrorKind::MissingCommand)
+    }
+
+    #[inline]
+    fn unknown(name: &str) -> Self {
+        Self::new(CliErrorKind::UnknownCommand(name.to_string()))
+    }
+
+    #[inline]
+    fn unknown_option(option: &str) -> Self {
+        Self::new(CliErrorKind::UnknownOption(option.to_string()))
+    }
+
+    #[inline]
+    fn missing_value(option: &str) -> Self {
+        Self::new(CliErrorKind::MissingValue(option.to_string()))
+    }
+
+    #[inline]
+    fn missing_argument(command: &'static str, argument: &'static str) -> Self {
+        Self::new(CliErrorKind::MissingArgument(command, argument))
+    }
+
+    #[inline]
+    fn unexpected_argument(argument: &str) -> Self {
+        Self::new(CliErrorKind::UnexpectedArgument(argument.to_string()))
+    }
+
+    #[inline]
+    fn unsupported(command: &'static str) -> Self {
+        Self::new(CliErrorKind::Unsupported(command))
+    }
+
+    #[inline]
+    fn invalid(message: impl ToString) -> Self {
+        Self::new(CliErrorKind::Invalid(message.to_string()))
+    }
+
+    #[inline]
+    fn failed() -> Self {
+        Self::new(CliErrorKind::Failed)
+    }
+}
+
+impl From<StoreError> for CliError {
+    #[inline]
+    fn from(e: StoreError) -> Self {
+        Self::new(CliErrorKind::Store(e))
+    }
+}
+
+impl From<io::Error> for CliError {
+    #[inline]
+    fn from(e: io::Error) -> Self {
+        Self::new(CliErrorKind::Io(e))
+    }
+}
+
+impl Display for CliError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            CliErrorKind::MissingCommand => write!(f, "missing command"),
+            CliErrorKind::UnknownCommand(name) => write!(f, "unknown command `{}`", name),
+            CliErrorKind::UnknownOption(option) => write!(f, "unknown option `{}`", option),
+            CliErrorKind::MissingValue(option) => write!(f, "missing value for `{}`", option),
+            CliErrorKind::MissingArgument(command, argument) => {
+                write!(f, "`{}` needs {}", command, argument)
+            }
+            CliErrorKind::UnexpectedArgument(argument) => {
+                write!(f, "unexpected argument `{}`", argument)
+            }
+            CliErrorKind::Unsupported(command) => {
+                write!(f, "`{}` must run on the lunatic runtime", command)
+            }
+            CliErrorKind::MemoryStore(command) => write!(
+                f,
+                "`{}` needs the store of the server, set with `store.url` or PASTA6_STORE",
+                command
+            ),
+            CliErrorKind::Store(e) => e.fmt(f),
+            CliErrorKind::Io(e) => e.fmt(f),
+            CliErrorKind::Invalid(message) => f.write_str(message),
+            CliErrorKind::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+#[cfg(test)]
+mod test {
+    use super::{is_loopback, parse, parse_url, Command, COMMANDS};
+
+    #[test]
+    fn test_parse() {
+        let parse = |args: &str| {
+            let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+            parse(&args)
+        };
+        let command = |args: &str| parse(args).unwrap().command;
+
+        assert_eq!(command("serve"), Command::Serve { bind: None });
+        assert_eq!(
+            command("serve --bind 8080 --bind=[::]:8080"),
+            Command::Serve {
+                bind: Some("[::]:8080".to_string())
+            }
+        );
+        let args = parse("--config /etc/pasta6.toml config check").unwrap();
+        assert_eq!(args.config.as_deref(), Some("/etc/pasta6.toml"));
+        assert_eq!(args.command, Command::CheckConfig);
+        assert_eq!(command("export"), Command::Export { path: None });
+        assert_eq!(
+            command("import backup.jsonl"),
+            Command::Import {
+                path: Some("backup.jsonl".to_string())
+            }
+        );
+        assert_eq!(
+            command("paste --url http://localhost:3000/ --title main.rs --expires 1d"),
+            Command::Paste {
+                url: Some("http://localhost:3000/".to_string()),
+                token: None,
+                title: Some("main.rs".to_string()),
+                language: None,
+                expires: Some("1d".to_string()),
+            }
+        );
+        assert_eq!(command("--help"), Command::Help(None));
+        assert_eq!(command("import -h"), Command::Help(Some(&COMMANDS[2])));
+        assert_eq!(command("help config"), Command::Help(Some(&COMMANDS[5])));
+
+        let invalid = [
+            "",
+            "serve 8080",
+            "serve --bind",
+            "serve --title main.rs",
+            "--bind 8080 serve",
+            "create-user",
+            "create-user alice bob",
+            "config",
+            "config show",
+            "unknown",
+            "help unknown",
+        ];
+        for args in invalid {
+            assert!(parse(args).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://localhost:3000/"),
+            Some(("localhost:3000", "localhost", 3000, ""))
+        );
+        assert_eq!(
+            parse_url("http://pasta6.fly.dev/pastes"),
+            Some(("pasta6.fly.dev", "pasta6.fly.dev", 80, "/pastes"))
+        );
+        assert_eq!(parse_url("http://[::1]:8080"), Some(("[::1]:8080", "::1", 8080, "")));
+        assert_eq!(parse_url("http://[::1]"), Some(("[::1]", "::1", 80, "")));
+        assert_eq!(parse_url("https://pasta6.fly.dev"), None);
+        assert_eq!(parse_url("http://localhost:http"), None);
+        assert_eq!(parse_url("http:///pastes"), None);
+    }
+
+    #[test]
+    fn test_is_loopback() {
+        let hosts = ["localhost", "LocalHost", "127.0.0.1", "127.1.2.3", "::1", "::ffff:127.0.0.1"];
+        for host in hosts {
+            assert!(is_loopback(host), "{}", host);
+        }
+        for host in ["pasta6.fly.dev", "localhost.example.com", "192.0.2.1", "fdaa::1", ""] {
+            assert!(!is_loopback(host), "{}", host);
+        }
+    }
+}
@@ -0,0 +1,913 @@
+//! The configuration.
+//!
+//! [`Config`] holds every setting of the server and of the commands. Each of
+//! its [`SETTINGS`] has a default, which the configuration file overrides,
+//! and which environment variables override in turn, e.g. `server.bind` is
+//! overridden by `PASTA6_BIND`. Command line flags such as `serve --bind`
+//! override them all. Every value is checked when the configuration is
+//! loaded, and all the invalid ones are reported at once.
+//!
+//! The file is written in a subset of [TOML]: tables, and keys with
+//! strings, non-negative integers, booleans or arrays of strings, e.g.
+//!
+//! ```toml
+//! [server]
+//! bind = ["0.0.0.0:8080", "[::]:8080"]
+//!
+//! [limits]
+//! max_body_size = 16_777_216
+//!
+//! [store]
+//! url = "file:/data/pasta6.log"
+//!
+//! [logging]
+//! level = "debug"
+//! ```
+//!
+//! [TOML]: https://toml.io/en/v1.0.0
+use crate::http::{BindAddrs, CookieKey, Cors, SecurityHeaders, Timeouts, TrustedProxies};
+use crate::postgres::PoolConfig;
+use crate::ratelimit::RouteQuotas;
+use crate::store::StoreConfig;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// Environment variable holding the path of the configuration file.
+const CONFIG_ENV: &str = "PASTA6_CONFIG";
+/// The configuration file read unless another is given, if it exists.
+const DEFAULT_PATH: &str = "pasta6.toml";
+
+/// The settings of the configuration file.
+const SETTINGS: &[Setting] = &[
+    Setting::new("server.bind", &["PASTA6_BIND"], Kind::List),
+    Setting::new("server.proxy_protocol", &["PASTA6_PROXY_PROTOCOL"], Kind::Bool),
+    Setting::new("server.trusted_proxies", &["PASTA6_TRUSTED_PROXIES"], Kind::List),
+    Setting::new("server.forwarded_header", &["PASTA6_FORWARDED_HEADER"], Kind::String),
+    Setting::new("server.rate_limits", &["PASTA6_RATE_LIMITS"], Kind::List),
+    Setting::new("limits.max_body_size", &["PASTA6_MAX_BODY_SIZE"], Kind::Integer),
+    Setting::new("limits.max_sessions", &["PASTA6_MAX_SESSIONS"], Kind::Integer),
+    Setting::new("limits.store_connections", &["PASTA6_STORE_CONNECTIONS"], Kind::Integer),
+    Setting::new("timeouts.http_idle", &["PASTA6_HTTP_IDLE_TIMEOUT"], Kind::Duration),
+    Setting::new("timeouts.http_header", &["PASTA6_HTTP_HEADER_TIMEOUT"], Kind::Duration),
+    Setting::new("timeouts.http_body", &["PASTA6_HTTP_BODY_TIMEOUT"], Kind::Duration),
+    Setting::new("timeouts.store_checkout", &["PASTA6_STORE_CHECKOUT_TIMEOUT"], Kind::Duration),
+    Setting::new(
+        "timeouts.store_health_check",
+        &["PASTA6_STORE_HEALTH_CHECK_INTERVAL"],
+        Kind::Duration,
+    ),
+    // fly.io sets `DATABASE_URL` when a PostgreSQL cluster is attached.
+    Setting::new("store.url", &["PASTA6_STORE", "DATABASE_URL"], Kind::String),
+    Setting::new("store.migrate", &["PASTA6_MIGRATE"], Kind::Bool),
+    Setting::new("sessions.persist", &["PASTA6_PERSIST_SESSIONS"], Kind::Bool),
+    Setting::new("sessions.secure_cookies", &["PASTA6_SECURE_COOKIES"], Kind::Bool),
+    Setting::new("sessions.cookie_secret", &["PASTA6_COOKIE_SECRET"], Kind::String),
+    Setting::new(
+        "security.content_security_policy",
+        &["PASTA6_CONTENT_SECURITY_POLICY"],
+        Kind::String,
+    ),
+    Setting::new("security.frame_ancestors", &["PASTA6_FRAME_ANCESTORS"], Kind::String),
+    Setting::new(
+        "security.strict_transport_security",
+        &["PASTA6_STRICT_TRANSPORT_SECURITY"],
+        Kind::String,
+    ),
+    Setting::new("security.content_type_options", &["PASTA6_CONTENT_TYPE_OPTIONS"], Kind::String),
+    Setting::new("security.referrer_policy", &["PASTA6_REFERRER_POLICY"], Kind::String),
+    Setting::new("cors.origins", &["PASTA6_CORS_ORIGINS"], Kind::List),
+    Setting::new("cors.credentials", &["PASTA6_CORS_CREDENTIALS"], Kind::Bool),
+    Setting::new("cors.max_age", &["PASTA6_CORS_MAX_AGE"], Kind::Integer),
+    Setting::new("logging.level", &["PASTA6_LOG"], Kind::String),
+];
+
+/// The configuration of the server and of the commands.
+#[derive(Clone)]
+pub(crate) struct Config {
+    /// The configuration file which was read, if any.
+    pub(crate) path: Option<String>,
+    pub(crate) bind: BindAddrs,
+    /// Connections start with a PROXY protocol header, see
+    /// [`crate::http::proxy_protocol`].
+    pub(crate) proxy_protocol: bool,
+    pub(crate) trusted_proxies: TrustedProxies,
+    pub(crate) route_quotas: RouteQuotas,
+    /// The largest request body accepted, which bounds uploads.
+    pub(crate) max_body_size: usize,
+    /// The number of sessions kept in memory.
+    pub(crate) max_sessions: usize,
+    /// How long clients may take to send requests.
+    pub(crate) http_timeouts: Timeouts,
+    pub(crate) store: StoreConfig,
+    /// The PostgreSQL connections: how many, and their timeouts.
+    pub(crate) pool: PoolConfig,
+    /// Applies pending migrations before the server starts.
+    pub(crate) migrate: bool,
+    /// Writes sessions to the store, so that they survive restarts.
+    pub(crate) persist_sessions: bool,
+    /// Only sends the session cookie over HTTPS, which is right whenever the
+    /// server is behind a TLS proxy.
+    pub(crate) secure_cookies: bool,
+    /// The key derived from the cookie secret. Without a secret, a random
+    /// key is used, so cookies don't survive a restart.
+    pub(crate) cookie_key: Option<CookieKey>,
+    pub(crate) security_headers: SecurityHeaders,
+    pub(crate) cors: Cors,
+    pub(crate) log_level: LevelFilter,
+}
+
+/// A setting of the configuration file.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Setting {
+    /// The table and key of the setting, e.g. `server.bind`.
+    key: &'static str,
+    /// The environment variables overriding it, by precedence.
+    envs: &'static [&'static str],
+    kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    String,
+    Bool,
+    Integer,
+    /// An array of strings, or a string of comma-separated values.
+    List,
+    /// A number of seconds, or a string such as `500ms`, `30s`, `5m` or
+    /// `1h`.
+    Duration,
+}
+
+/// A value of the configuration file.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Where the value of a setting came from.
+#[derive(Clone, Debug, PartialEq)]
+enum Origin {
+    /// A line of the configuration file.
+    File(String, usize),
+    Env(&'static str),
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    kind: ConfigErrorKind,
+}
+
+#[derive(Debug)]
+enum ConfigErrorKind {
+    Io(String, io::Error),
+    /// The file isn't valid, on a given line.
+    Syntax(Origin, &'static str),
+    UnknownSetting(Origin, String),
+    DuplicateSetting(Origin, String),
+    /// A setting and why its value was refused.
+    InvalidValue(Origin, &'static str, String),
+}
+
+/// The values of the settings, as strings, which are parsed one by one.
+#[derive(Default)]
+struct Values {
+    values: Vec<(&'static Setting, Origin, String)>,
+    errors: Vec<ConfigError>,
+}
+
+impl Config {
+    /// Loads the configuration from the file `path`, or else the one in
+    /// `PASTA6_CONFIG`, or else `pasta6.toml` if it exists, and from the
+    /// environment. Returns every invalid setting on failure.
+    #[inline]
+    pub(crate) fn load(path: Option<&str>) -> Result<Self, Vec<ConfigError>> {
+        let path = path.map(str::to_string).or_else(|| std::env::var(CONFIG_ENV).ok());
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (DEFAULT_PATH.to_string(), false),
+        };
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => Some(source),
+            Err(e) if !required && e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(vec![ConfigError::new(ConfigErrorKind::Io(path, e))]),
+        };
+        let file = source.as_deref().map(|source| (path.as_str(), source));
+        Self::parse(file, |name| std::env::var(name).ok())
+    }
+
+    /// Parses the configuration file, given as its path and contents, with
+    /// environment variables read by `env` overriding it.
+    #[inline]
+    fn parse(
+        file: Option<(&str, &str)>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Vec<ConfigError>> {
+        let mut values = match file {
+            Some((path, source)) => read(path, source)?,
+            None => Values::default(),
+        };
+        for setting in SETTINGS {
+            let value = setting.envs.iter().find_map(|name| Some((*name, env(name)?)));
+            if let Some((name, value)) = value {
+                values.values.retain(|(other, _, _)| *other != setting);
+                values.values.push((setting, Origin::Env(name), value));
+            }
+        }
+
+        let default_pool = PoolConfig::default();
+        let default_timeouts = Timeouts::default();
+        let default_headers = SecurityHeaders::default();
+        let default_cors = Cors::default();
+        let trusted_proxies: TrustedProxies =
+            values.get("server.trusted_proxies", Default::default(), |value| {
+                TrustedProxies::parse(value).map_err(|e| e.to_string())
+            });
+        let config = Config {
+            path: file.map(|(path, _)| path.to_string()),
+            bind: values.get("server.bind", BindAddrs::default(), |value| {
+                BindAddrs::parse(value).map_err(|e| e.to_string())
+            }),
+            proxy_protocol: values.get("server.proxy_protocol", false, parse_bool),
+            trusted_proxies: values.get(
+                "server.forwarded_header",
+                trusted_proxies.clone(),
+                |value| trusted_proxies.with_header(value).map_err(|e| e.to_string()),
+            ),
+            route_quotas: values.get("server.rate_limits", Default::default(), |value| {
+                RouteQuotas::parse(value).map_err(|e| e.to_string())
+            }),
+            max_body_size: values.get(
+                "limits.max_body_size",
+                crate::http::MAX_REQUEST_BODY_SIZE,
+                parse_positive,
+            ),
+            max_sessions: values.get(
+                "limits.max_sessions",
+                crate::session::MAX_SESSIONS,
+                parse_positive,
+            ),
+            http_timeouts: Timeouts {
+                idle: values.get("timeouts.http_idle", default_timeouts.idle, parse_duration),
+                header_read: values.get(
+                    "timeouts.http_header",
+                    default_timeouts.header_read,
+                    parse_duration,
+                ),
+                body_read: values.get(
+                    "timeouts.http_body",
+                    default_timeouts.body_read,
+                    parse_duration,
+                ),
+            },
+            store: values.get("store.url", StoreConfig::Memory, |value| {
+                value.parse().map_err(|e: crate::store::StoreError| e.to_string())
+            }),
+            pool: PoolConfig {
+                max_size: values.get(
+                    "limits.store_connections",
+                    default_pool.max_size,
+                    parse_positive,
+                ),
+                checkout_timeout: values.get(
+                    "timeouts.store_checkout",
+                    default_pool.checkout_timeout,
+                    parse_duration,
+                ),
+                health_check_interval: values.get(
+                    "timeouts.store_health_check",
+                    default_pool.health_check_interval,
+                    parse_duration,
+                ),
+            },
+            migrate: values.get("store.migrate", false, parse_bool),
+            persist_sessions: values.get("sessions.persist", false, parse_bool),
+            secure_cookies: values.get("sessions.secure_cookies", false, parse_bool),
+            cookie_key: values.get("sessions.cookie_secret", None, |secret| {
+                match CookieKey::from_secret(secret.as_bytes()) {
+                    Ok(key) => Ok(Some(key)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }),
+            // Empty values turn security headers off.
+            security_headers: SecurityHeaders {
+                content_security_policy: values.get(
+                    "security.content_security_policy",
+                    default_headers.content_security_policy,
+                    parse_header,
+                ),
+                frame_ancestors: values.get(
+                    "security.frame_ancestors",
+                    default_headers.frame_ancestors,
+                    parse_header,
+                ),
+                strict_transport_security: values.get(
+                    "security.strict_transport_security",
+                    default_headers.strict_transport_security,
+                    parse_header,
+                ),
+                content_type_options: values.get(
+                    "security.content_type_options",
+                    default_headers.content_type_options,
+                    parse_header,
+                ),
+                referrer_policy: values.get(
+                    "security.referrer_policy",
+                    default_headers.referrer_policy,
+                    parse_header,
+                ),
+            },
+            cors: Cors {
+                allowed_origins: values.get("cors.origins", Vec::new(), |value| {
+                    Ok(crate::http::parse_origins(value))
+                }),
+                allow_credentials: values.get("cors.credentials", false, parse_bool),
+                max_age: values.get("cors.max_age", default_cors.max_age, |value| {
+                    match value.parse() {
+                        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+                        Err(_e) => Err(Kind::Integer.expected()),
+                    }
+                }),
+            },
+            log_level: values.get("logging.level", LevelFilter::INFO, |value| match value {
+                "off" => Ok(LevelFilter::OFF),
+                "error" => Ok(LevelFilter::ERROR),
+                "warn" => Ok(LevelFilter::WARN),
+                "info" => Ok(LevelFilter::INFO),
+                "debug" => Ok(LevelFilter::DEBUG),
+                "trace" => Ok(LevelFilter::TRACE),
+                _ => Err("expected `off`, `error`, `warn`, `info`, `debug` or `trace`".to_string()),
+            }),
+        };
+        match values.errors.is_empty() {
+            true => Ok(config),
+            false => Err(values.errors),
+        }
+    }
+}
+
+/// Parses the configuration file `source`, returning the value of each
+/// setting it sets, as a string.
+#[inline]
+fn read(path: &str, source: &str) -> Result<Values, Vec<ConfigError>> {
+    let entries = Parser::new(source).parse().map_err(|(line, message)| {
+        let origin = Origin::File(path.to_string(), line);
+        vec![ConfigError::new(ConfigErrorKind::Syntax(origin, message))]
+    })?;
+    let mut values = Values::default();
+    for (line, key, value) in entries {
+        let origin = Origin::File(path.to_string(), line);
+        let setting = match SETTINGS.iter().find(|setting| setting.key == key) {
+            Some(setting) => setting,
+            None => {
+                let kind = ConfigErrorKind::UnknownSetting(origin, key);
+                values.errors.push(ConfigError::new(kind));
+                continue;
+            }
+        };
+        if values.values.iter().any(|(other, _, _)| *other == setting) {
+            let kind = ConfigErrorKind::DuplicateSetting(origin, key);
+            values.errors.push(ConfigError::new(kind));
+            continue;
+        }
+        match setting.kind.to_env(value) {
+            Some(value) => values.values.push((setting, origin, value)),
+            None => {
+                let kind =
+                    ConfigErrorKind::InvalidValue(origin, setting.key, setting.kind.expected());
+                values.errors.push(ConfigError::new(kind));
+            }
+        }
+    }
+    Ok(values)
+}
+
+impl Values {
+    /// Returns the value of the setting `key` parsed by `parse`, or else
+    /// `default`. Invalid values are recorded as errors.
+    #[inline]
+    fn get<T>(
+        &mut self,
+        key: &'static str,
+        default: T,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> T {
+        debug_assert!(SETTINGS.iter().any(|setting| setting.key == key), "{}", key);
+        let index = match self.values.iter().position(|(setting, _, _)| setting.key == key) {
+            Some(index) => index,
+            None => return default,
+        };
+        let (_setting, origin, value) = self.values.swap_remove(index);
+        match parse(&value) {
+            Ok(value) => value,
+            Err(message) => {
+                let kind = ConfigErrorKind::InvalidValue(origin, key, message);
+                self.errors.push(ConfigError::new(kind));
+                default
+            }
+        }
+    }
+}
+
+/// Parses `true` or `false`, or `1` or `0` as environment variables often
+/// are.
+#[inline]
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(Kind::Bool.expected()),
+    }
+}
+
+#[inline]
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err("expected a positive integer".to_string()),
+    }
+}
+
+/// Parses a duration, see [`Kind::Duration`], which can't be zero.
+#[inline]
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let millis = match &value[digits.len()..] {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(Kind::Duration.expected()),
+    };
+    match digits.parse::<u64>().ok().and_then(|count| count.checked_mul(millis)) {
+        Some(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+        _ => Err(Kind::Duration.expected()),
+    }
+}
+
+/// Parses the value of a security header, which is turned off when empty.
+#[inline]
+fn parse_header(value: &str) -> Result<Option<String>, String> {
+    Ok((!value.trim().is_empty()).then(|| value.to_string()))
+}
+
+impl Setting {
+    #[inline]
+    const fn new(key: &'static str, envs: &'static [&'static str], kind: Kind) -> Self {
+        Self { key, envs, kind }
+    }
+}
+
+impl Kind {
+    /// Returns `value` as the value of an environment variable, if it's of
+    /// this kind.
+    #[inline]
+    fn to_env(self, value: Value) -> Option<String> {
+        match (self, value) {
+            (Kind::String | Kind::List | Kind::Duration, Value::String(value)) => Some(value),
+            (Kind::Bool, Value::Bool(value)) => Some(value.to_string()),
+            (Kind::Integer | Kind::Duration, Value::Integer(value)) => Some(value.to_string()),
+            (Kind::List, Value::Array(values)) => {
+                let values = values
+                    .into_iter()
+                    .map(|value| match value {
+                        Value::String(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(values.join(","))
+            }
+            _ => None,
+        }
+    }
+
+    /// Describes the values of this kind, for errors.
+    #[inline]
+    fn expected(self) -> String {
+        let expected = match self {
+            Kind::String => "a string",
+            Kind::Bool => "`true` or `false`",
+            Kind::Integer => "a non-negative integer",
+            Kind::List => "an array of strings",
+            Kind::Duration => "a duration, e.g. `30s`",
+        };
+        format!("expected {}", expected)
+    }
+}
+
+/// Parses the supported subset of TOML, keeping track of lines for errors.
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+    line: usize,
+}
+
+/// A syntax error: the line and what was wrong.
+type SyntaxError = (usize, &'static str);
+
+impl<'a> Parser<'a> {
+    #[inline]
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: 0,
+            line: 1,
+        }
+    }
+
+    /// Returns the keys, prefixed with their table, and their values, along
+    /// with their lines.
+    #[inline]
+    fn parse(mut self) -> Result<Vec<(usize, String, Value)>, SyntaxError> {
+        let mut table = None;
+        let mut entries = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            match self.peek() {
+                None => return Ok(entries),
+                Some('[') => {
+                    self.bump();
+                    self.skip_spaces();
+                    table = Some(self.key()?);
+                    self.skip_spaces();
+                    self.expect(']', "expected `]`")?;
+                    self.end_of_line()?;
+                }
+                Some(_) => {
+                    let line = self.line;
+                    let key = self.key()?;
+                    self.skip_spaces();
+                    self.expect('=', "expected `=`")?;
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+                    let key = match &table {
+                        Some(table) => format!("{}.{}", table, key),
+                        None => key,
+                    };
+                    entries.push((line, key, value));
+                }
+            }
+        }
+    }
+
+    /// Parses a bare key, which may be dotted.
+    #[inline]
+    fn key(&mut self) -> Result<String, SyntaxError> {
+        let start = self.position;
+        while matches!(self.peek(), Some('A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' | '.')) {
+            self.bump();
+        }
+        let key = &self.source[start..self.position];
+        if key.is_empty() || key.starts_with('.') || key.ends_with('.') || key.contains("..") {
+            return Err((self.line, "expected a key"));
+        }
+        Ok(key.to_string())
+    }
+
+    #[inline]
+    fn value(&mut self) -> Result<Value, SyntaxError> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('0'..='9' | '+') => {
+                let word = self.word();
+                let digits = word.strip_prefix('+').unwrap_or(word).replace('_', "");
+                match digits.parse() {
+                    Ok(value) => Ok(Value::Integer(value)),
+                    Err(_e) => Err((self.line, "expected a non-negative integer")),
+                }
+            }
+            Some('t' | 'f') => match self.word() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err((self.line, "expected a value")),
+            },
+            _ => Err((self.line, "expected a value")),
+        }
+    }
+
+    /// Parses a string in double quotes, with escapes.
+    #[inline]
+    fn basic_string(&mut self) -> Result<String, SyntaxError> {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(value),
+                Some('\\') => value.push(match self.bump() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    _ => return Err((self.line, "unsupported escape sequence")),
+                }),
+                Some('\n') | None => return Err((self.line, "unterminated string")),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    /// Parses a string in single quotes, without escapes.
+    #[inline]
+    fn literal_string(&mut self) -> Result<String, SyntaxError> {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(value),
+                Some('\n') | None => return Err((self.line, "unterminated string")),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    /// Parses an array, which may span several lines.
+    #[inline]
+    fn array(&mut self) -> Result<Value, SyntaxError> {
+        self.bump();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank_lines();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err((self.line, "expected `,` or `]`")),
+            }
+        }
+    }
+
+    /// Returns the characters up to the next space, comma, bracket or
+    /// comment.
+    #[inline]
+    fn word(&mut self) -> &'a str {
+        let start = self.position;
+        while !matches!(self.peek(), None | Some(' ' | '\t' | '\r' | '\n' | ',' | ']' | '#')) {
+            self.bump();
+        }
+        &self.source[start..self.position]
+    }
+
+    #[inline]
+    fn expect(&mut self, c: char, message: &'static str) -> Result<(), SyntaxError> {
+        match self.bump() {
+            Some(next) if next == c => Ok(()),
+            _ => Err((self.line, message)),
+        }
+    }
+
+    /// Skips the rest of the line, which may only hold a comment.
+    #[inline]
+    fn end_of_line(&mut self) -> Result<(), SyntaxError> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+        match self.bump() {
+            None | Some('\n') => Ok(()),
+            Some(_) => Err((self.line, "expected the end of the line")),
+        }
+    }
+
+    /// Skips spaces, newlines and comments.
+    #[inline]
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    #[inline]
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.bump();
+        }
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        self.source[self.position..].chars().next()
+    }
+
+    #[inline]
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+}
+
+impl ConfigError {
+    #[inline]
+    fn new(kind: ConfigErrorKind) -> Self {
+        Self { kind }
+    }
+}
+
+impl Display for Origin {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File(path, line) => write!(f, "{}:{}", path, line),
+            Origin::Env(name) => f.write_str(name),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ConfigErrorKind::Io(path, e) => write!(f, "{}: {}", path, e),
+            ConfigErrorKind::Syntax(origin, message) => write!(f, "{}: {}", origin, message),
+            ConfigErrorKind::UnknownSetting(origin, key) => {
+                write!(f, "{}: unknown setting `{}`", origin, key)
+            }
+            ConfigErrorKind::DuplicateSetting(origin, key) => {
+                write!(f, "{}: `{}` is set twice", origin, key)
+            }
+            ConfigErrorKind::InvalidValue(origin, key, message) => {
+                write!(f, "{}: `{}`: {}", origin, key, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod test {
+    use super::{read, Config};
+    use crate::http::{Timeouts, TrustedProxies};
+    use crate::store::StoreConfig;
+    use std::time::Duration;
+    use tracing::level_filters::LevelFilter;
+
+    #[test]
+    fn test_read() {
+        let source = r#"
+# Comments are ignored.
+[server]
+bind = [
+    "0.0.0.0:8080",  # IPv4
+    '[::]:8080',
+]
+proxy_protocol = true # behind fly.io
+rate_limits = "POST /p=10/1m"
+
+[cors]
+max_age = 3_600
+origins = []
+"#;
+        let settings: Vec<_> = read("pasta6.toml", source)
+            .unwrap()
+            .values
+            .into_iter()
+            .map(|(setting, origin, value)| (setting.envs[0], origin.to_string(), value))
+            .collect();
+        let setting = |env, line, value: &str| (env, format!("pasta6.toml:{}", line), value.into());
+        assert_eq!(
+            settings,
+            [
+                setting("PASTA6_BIND", 4, "0.0.0.0:8080,[::]:8080"),
+                setting("PASTA6_PROXY_PROTOCOL", 8, "true"),
+                setting("PASTA6_RATE_LIMITS", 9, "POST /p=10/1m"),
+                setting("PASTA6_CORS_MAX_AGE", 12, "3600"),
+                setting("PASTA6_CORS_ORIGINS", 13, ""),
+            ]
+        );
+
+        let errors = |source| match read("pasta6.toml", source) {
+            Ok(values) => values.errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            Err(errors) => errors.iter().map(ToString::to_string).collect(),
+        };
+        assert_eq!(
+            errors("[server]\nbnd = \"0.0.0.0:8080\"\nproxy_protocol = \"yes\""),
+            [
+                "pasta6.toml:2: unknown setting `server.bnd`",
+                "pasta6.toml:3: `server.proxy_protocol`: expected `true` or `false`",
+            ]
+        );
+        assert_eq!(errors("[cors]\nmax_age = -1"), ["pasta6.toml:2: expected a value"]);
+        assert_eq!(
+            errors("[store]\nurl = \"memory\"\nurl = \"memory\""),
+            ["pasta6.toml:3: `store.url` is set twice"]
+        );
+        assert_eq!(errors("[store]\nurl = \"memory"), ["pasta6.toml:2: unterminated string"]);
+        assert_eq!(
+            errors("[server]\nbind = [\"a\"\n\"b\"]"),
+            ["pasta6.toml:3: expected `,` or `]`"]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let source = r#"
+[limits]
+max_body_size = 1_048_576
+
+[timeouts]
+store_checkout = "500ms"
+store_health_check = 60
+http_header = "5s"
+
+[store]
+url = "file:/data/pasta6.log"
+
+[security]
+referrer_policy = ""
+
+[logging]
+level = "debug"
+"#;
+        let env = |name: &str| match name {
+            "PASTA6_STORE" => Some("memory".to_string()),
+            "PASTA6_CORS_CREDENTIALS" => Some("1".to_string()),
+            "PASTA6_TRUSTED_PROXIES" => Some("fdaa::/16".to_string()),
+            "PASTA6_FORWARDED_HEADER" => Some("fly-client-ip".to_string()),
+            "PASTA6_HTTP_IDLE_TIMEOUT" => Some("2m".to_string()),
+            _ => None,
+        };
+        let config = Config::parse(Some(("pasta6.toml", source)), env).ok().unwrap();
+        assert_eq!(config.path.as_deref(), Some("pasta6.toml"));
+        assert_eq!(config.max_body_size, 1_048_576);
+        assert_eq!(config.pool.checkout_timeout, Duration::from_millis(500));
+        assert_eq!(config.pool.health_check_interval, Duration::from_secs(60));
+        assert_eq!(config.http_timeouts.idle, Duration::from_secs(120));
+        assert_eq!(config.http_timeouts.header_read, Duration::from_secs(5));
+        assert_eq!(config.http_timeouts.body_read, Timeouts::default().body_read);
+        // The environment takes precedence over the file.
+        assert_eq!(config.store, StoreConfig::Memory);
+        assert_eq!(config.security_headers.referrer_policy, None);
+        assert!(config.cors.allow_credentials);
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+        assert!(!config.proxy_protocol);
+        assert!(config.cookie_key.is_none());
+        assert_eq!(
+            config.trusted_proxies,
+            TrustedProxies::parse("fdaa::/16").unwrap().with_header("fly-client-ip").unwrap()
+        );
+
+        // Every invalid value is reported, from the file as from the
+        // environment.
+        let env = |name: &str| match name {
+            "PASTA6_CORS_MAX_AGE" => Some("2h".to_string()),
+            "PASTA6_SECURE_COOKIES" => Some("yes".to_string()),
+            "PASTA6_COOKIE_SECRET" => Some("secret".to_string()),
+            "PASTA6_LOG" => Some("verbose".to_string()),
+            "PASTA6_FORWARDED_HEADER" => Some("client-ip".to_string()),
+            "PASTA6_HTTP_BODY_TIMEOUT" => Some("0".to_string()),
+            _ => None,
+        };
+        let source = "[limits]\nmax_body_size = 0\n[timeouts]\nstore_checkout = \"5 s\"";
+        let errors: Vec<_> = match Config::parse(Some(("pasta6.toml", source)), env) {
+            Ok(_config) => panic!("invalid configuration accepted"),
+            Err(errors) => errors.iter().map(ToString::to_string).collect(),
+        };
+        assert_eq!(
+            errors,
+            [
+                "PASTA6_FORWARDED_HEADER: `server.forwarded_header`: invalid forwarded header \
+                 \"client-ip\", expected `x-forwarded-for`, `forwarded`, `fly-client-ip` or \
+                 `x-real-ip`",
+                "pasta6.toml:2: `limits.max_body_size`: expected a positive integer",
+                "PASTA6_HTTP_BODY_TIMEOUT: `timeouts.http_body`: expected a duration, e.g. `30s`",
+                "pasta6.toml:4: `timeouts.store_checkout`: expected a duration, e.g. `30s`",
+                "PASTA6_SECURE_COOKIES: `sessions.secure_cookies`: expected `true` or `false`",
+                "PASTA6_COOKIE_SECRET: `sessions.cookie_secret`: cookie secret must be at least \
+                 32 bytes long",
+                "PASTA6_CORS_MAX_AGE: `cors.max_age`: expected a non-negative integer",
+                "PASTA6_LOG: `logging.level`: expected `off`, `error`, `warn`, `info`, `debug` \
+                 or `trace`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Password-based encryption of paste contents.
+//!
+//! A key is derived from the password with Argon2id and a random salt, and
+//! the content is encrypted with ChaCha20-Poly1305 under a random nonce. The
+//! sealed content is stored base64-encoded as
+//!
+//! ```text
+//! version (1 byte) || salt (16 bytes) || nonce (12 bytes) || ciphertext
+//! ```
+//!
+//! so it's self-contained: the store never sees the password or the key.
+//! Since the ciphertext is authenticated, a wrong password is detected rather
+//! than producing garbage.
+//!
+//! Account passwords are hashed with the same Argon2id parameters, and stored
+//! as PHC strings (`$argon2id$v=19$m=...`), which record the parameters, so
+//! that hashes made before they change can still be verified.
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::Rng;
+use std::fmt::{self, Formatter};
+
+/// Format of sealed content, bumped whenever the KDF parameters or cipher
+/// change.
+const VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+/// Argon2id memory cost in KiB, as recommended by OWASP.
+const ARGON2_MEMORY: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+
+#[derive(Debug)]
+pub(crate) struct CryptoError {
+    kind: CryptoErrorKind,
+}
+
+#[derive(Debug)]
+enum CryptoErrorKind {
+    /// The password is wrong, or the content was tampered with; the two
+    /// can't be told apart.
+    WrongPassword,
+    Malformed(&'static str),
+    Kdf(String),
+}
+
+/// Encrypts `plaintext` with a key derived from `password`.
+#[inline]
+pub(crate) fn seal(password: &str, plaintext: &str) -> Result<String, CryptoError> {
+    let mut rng = rand::thread_rng();
+    let mut salt = [0; SALT_LENGTH];
+    rng.fill(&mut salt);
+    let mut nonce = [0; NONCE_LENGTH];
+    rng.fill(&mut nonce);
+    let cipher = cipher(password, &salt)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_e| CryptoError::malformed("content too long"))?;
+    let mut sealed = Vec::with_capacity(1 + SALT_LENGTH + NONCE_LENGTH + ciphertext.len());
+    sealed.push(VERSION);
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(base64::encode(sealed))
+}
+
+/// Decrypts content sealed by [`seal`].
+#[inline]
+pub(crate) fn open(password: &str, sealed: &str) -> Result<String, CryptoError> {
+    let sealed = base64::decode(sealed).map_err(|_e| CryptoError::malformed("invalid base64"))?;
+    let (version, rest) = sealed
+        .split_first()
+        .ok_or_else(|| CryptoError::malformed("empty"))?;
+    if *version != VERSION {
+        return Err(CryptoError::malformed("unknown version"));
+    }
+    if rest.len() < SALT_LENGTH + NONCE_LENGTH {
+        return Err(CryptoError::malformed("truncated"));
+    }
+    let (salt, rest) = rest.split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+    let plaintext = cipher(password, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_e| CryptoError::new(CryptoErrorKind::WrongPassword))?;
+    String::from_utf8(plaintext).map_err(|_e| CryptoError::malformed("invalid UTF-8"))
+}
+
+/// Hashes an account password.
+#[inline]
+pub(crate) fn hash_password(password: &str) -> Result<String, CryptoError> {
+    let mut salt = [0; SALT_LENGTH];
+    rand::thread_rng().fill(&mut salt);
+    let salt = SaltString::b64_encode(&salt).map_err(CryptoError::password_hash)?;
+    let params =
+        Params::new(ARGON2_MEMORY, ARGON2_ITERATIONS, 1, None).map_err(CryptoError::kdf)?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(CryptoError::password_hash)?
+        .to_string())
+}
+
+/// Checks an account password against a hash made by [`hash_password`].
+#[inline]
+pub(crate) fn verify_password(password: &str, hash: &str) -> Result<(), CryptoError> {
+    let hash = PasswordHash::new(hash).map_err(|_e| CryptoError::malformed("invalid hash"))?;
+    // The algorithm and parameters are taken from the hash.
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .map_err(|e| match e {
+            argon2::password_hash::Error::Password => {
+                CryptoError::new(CryptoErrorKind::WrongPassword)
+            }
+            e => CryptoError::password_hash(e),
+        })
+}
+
+#[inline]
+fn cipher(password: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, CryptoError> {
+    let params = Params::new(ARGON2_MEMORY, ARGON2_ITERATIONS, 1, Some(KEY_LENGTH))
+        .map_err(CryptoError::kdf)?;
+    let mut key = [0; KEY_LENGTH];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(CryptoError::kdf)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+impl CryptoError {
+    #[inline]
+    fn new(kind: CryptoErrorKind) -> Self {
+        Self { kind }
+    }
+
+    #[inline]
+    fn malformed(message: &'static str) -> Self {
+        Self::new(CryptoErrorKind::Malformed(message))
+    }
+
+    #[inline]
+    fn kdf(source: argon2::Error) -> Self {
+        Self::new(CryptoErrorKind::Kdf(source.to_string()))
+    }
+
+    #[inline]
+    fn password_hash(source: argon2::password_hash::Error) -> Self {
+        Self::new(CryptoErrorKind::Kdf(source.to_string()))
+    }
+
+    #[inline]
+    pub(crate) fn is_wrong_password(&self) -> bool {
+        matches!(self.kind, CryptoErrorKind::WrongPassword)
+    }
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            CryptoErrorKind::WrongPassword => write!(f, "wrong password"),
+            CryptoErrorKind::Malformed(message) => write!(f, "malformed content: {}", message),
+            CryptoErrorKind::Kdf(message) => write!(f, "key derivation failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+#[cfg(test)]
+mod test {
+    use super::{hash_password, open, seal, verify_password};
+
+    #[test]
+    fn test_seal() {
+        let sealed = seal("hunter2", "DATABASE_URL=postgres://...").unwrap();
+        assert!(!sealed.contains("DATABASE_URL"));
+        assert_eq!(open("hunter2", &sealed).unwrap(), "DATABASE_URL=postgres://...");
+        assert!(open("hunter3", &sealed).unwrap_err().is_wrong_password());
+        // Salts and nonces are random.
+        assert_ne!(seal("hunter2", "").unwrap(), seal("hunter2", "").unwrap());
+
+        let mut tampered = base64::decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = base64::encode(tampered);
+        assert!(open("hunter2", &tampered).unwrap_err().is_wrong_password());
+        assert!(!open("hunter2", "AQ==").unwrap_err().is_wrong_password());
+        assert!(!open("hunter2", "not base64!").unwrap_err().is_wrong_password());
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"), "{}", hash);
+        assert!(verify_password("correct horse", &hash).is_ok());
+        assert!(verify_password("correct horse!", &hash)
+            .unwrap_err()
+            .is_wrong_password());
+        assert_ne!(hash_password("correct horse").unwrap(), hash);
+        assert!(!verify_password("correct horse", "plain")
+            .unwrap_err()
+            .is_wrong_password());
+    }
+}
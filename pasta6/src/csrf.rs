@@ -0,0 +1,143 @@
+//! Protection against cross-site request forgery (CSRF).
+//!
+//! Requests which change state, i.e. anything but `GET`, are refused with
+//! `403 Forbidden` unless they come from one of our own pages:
+//!
+//! - browsers tell where a request comes from with `Sec-Fetch-Site` or, if
+//!   they're older, `Origin`, which must name this site.
+//! - requests in a session must also carry its CSRF token, as the
+//!   `csrf_token` field of a form (see `templates/csrf.html`) or in the
+//!   `x-csrf-token` header. Tokens are an HMAC of the session ID, so only we
+//!   can make them, and they change whenever the session is rotated.
+//!
+//! Requests without a session act as nobody, so there is nothing to forge
+//! with them beyond what the origin check covers. The JSON API is
+//! authenticated by bearer tokens, which browsers never send on their own,
+//! so it isn't checked at all.
+use crate::http::{CookieKey, Form, Request};
+
+/// Name of the form field holding the CSRF token.
+const TOKEN_FIELD: &str = "csrf_token";
+/// Domain separation for [`CookieKey::sign`], so that tokens are unlike any
+/// cookie signature.
+const TOKEN_PURPOSE: &str = "csrf";
+
+/// Returns the CSRF token of the session `session_id`.
+#[inline]
+pub(crate) fn token(key: &CookieKey, session_id: &str) -> String {
+    let signed = key.sign(TOKEN_PURPOSE, session_id);
+    // Only the signature, as the session ID must stay in its cookie.
+    signed[session_id.len() + 1..].to_string()
+}
+
+/// Returns `true` if `request` may change state in the session
+/// `session_id`, if any.
+#[inline]
+pub(crate) fn verify(key: &CookieKey, session_id: Option<&str>, request: &Request) -> bool {
+    if !is_same_site(request) {
+        tracing::debug!("refusing cross-site request");
+        return false;
+    }
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => return true,
+    };
+    let token = match request_token(request) {
+        Some(token) => token,
+        None => {
+            tracing::debug!("refusing request without a CSRF token");
+            return false;
+        }
+    };
+    // Compared in constant time.
+    let signed = format!("{}.{}", session_id, token);
+    key.verify(TOKEN_PURPOSE, &signed) == Some(session_id)
+}
+
+/// Returns the token sent with `request`, preferring the header, which
+/// doesn't require reading the form.
+#[inline]
+fn request_token(request: &Request) -> Option<String> {
+    if let Some(token) = request.headers().get("x-csrf-token") {
+        return std::str::from_utf8(token).ok().map(str::to_string);
+    }
+    let form = Form::from_request(request).ok()?;
+    form.get(TOKEN_FIELD).map(str::to_string)
+}
+
+/// Returns `false` if the browser says `request` comes from another site.
+/// Clients which say nothing, e.g. scripts, are trusted, as they can send
+/// whatever they like anyway.
+#[inline]
+fn is_same_site(request: &Request) -> bool {
+    // Sent by current browsers with every request (Fetch Metadata).
+    if let Some(site) = request.headers().get("sec-fetch-site") {
+        // `none` is a navigation by the user, e.g. from a bookmark.
+        return site == b"same-origin" || site == b"none";
+    }
+    match request.headers().get("origin") {
+        Some(origin) => match (origin_host(origin), request.headers().get("host")) {
+            (Some(origin), Some(host)) => origin.eq_ignore_ascii_case(host),
+            _ => false,
+        },
+        None => true,
+    }
+}
+
+/// Returns the host and port of an `Origin` header, e.g. `example.com:8080`
+/// for `https://example.com:8080`. Opaque origins (`null`) have none.
+#[inline]
+fn origin_host(origin: &[u8]) -> Option<&[u8]> {
+    let origin = std::str::from_utf8(origin).ok()?;
+    let (scheme, host) = origin.split_once("://")?;
+    if !(scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")) {
+        return None;
+    }
+    Some(host.as_bytes())
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use crate::http::{CookieKey, Headers};
+
+    fn verify(key: &CookieKey, session_id: Option<&str>, headers: &[(&'static str, &str)]) -> bool {
+        let mut request_headers = Headers::empty();
+        for (name, value) in headers {
+            request_headers.insert(name, value);
+        }
+        let request = crate::http::from_parts("/".to_string(), "POST", request_headers, b"");
+        super::verify(key, session_id, &request)
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = CookieKey::generate();
+        let token = super::token(&key, "id");
+        assert_eq!(token, super::token(&key, "id"));
+        assert_ne!(token, super::token(&key, "other"));
+        assert_ne!(token, super::token(&CookieKey::generate(), "id"));
+
+        // Requests outside of a session only need to be same-site.
+        assert!(verify(&key, None, &[]));
+        assert!(verify(&key, None, &[("sec-fetch-site", "same-origin")]));
+        assert!(verify(&key, None, &[("sec-fetch-site", "none")]));
+        assert!(!verify(&key, None, &[("sec-fetch-site", "cross-site")]));
+        assert!(!verify(&key, None, &[("sec-fetch-site", "same-site")]));
+        let same_origin = [("host", "Example.com:8080"), ("origin", "https://example.com:8080")];
+        assert!(verify(&key, None, &same_origin));
+        assert!(!verify(&key, None, &[("host", "example.com"), ("origin", "https://evil.com")]));
+        assert!(!verify(&key, None, &[("host", "example.com"), ("origin", "null")]));
+        assert!(!verify(&key, None, &[("origin", "https://example.com")]));
+
+        // Requests in a session need its token.
+        assert!(verify(&key, Some("id"), &[("x-csrf-token", &token)]));
+        assert!(!verify(&key, Some("id"), &[]));
+        assert!(!verify(&key, Some("other"), &[("x-csrf-token", &token)]));
+        assert!(!verify(&key, Some("id"), &[("x-csrf-token", "")]));
+        assert!(!verify(
+            &key,
+            Some("id"),
+            &[("x-csrf-token", &token), ("sec-fetch-site", "cross-site")]
+        ));
+    }
+}
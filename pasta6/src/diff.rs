@@ -0,0 +1,321 @@
+//! Line diffs between paste revisions.
+//!
+//! [`diff_lines`] implements Myers' O(ND) algorithm, after trimming the
+//! common prefix and suffix, which is usually most of an edited paste. The
+//! changes can then be grouped into [`Hunk`]s and rendered as a unified diff
+//! or side by side.
+use std::fmt::Write;
+
+/// Edit distance past which the middle of two texts is treated as entirely
+/// replaced, bounding the memory used by the backtrace, which is quadratic in
+/// the edit distance.
+const MAX_EDIT_DISTANCE: usize = 2000;
+
+/// A line of either text, including its trailing newline if any.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Change<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// A run of changes with some unchanged lines of context around them.
+pub(crate) struct Hunk<'c, 'a> {
+    /// Line number in the old text of the first line of the hunk, or of the
+    /// line before it if it has no old lines.
+    pub(crate) old_start: usize,
+    pub(crate) old_len: usize,
+    pub(crate) new_start: usize,
+    pub(crate) new_len: usize,
+    pub(crate) changes: &'c [Change<'a>],
+}
+
+/// A row of a side-by-side diff: a line number and line from each text,
+/// either of which is missing if the line was inserted or deleted.
+pub(crate) type Row<'a> = (Option<(usize, &'a str)>, Option<(usize, &'a str)>);
+
+/// Returns the changes which turn `old` into `new`, line by line.
+#[inline]
+pub(crate) fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut changes: Vec<Change> = old[..prefix].iter().map(|line| Change::Equal(line)).collect();
+    changes.extend(myers(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ));
+    changes.extend(old[old.len() - suffix..].iter().map(|line| Change::Equal(line)));
+    changes
+}
+
+/// Returns a shortest edit script from `a` to `b`.
+#[inline]
+fn myers<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<Change<'a>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = a.len() + b.len();
+    let offset = max as isize;
+    // `v[k + offset]` is the furthest `x` reached on diagonal `k = x - y`.
+    let mut v = vec![0isize; 2 * max + 2];
+    // `trace[d]` is the part of `v` for diagonals `-d..=d` before round `d`.
+    let mut trace: Vec<Vec<isize>> = vec![];
+    'search: for d in 0..=max as isize {
+        if d as usize > MAX_EDIT_DISTANCE {
+            tracing::debug!("diff exceeds {} edits, replacing everything", MAX_EDIT_DISTANCE);
+            return a
+                .iter()
+                .map(|line| Change::Delete(line))
+                .chain(b.iter().map(|line| Change::Insert(line)))
+                .collect();
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut changes = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        if d == 0 {
+            // Only the initial run of equal lines is left.
+            break;
+        }
+        let k = x - y;
+        let at = |k: isize| v[(k + d) as usize];
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            changes.push(Change::Equal(a[x as usize - 1]));
+            x -= 1;
+            y -= 1;
+        }
+        if x == prev_x {
+            changes.push(Change::Insert(b[y as usize - 1]));
+        } else {
+            changes.push(Change::Delete(a[x as usize - 1]));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    while x > 0 && y > 0 {
+        changes.push(Change::Equal(a[x as usize - 1]));
+        x -= 1;
+        y -= 1;
+    }
+    changes.reverse();
+    changes
+}
+
+/// Groups changes into hunks with `context` unchanged lines on either side.
+/// Hunks whose context would overlap are merged.
+#[inline]
+pub(crate) fn hunks<'c, 'a>(changes: &'c [Change<'a>], context: usize) -> Vec<Hunk<'c, 'a>> {
+    // Number of old and new lines before each change.
+    let mut old_before = Vec::with_capacity(changes.len() + 1);
+    let mut new_before = Vec::with_capacity(changes.len() + 1);
+    let (mut old, mut new) = (0, 0);
+    for change in changes {
+        old_before.push(old);
+        new_before.push(new);
+        match change {
+            Change::Equal(_) => {
+                old += 1;
+                new += 1;
+            }
+            Change::Delete(_) => old += 1,
+            Change::Insert(_) => new += 1,
+        }
+    }
+    old_before.push(old);
+    new_before.push(new);
+
+    let changed: Vec<usize> = (0..changes.len())
+        .filter(|&i| !matches!(changes[i], Change::Equal(_)))
+        .collect();
+    let mut hunks = vec![];
+    let mut j = 0;
+    while j < changed.len() {
+        let start = changed[j].saturating_sub(context);
+        let mut end = changed[j] + 1;
+        j += 1;
+        while j < changed.len() && changed[j] <= end + 2 * context {
+            end = changed[j] + 1;
+            j += 1;
+        }
+        let end = (end + context).min(changes.len());
+        let old_len = old_before[end] - old_before[start];
+        let new_len = new_before[end] - new_before[start];
+        hunks.push(Hunk {
+            old_start: old_before[start] + (old_len > 0) as usize,
+            old_len,
+            new_start: new_before[start] + (new_len > 0) as usize,
+            new_len,
+            changes: &changes[start..end],
+        });
+    }
+    hunks
+}
+
+/// Formats changes as a unified diff, like `diff -u`.
+#[inline]
+pub(crate) fn unified(old_name: &str, new_name: &str, changes: &[Change], context: usize) -> String {
+    let hunks = hunks(changes, context);
+    let mut diff = String::new();
+    if hunks.is_empty() {
+        return diff;
+    }
+    let _ = writeln!(diff, "--- {}\n+++ {}", old_name, new_name);
+    for hunk in hunks {
+        let _ = writeln!(
+            diff,
+            "@@ -{},{} +{},{} @@",
+            hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len
+        );
+        for change in hunk.changes {
+            let (prefix, line) = match change {
+                Change::Equal(line) => (' ', line),
+                Change::Delete(line) => ('-', line),
+                Change::Insert(line) => ('+', line),
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
+
+/// Lines up the changes of a hunk side by side, pairing deleted lines with
+/// the lines inserted in their place.
+#[inline]
+pub(crate) fn side_by_side<'a>(hunk: &Hunk<'_, 'a>) -> Vec<Row<'a>> {
+    let mut rows = vec![];
+    let (mut old, mut new) = (hunk.old_start, hunk.new_start);
+    let mut changes = hunk.changes.iter().peekable();
+    while let Some(change) = changes.next() {
+        if let Change::Equal(line) = change {
+            rows.push((Some((old, *line)), Some((new, *line))));
+            old += 1;
+            new += 1;
+            continue;
+        }
+        // Collect a block of deletes and inserts.
+        let mut deleted = vec![];
+        let mut inserted = vec![];
+        let mut change = Some(change);
+        while let Some(current) = change {
+            match current {
+                Change::Delete(line) => deleted.push(*line),
+                Change::Insert(line) => inserted.push(*line),
+                Change::Equal(_) => unreachable!(),
+            }
+            change = changes.next_if(|change| !matches!(change, Change::Equal(_)));
+        }
+        for i in 0..deleted.len().max(inserted.len()) {
+            let left = deleted.get(i).map(|line| (old + i, *line));
+            let right = inserted.get(i).map(|line| (new + i, *line));
+            rows.push((left, right));
+        }
+        old += deleted.len();
+        new += inserted.len();
+    }
+    rows
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_lines, hunks, side_by_side, unified, Change};
+
+    #[test]
+    fn test_diff_lines() {
+        use Change::*;
+        assert_eq!(
+            diff_lines("a\nb\nc\n", "a\nc\nd\n"),
+            vec![Equal("a\n"), Delete("b\n"), Equal("c\n"), Insert("d\n")]
+        );
+        assert_eq!(diff_lines("", ""), vec![]);
+        assert_eq!(diff_lines("", "x"), vec![Insert("x")]);
+        assert_eq!(
+            diff_lines("x\ny", "x\ny\n"),
+            vec![Equal("x\n"), Delete("y"), Insert("y\n")]
+        );
+        // The edit script is a shortest one.
+        let changes = diff_lines("a\nb\nc\na\nb\nb\na\n", "c\nb\na\nb\na\nc\n");
+        let edits = changes
+            .iter()
+            .filter(|change| !matches!(change, Equal(_)))
+            .count();
+        assert_eq!(edits, 5);
+    }
+
+    #[test]
+    fn test_unified() {
+        let old: String = (1..=20).map(|i| format!("{}\n", i)).collect();
+        let new: String = (1..=20)
+            .map(|i| match i {
+                2 => "two\n".to_string(),
+                18 => "eighteen\n".to_string(),
+                i => format!("{}\n", i),
+            })
+            .collect();
+        let changes = diff_lines(&old, &new);
+        assert_eq!(hunks(&changes, 3).len(), 2);
+        assert_eq!(hunks(&changes, 8).len(), 1);
+        assert_eq!(
+            unified("a", "b", &changes, 1),
+            "--- a\n+++ b\n\
+             @@ -1,3 +1,3 @@\n 1\n-2\n+two\n 3\n\
+             @@ -17,3 +17,3 @@\n 17\n-18\n+eighteen\n 19\n"
+        );
+        assert_eq!(
+            unified("a", "b", &diff_lines("", "x"), 3),
+            "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+x\n\\ No newline at end of file\n"
+        );
+        assert_eq!(unified("a", "b", &diff_lines("same", "same"), 3), "");
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let changes = diff_lines("a\nb\nc\nd\n", "a\nB\nd\ne\n");
+        let hunks = hunks(&changes, 3);
+        assert_eq!(
+            side_by_side(&hunks[0]),
+            vec![
+                (Some((1, "a\n")), Some((1, "a\n"))),
+                (Some((2, "b\n")), Some((2, "B\n"))),
+                (Some((3, "c\n")), None),
+                (Some((4, "d\n")), Some((3, "d\n"))),
+                (None, Some((4, "e\n"))),
+            ]
+        );
+    }
+}
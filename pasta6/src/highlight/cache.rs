@@ -0,0 +1,177 @@
+//! A cache of highlighted pastes.
+//!
+//! Editing a paste adds a revision, but a revision never changes once
+//! created, so highlighted HTML is cached by paste ID and revision, e.g.
+//! `a1b2c3/2`. Connection processes don't share memory, so on lunatic the
+//! cache lives in a process of its own; it's a best-effort optimization, and
+//! a cache which can't be reached is treated as a miss.
+#[cfg(target_arch = "wasm32")]
+use lunatic::{process::Process, Mailbox, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Total size in bytes of the entries kept by the cache process.
+const MAX_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A handle to the highlight cache, which can be sent to other processes.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum HighlightCache {
+    /// Caches nothing, so every view is highlighted again.
+    Disabled,
+    /// Forwards all calls to a cache process.
+    #[cfg(target_arch = "wasm32")]
+    Process(Process<Request<CacheRequest, Option<String>>>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum CacheRequest {
+    Get(String),
+    Insert(String, String),
+}
+
+impl HighlightCache {
+    /// Spawns a cache process.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub(crate) fn spawn() -> Result<Self, lunatic::LunaticError> {
+        tracing::info!("spawning highlight cache process");
+        Ok(HighlightCache::Process(crate::spawn_with!(
+            MAX_CACHE_SIZE,
+            run
+        )?))
+    }
+
+    /// Returns the cached HTML for `key`, or else computes it with `f` and
+    /// caches it.
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+    #[inline]
+    pub(crate) fn get_or_insert_with(&self, key: &str, f: impl FnOnce() -> String) -> String {
+        match self {
+            HighlightCache::Disabled => f(),
+            #[cfg(target_arch = "wasm32")]
+            HighlightCache::Process(process) => {
+                match process.request(CacheRequest::Get(key.to_string())) {
+                    Ok(Some(html)) => return html,
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("highlight cache error: {}", e),
+                }
+                let html = f();
+                if let Err(e) = process.request(CacheRequest::Insert(key.to_string(), html.clone()))
+                {
+                    tracing::warn!("highlight cache error: {}", e);
+                }
+                html
+            }
+        }
+    }
+}
+
+/// Entry point of the cache process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn run(max_size: usize, mailbox: Mailbox<Request<CacheRequest, Option<String>>>) {
+    let mut lru = Lru::new(max_size);
+    loop {
+        let request = match mailbox.receive() {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("receive error: {}", e);
+                continue;
+            }
+        };
+        let response = match request.data() {
+            CacheRequest::Get(key) => lru.get(key).map(str::to_string),
+            CacheRequest::Insert(key, value) => {
+                lru.insert(key.clone(), value.clone());
+                None
+            }
+        };
+        request.reply(response);
+    }
+}
+
+/// A least-recently-used map of strings, bounded by the total size of its
+/// keys and values.
+struct Lru {
+    entries: HashMap<String, (String, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl Lru {
+    #[inline]
+    fn new(max_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    #[inline]
+    fn get(&mut self, key: &str) -> Option<&str> {
+        let (value, used) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        let key = self.order.remove(used).unwrap();
+        self.order.insert(self.tick, key);
+        *used = self.tick;
+        Some(value.as_str())
+    }
+
+    #[inline]
+    fn insert(&mut self, key: String, value: String) {
+        let size = key.len() + value.len();
+        if size > self.max_size {
+            return;
+        }
+        self.remove(&key);
+        while self.size + size > self.max_size {
+            let (_, oldest) = self.order.iter().next().unwrap();
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    #[inline]
+    fn remove(&mut self, key: &str) {
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.size -= key.len() + value.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lru;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(12);
+        lru.insert("a".to_string(), "111".to_string());
+        lru.insert("b".to_string(), "222".to_string());
+        lru.insert("c".to_string(), "333".to_string());
+        // Using `a` makes `b` the least recently used.
+        assert_eq!(lru.get("a"), Some("111"));
+        lru.insert("d".to_string(), "444".to_string());
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("c"), Some("333"));
+        assert_eq!(lru.get("d"), Some("444"));
+        // Replacing an entry doesn't count it twice.
+        lru.insert("a".to_string(), "1".to_string());
+        assert_eq!(lru.size, 10);
+        // Entries larger than the whole cache aren't kept.
+        lru.insert("e".to_string(), "x".repeat(12));
+        assert_eq!(lru.get("e"), None);
+        assert_eq!(lru.get("a"), Some("1"));
+    }
+}
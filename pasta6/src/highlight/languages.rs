@@ -0,0 +1,431 @@
+//! Syntax tables for the supported languages.
+
+/// Describes the tokens of a language to the lexer.
+pub(super) struct Syntax {
+    /// Canonical name, as shown to users.
+    pub(super) name: &'static str,
+    /// Other names the language is known by, in lowercase.
+    pub(super) aliases: &'static [&'static str],
+    /// File name extensions, in lowercase and without the dot.
+    pub(super) extensions: &'static [&'static str],
+    pub(super) line_comments: &'static [&'static str],
+    pub(super) block_comment: Option<(&'static str, &'static str)>,
+    /// Characters which delimit string literals.
+    pub(super) strings: &'static [char],
+    /// A string delimiter which only delimits single characters, so that it
+    /// can double as something else, like the `'` of Rust lifetimes.
+    pub(super) char_quote: Option<char>,
+    /// Whether `\` escapes the next character in a string.
+    pub(super) escapes: bool,
+    /// Whether tripled delimiters start a string which ends at the next
+    /// tripled delimiter, like Python's `"""`.
+    pub(super) triple_quoted_strings: bool,
+    pub(super) case_insensitive: bool,
+    pub(super) keywords: &'static [&'static str],
+    /// Built-in types.
+    pub(super) types: &'static [&'static str],
+    /// Built-in constants, like `true`.
+    pub(super) literals: &'static [&'static str],
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "const", "continue", "default", "do", "else", "enum", "extern", "for",
+    "goto", "if", "inline", "register", "restrict", "return", "sizeof", "static", "struct",
+    "switch", "typedef", "union", "volatile", "while",
+];
+const C_TYPES: &[&str] = &[
+    "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void",
+    "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t",
+    "uint32_t", "uint64_t",
+];
+
+pub(super) const SYNTAXES: &[Syntax] = &[
+    Syntax {
+        name: "rust",
+        aliases: &["rs"],
+        extensions: &["rs"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\''],
+        char_quote: Some('\''),
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+            "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+            "type", "unsafe", "use", "where", "while",
+        ],
+        types: &[
+            "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+            "i64", "i128", "isize", "f32", "f64", "String", "Vec", "Option", "Result", "Box",
+        ],
+        literals: &["true", "false", "None", "Some", "Ok", "Err"],
+    },
+    Syntax {
+        name: "c",
+        aliases: &["h"],
+        extensions: &["c", "h"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\''],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: C_KEYWORDS,
+        types: C_TYPES,
+        literals: &["NULL", "true", "false"],
+    },
+    Syntax {
+        name: "c++",
+        aliases: &["cpp", "cxx", "hpp"],
+        extensions: &["cpp", "cc", "cxx", "hpp", "hh", "hxx"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\''],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "auto",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "constexpr",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "enum",
+            "explicit",
+            "extern",
+            "for",
+            "friend",
+            "goto",
+            "if",
+            "inline",
+            "namespace",
+            "new",
+            "noexcept",
+            "operator",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "sizeof",
+            "static",
+            "struct",
+            "switch",
+            "template",
+            "this",
+            "throw",
+            "try",
+            "typedef",
+            "typename",
+            "union",
+            "using",
+            "virtual",
+            "volatile",
+            "while",
+        ],
+        types: C_TYPES,
+        literals: &["nullptr", "NULL", "true", "false"],
+    },
+    Syntax {
+        name: "go",
+        aliases: &["golang"],
+        extensions: &["go"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\'', '`'],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "fallthrough",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "type",
+            "var",
+        ],
+        types: &[
+            "bool", "byte", "error", "float32", "float64", "int", "int8", "int16", "int32",
+            "int64", "rune", "string", "uint", "uint8", "uint16", "uint32", "uint64", "uintptr",
+        ],
+        literals: &["true", "false", "nil", "iota"],
+    },
+    Syntax {
+        name: "java",
+        aliases: &[],
+        extensions: &["java"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\''],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "abstract",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "continue",
+            "default",
+            "do",
+            "else",
+            "enum",
+            "extends",
+            "final",
+            "finally",
+            "for",
+            "if",
+            "implements",
+            "import",
+            "instanceof",
+            "interface",
+            "new",
+            "package",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "static",
+            "super",
+            "switch",
+            "synchronized",
+            "this",
+            "throw",
+            "throws",
+            "try",
+            "var",
+            "volatile",
+            "while",
+        ],
+        types: &[
+            "boolean", "byte", "char", "double", "float", "int", "long", "short", "void", "String",
+            "Object",
+        ],
+        literals: &["true", "false", "null"],
+    },
+    Syntax {
+        name: "javascript",
+        aliases: &["js", "node", "typescript", "ts"],
+        extensions: &["js", "mjs", "cjs", "jsx", "ts", "tsx"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\'', '`'],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "export",
+            "extends",
+            "finally",
+            "for",
+            "from",
+            "function",
+            "if",
+            "import",
+            "in",
+            "instanceof",
+            "interface",
+            "let",
+            "new",
+            "of",
+            "return",
+            "switch",
+            "this",
+            "throw",
+            "try",
+            "type",
+            "typeof",
+            "var",
+            "void",
+            "while",
+            "yield",
+        ],
+        types: &["any", "boolean", "number", "string", "unknown", "never"],
+        literals: &["true", "false", "null", "undefined", "NaN", "Infinity"],
+    },
+    Syntax {
+        name: "python",
+        aliases: &["py"],
+        extensions: &["py", "pyi"],
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &['"', '\''],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: true,
+        case_insensitive: false,
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+            "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+            "with", "yield",
+        ],
+        types: &[
+            "bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple",
+        ],
+        literals: &["True", "False", "None"],
+    },
+    Syntax {
+        name: "shell",
+        aliases: &["sh", "bash", "zsh"],
+        extensions: &["sh", "bash", "zsh"],
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &['"', '\''],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        types: &[],
+        literals: &["true", "false"],
+    },
+    Syntax {
+        name: "sql",
+        aliases: &["psql", "postgresql"],
+        extensions: &["sql"],
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['\'', '"'],
+        char_quote: None,
+        escapes: false,
+        triple_quoted_strings: false,
+        case_insensitive: true,
+        keywords: &[
+            "add",
+            "alter",
+            "and",
+            "as",
+            "asc",
+            "begin",
+            "by",
+            "case",
+            "commit",
+            "constraint",
+            "create",
+            "default",
+            "delete",
+            "desc",
+            "distinct",
+            "drop",
+            "else",
+            "end",
+            "exists",
+            "from",
+            "group",
+            "having",
+            "if",
+            "in",
+            "index",
+            "insert",
+            "into",
+            "is",
+            "join",
+            "key",
+            "left",
+            "limit",
+            "not",
+            "offset",
+            "on",
+            "or",
+            "order",
+            "primary",
+            "references",
+            "returning",
+            "rollback",
+            "select",
+            "set",
+            "table",
+            "then",
+            "union",
+            "update",
+            "values",
+            "when",
+            "where",
+            "with",
+        ],
+        types: &[
+            "bigint",
+            "boolean",
+            "char",
+            "date",
+            "integer",
+            "int",
+            "jsonb",
+            "numeric",
+            "serial",
+            "bigserial",
+            "smallint",
+            "text",
+            "timestamp",
+            "timestamptz",
+            "uuid",
+            "varchar",
+        ],
+        literals: &["null", "true", "false"],
+    },
+    Syntax {
+        name: "json",
+        aliases: &[],
+        extensions: &["json"],
+        line_comments: &[],
+        block_comment: None,
+        strings: &['"'],
+        char_quote: None,
+        escapes: true,
+        triple_quoted_strings: false,
+        case_insensitive: false,
+        keywords: &[],
+        types: &[],
+        literals: &["true", "false", "null"],
+    },
+];
@@ -0,0 +1,309 @@
+//! Server-side syntax highlighting for pastes.
+//!
+//! Each language is described by a [`Syntax`] table (keywords, comment and
+//! string delimiters), which drives a single lexer. The lexer is deliberately
+//! simple: it doesn't parse, so it can't be fooled into producing unbalanced
+//! markup, and every byte of the input is escaped exactly once. Tokens are
+//! wrapped in `<span>`s with the `hl-*` classes styled by [`STYLESHEET`].
+//!
+//! Highlighted HTML is cached per paste, see [`HighlightCache`].
+mod cache;
+mod languages;
+
+pub(crate) use cache::HighlightCache;
+
+use crate::html::escape_html;
+use languages::{Syntax, SYNTAXES};
+
+/// Styles for the classes produced by [`highlight`].
+pub(crate) const STYLESHEET: &str = "\
+.hl-keyword{color:#a626a4}\
+.hl-type{color:#c18401}\
+.hl-literal{color:#986801}\
+.hl-number{color:#986801}\
+.hl-string{color:#50a14f}\
+.hl-comment{color:#a0a1a7;font-style:italic}\
+.hl-function{color:#4078f2}";
+
+/// A language which can be highlighted.
+#[derive(Clone, Copy)]
+pub(crate) struct Language(&'static Syntax);
+
+impl Language {
+    /// Looks up a language by name or alias, ignoring case, e.g. `Rust` or
+    /// `rs`.
+    #[inline]
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        SYNTAXES
+            .iter()
+            .find(|syntax| syntax.name == name || syntax.aliases.contains(&name.as_str()))
+            .map(Language)
+    }
+
+    /// Looks up a language by the extension of a file name, e.g. `main.rs`.
+    #[inline]
+    pub(crate) fn from_file_name(file_name: &str) -> Option<Self> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        let extension = extension.to_ascii_lowercase();
+        SYNTAXES
+            .iter()
+            .find(|syntax| syntax.extensions.contains(&extension.as_str()))
+            .map(Language)
+    }
+
+    /// Guesses the language of `content` from telltale lines such as a
+    /// shebang, e.g. `#!/bin/sh`.
+    #[inline]
+    pub(crate) fn guess(content: &str) -> Option<Self> {
+        let first_line = content.lines().next()?.trim();
+        if let Some(interpreter) = first_line.strip_prefix("#!") {
+            // `#!/usr/bin/env python3` or `#!/bin/bash -e`.
+            let mut words = interpreter.split_whitespace();
+            let mut program = words.next()?.rsplit('/').next()?;
+            if program == "env" {
+                program = words.next()?;
+            }
+            let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+            return Self::from_name(program);
+        }
+        let trimmed = content.trim_start();
+        if trimmed.starts_with('{') && serde_json::from_str::<serde_json::Value>(content).is_ok() {
+            return Self::from_name("json");
+        }
+        content.lines().take(20).find_map(|line| {
+            let name = match line.trim_start() {
+                l if l.starts_with("fn main(") || l.starts_with("use std::") => "rust",
+                l if l.starts_with("package main") => "go",
+                l if l.starts_with("#include") => "c",
+                l if l.starts_with("def ") || l.starts_with("from ") => "python",
+                l if l.starts_with("public class ") => "java",
+                l if l.starts_with("const ") && l.contains("require(") => "javascript",
+                _ => return None,
+            };
+            Self::from_name(name)
+        })
+    }
+
+    /// Returns the canonical name of the language, e.g. `rust`.
+    #[inline]
+    pub(crate) fn name(&self) -> &'static str {
+        self.0.name
+    }
+}
+
+/// Picks the language to highlight a paste with: the one it was created with
+/// if it's known, or else one detected from its title or content.
+#[inline]
+pub(crate) fn detect(
+    language: Option<&str>,
+    title: Option<&str>,
+    content: &str,
+) -> Option<Language> {
+    language
+        .and_then(Language::from_name)
+        .or_else(|| title.and_then(Language::from_file_name))
+        .or_else(|| Language::guess(content))
+}
+
+/// Returns `source` as escaped HTML, with its tokens highlighted as
+/// `language`.
+#[inline]
+pub(crate) fn highlight(language: Language, source: &str) -> String {
+    let syntax = language.0;
+    let mut html = String::with_capacity(source.len() * 2);
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        let (class, len) = next_token(syntax, rest, c);
+        let (token, remainder) = rest.split_at(len);
+        match class {
+            Some(class) => {
+                html.push_str("<span class=\"hl-");
+                html.push_str(class);
+                html.push_str("\">");
+                escape_html(&mut html, token);
+                html.push_str("</span>");
+            }
+            None => escape_html(&mut html, token),
+        }
+        rest = remainder;
+    }
+    html
+}
+
+/// Returns the class and length in bytes of the token at the start of
+/// `rest`, whose first character is `c`. The length is never zero.
+#[inline]
+fn next_token(syntax: &Syntax, rest: &str, c: char) -> (Option<&'static str>, usize) {
+    if syntax
+        .line_comments
+        .iter()
+        .any(|prefix| rest.starts_with(prefix))
+    {
+        return (Some("comment"), rest.find('\n').unwrap_or(rest.len()));
+    }
+    if let Some((start, end)) = syntax.block_comment {
+        if let Some(body) = rest.strip_prefix(start) {
+            let len = body
+                .find(end)
+                .map_or(rest.len(), |i| start.len() + i + end.len());
+            return (Some("comment"), len);
+        }
+    }
+    if syntax.strings.contains(&c) {
+        if let Some(len) = string_len(syntax, rest, c) {
+            return (Some("string"), len);
+        }
+    }
+    if c.is_ascii_digit() {
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        // `1..2` is a range, not a number.
+        let len = rest[..len].find("..").unwrap_or(len);
+        return (Some("number"), len);
+    }
+    if is_identifier_start(c) {
+        let len = rest
+            .find(|c: char| !is_identifier_continue(c))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        let is = |words: &[&str]| {
+            if syntax.case_insensitive {
+                words.iter().any(|w| w.eq_ignore_ascii_case(word))
+            } else {
+                words.contains(&word)
+            }
+        };
+        let class = if is(syntax.keywords) {
+            Some("keyword")
+        } else if is(syntax.types) {
+            Some("type")
+        } else if is(syntax.literals) {
+            Some("literal")
+        } else if rest[len..].starts_with('(') {
+            Some("function")
+        } else {
+            None
+        };
+        return (class, len);
+    }
+    (None, c.len_utf8())
+}
+
+/// Returns the length of the string literal at the start of `rest`, or
+/// `None` if `quote` doesn't start one here, like the `'` of a Rust lifetime.
+#[inline]
+fn string_len(syntax: &Syntax, rest: &str, quote: char) -> Option<usize> {
+    if syntax.char_quote == Some(quote) {
+        return char_len(rest, quote);
+    }
+    let triple = [quote; 3].iter().collect::<String>();
+    if syntax.triple_quoted_strings && rest.starts_with(&triple) {
+        let len = rest[3..].find(&triple).map_or(rest.len(), |i| 3 + i + 3);
+        return Some(len);
+    }
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if syntax.escapes => {
+                chars.next();
+            }
+            c if c == quote => return Some(i + c.len_utf8()),
+            _ => {}
+        }
+    }
+    // Unterminated strings run to the end of the input.
+    Some(rest.len())
+}
+
+/// Returns the length of the character literal at the start of `rest`, e.g.
+/// `'a'`, `'\n'` or `'\u{1F600}'`.
+#[inline]
+fn char_len(rest: &str, quote: char) -> Option<usize> {
+    let body = &rest[quote.len_utf8()..];
+    let len = if body.starts_with('\\') {
+        body.char_indices()
+            .skip(2)
+            .take(9)
+            .find(|&(_, c)| c == quote)?
+            .0
+    } else {
+        let c = body.chars().next()?;
+        if !body[c.len_utf8()..].starts_with(quote) {
+            return None;
+        }
+        c.len_utf8()
+    };
+    Some(quote.len_utf8() + len + quote.len_utf8())
+}
+
+#[inline]
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+#[inline]
+fn is_identifier_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::{detect, highlight, Language};
+
+    fn highlight_as(name: &str, source: &str) -> String {
+        highlight(Language::from_name(name).unwrap(), source)
+    }
+
+    #[test]
+    fn test_highlight_rust() {
+        assert_eq!(
+            highlight_as("rust", "fn main() { let s: &'static str = \"<b>\"; } // done"),
+            "<span class=\"hl-keyword\">fn</span> <span class=\"hl-function\">main</span>() { \
+             <span class=\"hl-keyword\">let</span> s: &amp;&#39;<span class=\"hl-keyword\">static</span> \
+             <span class=\"hl-type\">str</span> = <span class=\"hl-string\">&quot;&lt;b&gt;&quot;</span>; } \
+             <span class=\"hl-comment\">// done</span>"
+        );
+        assert_eq!(
+            highlight_as("rust", "'a' 0..10"),
+            "<span class=\"hl-string\">&#39;a&#39;</span> \
+             <span class=\"hl-number\">0</span>..<span class=\"hl-number\">10</span>"
+        );
+    }
+
+    #[test]
+    fn test_highlight_unterminated() {
+        assert_eq!(
+            highlight_as("c", "/* x"),
+            "<span class=\"hl-comment\">/* x</span>"
+        );
+        assert_eq!(
+            highlight_as("python", "'''a\n'b"),
+            "<span class=\"hl-string\">&#39;&#39;&#39;a\n&#39;b</span>"
+        );
+        assert_eq!(
+            highlight_as("sql", "select \"x\\"),
+            "<span class=\"hl-keyword\">select</span> <span class=\"hl-string\">&quot;x\\</span>"
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        let name = |language: Option<Language>| language.map(|language| language.name());
+        assert_eq!(name(detect(Some("RS"), None, "")), Some("rust"));
+        assert_eq!(
+            name(detect(Some("brainfuck"), Some("a.py"), "")),
+            Some("python")
+        );
+        assert_eq!(name(detect(None, Some("main.go"), "")), Some("go"));
+        assert_eq!(
+            name(detect(None, None, "#!/usr/bin/env python3\nprint(1)")),
+            Some("python")
+        );
+        assert_eq!(name(detect(None, None, "#!/bin/bash -e\n")), Some("shell"));
+        assert_eq!(name(detect(None, None, "{\"a\": [1]}")), Some("json"));
+        assert_eq!(name(detect(None, None, "#include <stdio.h>\n")), Some("c"));
+        assert_eq!(name(detect(None, Some("notes"), "hello")), None);
+    }
+}
@@ -0,0 +1,17 @@
+//! Helpers for building HTML pages.
+
+/// Appends `text` to `buf`, escaping the characters which are special in
+/// HTML.
+#[inline]
+pub(crate) fn escape_html(buf: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => buf.push_str("&amp;"),
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '"' => buf.push_str("&quot;"),
+            '\'' => buf.push_str("&#39;"),
+            c => buf.push(c),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! The addresses the server listens on.
+//!
+//! The server accepts connections on every address of [`BindAddrs`], each
+//! with a listener process of its own, so that it can listen on both IPv4
+//! and IPv6, or on a public and a private network. Port 0 lets the OS pick
+//! a free port, which is then reported back by [`crate::app::server`].
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// The port listened on unless given.
+const DEFAULT_PORT: u16 = 3000;
+
+/// The addresses to listen on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BindAddrs {
+    addrs: Vec<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BindError {
+    kind: BindErrorKind,
+}
+
+#[derive(Debug)]
+enum BindErrorKind {
+    /// An address which couldn't be parsed.
+    InvalidAddr(String),
+    /// An empty list, which would never accept a connection.
+    Empty,
+}
+
+impl Default for BindAddrs {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            addrs: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT))],
+        }
+    }
+}
+
+impl BindAddrs {
+    /// Parses a comma-separated list of socket addresses, e.g.
+    /// `0.0.0.0:8080,[::]:8080`. A bare port, e.g. `8080`, stands for that
+    /// port on every IPv4 address. The default is `0.0.0.0:3000`.
+    #[inline]
+    pub(crate) fn parse(addrs: &str) -> Result<Self, BindError> {
+        let addrs: Vec<_> = addrs
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| parse_addr(addr).ok_or_else(|| BindError::invalid_addr(addr)))
+            .collect::<Result<_, _>>()?;
+        if addrs.is_empty() {
+            return Err(BindError::empty());
+        }
+        Ok(Self { addrs })
+    }
+
+    #[inline]
+    pub(crate) fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+}
+
+#[inline]
+fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    match addr.parse::<u16>() {
+        Ok(port) => Some(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))),
+        Err(_e) => addr.parse().ok(),
+    }
+}
+
+impl BindError {
+    #[inline]
+    fn invalid_addr(addr: &str) -> Self {
+        Self {
+            kind: BindErrorKind::InvalidAddr(addr.to_string()),
+        }
+    }
+
+    #[inline]
+    fn empty() -> Self {
+        Self {
+            kind: BindErrorKind::Empty,
+        }
+    }
+}
+
+impl Display for BindError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            BindErrorKind::InvalidAddr(addr) => write!(
+                f,
+                "invalid bind address {:?}, expected e.g. `0.0.0.0:8080` or `[::]:8080`",
+                addr
+            ),
+            BindErrorKind::Empty => write!(f, "no bind address"),
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+#[cfg(test)]
+mod test {
+    use super::BindAddrs;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_parse() {
+        let addrs = BindAddrs::parse("127.0.0.1:8080, [::1]:0,8081").unwrap();
+        let expected: Vec<SocketAddr> = ["127.0.0.1:8080", "[::1]:0", "0.0.0.0:8081"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(addrs.addrs(), expected);
+        assert_eq!(BindAddrs::default().addrs()[0].to_string(), "0.0.0.0:3000");
+        for invalid in ["", " , ", "localhost:8080", "::1:8080", "0.0.0.0:65536"] {
+            assert!(BindAddrs::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -36,6 +36,24 @@ impl Client {
         let response = self.connection.next_response()?;
         Ok(response)
     }
+
+    #[inline]
+    pub(crate) fn request_with_body(
+        &mut self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> ClientResult<Response> {
+        write!(self.tcp_stream, "{} {} HTTP/1.1\r\n", method.as_ref(), path)?;
+        for (name, value) in headers {
+            write!(self.tcp_stream, "{}: {}\r\n", name, value)?;
+        }
+        write!(self.tcp_stream, "content-length: {}\r\n\r\n", body.len())?;
+        self.tcp_stream.write_all(body)?;
+        let response = self.connection.next_response()?;
+        Ok(response)
+    }
 }
 
 impl<E> From<E> for ClientError
@@ -1,9 +1,12 @@
-use crate::http::{ConnectionError, Headers};
+use crate::http::cookie::{self, SetCookie};
+use crate::http::timeout::{self, Phase, Timer};
+use crate::http::{ConnectionError, Headers, SecurityHeaders};
 use crate::net::TcpStream;
 use bytes::Bytes;
 use std::fmt::{self, Formatter};
 use std::io::Read;
 use std::io::{self, Write};
+use std::net::SocketAddr;
 use std::str;
 use std::str::Utf8Error;
 
@@ -13,6 +16,11 @@ const MAX_RESPONSE_HEADERS: usize = 16;
 const MAX_REQUEST_HEADERS: usize = 100;
 /// Initial buffer size allocated for an HTTP request.
 const INIT_REQUEST_BUFFER_SIZE: usize = 1024;
+/// Maximum size of an HTTP request head.
+const MAX_REQUEST_HEAD_SIZE: usize = 64 * 1024;
+/// Default maximum size of an HTTP request body, which bounds the size of
+/// uploaded files, see [`crate::config`].
+pub(crate) const MAX_REQUEST_BODY_SIZE: usize = 8 * 1024 * 1024;
 
 #[cfg_attr(test, derive(Debug))]
 pub(super) struct Connection {
@@ -22,6 +30,14 @@ pub(super) struct Connection {
     buf: Vec<u8>,
     #[cfg_attr(not(test), allow(dead_code))]
     tcp_stream: TcpStream,
+    /// The address of the other end, if known.
+    remote_addr: Option<SocketAddr>,
+    /// The address of our end, if known.
+    local_addr: Option<SocketAddr>,
+    /// Requests with a larger body are refused.
+    max_body_size: usize,
+    /// Times the reading of requests, see [`crate::http::timeout`].
+    timer: Option<Timer>,
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -31,6 +47,9 @@ pub(crate) struct Response<'body> {
     // TODO: find a way to avoid copying the headers?
     headers: Headers,
     body: Body<'body>,
+    /// The nonce of the inline scripts and styles of the body, see
+    /// [`crate::http::security`]. Boxed, as responses are often moved.
+    nonce: Option<Box<str>>,
 }
 
 pub(crate) struct Request<'body> {
@@ -40,12 +59,23 @@ pub(crate) struct Request<'body> {
     // TODO: find a way to avoid copying the headers?
     headers: Headers,
     body: Body<'body>,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    /// Whether this is a `HEAD` request, which is handled as a `GET` whose
+    /// response is sent without its body.
+    head: bool,
 }
 
+/// The methods the server implements, for `allow` headers.
+pub(crate) const ALLOWED_METHODS: &str = "GET, HEAD, POST, DELETE, OPTIONS";
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum Method {
     Get,
     Post,
+    Delete,
+    /// Only used for CORS preflights, see [`crate::http::Cors`].
+    Options,
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -66,7 +96,6 @@ enum BodyKind<'a> {
 #[cfg_attr(test, derive(Debug))]
 enum BodyLength {
     Known(usize),
-    Unknown,
     Empty,
 }
 
@@ -81,6 +110,11 @@ enum ResponseErrorKind {
     ParseHead,
     ParseInt,
     InvalidMessageFraming,
+    /// The response has a `transfer-encoding`, which isn't supported.
+    TransferEncoding,
+    Read,
+    /// The connection was closed before the end of the response.
+    UnexpectedEof,
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -94,7 +128,50 @@ impl Connection {
             // FIXME: rename the constant? This is for both req/recv bufs.
             buf: vec![0; INIT_REQUEST_BUFFER_SIZE],
             tcp_stream,
+            remote_addr: None,
+            local_addr: None,
+            max_body_size: MAX_REQUEST_BODY_SIZE,
+            timer: None,
+        }
+    }
+
+    /// Sets the addresses of both ends, which requests are tagged with.
+    #[inline]
+    pub(super) fn with_addrs(mut self, remote_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        self.remote_addr = Some(remote_addr);
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Sets the largest request body accepted.
+    #[inline]
+    pub(super) fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets the timer which times out the reading of requests.
+    #[inline]
+    pub(super) fn with_timer(mut self, timer: Timer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    #[inline]
+    fn start(&mut self, phase: Phase) {
+        if let Some(timer) = &mut self.timer {
+            timer.start(phase);
+        }
+    }
+
+    /// Reads the request into `buf[start..end]`, failing with a timeout
+    /// error once the current phase is over.
+    #[inline]
+    fn read_request(&mut self, start: usize, end: usize) -> io::Result<usize> {
+        if let Some(timer) = &self.timer {
+            timer.before_read(&self.tcp_stream)?;
         }
+        self.tcp_stream.read(&mut self.buf[start..end])
     }
 
     /// Parses a byte slice into an HTTP response.
@@ -115,12 +192,24 @@ where {
         //   CPU cycles on parsing.
         let mut bytes_read = 0;
         loop {
-            bytes_read += match self.tcp_stream.read(&mut self.buf[bytes_read..]) {
-                Ok(bytes_read) => bytes_read,
-                Err(e) => {
-                    tracing::error!("read error: {}", e);
-                    panic!();
+            if bytes_read == self.buf.len() {
+                if self.buf.len() >= MAX_REQUEST_HEAD_SIZE {
+                    return Err(ResponseError {
+                        kind: ResponseErrorKind::ParseHead,
+                        _source: None,
+                    });
+                }
+                let len = self.buf.len() * 2;
+                self.buf.resize(len, 0);
+            }
+            bytes_read += match self.read_response(bytes_read, self.buf.len())? {
+                0 => {
+                    return Err(ResponseError {
+                        kind: ResponseErrorKind::UnexpectedEof,
+                        _source: None,
+                    })
                 }
+                bytes_read => bytes_read,
             };
             tracing::trace!("connection bytes read: {}", bytes_read);
             let response_bytes = &mut self.buf[..bytes_read];
@@ -136,14 +225,8 @@ where {
                     debug_assert!(httparse_response.code.is_some(), "missing code");
                     debug_assert!(httparse_response.reason.is_some(), "missing reason");
                     debug_assert!(httparse_response.version.is_some(), "missing version");
-                    let response_str = match str::from_utf8(response_bytes) {
-                        Ok(response_str) => response_str,
-                        Err(e) => {
-                            tracing::error!("utf-8 error: {}", e);
-                            panic!();
-                        }
-                    };
-                    tracing::trace!("client received response: {}", response_str);
+                    // Bodies can be binary, e.g. uploaded files.
+                    tracing::trace!("client received response: {}", lossy_response_str);
 
                     // RFC 7230 section 3.3.3 point 4:
                     // > If a message is received without Transfer-Encoding and with
@@ -154,17 +237,16 @@ where {
                     // > error. ... If this is a response message received by a user
                     // > agent, the user agent MUST close the connection to the
                     // > server and discard the received response.
-                    // TODO: verify that this works case-insensitively.
                     let mut content_length = None;
                     let mut has_transfer_encoding = false;
                     for (name, value) in httparse_response.headers.iter().map(|h| (h.name, h.value))
                     {
-                        if name == "transfer-encoding" {
+                        if name.eq_ignore_ascii_case("transfer-encoding") {
                             has_transfer_encoding = true;
                             continue;
                         }
 
-                        if name == "content-length" {
+                        if name.eq_ignore_ascii_case("content-length") {
                             if let Some(content_length) = content_length {
                                 if content_length == value {
                                     tracing::warn!("duplicate content-length header");
@@ -179,9 +261,15 @@ where {
                             content_length = Some(value);
                         }
                     }
+                    // Chunked bodies aren't supported, which our server never
+                    // sends anyway.
                     if has_transfer_encoding {
-                        unimplemented!()
+                        return Err(ResponseError {
+                            kind: ResponseErrorKind::TransferEncoding,
+                            _source: None,
+                        });
                     }
+                    // `None` if the body ends when the connection is closed.
                     let body_length = match content_length {
                         // RFC 7230 section 3.3.3 point 5:
                         // > If a valid Content-Length header field is present
@@ -194,18 +282,62 @@ where {
                                     _source: None,
                                 })?;
                             tracing::trace!("response content-length: {}", content_length);
-                            BodyLength::Known(content_length)
+                            Some(BodyLength::Known(content_length))
+                        }
+                        // RFC 7230 section 3.3.3 point 1:
+                        // > Any response to a HEAD request and any response with a
+                        // > 1xx (Informational), 204 (No Content), or 304 (Not
+                        // > Modified) status code is always terminated by the first
+                        // > empty line after the header fields
+                        None if matches!(httparse_response.code, Some(204 | 304)) => {
+                            Some(BodyLength::Empty)
                         }
                         // RFC 7230 section 3.3.3 point 7:
                         // > Otherwise, this is a response message without a declared
                         // > message body length, so the message body length is
                         // > determined by the number of octets received prior to the
                         // > server closing the connection.
-                        None => unimplemented!(),
+                        None => None,
                     };
 
                     let code = httparse_response.code.unwrap();
                     let headers = httparse_response.headers.into();
+                    // The body may be larger than the buffer, or not have
+                    // arrived in full yet.
+                    let body_length = match body_length {
+                        Some(BodyLength::Known(length)) => {
+                            let end = head_length + length;
+                            if self.buf.len() < end {
+                                self.buf.resize(end, 0);
+                            }
+                            while bytes_read < end {
+                                bytes_read += match self.read_response(bytes_read, end)? {
+                                    0 => {
+                                        return Err(ResponseError {
+                                            kind: ResponseErrorKind::UnexpectedEof,
+                                            _source: None,
+                                        })
+                                    }
+                                    bytes_read => bytes_read,
+                                };
+                            }
+                            BodyLength::Known(length)
+                        }
+                        Some(body_length) => body_length,
+                        None => {
+                            loop {
+                                if bytes_read == self.buf.len() {
+                                    let len = self.buf.len() * 2;
+                                    self.buf.resize(len, 0);
+                                }
+                                match self.read_response(bytes_read, self.buf.len())? {
+                                    0 => break,
+                                    n => bytes_read += n,
+                                }
+                            }
+                            BodyLength::Known(bytes_read - head_length)
+                        }
+                    };
                     let body = Body {
                         kind: BodyKind::Connection {
                             connection: self,
@@ -227,9 +359,25 @@ where {
         }
     }
 
+    /// Reads more of a response into `buf[start..end]`, returning the number
+    /// of bytes read.
+    #[inline]
+    fn read_response(&mut self, start: usize, end: usize) -> Result<usize, ResponseError> {
+        self.tcp_stream
+            .read(&mut self.buf[start..end])
+            .map_err(|e| {
+                tracing::debug!("read error: {}", e);
+                ResponseError {
+                    kind: ResponseErrorKind::Read,
+                    _source: None,
+                }
+            })
+    }
+
     #[inline]
     pub(super) fn next_request(&mut self) -> Result<Request, ConnectionError> {
         tracing::trace!("server handling connection");
+        self.start(Phase::Idle);
         loop {
             // TODO: we probably shouldn't reuse the same buf on a single
             //   connection (although it is uni-directional...)
@@ -243,19 +391,20 @@ where {
             let mut bytes_read = 0;
             let mut reached_eof = false;
             loop {
-                // FIXME: make resizing work again
-                //// If there is no remaining space in the buffer to read into, then
-                //// we need to grow the buffer.
-                //// TODO: grow the buffer in powers of two to perform O(log n)
-                ////   allocations rather than O(n) allocations.
-                //if bytes_read >= self.buf.len() {
-                //    debug_assert!(bytes_read == self.buf.len());
-                //    const EMPTY_BUFFER: [u8; INIT_REQUEST_BUFFER_SIZE] =
-                //        [0; INIT_REQUEST_BUFFER_SIZE];
-                //    self.buf.extend_from_slice(&EMPTY_BUFFER);
-                //}
-
-                bytes_read += match self.tcp_stream.read(&mut self.buf[bytes_read..]) {
+                // If there is no remaining space in the buffer to read into, then
+                // we need to grow the buffer. The buffer grows in powers of two
+                // to perform O(log n) allocations rather than O(n) allocations.
+                if bytes_read >= self.buf.len() {
+                    debug_assert!(bytes_read == self.buf.len());
+                    if self.buf.len() >= MAX_REQUEST_HEAD_SIZE {
+                        tracing::debug!("request head too large");
+                        return Err(ConnectionError::header_fields_too_large());
+                    }
+                    let len = self.buf.len() * 2;
+                    self.buf.resize(len, 0);
+                }
+
+                bytes_read += match self.read_request(bytes_read, self.buf.len()) {
                     Ok(0) => {
                         tracing::trace!("reached EOF");
                         // If we've reached EOF and there are no unparsed bytes,
@@ -271,6 +420,14 @@ where {
                         tracing::trace!("server bytes read: {}", bytes_read);
                         bytes_read
                     }
+                    Err(e) if timeout::is_timeout(&e) => {
+                        tracing::debug!("read timed out");
+                        return Err(if bytes_read == 0 {
+                            ConnectionError::idle()
+                        } else {
+                            ConnectionError::timed_out()
+                        });
+                    }
                     Err(e) => {
                         tracing::debug!("read error: {}", e);
                         // If the client dropped the socket without properly
@@ -282,6 +439,10 @@ where {
                         return Err(ConnectionError::unexpected_eof());
                     }
                 };
+                // The head is timed from its first byte.
+                if self.timer.as_ref().is_some_and(|timer| timer.phase() == Phase::Idle) {
+                    self.start(Phase::Head);
+                }
                 // Parse the data into an HTTP request.
                 // FIXME: remove these logs
                 //tracing::trace!(
@@ -300,31 +461,88 @@ where {
                         debug_assert!(request.method.is_some(), "missing method");
                         debug_assert!(request.version.is_some(), "missing version");
                         // TODO: avoid re-parsing the request head once we're on the body.
-                        // TODO: content-length may be omitted for some kinds of requests.
-                        let body_length = match request
-                            .headers
-                            .iter()
-                            .find(|h| h.name == "content-length")
-                            .map(|h| h.value)
-                        {
+                        let mut content_length = None;
+                        for header in request.headers.iter() {
+                            // RFC 7230 section 3.3.3 point 3:
+                            // > If a Transfer-Encoding header field is present in a
+                            // > request and the chunked transfer coding is not the final
+                            // > encoding, the message body length cannot be determined
+                            // > reliably; the server MUST respond with the 400 (Bad
+                            // > Request) status code and then close the connection.
+                            // Chunked bodies aren't supported either, so their clients
+                            // are asked to send a length instead.
+                            if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                                let chunked = str::from_utf8(header.value)
+                                    .ok()
+                                    .and_then(|codings| codings.rsplit(',').next())
+                                    .is_some_and(|coding| {
+                                        coding.trim().eq_ignore_ascii_case("chunked")
+                                    });
+                                tracing::debug!("request with a transfer-encoding");
+                                return Err(if chunked {
+                                    ConnectionError::length_required()
+                                } else {
+                                    ConnectionError::bad_request()
+                                });
+                            }
+                            // RFC 7230 section 3.3.3 point 4, see `next_response`.
+                            if header.name.eq_ignore_ascii_case("content-length") {
+                                match content_length {
+                                    Some(content_length) if content_length != header.value => {
+                                        tracing::debug!("differing content-length headers");
+                                        return Err(ConnectionError::bad_request());
+                                    }
+                                    _ => content_length = Some(header.value),
+                                }
+                            }
+                        }
+                        let body_length = match content_length {
                             // FIXME: don't discard the source error
                             Some(content_length) => {
                                 let content_length =
                                     crate::http::connection::usize_from_bytes(content_length)
-                                        .map_err(|_source| ConnectionError::request_error())?;
+                                        .map_err(|_source| ConnectionError::bad_request())?;
                                 BodyLength::Known(content_length)
                             }
-                            None => {
-                                // FIXME: we're assuming no content-length
-                                //   means no message-body but this is
-                                //   incorrect.
-                                BodyLength::Empty
-                            }
+                            // RFC 7230 section 3.3.3 point 6:
+                            // > If this is a request message and none of the above are
+                            // > true, then the message body length is zero (no message
+                            // > body is present).
+                            None => BodyLength::Empty,
                         };
-                        // FIXME: include the headers of the request.
                         let path = request.path.unwrap().to_string();
-                        let method = request.method.unwrap().into();
+                        // Other methods are refused before the body is
+                        // read, like bodies which are too large.
+                        let (method, head) = match request.method.unwrap() {
+                            "HEAD" => (Method::Get, true),
+                            method => (Method::try_from(method)?, false),
+                        };
                         let headers = request.headers.into();
+                        let (remote_addr, local_addr) = (self.remote_addr, self.local_addr);
+                        // Read the remainder of the body, since the handler
+                        // only has access to the buffer.
+                        if let BodyLength::Known(length) = body_length {
+                            if length > self.max_body_size {
+                                tracing::debug!("request body too large: {}", length);
+                                return Err(ConnectionError::payload_too_large());
+                            }
+                            let end = head_length + length;
+                            if self.buf.len() < end {
+                                self.buf.resize(end, 0);
+                            }
+                            self.start(Phase::Body);
+                            while bytes_read < end {
+                                bytes_read += match self.read_request(bytes_read, end) {
+                                    Err(e) if timeout::is_timeout(&e) => {
+                                        tracing::debug!("read timed out");
+                                        return Err(ConnectionError::timed_out());
+                                    }
+                                    Ok(0) | Err(_) => return Err(ConnectionError::unexpected_eof()),
+                                    Ok(bytes_read) => bytes_read,
+                                };
+                            }
+                        }
+                        self.start(Phase::Handling);
                         let body = Body {
                             kind: BodyKind::Connection {
                                 connection: self,
@@ -336,6 +554,9 @@ where {
                             method,
                             headers,
                             body,
+                            remote_addr,
+                            local_addr,
+                            head,
                         };
                         return Ok(request);
                     }
@@ -362,11 +583,12 @@ where {
                         // > field is at fault. In the latter case, the response
                         // > representation SHOULD specify which header field was too
                         // > large.
-                        // FIXME: return an error here
-                        httparse::Error::TooManyHeaders => unimplemented!(),
+                        httparse::Error::TooManyHeaders => {
+                            return Err(ConnectionError::header_fields_too_large())
+                        }
                         e => {
-                            tracing::error!("parse error: {}", e);
-                            panic!()
+                            tracing::debug!("parse error: {}", e);
+                            return Err(ConnectionError::bad_request());
                         }
                     },
                 };
@@ -381,6 +603,68 @@ impl<'body> Response<'body> {
         Self::new(code, Headers::empty(), body.as_bytes().into())
     }
 
+    #[inline]
+    pub(crate) fn from_string(code: u16, body: String) -> Response<'body> {
+        Self::new(code, Headers::empty(), body.into_bytes().into())
+    }
+
+    #[inline]
+    pub(crate) fn from_bytes(code: u16, body: Vec<u8>) -> Response<'body> {
+        Self::new(code, Headers::empty(), body.into())
+    }
+
+    /// Returns an HTML response with the appropriate `content-type`.
+    #[inline]
+    pub(crate) fn html(code: u16, body: String) -> Response<'body> {
+        Self::from_string(code, body).with_header("content-type", "text/html; charset=utf-8")
+    }
+
+    /// Returns a plain text response with the appropriate `content-type`.
+    #[inline]
+    pub(crate) fn text(code: u16, body: String) -> Response<'body> {
+        Self::from_string(code, body).with_header("content-type", "text/plain; charset=utf-8")
+    }
+
+    /// Returns a JSON response with the appropriate `content-type`.
+    #[inline]
+    pub(crate) fn json(code: u16, body: String) -> Response<'body> {
+        Self::from_string(code, body).with_header("content-type", "application/json")
+    }
+
+    /// Returns a `303 See Other` response, which redirects the client to
+    /// `location` with a `GET` request.
+    #[inline]
+    pub(crate) fn redirect(location: &str) -> Response<'body> {
+        Self::from_static(303, "").with_header("location", location)
+    }
+
+    #[inline]
+    pub(crate) fn with_header(mut self, name: &'static str, value: impl AsRef<[u8]>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Allows the inline scripts and styles with `nonce` to run, see
+    /// [`crate::http::security`].
+    #[inline]
+    pub(crate) fn with_nonce(mut self, nonce: String) -> Self {
+        self.nonce = Some(nonce.into_boxed_str());
+        self
+    }
+
+    #[inline]
+    pub(crate) fn take_nonce(&mut self) -> Option<String> {
+        self.nonce.take().map(String::from)
+    }
+
+    /// Adds a `set-cookie` header. Unlike other headers, a response can set
+    /// any number of cookies.
+    #[inline]
+    pub(crate) fn with_cookie(mut self, cookie: &SetCookie) -> Self {
+        self.headers.append("set-cookie", cookie.to_string());
+        self
+    }
+
     #[inline]
     pub(crate) fn code(&self) -> u16 {
         self.code
@@ -397,20 +681,53 @@ impl<'body> Response<'body> {
     }
 
     #[inline]
-    fn new(code: u16, headers: Headers, body: Body<'body>) -> Response<'body> {
+    pub(crate) fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    #[inline]
+    pub(crate) fn body(&self) -> &Body {
+        &self.body
+    }
+
+    #[inline]
+    pub(crate) fn new(code: u16, headers: Headers, body: Body<'body>) -> Response<'body> {
         let reason = match code {
             200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            303 => "See Other",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            429 => "Too Many Requests",
             431 => "Request Header Fields Too Large",
-            code => {
-                tracing::error!("unknown status code: {}", code);
-                unimplemented!()
-            }
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            // Other codes only come from other servers, e.g. a proxy in
+            // front of ours, so their class is good enough.
+            code => match code / 100 {
+                1 => "Informational",
+                2 => "Successful",
+                3 => "Redirection",
+                4 => "Client Error",
+                _ => "Server Error",
+            },
         };
         Self {
             code,
             reason,
             headers,
             body,
+            nonce: None,
         }
     }
 }
@@ -419,7 +736,11 @@ impl<'body> Response<'body> {
 impl From<Response<'_>> for hyper::Response<hyper::Body> {
     #[inline]
     fn from(response: Response) -> Self {
-        hyper::Response::new(response.body.into())
+        let mut builder = hyper::Response::builder().status(response.code);
+        for (name, value) in response.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        builder.body(response.body.into()).unwrap()
     }
 }
 
@@ -429,18 +750,23 @@ impl AsRef<str> for Method {
         match self {
             Method::Get => "GET",
             Method::Post => "POST",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
         }
     }
 }
 
-// FIXME: replace this with `TryFrom`.
-impl From<&str> for Method {
+impl TryFrom<&str> for Method {
+    type Error = ConnectionError;
+
     #[inline]
-    fn from(string: &str) -> Self {
+    fn try_from(string: &str) -> Result<Self, Self::Error> {
         match string {
-            "GET" => Method::Get,
-            "POST" => Method::Post,
-            _ => unimplemented!(),
+            "GET" => Ok(Method::Get),
+            "POST" => Ok(Method::Post),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
+            _ => Err(ConnectionError::not_implemented()),
         }
     }
 }
@@ -461,6 +787,51 @@ impl Request<'_> {
         &self.headers
     }
 
+    /// Returns the address the request came from. Behind a proxy, that's
+    /// the address of the proxy, see [`crate::http::TrustedProxies`] for
+    /// the address of the client.
+    #[inline]
+    pub(crate) fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Returns the address the request was received on.
+    #[inline]
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns `true` for `HEAD` requests, whose method is [`Method::Get`].
+    #[inline]
+    pub(crate) fn is_head(&self) -> bool {
+        self.head
+    }
+
+    /// Sets the addresses the request came from and was received on.
+    #[inline]
+    pub(crate) fn with_addrs(mut self, remote_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        self.remote_addr = Some(remote_addr);
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Returns the value of the cookie `name`, if the client sent it.
+    #[inline]
+    pub(crate) fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies()
+            .find(|(cookie, _)| *cookie == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the cookies sent by the client, as name/value pairs.
+    #[inline]
+    pub(crate) fn cookies(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .get_all("cookie")
+            .filter_map(|value| str::from_utf8(value).ok())
+            .flat_map(cookie::parse)
+    }
+
     // TODO: have this consume the request since it involves reading from the
     //   stream.
     #[inline]
@@ -470,6 +841,20 @@ impl Request<'_> {
 }
 
 impl Body<'_> {
+    /// Returns the bytes of the body.
+    #[inline]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match &self.kind {
+            BodyKind::Connection { connection, length } => match length {
+                BodyLength::Known(length) => {
+                    &connection.buf[connection.head_length..connection.head_length + length]
+                }
+                BodyLength::Empty => &[],
+            },
+            BodyKind::Bytes(bytes) => &bytes[..],
+        }
+    }
+
     #[inline]
     fn len(&self) -> BodyLength {
         match &self.kind {
@@ -498,7 +883,6 @@ impl Body<'_> {
                     let string = str::from_utf8(buf)?.to_string();
                     Ok(string)
                 }
-                BodyLength::Unknown => unimplemented!(),
                 // FIXME: avoid allocating a string here
                 BodyLength::Empty => Ok(String::new()),
             },
@@ -516,6 +900,15 @@ impl From<&'static [u8]> for Body<'_> {
     }
 }
 
+impl From<Vec<u8>> for Body<'_> {
+    #[inline]
+    fn from(bytes: Vec<u8>) -> Self {
+        Body {
+            kind: BodyKind::Bytes(Bytes::from(bytes)),
+        }
+    }
+}
+
 #[cfg(all(test, not(target_arch = "wasm32")))]
 impl From<Body<'_>> for hyper::Body {
     fn from(body: Body<'_>) -> Self {
@@ -540,14 +933,6 @@ impl fmt::Debug for BodyKind<'_> {
                     let string = String::from_utf8_lossy(buf);
                     write!(f, "{}", string)
                 }
-                BodyLength::Unknown => {
-                    // FIXME: not guaranteed to have read all the way to the
-                    //   end here. Also not guaranteed to not be reading the
-                    //   next request in the pipelin.
-                    let buf = &connection.buf[connection.head_length..];
-                    let string = String::from_utf8_lossy(buf);
-                    write!(f, "{}", string)
-                }
                 BodyLength::Empty => Ok(()),
             },
             BodyKind::Bytes(bytes) => write!(f, "{}", String::from_utf8_lossy(bytes)),
@@ -561,6 +946,9 @@ impl fmt::Display for ResponseError {
             ResponseErrorKind::ParseInt => write!(f, "parse integer error"),
             ResponseErrorKind::ParseHead => write!(f, "parse head error"),
             ResponseErrorKind::InvalidMessageFraming => write!(f, "invalid message framing"),
+            ResponseErrorKind::TransferEncoding => write!(f, "unsupported transfer-encoding"),
+            ResponseErrorKind::Read => write!(f, "read error"),
+            ResponseErrorKind::UnexpectedEof => write!(f, "unexpected end of response"),
         }
     }
 }
@@ -569,10 +957,16 @@ impl std::error::Error for ResponseError {}
 
 // TODO: would it be better to `io::copy` the response into the `tcp_stream`?
 #[inline]
+/// Writes `response`, without its body if `head`, though with its length.
 pub(super) fn write_response(
     tcp_stream: &mut TcpStream,
-    response: Response,
+    mut response: Response,
+    security_headers: Option<&SecurityHeaders>,
+    head: bool,
 ) -> Result<(), io::Error> {
+    if let Some(security_headers) = security_headers {
+        security_headers.apply(&mut response);
+    }
     tcp_stream.write_all(b"HTTP/1.1 ")?;
     write!(tcp_stream, "{}", response.code)?;
     tcp_stream.write_all(b" ")?;
@@ -582,12 +976,20 @@ pub(super) fn write_response(
             tcp_stream.write_all(b"\r\ncontent-length: ")?;
             write!(tcp_stream, "{}", length)?;
         }
-        BodyLength::Unknown => unimplemented!(),
-        BodyLength::Empty => unimplemented!(),
+        BodyLength::Empty => tcp_stream.write_all(b"\r\ncontent-length: 0")?,
     }
     // FIXME: don't hardcode the timestamp here.
     tcp_stream.write_all(b"\r\ndate: Fri, 14 Jan 2022 02:28:00 GMT")?;
+    for (name, value) in response.headers.iter() {
+        tcp_stream.write_all(b"\r\n")?;
+        tcp_stream.write_all(name.as_bytes())?;
+        tcp_stream.write_all(b": ")?;
+        tcp_stream.write_all(value)?;
+    }
     tcp_stream.write_all(b"\r\n\r\n")?;
+    if head {
+        return Ok(());
+    }
     match response.body.kind {
         BodyKind::Connection {
             connection: _,
@@ -633,12 +1035,15 @@ pub(crate) fn from_parts<'a>(
 ) -> Request<'a> {
     Request {
         path,
-        method: method.into(),
+        method: Method::try_from(method).unwrap(),
         headers,
         body: Body {
             // TODO: avoid this copy
             kind: BodyKind::Bytes(Bytes::copy_from_slice(body)),
         },
+        remote_addr: None,
+        local_addr: None,
+        head: false,
     }
 }
 
@@ -654,11 +1059,22 @@ mod test {
     use std::str;
 
     use super::MAX_REQUEST_HEADERS;
+    #[cfg(not(target_arch = "wasm32"))]
+    use crate::http::timeout::{Timeouts, Timer};
+    #[cfg(not(target_arch = "wasm32"))]
+    use std::time::Duration;
 
     struct HelloWorld;
 
     impl Handler for HelloWorld {
+        type Config = ();
+
+        fn new(_config: ()) -> Self {
+            HelloWorld
+        }
+
         fn handle<'request, 'response>(
+            &mut self,
             request: &'request Request<'request>,
         ) -> Response<'response> {
             tracing::debug!("HelloWorld server handling request");
@@ -673,8 +1089,7 @@ mod test {
     fn test_hello_world() {
         #[cfg(feature = "logging")]
         let _ = tracing_subscriber::fmt::try_init();
-        let port = random_port();
-        crate::request!(HelloWorld::handle, port, |port: u16| {
+        crate::request!(HelloWorld, (), |port: u16| {
             tracing::debug!("connecting to 127.0.0.1:{}", port);
             let tcp_stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
             let mut tcp_stream = crate::net::TcpStream::from(tcp_stream);
@@ -706,8 +1121,7 @@ mod test {
     //fn test_too_many_headers() {
     //    #[cfg(feature = "logging")]
     //    let _ = tracing_subscriber::fmt::try_init();
-    //    let port = random_port();
-    //    crate::request!(HelloWorld::handle, port, |port: u16| {
+    //    crate::request!(HelloWorld, (), |port: u16| {
     //        let mut tcp_stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
     //        let mut tcp_stream = crate::net::TcpStream::from(tcp_stream);
     //        tcp_stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
@@ -736,8 +1150,7 @@ mod test {
     fn test_multiple_requests() {
         #[cfg(feature = "logging")]
         let _ = tracing_subscriber::fmt::try_init();
-        let port = random_port();
-        crate::request!(HelloWorld::handle, port, |port: u16| {
+        crate::request!(HelloWorld, (), |port: u16| {
             let mut tcp_stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
             let mut tcp_stream = crate::net::TcpStream::from(tcp_stream);
             tcp_stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
@@ -750,19 +1163,150 @@ mod test {
         });
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_request_methods() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut connection = Connection::new(crate::net::TcpStream::from(server_stream));
+        // `HEAD` is handled as `GET`.
+        tcp_stream.write_all(b"HEAD / HTTP/1.1\r\n\r\n").unwrap();
+        let request = connection.next_request().unwrap();
+        assert_eq!(request.method(), Method::Get);
+        assert!(request.is_head());
+        tcp_stream.write_all(b"PUT / HTTP/1.1\r\ncontent-length: 2\r\n\r\nhi").unwrap();
+        assert!(connection.next_request().err().unwrap().is_not_implemented());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_request_framing() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut connection = Connection::new(crate::net::TcpStream::from(server_stream));
+        // Header names are case-insensitive.
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert_eq!(connection.next_request().unwrap().body().as_bytes(), b"hi");
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(connection.next_request().unwrap().body().as_bytes(), b"");
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(411));
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\ntransfer-encoding: gzip\r\n\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(400));
+        tcp_stream
+            .write_all(b"POST / HTTP/1.1\r\ncontent-length: 1\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(400));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_request_malformed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut connection = Connection::new(crate::net::TcpStream::from(server_stream));
+        tcp_stream.write_all(b"GET / HTTP/1.1\r\nbad header\r\n\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(400));
+        tcp_stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for _ in 0..(MAX_REQUEST_HEADERS + 1) {
+            tcp_stream.write_all(b"foo: bar\r\n").unwrap();
+        }
+        tcp_stream.write_all(b"\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(431));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_request_timeouts() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let server_stream = crate::net::TcpStream::from(server_stream);
+        let timeouts = Timeouts {
+            idle: Duration::from_millis(300),
+            header_read: Duration::from_millis(300),
+            body_read: Duration::from_millis(300),
+        };
+        let timer = Timer::new(&server_stream, timeouts);
+        let mut connection = Connection::new(server_stream).with_timer(timer);
+        // Each phase has its own deadline, so a request sent within them
+        // all is read, even if it takes longer than any one of them.
+        std::thread::sleep(Duration::from_millis(150));
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        tcp_stream.write_all(b"content-length: 2\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        tcp_stream.write_all(b"hi").unwrap();
+        assert_eq!(connection.next_request().unwrap().body().as_bytes(), b"hi");
+        // A slow head or body gets a response, an idle connection doesn't.
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(408));
+        tcp_stream.write_all(b"POST / HTTP/1.1\r\ncontent-length: 2\r\n\r\n").unwrap();
+        assert_eq!(connection.next_request().err().unwrap().status(), Some(408));
+        let e = connection.next_request().err().unwrap();
+        assert!(e.is_closed() && e.status().is_none());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_response_framing() {
+        let respond = |response: &'static [u8]| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server_stream, _) = listener.accept().unwrap();
+            server_stream.write_all(response).unwrap();
+            drop(server_stream);
+            Connection::new(crate::net::TcpStream::from(tcp_stream))
+        };
+        let mut connection = respond(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        let response = connection.next_response().unwrap();
+        assert_eq!(response.body().as_bytes(), b"hi");
+        let mut connection = respond(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let response = connection.next_response().unwrap();
+        assert_eq!(response.body().as_bytes(), b"");
+        // Without a length, the body ends with the connection.
+        let mut connection = respond(b"HTTP/1.1 502 Bad Gateway\r\n\r\n<h1>502</h1>");
+        let response = connection.next_response().unwrap();
+        assert_eq!(response.body().as_bytes(), b"<h1>502</h1>");
+        let mut connection = respond(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nhi");
+        assert!(connection.next_response().is_err());
+        let mut connection = respond(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(connection.next_response().is_err());
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn test_not_implemented() {
+        crate::request!(HelloWorld, (), |port: u16| {
+            let tcp_stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+            let mut tcp_stream = crate::net::TcpStream::from(tcp_stream);
+            tcp_stream.write_all(b"PATCH / HTTP/1.1\r\ncontent-length: 0\r\n\r\n").unwrap();
+            let mut connection = Connection::new(tcp_stream);
+            let response = connection.next_response().unwrap();
+            assert_eq!(response.code, 501);
+            assert_eq!(
+                response.headers.get("allow"),
+                Some(&b"GET, HEAD, POST, DELETE, OPTIONS"[..])
+            );
+        });
+    }
+
     #[macro_export]
     macro_rules! request {
-        ( $handler:expr, $port:expr, $test:expr ) => {
+        ( $handler:ty, $config:expr, $test:expr ) => {
             #[cfg(target_arch = "wasm32")]
             {
-                let mailbox = unsafe { lunatic::Mailbox::new() };
-                let this = lunatic::process::this(&mailbox);
+                let bound = unsafe {
+                    lunatic::Mailbox::<Result<std::net::SocketAddr, String>>::new()
+                };
+                // Port 0, so that tests can run in parallel.
+                let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
                 let _server_proc = match crate::spawn_with!(
-                    (this.clone(), ([127, 0, 0, 1], $port)),
-                    |(parent, addr), mailbox| {
-                        let handler = $handler;
-                        crate::http::server((parent, handler, addr), mailbox)
-                    }
+                    (lunatic::process::this(&bound), $config, addr),
+                    crate::http::server::<$handler>
                 ) {
                     Ok(proc) => proc,
                     Err(e) => {
@@ -770,13 +1314,19 @@ mod test {
                         panic!();
                     }
                 };
-                match mailbox.receive() {
-                    Ok(()) => {}
+                let port = match bound.receive() {
+                    Ok(Ok(addr)) => addr.port(),
+                    Ok(Err(e)) => {
+                        tracing::error!("bind error: {}", e);
+                        panic!();
+                    }
                     Err(e) => {
                         tracing::error!("receive error: {}", e);
                         panic!();
                     }
-                }
+                };
+                let mailbox = unsafe { lunatic::Mailbox::<()>::new() };
+                let this = lunatic::process::this(&mailbox);
 
                 fn client(
                     (parent, port): (lunatic::process::Process<()>, u16),
@@ -788,7 +1338,7 @@ mod test {
 
                 // Run the entire test in a lunatic process because
                 // `println!` doesn't work outside of one.
-                let _client_proc = match crate::spawn_with!((this, $port), client) {
+                let _client_proc = match crate::spawn_with!((this, port), client) {
                     Ok(proc) => proc,
                     Err(e) => {
                         tracing::error!("process error: {}", e);
@@ -807,14 +1357,8 @@ mod test {
             #[cfg(not(target_arch = "wasm32"))]
             {
                 let callback = move |port| $test(port);
-                crate::app::server($handler, callback, $port);
+                crate::app::server::<$handler>($config, callback);
             }
         };
     }
-
-    /// Returns a random non-privileged port.
-    fn random_port() -> u16 {
-        use rand::Rng;
-        rand::thread_rng().gen_range(1025..=65535)
-    }
 }
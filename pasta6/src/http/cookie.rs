@@ -0,0 +1,474 @@
+//! Cookies (RFC 6265).
+//!
+//! [`Request::cookie`](crate::http::Request::cookie) reads the cookies sent
+//! by the client, and [`SetCookie`] builds the `set-cookie` header which
+//! sends one. Cookies which must be trusted, e.g. session IDs, are protected
+//! with a [`CookieKey`]:
+//!
+//! - signed values can be read by the client but not changed. They're sent
+//!   as `value.signature`, where the signature is an HMAC-SHA256 of the
+//!   cookie's name and value.
+//! - encrypted values can't be read either. They're encrypted with
+//!   ChaCha20-Poly1305 under a random nonce, with the cookie's name as
+//!   associated data, so that a value can't be moved to another cookie.
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+const MIN_SECRET_LENGTH: usize = 32;
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Whether a cookie is sent with requests from other sites.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A `set-cookie` header, built with its attributes.
+///
+/// Names must be tokens and values cookie octets (RFC 6265 section 4.1.1),
+/// which rules out e.g. spaces, quotes, commas and semicolons. Values which
+/// could contain them must be encoded, e.g. as base64.
+#[derive(Clone, Debug)]
+pub(crate) struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    /// Seconds since the Unix epoch.
+    expires: Option<i64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+/// Keys protecting signed and encrypted cookies.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct CookieKey {
+    signing: [u8; KEY_LENGTH],
+    encryption: [u8; KEY_LENGTH],
+}
+
+#[derive(Debug)]
+pub(crate) struct CookieError {
+    kind: CookieErrorKind,
+}
+
+#[derive(Debug)]
+enum CookieErrorKind {
+    SecretTooShort,
+}
+
+/// Parses the value of a `cookie` header into name/value pairs. Malformed
+/// pairs are skipped.
+#[inline]
+pub(crate) fn parse(header: &str) -> impl Iterator<Item = (&str, &str)> {
+    header.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let name = name.trim();
+        let value = value.trim();
+        // Values may be quoted, but the quotes aren't part of the value.
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        if name.is_empty() {
+            return None;
+        }
+        Some((name, value))
+    })
+}
+
+impl SetCookie {
+    /// Creates a cookie which lasts until the browser is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name or value contain characters which aren't allowed,
+    /// as they could be used to inject other attributes or headers.
+    #[inline]
+    pub(crate) fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        let value = value.into();
+        assert!(is_token(&name), "invalid cookie name: {:?}", name);
+        assert!(
+            value.bytes().all(is_cookie_octet),
+            "invalid value for cookie {}",
+            name
+        );
+        Self {
+            name,
+            value,
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Creates a cookie which deletes the cookie `name` from the client. Its
+    /// path and domain must match those of the cookie.
+    #[inline]
+    pub(crate) fn removal(name: impl Into<String>) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO).expires(0)
+    }
+
+    /// Limits the cookie to paths under `path`.
+    #[inline]
+    pub(crate) fn path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        assert!(is_attribute_value(&path), "invalid cookie path: {:?}", path);
+        self.path = Some(path);
+        self
+    }
+
+    /// Sends the cookie to `domain` and its subdomains, rather than only the
+    /// host which set it.
+    #[inline]
+    pub(crate) fn domain(mut self, domain: impl Into<String>) -> Self {
+        let domain = domain.into();
+        assert!(
+            is_attribute_value(&domain),
+            "invalid cookie domain: {:?}",
+            domain
+        );
+        self.domain = Some(domain);
+        self
+    }
+
+    /// Keeps the cookie for `max_age`, rather than until the browser is
+    /// closed.
+    #[inline]
+    pub(crate) fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Keeps the cookie until `expires`, in seconds since the Unix epoch.
+    /// Clients prefer `Max-Age` if both are set, but some old ones only
+    /// understand `Expires`.
+    #[inline]
+    pub(crate) fn expires(mut self, expires: i64) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    #[inline]
+    pub(crate) fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Hides the cookie from scripts.
+    #[inline]
+    pub(crate) fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl Display for SetCookie {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax"),
+            Some(SameSite::None) => f.write_str("; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CookieKey {
+    /// Derives the keys from `secret`, which must be at least 32 bytes long,
+    /// e.g. the output of `openssl rand -base64 32`.
+    #[inline]
+    pub(crate) fn from_secret(secret: &[u8]) -> Result<Self, CookieError> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(CookieError::secret_too_short());
+        }
+        // Separate keys for separate purposes.
+        Ok(Self {
+            signing: hmac(secret, b"pasta6 cookie signing").into_bytes().into(),
+            encryption: hmac(secret, b"pasta6 cookie encryption").into_bytes().into(),
+        })
+    }
+
+    /// Generates random keys.
+    #[inline]
+    pub(crate) fn generate() -> Self {
+        let mut secret = [0; KEY_LENGTH];
+        rand::thread_rng().fill(&mut secret);
+        Self::from_secret(&secret).unwrap()
+    }
+
+    /// Signs `value` as the value of the cookie `name`.
+    #[inline]
+    pub(crate) fn sign(&self, name: &str, value: &str) -> String {
+        let signature = self.mac(name, value).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            value,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns the value of the cookie `name` signed by [`CookieKey::sign`],
+    /// or `None` if the signature is wrong.
+    #[inline]
+    pub(crate) fn verify<'a>(&self, name: &str, signed: &'a str) -> Option<&'a str> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        // Compared in constant time.
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value)
+    }
+
+    /// Encrypts `value` as the value of the cookie `name`.
+    #[inline]
+    pub(crate) fn encrypt(&self, name: &str, value: &str) -> String {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill(&mut nonce);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("cookie too long");
+        let mut sealed = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Returns the value of the cookie `name` encrypted by
+    /// [`CookieKey::encrypt`], or `None` if it was tampered with.
+    #[inline]
+    pub(crate) fn decrypt(&self, name: &str, sealed: &str) -> Option<String> {
+        let sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).ok()?;
+        if sealed.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: name.as_bytes(),
+        };
+        let plaintext = self
+            .cipher()
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    #[inline]
+    fn mac(&self, name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.signing)
+            .expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    #[inline]
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.encryption))
+    }
+}
+
+#[inline]
+fn hmac(key: &[u8], message: &[u8]) -> hmac::digest::CtOutput<Hmac<Sha256>> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize()
+}
+
+/// Tokens are made of visible ASCII characters, except separators (RFC 7230
+/// section 3.2.6).
+#[inline]
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Visible ASCII characters, except for `"`, `,`, `;` and `\`.
+#[inline]
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+/// Attribute values may contain anything but control characters and `;`.
+#[inline]
+fn is_attribute_value(value: &str) -> bool {
+    !value.bytes().any(|b| b.is_ascii_control() || b == b';')
+}
+
+/// Formats seconds since the Unix epoch as an HTTP date (RFC 7231 section
+/// 7.1.1.1), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+#[inline]
+fn http_date(seconds: i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let time = seconds.rem_euclid(86_400);
+    // Converts days to a date in the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    // Months are counted from March, so that leap days come last.
+    let month = if month_index < 10 {
+        month_index + 2
+    } else {
+        month_index - 10
+    };
+    let year = era * 400 + year_of_era + if month < 2 { 1 } else { 0 };
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 4).rem_euclid(7);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[weekday as usize],
+        day,
+        MONTHS[month as usize],
+        year,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+impl CookieError {
+    #[inline]
+    fn secret_too_short() -> Self {
+        Self {
+            kind: CookieErrorKind::SecretTooShort,
+        }
+    }
+}
+
+impl Display for CookieError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            CookieErrorKind::SecretTooShort => write!(
+                f,
+                "cookie secret must be at least {} bytes long",
+                MIN_SECRET_LENGTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CookieError {}
+
+#[cfg(test)]
+mod test {
+    use super::{http_date, parse, CookieKey, SameSite, SetCookie};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        let cookies: Vec<_> = parse("a=1; b=\"two\";c=; =x; junk; d = 4 ").collect();
+        assert_eq!(cookies, [("a", "1"), ("b", "two"), ("c", ""), ("d", "4")]);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        assert_eq!(SetCookie::new("a", "1").to_string(), "a=1");
+        let cookie = SetCookie::new("session", "abc")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3_600))
+            .expires(784_111_777)
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            cookie.to_string(),
+            "session=abc; Path=/; Domain=example.com; Max-Age=3600; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(
+            SetCookie::removal("session").path("/").to_string(),
+            "session=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        assert_eq!(http_date(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(4_102_444_799), "Thu, 31 Dec 2099 23:59:59 GMT");
+        assert!(std::panic::catch_unwind(|| SetCookie::new("a", "1\r\nx: y")).is_err());
+        assert!(std::panic::catch_unwind(|| SetCookie::new("a b", "1")).is_err());
+    }
+
+    #[test]
+    fn test_cookie_key() {
+        assert!(CookieKey::from_secret(b"too short").is_err());
+        let key = CookieKey::from_secret(b"0123456789abcdef0123456789abcdef").unwrap();
+        let signed = key.sign("user", "42");
+        assert!(signed.starts_with("42."), "{}", signed);
+        assert_eq!(key.verify("user", &signed), Some("42"));
+        assert_eq!(key.verify("admin", &signed), None);
+        assert_eq!(key.verify("user", &signed.replacen("42", "43", 1)), None);
+        assert_eq!(key.verify("user", "42"), None);
+
+        let encrypted = key.encrypt("flash", "Saved; ok");
+        assert!(!encrypted.contains("Saved"), "{}", encrypted);
+        SetCookie::new("flash", encrypted.as_str());
+        assert_eq!(key.decrypt("flash", &encrypted).as_deref(), Some("Saved; ok"));
+        assert_eq!(key.decrypt("other", &encrypted), None);
+        // Nonces are random, so the same value encrypts differently.
+        assert_ne!(key.encrypt("flash", "Saved; ok"), encrypted);
+
+        let other = CookieKey::from_secret(b"0123456789abcdef0123456789abcdeF").unwrap();
+        assert_eq!(other.verify("user", &signed), None);
+        assert_eq!(other.decrypt("flash", &encrypted), None);
+    }
+}
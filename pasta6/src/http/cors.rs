@@ -0,0 +1,250 @@
+//! Cross-origin resource sharing (CORS).
+//!
+//! Browsers only let scripts read responses from other origins, e.g. a
+//! dashboard calling the JSON API, if the response allows it with
+//! `access-control-*` headers. Requests which scripts couldn't send with a
+//! plain form, e.g. with JSON or an `authorization` header, are first
+//! checked with an `OPTIONS` preflight request, answered by
+//! [`Cors::preflight`]. Other responses get their headers from
+//! [`Cors::apply`].
+//!
+//! Only origins on the allowlist of [`Cors`] are allowed, which by default
+//! is empty.
+use crate::http::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Browsers cap this anyway, Chromium at 2 hours.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+/// Methods allowed from other origins, which are all that the API has.
+const ALLOWED_METHODS: &[&str] = &["GET", "POST", "DELETE"];
+/// Request headers allowed from other origins, besides those browsers
+/// always allow.
+const ALLOWED_HEADERS: &[&str] = &["authorization", "content-type"];
+/// Response headers scripts may read, besides those browsers always expose.
+const EXPOSED_HEADERS: &str = "location, ratelimit-limit, ratelimit-remaining, \
+    ratelimit-reset, retry-after, www-authenticate";
+
+/// Which other origins may use the API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Cors {
+    /// Origins such as `https://dashboard.example.com`, compared ignoring
+    /// case.
+    pub(crate) allowed_origins: Vec<String>,
+    /// Allows requests with credentials, i.e. cookies and HTTP
+    /// authentication, with `access-control-allow-credentials`.
+    pub(crate) allow_credentials: bool,
+    /// How long browsers may cache a preflight for.
+    pub(crate) max_age: Duration,
+}
+
+impl Default for Cors {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allow_credentials: false,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+impl Cors {
+    /// Returns `true` if `origin` is on the allowlist.
+    #[inline]
+    pub(crate) fn is_allowed(&self, origin: &[u8]) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(origin))
+    }
+
+    /// Answers an `OPTIONS` preflight request: `204 No Content` allowing the
+    /// requested method and headers, or `403 Forbidden` without any CORS
+    /// headers, which fails the preflight.
+    #[inline]
+    pub(crate) fn preflight<'response>(&self, request: &Request) -> Response<'response> {
+        let origin = match request.headers().get("origin") {
+            Some(origin) if self.is_allowed(origin) => origin,
+            _ => return self.vary(Response::from_static(403, "")),
+        };
+        let method = request.headers().get("access-control-request-method");
+        if !matches!(method, Some(method) if is_allowed_method(method)) {
+            return self.vary(Response::from_static(403, ""));
+        }
+        let headers = request
+            .headers()
+            .get("access-control-request-headers")
+            .unwrap_or_default();
+        if !is_allowed_headers(headers) {
+            return self.vary(Response::from_static(403, ""));
+        }
+        let response = self
+            .allow(Response::from_static(204, ""), origin)
+            .with_header("access-control-allow-methods", ALLOWED_METHODS.join(", "))
+            .with_header("access-control-allow-headers", ALLOWED_HEADERS.join(", "))
+            .with_header("access-control-max-age", self.max_age.as_secs().to_string());
+        self.vary(response)
+    }
+
+    /// Allows the origin of `request` to read `response`, if it's on the
+    /// allowlist.
+    #[inline]
+    pub(crate) fn apply<'response>(
+        &self,
+        request: &Request,
+        response: Response<'response>,
+    ) -> Response<'response> {
+        let response = match request.headers().get("origin") {
+            Some(origin) if self.is_allowed(origin) => self
+                .allow(response, origin)
+                .with_header("access-control-expose-headers", EXPOSED_HEADERS),
+            _ => response,
+        };
+        self.vary(response)
+    }
+
+    #[inline]
+    fn allow<'response>(
+        &self,
+        response: Response<'response>,
+        origin: &[u8],
+    ) -> Response<'response> {
+        // The origin rather than `*`, which doesn't work with credentials.
+        let response = response.with_header("access-control-allow-origin", origin);
+        if self.allow_credentials {
+            response.with_header("access-control-allow-credentials", "true")
+        } else {
+            response
+        }
+    }
+
+    /// Tells caches that responses depend on the origin, unless no origin is
+    /// ever allowed.
+    #[inline]
+    fn vary<'response>(&self, response: Response<'response>) -> Response<'response> {
+        if self.allowed_origins.is_empty() {
+            response
+        } else {
+            response.with_header("vary", "origin")
+        }
+    }
+}
+
+/// Parses a comma-separated list of origins, e.g.
+/// `https://a.example.com,https://b.example.com`, ignoring a trailing `/`.
+#[inline]
+pub(crate) fn parse_origins(origins: &str) -> Vec<String> {
+    origins
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[inline]
+fn is_allowed_method(method: &[u8]) -> bool {
+    ALLOWED_METHODS
+        .iter()
+        .any(|allowed| allowed.as_bytes() == method)
+}
+
+/// Returns `true` if every header of `access-control-request-headers` is
+/// allowed.
+#[inline]
+fn is_allowed_headers(headers: &[u8]) -> bool {
+    headers
+        .split(|byte| *byte == b',')
+        .map(|header| header.trim_ascii())
+        .filter(|header| !header.is_empty())
+        .all(|header| {
+            ALLOWED_HEADERS
+                .iter()
+                .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(header))
+        })
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use super::Cors;
+    use crate::http::{Headers, Response};
+
+    fn request(cors: &Cors, method: &str, headers: &[(&'static str, &str)]) -> Response<'static> {
+        let mut request_headers = Headers::empty();
+        for (name, value) in headers {
+            request_headers.insert(name, value);
+        }
+        let path = "/api/todos".to_string();
+        let request = crate::http::from_parts(path, method, request_headers, b"");
+        if method == "OPTIONS" {
+            cors.preflight(&request)
+        } else {
+            cors.apply(&request, Response::from_static(200, ""))
+        }
+    }
+
+    #[test]
+    fn test_cors() {
+        assert_eq!(
+            super::parse_origins(" https://a.example, https://B.example/,"),
+            ["https://a.example", "https://B.example"]
+        );
+        let cors = Cors {
+            allowed_origins: super::parse_origins("https://a.example"),
+            allow_credentials: true,
+            ..Cors::default()
+        };
+        let header = |response: &Response, name| {
+            response
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8(value.to_vec()).unwrap())
+        };
+
+        let response = request(
+            &cors,
+            "OPTIONS",
+            &[
+                ("origin", "https://A.example"),
+                ("access-control-request-method", "POST"),
+                ("access-control-request-headers", "Authorization, content-type"),
+            ],
+        );
+        assert_eq!(response.code(), 204);
+        let allowed = header(&response, "access-control-allow-origin");
+        assert_eq!(allowed.as_deref(), Some("https://A.example"));
+        let allowed = header(&response, "access-control-allow-credentials");
+        assert_eq!(allowed.as_deref(), Some("true"));
+        let max_age = header(&response, "access-control-max-age");
+        assert_eq!(max_age.as_deref(), Some("7200"));
+        assert_eq!(header(&response, "vary").as_deref(), Some("origin"));
+
+        let refused = [
+            vec![("origin", "https://evil.example"), ("access-control-request-method", "GET")],
+            vec![("access-control-request-method", "GET")],
+            vec![("origin", "https://a.example"), ("access-control-request-method", "PUT")],
+            vec![("origin", "https://a.example")],
+            vec![
+                ("origin", "https://a.example"),
+                ("access-control-request-method", "GET"),
+                ("access-control-request-headers", "x-secret"),
+            ],
+        ];
+        for headers in refused {
+            let response = request(&cors, "OPTIONS", &headers);
+            assert_eq!(response.code(), 403);
+            assert_eq!(header(&response, "access-control-allow-origin"), None);
+        }
+
+        let response = request(&cors, "GET", &[("origin", "https://a.example")]);
+        let allowed = header(&response, "access-control-allow-origin");
+        assert_eq!(allowed.as_deref(), Some("https://a.example"));
+        let response = request(&cors, "GET", &[("origin", "https://evil.example")]);
+        assert_eq!(header(&response, "access-control-allow-origin"), None);
+        assert_eq!(header(&response, "vary").as_deref(), Some("origin"));
+
+        // Without an allowlist, responses don't depend on the origin.
+        let response = request(&Cors::default(), "GET", &[("origin", "https://a.example")]);
+        assert_eq!(response.headers().len(), 0);
+    }
+}
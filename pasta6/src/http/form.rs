@@ -0,0 +1,363 @@
+//! Decoding of HTML form submissions.
+//!
+//! # RFCs
+//!
+//! - [RFC 7578 (Returning Values from Forms: multipart/form-data)][rfc7578]
+//!
+//! [rfc7578]: https://datatracker.ietf.org/doc/html/rfc7578 "Returning Values from Forms: multipart/form-data"
+use crate::http::Request;
+use std::fmt::{self, Formatter};
+use std::str;
+
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct Form {
+    fields: Vec<Field>,
+}
+
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct Field {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    value: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct FormError {
+    kind: FormErrorKind,
+}
+
+#[derive(Debug)]
+enum FormErrorKind {
+    UnsupportedMediaType,
+    Malformed(&'static str),
+}
+
+impl Form {
+    /// Decodes the body of a `application/x-www-form-urlencoded` or
+    /// `multipart/form-data` request.
+    #[inline]
+    pub(crate) fn from_request(request: &Request) -> Result<Self, FormError> {
+        let content_type = request
+            .headers()
+            .get("content-type")
+            .and_then(|v| str::from_utf8(v).ok())
+            .unwrap_or_default();
+        let (media_type, parameters) = match content_type.split_once(';') {
+            Some((media_type, parameters)) => (media_type.trim(), parameters),
+            None => (content_type.trim(), ""),
+        };
+        let body = request.body().as_bytes();
+        if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            Ok(Self::from_urlencoded(body))
+        } else if media_type.eq_ignore_ascii_case("multipart/form-data") {
+            let boundary = parse_parameters(parameters)
+                .into_iter()
+                .find(|(name, _)| name == "boundary")
+                .map(|(_, value)| value)
+                .filter(|boundary| !boundary.is_empty())
+                .ok_or_else(|| FormError::malformed("missing boundary"))?;
+            Self::from_multipart(body, &boundary)
+        } else {
+            Err(FormError {
+                kind: FormErrorKind::UnsupportedMediaType,
+            })
+        }
+    }
+
+    #[inline]
+    pub(crate) fn from_urlencoded(body: &[u8]) -> Self {
+        let fields = body
+            .split(|b| *b == b'&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = match pair.iter().position(|b| *b == b'=') {
+                    Some(i) => (&pair[..i], &pair[i + 1..]),
+                    None => (pair, &b""[..]),
+                };
+                Field {
+                    name: String::from_utf8_lossy(&url_decode(name)).into_owned(),
+                    filename: None,
+                    content_type: None,
+                    value: url_decode(value),
+                }
+            })
+            .collect();
+        Self { fields }
+    }
+
+    #[inline]
+    fn from_multipart(body: &[u8], boundary: &str) -> Result<Self, FormError> {
+        let delimiter = format!("--{}", boundary);
+        // The body starts with the first delimiter. We prepend a CRLF so
+        // that every delimiter, including the first, is `CRLF--boundary`.
+        let mut rest = body
+            .strip_prefix(delimiter.as_bytes())
+            .ok_or_else(|| FormError::malformed("missing first boundary"))?;
+        let delimiter = format!("\r\n--{}", boundary);
+        let mut fields = vec![];
+        loop {
+            if rest.starts_with(b"--") {
+                // This was the close delimiter.
+                break;
+            }
+            rest = rest
+                .strip_prefix(b"\r\n")
+                .ok_or_else(|| FormError::malformed("invalid boundary"))?;
+            let head_end = find(rest, b"\r\n\r\n")
+                .ok_or_else(|| FormError::malformed("unterminated part headers"))?;
+            let head = str::from_utf8(&rest[..head_end])
+                .map_err(|_e| FormError::malformed("invalid part headers"))?;
+            rest = &rest[head_end + 4..];
+            let value_end = find(rest, delimiter.as_bytes())
+                .ok_or_else(|| FormError::malformed("missing close boundary"))?;
+            let value = rest[..value_end].to_vec();
+            rest = &rest[value_end + delimiter.len()..];
+
+            let mut name = None;
+            let mut filename = None;
+            let mut content_type = None;
+            for line in head.split("\r\n") {
+                let (header, value) = match line.split_once(':') {
+                    Some((header, value)) => (header.trim(), value.trim()),
+                    None => continue,
+                };
+                if header.eq_ignore_ascii_case("content-disposition") {
+                    let parameters = value.split_once(';').map_or("", |(_, p)| p);
+                    for (parameter, value) in parse_parameters(parameters) {
+                        match parameter.as_str() {
+                            "name" => name = Some(value),
+                            "filename" => filename = Some(value),
+                            _ => {}
+                        }
+                    }
+                } else if header.eq_ignore_ascii_case("content-type") {
+                    content_type = Some(value.to_string());
+                }
+            }
+            fields.push(Field {
+                name: name.ok_or_else(|| FormError::malformed("part without a name"))?,
+                filename,
+                content_type,
+                value,
+            });
+        }
+        Ok(Self { fields })
+    }
+
+    #[inline]
+    pub(crate) fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Returns the value of the text field `name`, if it's valid UTF-8.
+    #[inline]
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.field(name).and_then(|f| str::from_utf8(&f.value).ok())
+    }
+
+    #[inline]
+    pub(crate) fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+}
+
+impl Field {
+    #[inline]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file name of an uploaded file.
+    #[inline]
+    pub(crate) fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+impl FormError {
+    #[inline]
+    fn malformed(message: &'static str) -> Self {
+        Self {
+            kind: FormErrorKind::Malformed(message),
+        }
+    }
+
+    /// Returns `true` if the request body isn't a form.
+    #[inline]
+    pub(crate) fn is_unsupported_media_type(&self) -> bool {
+        matches!(self.kind, FormErrorKind::UnsupportedMediaType)
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FormErrorKind::UnsupportedMediaType => write!(f, "unsupported media type"),
+            FormErrorKind::Malformed(message) => write!(f, "malformed form: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+/// Decodes `+` and `%XX` escapes. Invalid escapes are left as-is.
+#[inline]
+fn url_decode(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let byte = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = byte {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                decoded.push(b'%');
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+/// Parses the `name=value` parameters of a header, separated by `;`, e.g.
+/// those after `form-data` in a `content-disposition`. Names are lowercased.
+/// Values are tokens or quoted strings, which may contain `;` and
+/// backslash-escaped characters, see RFC 7578 and RFC 6266.
+#[inline]
+fn parse_parameters(parameters: &str) -> Vec<(String, String)> {
+    let mut parsed = vec![];
+    let mut chars = parameters.chars().peekable();
+    loop {
+        while chars.next_if(|&c| c == ';' || c == ' ' || c == '\t').is_some() {}
+        if chars.peek().is_none() {
+            return parsed;
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ';') {
+            name.push(c);
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        // Browsers don't escape backslashes, and IE sends
+                        // full paths, so only treat them as escapes where
+                        // they can't be part of a Windows path.
+                        '\\' => match chars.next_if(|&c| c == '"' || c == '\\') {
+                            Some(c) => value.push(c),
+                            None => value.push('\\'),
+                        },
+                        c => value.push(c),
+                    }
+                }
+                // Anything between the closing quote and the next `;` is
+                // ignored.
+                while chars.next_if(|&c| c != ';').is_some() {}
+            } else {
+                while let Some(c) = chars.next_if(|&c| c != ';') {
+                    value.push(c);
+                }
+                value.truncate(value.trim_end().len());
+            }
+        }
+        parsed.push((name.trim().to_ascii_lowercase(), value));
+    }
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`.
+#[inline]
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Form;
+
+    #[test]
+    fn test_urlencoded() {
+        let form = Form::from_urlencoded(b"content=buy+milk%21&empty=&flag");
+        assert_eq!(form.get("content"), Some("buy milk!"));
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.get("missing"), None);
+    }
+
+    #[test]
+    fn test_multipart() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"content\"\r\n\
+            \r\n\
+            buy milk\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            line 1\r\nline 2\r\n\
+            --XyZ--\r\n";
+        let form = Form::from_multipart(body, "XyZ").unwrap();
+        assert_eq!(form.fields().len(), 2);
+        assert_eq!(form.get("content"), Some("buy milk"));
+        let file = form.field("file").unwrap();
+        assert_eq!(file.filename(), Some("a.txt"));
+        assert_eq!(file.content_type(), Some("text/plain"));
+        assert_eq!(file.value(), b"line 1\r\nline 2");
+    }
+
+    #[test]
+    fn test_multipart_quoted_parameters() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; filename=\"a;b.txt\"; NAME=\"file\"\r\n\
+            \r\n\
+            a\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=quote; filename=\"say \\\"hi\\\".txt\"\r\n\
+            \r\n\
+            b\r\n\
+            --XyZ--\r\n";
+        let form = Form::from_multipart(body, "XyZ").unwrap();
+        assert_eq!(form.field("file").unwrap().filename(), Some("a;b.txt"));
+        assert_eq!(form.field("quote").unwrap().filename(), Some("say \"hi\".txt"));
+        assert_eq!(
+            super::parse_parameters(r#"filename="C:\Photos\a \\ \"b\".png""#),
+            vec![("filename".to_string(), r#"C:\Photos\a \ "b".png"#.to_string())]
+        );
+        assert_eq!(
+            super::parse_parameters(" boundary=\"a;b\"; charset=utf-8 "),
+            vec![
+                ("boundary".to_string(), "a;b".to_string()),
+                ("charset".to_string(), "utf-8".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multipart_malformed() {
+        assert!(Form::from_multipart(b"--XyZ\r\nno headers", "XyZ").is_err());
+        assert!(Form::from_multipart(b"garbage", "XyZ").is_err());
+    }
+}
@@ -1,3 +1,43 @@
+/// Names of the headers we retain when parsing a message. Any other headers
+/// are discarded.
+const KNOWN_HEADERS: &[&str] = &[
+    "access-control-allow-credentials",
+    "access-control-allow-headers",
+    "access-control-allow-methods",
+    "access-control-allow-origin",
+    "access-control-expose-headers",
+    "access-control-max-age",
+    "access-control-request-headers",
+    "access-control-request-method",
+    "authorization",
+    "cache-control",
+    "content-disposition",
+    "content-length",
+    "content-security-policy",
+    "content-type",
+    "cookie",
+    "date",
+    "fly-client-ip",
+    "forwarded",
+    "host",
+    "location",
+    "origin",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "referrer-policy",
+    "retry-after",
+    "sec-fetch-site",
+    "set-cookie",
+    "strict-transport-security",
+    "vary",
+    "www-authenticate",
+    "x-content-type-options",
+    "x-csrf-token",
+    "x-forwarded-for",
+    "x-real-ip",
+];
+
 #[cfg_attr(test, derive(Debug))]
 pub(crate) struct Headers {
     values: Vec<u8>,
@@ -34,6 +74,41 @@ impl Headers {
             .map(|p| &self.values[p.start..p.end])
     }
 
+    /// Returns all values of the header `name`, e.g. of `set-cookie`, which
+    /// can't be combined into one.
+    #[inline]
+    pub(crate) fn get_all(&self, name: &'static str) -> impl Iterator<Item = &[u8]> {
+        self.parts
+            .iter()
+            .filter(move |p| p.name == name)
+            .map(|p| &self.values[p.start..p.end])
+    }
+
+    #[inline]
+    pub(crate) fn insert(&mut self, name: &'static str, value: impl AsRef<[u8]>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    #[inline]
+    pub(crate) fn append(&mut self, name: &'static str, value: impl AsRef<[u8]>) {
+        let value = value.as_ref();
+        let start = self.values.len();
+        self.values.extend_from_slice(value);
+        self.parts.push(HeaderPart {
+            name,
+            start,
+            end: start + value.len(),
+        });
+    }
+
+    /// Removes all values of the header `name`. The bytes of the removed
+    /// values are left in place.
+    #[inline]
+    pub(crate) fn remove(&mut self, name: &'static str) {
+        self.parts.retain(|p| p.name != name);
+    }
+
     #[inline]
     pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, &[u8])> {
         self.parts
@@ -42,6 +117,16 @@ impl Headers {
     }
 }
 
+/// Returns the canonical (lowercase) name of a supported header. Header
+/// names are case-insensitive (RFC 7230 section 3.2).
+#[inline]
+fn known_name(name: &str) -> Option<&'static str> {
+    KNOWN_HEADERS
+        .iter()
+        .copied()
+        .find(|known| known.eq_ignore_ascii_case(name))
+}
+
 impl From<&mut [httparse::Header<'_>]> for Headers {
     #[inline]
     fn from(httparse_headers: &mut [httparse::Header<'_>]) -> Self {
@@ -55,10 +140,9 @@ impl From<&mut [httparse::Header<'_>]> for Headers {
             .iter()
             .map(|h| (h.name, h.value))
             .for_each(|(n, v)| {
-                let name = match n {
-                    "content-length" => "content-length",
-                    "date" => "date",
-                    n => {
+                let name = match known_name(n) {
+                    Some(name) => name,
+                    None => {
                         tracing::warn!("ignoring unsupported header: {}", n);
                         return;
                     }
@@ -86,10 +170,9 @@ impl From<hyper::HeaderMap<hyper::header::HeaderValue>> for Headers {
         };
         let mut start = 0;
         hyper_headers.iter().for_each(|(n, v)| {
-            let name = match n {
-                &hyper::http::header::CONTENT_LENGTH => "content-length",
-                &hyper::http::header::DATE => "date",
-                n => {
+            let name = match known_name(n.as_str()) {
+                Some(name) => name,
+                None => {
                     tracing::warn!("ignoring unsupported header: {}", n);
                     return;
                 }
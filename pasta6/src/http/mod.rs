@@ -3,32 +3,58 @@
 //! # RFCs
 //!
 //! - [RFC 2616 (Hypertext Transfer Protocol -- HTTP/1.1)][rfc2616]
+//! - [RFC 6265 (HTTP State Management Mechanism)][rfc6265]
 //! - [RFC 6585 (Additional HTTP Status Codes)][rfc6585]
+//! - [RFC 6797 (HTTP Strict Transport Security (HSTS))][rfc6797]
 //! - [RFC 7230 (Hypertext Transfer Protocol (HTTP/1.1): Message Syntax and Routing)][rfc7230]
 //! - [RFC 7231 (Hypertext Transfer Protocol (HTTP/1.1): Semantics and Content)][rfc7231]
+//! - [RFC 7239 (Forwarded HTTP Extension)][rfc7239]
+//! - [RFC 7578 (Returning Values from Forms: multipart/form-data)][rfc7578]
 //!
 //! [rfc2616]: https://datatracker.ietf.org/doc/html/rfc2616 "Hypertext Transfer Protocol -- HTTP/1.1"
+//! [rfc6265]: https://datatracker.ietf.org/doc/html/rfc6265 "HTTP State Management Mechanism"
 //! [rfc6585]: https://datatracker.ietf.org/doc/html/rfc6585 "Additional HTTP Status Codes"
+//! [rfc6797]: https://datatracker.ietf.org/doc/html/rfc6797 "HTTP Strict Transport Security (HSTS)"
 //! [rfc7230]: https://datatracker.ietf.org/doc/html/rfc7231 "Hypertext Transfer Protocol (HTTP/1.1): Message Syntax and Routing"
 //! [rfc7231]: https://datatracker.ietf.org/doc/html/rfc7231 "Hypertext Transfer Protocol (HTTP/1.1): Semantics and Content"
+//! [rfc7239]: https://datatracker.ietf.org/doc/html/rfc7239 "Forwarded HTTP Extension"
+//! [rfc7578]: https://datatracker.ietf.org/doc/html/rfc7578 "Returning Values from Forms: multipart/form-data"
 use crate::http::connection::Connection;
 use crate::net::TcpStream;
 use lunatic::net::TcpListener;
 use lunatic::process::Process;
 use lunatic::Mailbox;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::io::Write;
-use std::mem;
 use std::net::SocketAddr;
 
+mod bind;
 mod client;
 mod connection;
+mod cookie;
+mod cors;
+mod form;
 mod header;
+mod proxy;
+mod proxy_protocol;
+mod security;
+mod timeout;
 
+pub(super) use crate::http::bind::BindAddrs;
 pub(super) use crate::http::client::Client;
 #[cfg(all(test, not(target_arch = "wasm32")))]
 pub(super) use crate::http::connection::from_parts;
-pub(super) use crate::http::connection::{Method, Request, Response};
+pub(super) use crate::http::connection::{
+    Method, Request, Response, ALLOWED_METHODS, MAX_REQUEST_BODY_SIZE,
+};
+pub(super) use crate::http::cookie::{CookieKey, SameSite, SetCookie};
+pub(super) use crate::http::cors::{parse_origins, Cors};
+pub(super) use crate::http::form::Form;
 pub(super) use crate::http::header::Headers;
+pub(super) use crate::http::proxy::TrustedProxies;
+pub(super) use crate::http::security::{generate_nonce, SecurityHeaders};
+pub(super) use crate::http::timeout::Timeouts;
 
 #[cfg_attr(test, derive(Debug))]
 enum ParseResult<T> {
@@ -38,68 +64,111 @@ enum ParseResult<T> {
 }
 
 #[derive(Debug)]
-struct ConnectionError {
+pub(crate) struct ConnectionError {
     kind: ConnectionErrorKind,
 }
 
 #[derive(Debug)]
 enum ConnectionErrorKind {
     UnexpectedEof,
-    RequestError,
+    /// A malformed request, e.g. with an invalid `content-length`.
+    BadRequest,
+    /// A request with a `transfer-encoding`, which isn't supported.
+    LengthRequired,
+    PayloadTooLarge,
+    /// A request with too many headers, or too large a head.
+    HeaderFieldsTooLarge,
+    /// A request which took too long to arrive, see [`Timeouts`].
+    TimedOut,
     Closed,
+    /// A connection which stayed idle for too long.
+    Idle,
+    /// The connection didn't start with a valid PROXY protocol header.
+    ProxyHeader,
+    /// A request with a method the server doesn't implement, e.g. `PUT`.
+    NotImplemented,
 }
 
+/// A request handler.
+///
+/// Every connection is served by its own lunatic process, so handlers can't
+/// share memory. Instead, each connection process constructs its own handler
+/// from a copy of `Config`, which is sent to it when the process is spawned.
 pub(crate) trait Handler {
-    fn handle<'request, 'response>(request: &'request Request<'request>) -> Response<'response>;
-}
+    type Config: Clone + Serialize + DeserializeOwned;
 
-#[inline]
-pub(crate) fn handler_as_int(handler: for<'r, 's> fn(&'r Request<'s>) -> Response<'r>) -> usize {
-    handler as *const () as usize
-}
+    fn new(config: Self::Config) -> Self;
 
-#[inline]
-pub(crate) fn handler_from_int(
-    handler_int: usize,
-) -> for<'r, 's> fn(&'r Request<'s>) -> Response<'r> {
-    unsafe {
-        let pointer = handler_int as *const ();
-        mem::transmute::<*const (), for<'r> fn(&'r Request) -> Response<'r>>(pointer)
+    fn handle<'request, 'response>(
+        &mut self,
+        request: &'request Request<'request>,
+    ) -> Response<'response>;
+
+    /// Returns the headers added to every response, including those not
+    /// made by [`Handler::handle`], see [`SecurityHeaders`].
+    #[inline]
+    fn security_headers(&self) -> Option<&SecurityHeaders> {
+        None
+    }
+
+    /// Returns `true` if connections start with a PROXY protocol header,
+    /// which tells the addresses of requests, see
+    /// [`crate::http::proxy_protocol`].
+    #[inline]
+    fn proxy_protocol(&self) -> bool {
+        false
+    }
+
+    /// Returns the largest request body accepted. Requests with a larger
+    /// one are answered with `413 Payload Too Large`.
+    #[inline]
+    fn max_body_size(&self) -> usize {
+        MAX_REQUEST_BODY_SIZE
+    }
+
+    /// Returns how long reading each request may take.
+    #[inline]
+    fn timeouts(&self) -> Timeouts {
+        Timeouts::default()
     }
 }
 
+/// Listens on `addr`, sending the address it's bound to, or the bind error,
+/// to `parent`.
 #[cfg(target_arch = "wasm32")]
 #[inline]
-pub(crate) fn server(
-    (parent, handler, (ip, port)): (
-        Process<()>,
-        for<'r, 's> fn(&'r Request<'s>) -> Response<'r>,
-        ([u8; 4], u16),
-    ),
+pub(crate) fn server<H: Handler>(
+    (parent, config, addr): (Process<Result<SocketAddr, String>>, H::Config, SocketAddr),
     _mailbox: Mailbox<()>,
 ) {
-    let addr = SocketAddr::from((ip, port));
-    tracing::info!("server binding to {:?}", addr);
+    tracing::info!("server binding to {}", addr);
     let listener = match TcpListener::bind(addr) {
         Ok(listener) => listener,
         Err(e) => {
             tracing::error!("bind error: {}", e);
-            panic!();
+            parent.send(Err(e.to_string()));
+            return;
         }
     };
-    parent.send(());
-    tracing::info!("server accepting connections");
-    let handler_int = handler_as_int(handler);
+    // Ask the listener, in case the port was picked by the OS.
+    let local_addr = listener.local_addr().unwrap_or(addr);
+    parent.send(Ok(local_addr));
+    tracing::info!("server accepting connections on {}", local_addr);
     loop {
         match listener.accept() {
             Ok((tcp_stream, peer)) => {
                 tracing::debug!("server accepted connection: {}", peer);
                 match crate::spawn_with!(
-                    (tcp_stream, peer, handler_int),
-                    |(tcp_stream, peer, handler_int): (lunatic::net::TcpStream, _, _),
+                    (tcp_stream, (peer, local_addr), config.clone()),
+                    |(tcp_stream, (peer, local_addr), config): (
+                        lunatic::net::TcpStream,
+                        (SocketAddr, SocketAddr),
+                        H::Config,
+                    ),
                      _mailbox: Mailbox::<()>| {
-                        let handler = handler_from_int(handler_int);
-                        match handle_connection(tcp_stream.into(), &handler) {
+                        let mut handler = H::new(config);
+                        let tcp_stream = tcp_stream.into();
+                        match handle_connection(tcp_stream, peer, local_addr, &mut handler) {
                             Ok(()) => {
                                 tracing::debug!("closed connection: {}", peer);
                             }
@@ -128,19 +197,66 @@ pub(crate) fn server(
 }
 
 #[inline]
-fn handle_connection(
+fn handle_connection<H: Handler>(
     // FIXME: make this agnostic over both stream types.
     mut tcp_stream: TcpStream,
-    handler: &for<'r, 's> fn(&'r Request<'s>) -> Response<'r>,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    handler: &mut H,
 ) -> Result<(), ConnectionError> {
     tracing::trace!("server handling connection");
+    let mut timer = timeout::Timer::new(&tcp_stream, handler.timeouts());
+    let (peer, local_addr) = if handler.proxy_protocol() {
+        // The PROXY protocol header is timed as part of the first request.
+        timer.start(timeout::Phase::Head);
+        let header = match timer.before_read(&tcp_stream) {
+            Ok(()) => proxy_protocol::read_header(&mut tcp_stream),
+            Err(e) => Err(e.into()),
+        };
+        match header {
+            Ok(Some(header)) => (header.source, header.destination),
+            Ok(None) => (peer, local_addr),
+            Err(e) => {
+                tracing::debug!("{} from {}", e, peer);
+                return Err(ConnectionError::proxy_header());
+            }
+        }
+    } else {
+        (peer, local_addr)
+    };
     // FIXME: keep the connection around.
     // FIXME: wrap the error, don't unwrap
-    let mut connection = Connection::new(tcp_stream.try_clone().unwrap());
+    let mut connection = Connection::new(tcp_stream.try_clone().unwrap())
+        .with_addrs(peer, local_addr)
+        .with_max_body_size(handler.max_body_size())
+        .with_timer(timer);
     loop {
         // Invoke the provided handler function to process the request.
-        let request = connection.next_request()?;
-        let response = handler(&request);
+        let request = match connection.next_request() {
+            Ok(request) => request,
+            Err(e) => {
+                // We haven't read the body, so the connection can't be
+                // reused after the response.
+                if let Some(code) = e.status() {
+                    let mut response = Response::from_static(code, "");
+                    if e.is_not_implemented() {
+                        response = response.with_header("allow", ALLOWED_METHODS);
+                    }
+                    let security_headers = handler.security_headers();
+                    if let Err(e) = connection::write_response(
+                        &mut tcp_stream,
+                        response,
+                        security_headers,
+                        false,
+                    ) {
+                        tracing::error!("write error: {}", e);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let head = request.is_head();
+        let response = handler.handle(&request);
         // TODO: what's the proper behaviour if the handler defined these headers?
         if response.headers().get("content-length").is_some() {
             tracing::error!("unexpected header: content-length");
@@ -153,7 +269,8 @@ fn handle_connection(
         // Send the response back to the client.
         // TODO: investigate perf of multiple `write_all` vs single `write!`.
         tracing::trace!("server writing response");
-        match connection::write_response(&mut tcp_stream, response) {
+        let security_headers = handler.security_headers();
+        match connection::write_response(&mut tcp_stream, response, security_headers, head) {
             Ok(()) => {}
             Err(e) => {
                 tracing::error!("write error: {}", e);
@@ -174,7 +291,30 @@ fn handle_connection(
 impl ConnectionError {
     #[inline]
     fn is_closed(&self) -> bool {
-        matches!(self.kind, ConnectionErrorKind::Closed)
+        matches!(self.kind, ConnectionErrorKind::Closed | ConnectionErrorKind::Idle)
+    }
+
+    /// Returns the status code of the response the request is refused with,
+    /// if it gets one before the connection is closed.
+    #[inline]
+    fn status(&self) -> Option<u16> {
+        match self.kind {
+            ConnectionErrorKind::BadRequest => Some(400),
+            ConnectionErrorKind::LengthRequired => Some(411),
+            ConnectionErrorKind::TimedOut => Some(408),
+            ConnectionErrorKind::PayloadTooLarge => Some(413),
+            ConnectionErrorKind::HeaderFieldsTooLarge => Some(431),
+            ConnectionErrorKind::NotImplemented => Some(501),
+            ConnectionErrorKind::UnexpectedEof
+            | ConnectionErrorKind::Closed
+            | ConnectionErrorKind::Idle
+            | ConnectionErrorKind::ProxyHeader => None,
+        }
+    }
+
+    #[inline]
+    fn is_not_implemented(&self) -> bool {
+        matches!(self.kind, ConnectionErrorKind::NotImplemented)
     }
 
     #[inline]
@@ -185,9 +325,44 @@ impl ConnectionError {
     }
 
     #[inline]
-    fn request_error() -> Self {
+    fn bad_request() -> Self {
         Self {
-            kind: ConnectionErrorKind::RequestError,
+            kind: ConnectionErrorKind::BadRequest,
+        }
+    }
+
+    #[inline]
+    fn length_required() -> Self {
+        Self {
+            kind: ConnectionErrorKind::LengthRequired,
+        }
+    }
+
+    #[inline]
+    fn payload_too_large() -> Self {
+        Self {
+            kind: ConnectionErrorKind::PayloadTooLarge,
+        }
+    }
+
+    #[inline]
+    fn header_fields_too_large() -> Self {
+        Self {
+            kind: ConnectionErrorKind::HeaderFieldsTooLarge,
+        }
+    }
+
+    #[inline]
+    fn timed_out() -> Self {
+        Self {
+            kind: ConnectionErrorKind::TimedOut,
+        }
+    }
+
+    #[inline]
+    fn idle() -> Self {
+        Self {
+            kind: ConnectionErrorKind::Idle,
         }
     }
 
@@ -197,4 +372,18 @@ impl ConnectionError {
             kind: ConnectionErrorKind::Closed,
         }
     }
+
+    #[inline]
+    fn proxy_header() -> Self {
+        Self {
+            kind: ConnectionErrorKind::ProxyHeader,
+        }
+    }
+
+    #[inline]
+    fn not_implemented() -> Self {
+        Self {
+            kind: ConnectionErrorKind::NotImplemented,
+        }
+    }
 }
@@ -0,0 +1,337 @@
+//! Client addresses behind reverse proxies.
+//!
+//! Behind a reverse proxy, e.g. the edge proxy of fly.io, every connection
+//! comes from the proxy, which tells the address of the client in a header
+//! instead, `x-forwarded-for` unless configured otherwise. Anyone can send
+//! these headers, so they're only believed when the connection comes from
+//! one of the [`TrustedProxies`].
+//!
+//! Only the header the proxies set is read: a proxy appending to
+//! `x-forwarded-for` leaves a `forwarded` ([RFC 7239]) header sent by the
+//! client as it is, so reading both would let the client pick its address.
+//!
+//! Each proxy appends the address it got the request from, so the header is
+//! read from the right, skipping proxies we trust, up to the first address
+//! we don't, which is the client.
+//!
+//! [RFC 7239]: https://datatracker.ietf.org/doc/html/rfc7239
+use crate::http::Request;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+
+/// The proxies whose forwarding headers are believed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TrustedProxies {
+    networks: Vec<Network>,
+    header: ForwardedHeader,
+}
+
+/// The header the proxies tell the address of the client in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum ForwardedHeader {
+    /// [RFC 7239], whose `for` parameters are read.
+    ///
+    /// [RFC 7239]: https://datatracker.ietf.org/doc/html/rfc7239
+    Forwarded,
+    #[default]
+    XForwardedFor,
+    /// Set by the edge proxy of fly.io.
+    FlyClientIp,
+    XRealIp,
+}
+
+/// A range of addresses, such as `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Network {
+    addr: IpAddr,
+    prefix_length: u8,
+}
+
+#[derive(Debug)]
+pub(crate) struct ProxyError {
+    kind: ProxyErrorKind,
+}
+
+#[derive(Debug)]
+enum ProxyErrorKind {
+    /// A network which couldn't be parsed.
+    InvalidNetwork(String),
+    /// A header which isn't one of the [`ForwardedHeader`]s.
+    InvalidHeader(String),
+}
+
+impl TrustedProxies {
+    /// Parses a comma-separated list of addresses and networks, e.g.
+    /// `127.0.0.1, fdaa::/16`. By default, no proxy is trusted.
+    #[inline]
+    pub(crate) fn parse(networks: &str) -> Result<Self, ProxyError> {
+        let networks = networks
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| Network::parse(network).ok_or_else(|| ProxyError::invalid(network)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            networks,
+            ..Self::default()
+        })
+    }
+
+    /// Sets the header the proxies tell the address of the client in, one
+    /// of `x-forwarded-for`, the default, `forwarded`, `fly-client-ip` or
+    /// `x-real-ip`. The other ones are ignored.
+    #[inline]
+    pub(crate) fn with_header(self, header: &str) -> Result<Self, ProxyError> {
+        let header = match header.to_ascii_lowercase().as_str() {
+            "forwarded" => ForwardedHeader::Forwarded,
+            "x-forwarded-for" => ForwardedHeader::XForwardedFor,
+            "fly-client-ip" => ForwardedHeader::FlyClientIp,
+            "x-real-ip" => ForwardedHeader::XRealIp,
+            _ => return Err(ProxyError::invalid_header(header)),
+        };
+        Ok(Self { header, ..self })
+    }
+
+    /// Returns `true` if `addr` is one of the proxies.
+    #[inline]
+    pub(crate) fn is_trusted(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+
+    /// Returns the address of the client which sent `request`, if known.
+    #[inline]
+    pub(crate) fn client_addr(&self, request: &Request) -> Option<IpAddr> {
+        let mut client = request.remote_addr()?.ip().to_canonical();
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+        for addr in self.forwarded_for(request).into_iter().rev() {
+            match addr {
+                Some(addr) => client = addr,
+                // The proxy couldn't tell, so neither can we.
+                None => break,
+            }
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+impl Network {
+    /// Parses an address with an optional prefix length, e.g. `10.0.0.0/8`.
+    #[inline]
+    fn parse(network: &str) -> Option<Self> {
+        let (addr, prefix_length) = match network.split_once('/') {
+            Some((addr, prefix_length)) => (addr, Some(prefix_length)),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max_prefix_length = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_length: u8 = match prefix_length {
+            Some(prefix_length) => prefix_length.parse().ok()?,
+            None => max_prefix_length,
+        };
+        if prefix_length > max_prefix_length {
+            return None;
+        }
+        // IPv4-mapped addresses, e.g. `::ffff:10.0.0.0/104`, are matched as
+        // IPv4 ones, so their prefix is reduced to the IPv4 part.
+        let canonical = addr.to_canonical();
+        let prefix_length = match (addr, canonical) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => prefix_length.checked_sub(96)?,
+            _ => prefix_length,
+        };
+        Some(Self {
+            addr: canonical,
+            prefix_length,
+        })
+    }
+
+    #[inline]
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TrustedProxies {
+    /// Returns the addresses in the header set by the proxies, from the
+    /// client to the last proxy: the `for` parameters of a `forwarded`
+    /// header, or the comma-separated addresses of any other. Addresses
+    /// which were hidden or couldn't be parsed are `None`.
+    #[inline]
+    fn forwarded_for(&self, request: &Request) -> Vec<Option<IpAddr>> {
+        let elements = request
+            .headers()
+            .get_all(self.header.name())
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','));
+        if self.header != ForwardedHeader::Forwarded {
+            return elements.map(parse_node).collect();
+        }
+        elements
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect()
+    }
+}
+
+impl ForwardedHeader {
+    #[inline]
+    fn name(self) -> &'static str {
+        match self {
+            Self::Forwarded => "forwarded",
+            Self::XForwardedFor => "x-forwarded-for",
+            Self::FlyClientIp => "fly-client-ip",
+            Self::XRealIp => "x-real-ip",
+        }
+    }
+}
+
+/// Parses the address of a node, which may be quoted and have a port, e.g.
+/// `192.0.2.43`, `"192.0.2.43:47011"` or `"[2001:db8:cafe::17]:4711"`.
+/// Returns `None` for `unknown` and obfuscated identifiers.
+#[inline]
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let addr = match node.parse::<IpAddr>() {
+        Ok(addr) => addr,
+        Err(_e) => match node.strip_prefix('[') {
+            Some(node) => node.split(']').next()?.parse().ok()?,
+            None => node.parse::<SocketAddr>().ok()?.ip(),
+        },
+    };
+    Some(addr.to_canonical())
+}
+
+impl ProxyError {
+    #[inline]
+    fn invalid(network: &str) -> Self {
+        Self {
+            kind: ProxyErrorKind::InvalidNetwork(network.to_string()),
+        }
+    }
+
+    #[inline]
+    fn invalid_header(header: &str) -> Self {
+        Self {
+            kind: ProxyErrorKind::InvalidHeader(header.to_string()),
+        }
+    }
+}
+
+impl Display for ProxyError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ProxyErrorKind::InvalidNetwork(network) => write!(
+                f,
+                "invalid trusted proxy {:?}, expected e.g. `10.0.0.0/8`",
+                network
+            ),
+            ProxyErrorKind::InvalidHeader(header) => {
+                write!(
+                    f,
+                    "invalid forwarded header {:?}, expected `x-forwarded-for`, `forwarded`, \
+                     `fly-client-ip` or `x-real-ip`",
+                    header
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use super::TrustedProxies;
+    use crate::http::Headers;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_client_addr() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, fdaa::/16, 192.0.2.1").unwrap();
+        type Header = (&'static str, &'static str);
+        let client_addr_with = |proxies: &TrustedProxies, peer: &str, headers: &[Header]| {
+            let mut request_headers = Headers::empty();
+            for (name, value) in headers {
+                request_headers.append(name, value);
+            }
+            let request = crate::http::from_parts("/".to_string(), "GET", request_headers, b"")
+                .with_addrs(peer.parse().unwrap(), "127.0.0.1:3000".parse().unwrap());
+            proxies.client_addr(&request).unwrap()
+        };
+        let client_addr = |peer, headers: &[Header]| client_addr_with(&proxies, peer, headers);
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        // Untrusted peers can't pretend to be proxies.
+        let spoofed = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(client_addr("198.51.100.1:1234", &spoofed), addr("198.51.100.1"));
+        assert_eq!(client_addr("10.1.2.3:1234", &spoofed), addr("203.0.113.7"));
+        assert_eq!(client_addr("[::ffff:10.1.2.3]:1234", &spoofed), addr("203.0.113.7"));
+        assert_eq!(client_addr("10.1.2.3:1234", &[]), addr("10.1.2.3"));
+
+        // Addresses prepended by the client are skipped, but not those of
+        // trusted proxies.
+        let chained = [
+            ("x-forwarded-for", "1.1.1.1, 203.0.113.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ];
+        assert_eq!(client_addr("[fdaa::1]:1234", &chained), addr("203.0.113.7"));
+
+        // Only the header set by the proxies is read, so a client can't
+        // pick its address with another one, which the proxies pass on.
+        let spoofed = [
+            ("forwarded", "for=1.1.1.1"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ];
+        assert_eq!(client_addr("10.0.0.1:1234", &spoofed), addr("203.0.113.7"));
+        let forwarded_proxies = proxies.clone().with_header("Forwarded").unwrap();
+        let forwarded = [
+            ("forwarded", "for=1.1.1.1, for=\"[2001:db8:cafe::17]:4711\";proto=https"),
+            ("forwarded", "for=192.0.2.1:80;by=10.0.0.1"),
+            ("x-forwarded-for", "1.1.1.1"),
+        ];
+        let client_addr =
+            |peer, headers: &[Header]| client_addr_with(&forwarded_proxies, peer, headers);
+        assert_eq!(client_addr("10.0.0.1:1234", &forwarded), addr("2001:db8:cafe::17"));
+        assert_eq!(client_addr("10.0.0.1:1234", &spoofed), addr("1.1.1.1"));
+        let hidden = [("forwarded", "for=1.1.1.1, for=unknown, for=10.0.0.3")];
+        assert_eq!(client_addr("10.0.0.1:1234", &hidden), addr("10.0.0.3"));
+        assert!(proxies.clone().with_header("x-client-ip").is_err());
+
+        // IPv4-mapped networks match IPv4 peers by their IPv4 part only.
+        let mapped = TrustedProxies::parse("::ffff:10.0.0.1, ::ffff:192.0.2.0/120").unwrap();
+        assert!(mapped.is_trusted(addr("10.0.0.1")));
+        assert!(!mapped.is_trusted(addr("10.0.0.2")));
+        assert!(mapped.is_trusted(addr("192.0.2.200")));
+        assert!(!mapped.is_trusted(addr("192.0.3.1")));
+        assert!(mapped.is_trusted(addr("::ffff:10.0.0.1")));
+        let mapped = TrustedProxies::parse("::ffff:10.0.0.0/104").unwrap();
+        assert!(mapped.is_trusted(addr("10.9.9.9")));
+        assert!(!mapped.is_trusted(addr("11.0.0.1")));
+        assert!(TrustedProxies::parse("::ffff:10.0.0.0/95").is_err());
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("fly").is_err());
+        assert_eq!(TrustedProxies::parse(" ").unwrap(), TrustedProxies::default());
+    }
+}
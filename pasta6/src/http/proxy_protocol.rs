@@ -0,0 +1,264 @@
+//! The PROXY protocol.
+//!
+//! TCP proxies, such as fly.io services with `protocol = "tcp"`, can't add
+//! headers to requests, so they tell the original addresses of a connection
+//! with a [PROXY protocol] header in front of the stream instead: either a
+//! line of text (version 1) or a binary header (version 2). When enabled,
+//! every connection must start with one, which [`read_header`] consumes
+//! before the first request is parsed.
+//!
+//! Only the proxy must be able to reach the server then, as anyone else can
+//! send the header too.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.5/doc/proxy-protocol.txt
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The start of a version 1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest version 1 header, including `\r\n`.
+const V1_MAX_LENGTH: usize = 107;
+/// The start of a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The length of the fixed part of a version 2 header.
+const V2_HEADER_LENGTH: usize = 16;
+/// Bytes read before the version is known, which is less than any header.
+const PEEK_LENGTH: usize = 8;
+
+/// The original addresses of a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ProxyHeader {
+    /// The address of the client.
+    pub(crate) source: SocketAddr,
+    /// The address the client connected to, on the proxy.
+    pub(crate) destination: SocketAddr,
+}
+
+#[derive(Debug)]
+pub(crate) struct ProxyProtocolError {
+    kind: ProxyProtocolErrorKind,
+}
+
+#[derive(Debug)]
+enum ProxyProtocolErrorKind {
+    Io(io::Error),
+    /// The stream didn't start with a valid header.
+    InvalidHeader,
+}
+
+/// Reads a PROXY protocol header from the start of `reader`, without reading
+/// past it. Returns `None` if the proxy didn't tell the addresses, e.g. for
+/// its own health checks.
+#[inline]
+pub(crate) fn read_header(
+    reader: &mut impl Read,
+) -> Result<Option<ProxyHeader>, ProxyProtocolError> {
+    let mut buf = [0; V2_HEADER_LENGTH];
+    reader.read_exact(&mut buf[..PEEK_LENGTH])?;
+    if buf.starts_with(V1_PREFIX) {
+        read_v1(reader, &buf[..PEEK_LENGTH])
+    } else if buf[..PEEK_LENGTH] == V2_SIGNATURE[..PEEK_LENGTH] {
+        reader.read_exact(&mut buf[PEEK_LENGTH..])?;
+        read_v2(reader, &buf)
+    } else {
+        Err(ProxyProtocolError::invalid_header())
+    }
+}
+
+/// Reads the rest of a version 1 header, e.g.
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+#[inline]
+fn read_v1(
+    reader: &mut impl Read,
+    start: &[u8],
+) -> Result<Option<ProxyHeader>, ProxyProtocolError> {
+    let mut line = start.to_vec();
+    // Byte by byte, since the request follows right after.
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyProtocolError::invalid_header());
+        }
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_e| ProxyProtocolError::invalid_header())?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let parse = |addr: &str, port: &str| -> Option<SocketAddr> {
+                let addr: IpAddr = addr.parse().ok()?;
+                let port = port.parse().ok()?;
+                (addr.is_ipv4() == (*protocol == "TCP4")).then_some(SocketAddr::new(addr, port))
+            };
+            match (parse(source, source_port), parse(destination, destination_port)) {
+                (Some(source), Some(destination)) => Ok(Some(ProxyHeader {
+                    source,
+                    destination,
+                })),
+                _ => Err(ProxyProtocolError::invalid_header()),
+            }
+        }
+        _ => Err(ProxyProtocolError::invalid_header()),
+    }
+}
+
+/// Reads the addresses of a version 2 header following the fixed part in
+/// `header`, skipping any TLVs after them.
+#[inline]
+fn read_v2(
+    reader: &mut impl Read,
+    header: &[u8; V2_HEADER_LENGTH],
+) -> Result<Option<ProxyHeader>, ProxyProtocolError> {
+    if !header.starts_with(V2_SIGNATURE) || header[12] >> 4 != 2 {
+        return Err(ProxyProtocolError::invalid_header());
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0; length];
+    reader.read_exact(&mut addresses)?;
+    let command = header[12] & 0x0f;
+    let family = header[13];
+    match (command, family) {
+        // LOCAL, i.e. the proxy itself.
+        (0x0, _) => Ok(None),
+        // PROXY over TCP/IPv4.
+        (0x1, 0x11) if length >= 12 => {
+            let addr = |i: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addresses[i],
+                    addresses[i + 1],
+                    addresses[i + 2],
+                    addresses[i + 3],
+                ))
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(addr(0), port(8)),
+                destination: SocketAddr::new(addr(4), port(10)),
+            }))
+        }
+        // PROXY over TCP/IPv6.
+        (0x1, 0x21) if length >= 36 => {
+            let addr = |i: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[i..i + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(addr(0), port(32)),
+                destination: SocketAddr::new(addr(16), port(34)),
+            }))
+        }
+        // Too short for the addresses of the family, which a valid header
+        // always has room for.
+        (0x1, 0x11 | 0x21) => Err(ProxyProtocolError::invalid_header()),
+        // Other families, e.g. Unix sockets, have no addresses for us.
+        (0x1, _) => Ok(None),
+        _ => Err(ProxyProtocolError::invalid_header()),
+    }
+}
+
+impl ProxyProtocolError {
+    #[inline]
+    fn invalid_header() -> Self {
+        Self {
+            kind: ProxyProtocolErrorKind::InvalidHeader,
+        }
+    }
+}
+
+impl From<io::Error> for ProxyProtocolError {
+    #[inline]
+    fn from(e: io::Error) -> Self {
+        Self {
+            kind: ProxyProtocolErrorKind::Io(e),
+        }
+    }
+}
+
+impl Display for ProxyProtocolError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ProxyProtocolErrorKind::Io(e) => write!(f, "error reading PROXY header: {}", e),
+            ProxyProtocolErrorKind::InvalidHeader => write!(f, "invalid PROXY header"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyProtocolError {}
+
+#[cfg(test)]
+mod test {
+    use super::{read_header, ProxyHeader};
+    use std::io::Read;
+
+    #[test]
+    fn test_read_header() {
+        let header = |source: &str, destination: &str| {
+            Some(ProxyHeader {
+                source: source.parse().unwrap(),
+                destination: destination.parse().unwrap(),
+            })
+        };
+        let read = |bytes: &[u8]| {
+            let mut reader = bytes;
+            let header = read_header(&mut reader).ok()?;
+            // The request is left alone.
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            assert_eq!(rest, "GET / HTTP/1.1\r\n");
+            Some(header)
+        };
+
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n"),
+            Some(header("192.0.2.1:56324", "192.0.2.2:443"))
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\nGET / HTTP/1.1\r\n"),
+            Some(header("[2001:db8::1]:56324", "[2001:db8::2]:443"))
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n"), Some(None));
+        let invalid: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\nGET / HTTP/1.1\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443 GET / HTTP/1.1\r\n",
+        ];
+        for bytes in invalid {
+            assert_eq!(read(bytes), None, "{:?}", String::from_utf8_lossy(bytes));
+        }
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0f".to_vec();
+        v2.extend([192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        // A TLV, which is skipped.
+        v2.extend([0x04, 0x00, 0x00]);
+        v2.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(read(&v2), Some(header("192.0.2.1:56324", "192.0.2.2:443")));
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        v2.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        v2.extend([0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(read(&v2), Some(header("[2001:db8::1]:56324", "[2001:db8::2]:443")));
+        let local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00GET / HTTP/1.1\r\n";
+        assert_eq!(read(local), Some(None));
+        let truncated = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01";
+        assert_eq!(read(truncated), None);
+        // The address blocks of TCP over IPv4 and IPv6 are 12 and 36 bytes.
+        let mut short = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x08".to_vec();
+        short.extend([192, 0, 2, 1, 192, 0, 2, 2]);
+        short.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(read(&short), None);
+        let mut short = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x20".to_vec();
+        short.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        short.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        short.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(read(&short), None);
+    }
+}
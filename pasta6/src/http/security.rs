@@ -0,0 +1,183 @@
+//! Security headers, added to every response.
+//!
+//! [`SecurityHeaders`] holds the values of the headers which tell browsers
+//! to lock our pages down: what they may load (`content-security-policy`),
+//! who may frame them (its `frame-ancestors` directive), to only use HTTPS
+//! (`strict-transport-security`), not to guess content types
+//! (`x-content-type-options`) and what to tell other sites about where
+//! their visitors come from (`referrer-policy`). Each can be changed or
+//! turned off through the fields of [`SecurityHeaders`].
+//!
+//! The content security policy only allows inline scripts and styles which
+//! carry the nonce of the response, so that injected ones don't run. A
+//! handler which renders such elements generates a nonce with
+//! [`generate_nonce`], puts it in their `nonce` attribute and attaches it to
+//! the response with [`Response::with_nonce`]. Other responses get a random
+//! nonce, which nothing uses.
+use crate::http::{Headers, Response};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Replaced with the nonce of the response in the content security policy.
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+/// Random bytes in a nonce, well over the 128 bits CSP asks for.
+const NONCE_LENGTH: usize = 18;
+
+/// The default policy. Markdown pastes may show images from anywhere over
+/// HTTPS, but nothing else is loaded from other sites.
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'nonce-{nonce}'; style-src 'self' 'nonce-{nonce}'; \
+    img-src 'self' https:; object-src 'none'; base-uri 'none'; form-action 'self'";
+const DEFAULT_FRAME_ANCESTORS: &str = "'none'";
+/// One year, which browsers only heed over HTTPS.
+const DEFAULT_STRICT_TRANSPORT_SECURITY: &str = "max-age=31536000";
+const DEFAULT_CONTENT_TYPE_OPTIONS: &str = "nosniff";
+/// Other sites learn our origin, but not which paste linked to them.
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+
+/// The security headers added to responses. `None` leaves a header out.
+/// Headers the handler set itself are left alone.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SecurityHeaders {
+    /// The `content-security-policy` header, without `frame-ancestors`.
+    /// `{nonce}` is replaced with the nonce of the response.
+    pub(crate) content_security_policy: Option<String>,
+    /// The sources of the `frame-ancestors` directive, which is added to
+    /// the content security policy.
+    pub(crate) frame_ancestors: Option<String>,
+    pub(crate) strict_transport_security: Option<String>,
+    pub(crate) content_type_options: Option<String>,
+    pub(crate) referrer_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            content_security_policy: Some(DEFAULT_CONTENT_SECURITY_POLICY.to_string()),
+            frame_ancestors: Some(DEFAULT_FRAME_ANCESTORS.to_string()),
+            strict_transport_security: Some(DEFAULT_STRICT_TRANSPORT_SECURITY.to_string()),
+            content_type_options: Some(DEFAULT_CONTENT_TYPE_OPTIONS.to_string()),
+            referrer_policy: Some(DEFAULT_REFERRER_POLICY.to_string()),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Returns no headers at all.
+    #[inline]
+    pub(crate) fn none() -> Self {
+        Self {
+            content_security_policy: None,
+            frame_ancestors: None,
+            strict_transport_security: None,
+            content_type_options: None,
+            referrer_policy: None,
+        }
+    }
+
+    /// Adds the headers to `response`, unless it already has them.
+    #[inline]
+    pub(crate) fn apply(&self, response: &mut Response) {
+        let nonce = response.take_nonce();
+        let headers = response.headers_mut();
+        if let Some(policy) = self.content_security_policy(nonce.as_deref()) {
+            insert_missing(headers, "content-security-policy", policy);
+        }
+        let others = [
+            ("strict-transport-security", &self.strict_transport_security),
+            ("x-content-type-options", &self.content_type_options),
+            ("referrer-policy", &self.referrer_policy),
+        ];
+        for (name, value) in others {
+            if let Some(value) = value {
+                insert_missing(headers, name, value);
+            }
+        }
+    }
+
+    /// Returns the content security policy of a response with `nonce`, if
+    /// any.
+    #[inline]
+    fn content_security_policy(&self, nonce: Option<&str>) -> Option<String> {
+        let mut policy = match &self.content_security_policy {
+            Some(policy) if policy.contains(NONCE_PLACEHOLDER) => {
+                let nonce = match nonce {
+                    Some(nonce) => nonce.to_string(),
+                    None => generate_nonce(),
+                };
+                policy.replace(NONCE_PLACEHOLDER, &nonce)
+            }
+            Some(policy) => policy.clone(),
+            None => String::new(),
+        };
+        if let Some(sources) = &self.frame_ancestors {
+            if !policy.is_empty() {
+                policy.push_str("; ");
+            }
+            policy.push_str("frame-ancestors ");
+            policy.push_str(sources);
+        }
+        (!policy.is_empty()).then_some(policy)
+    }
+}
+
+/// Generates a nonce for the inline scripts and styles of a response.
+#[inline]
+pub(crate) fn generate_nonce() -> String {
+    let mut nonce = [0; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce);
+    base64::encode_config(nonce, base64::URL_SAFE_NO_PAD)
+}
+
+#[inline]
+fn insert_missing(headers: &mut Headers, name: &'static str, value: impl AsRef<[u8]>) {
+    if headers.get(name).is_none() {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SecurityHeaders;
+    use crate::http::Response;
+
+    #[test]
+    fn test_apply() {
+        let headers = SecurityHeaders::default();
+        let mut response = Response::from_static(200, "").with_nonce("abc".to_string());
+        headers.apply(&mut response);
+        let header = |name| std::str::from_utf8(response.headers().get(name).unwrap()).unwrap();
+        let policy = header("content-security-policy");
+        assert!(policy.starts_with("default-src 'self'; "), "{}", policy);
+        assert!(policy.contains("script-src 'self' 'nonce-abc'; "), "{}", policy);
+        assert!(policy.ends_with("; frame-ancestors 'none'"), "{}", policy);
+        assert_eq!(header("strict-transport-security"), "max-age=31536000");
+        assert_eq!(header("x-content-type-options"), "nosniff");
+        assert_eq!(header("referrer-policy"), "strict-origin-when-cross-origin");
+
+        // Without a nonce, each response gets its own.
+        let policy = |headers: &SecurityHeaders| {
+            let mut response = Response::from_static(200, "");
+            headers.apply(&mut response);
+            let policy = response.headers().get("content-security-policy").unwrap();
+            std::str::from_utf8(policy).unwrap().to_string()
+        };
+        assert!(!policy(&headers).contains("{nonce}"));
+        assert_ne!(policy(&headers), policy(&headers));
+
+        let headers = SecurityHeaders {
+            content_security_policy: None,
+            ..SecurityHeaders::default()
+        };
+        assert_eq!(policy(&headers), "frame-ancestors 'none'");
+
+        // Handlers may set their own headers.
+        let mut response =
+            Response::from_static(200, "").with_header("referrer-policy", "no-referrer");
+        SecurityHeaders::none().apply(&mut response);
+        assert_eq!(response.headers().len(), 1);
+        SecurityHeaders::default().apply(&mut response);
+        assert_eq!(response.headers().get("referrer-policy"), Some(&b"no-referrer"[..]));
+    }
+}
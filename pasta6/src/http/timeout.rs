@@ -0,0 +1,223 @@
+//! Timeouts of connections.
+//!
+//! Every connection is served by a process of its own, which a client could
+//! otherwise keep busy for ever by sending a request a byte at a time, or by
+//! not sending one at all. Each step of reading a request must end within its
+//! own [`Timeouts`]: the wait for the first byte of a request, reading the
+//! rest of its head, then reading its body. Handlers and writing responses
+//! aren't timed.
+//!
+//! Lunatic sockets have no timeouts, so there a watchdog process is linked to
+//! the connection process. It's told each deadline, and panics when one
+//! passes, which kills the connection process and closes its socket. Other
+//! sockets, as in tests, time out their reads instead, and the request is
+//! answered with `408 Request Timeout`.
+use crate::net::TcpStream;
+#[cfg(target_arch = "wasm32")]
+use lunatic::{process::Process, Mailbox, ReceiveError};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How long each step of reading a request may take.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Timeouts {
+    /// How long a connection may wait for a request.
+    pub(crate) idle: Duration,
+    /// How long the head of a request may take, from its first byte.
+    pub(crate) header_read: Duration,
+    /// How long the body of a request may take, after its head.
+    pub(crate) body_read: Duration,
+}
+
+/// A step of serving a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Phase {
+    Idle,
+    Head,
+    Body,
+    /// Handling the request and writing the response, which aren't timed.
+    Handling,
+}
+
+/// Enforces the [`Timeouts`] of a connection.
+pub(super) struct Timer {
+    timeouts: Timeouts,
+    phase: Phase,
+    deadline: Option<Instant>,
+    #[cfg(target_arch = "wasm32")]
+    watchdog: Option<Process<Watch>>,
+}
+
+/// A message to a watchdog process.
+#[cfg(target_arch = "wasm32")]
+#[derive(Serialize, Deserialize)]
+enum Watch {
+    /// Panics unless another message arrives within this many milliseconds.
+    Deadline(u32),
+    Pause,
+    Stop,
+}
+
+impl Default for Timeouts {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            header_read: Duration::from_secs(20),
+            body_read: Duration::from_secs(120),
+        }
+    }
+}
+
+impl Timer {
+    /// Returns a timer for the connection of `tcp_stream`, which is idle.
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+    #[inline]
+    pub(super) fn new(tcp_stream: &TcpStream, timeouts: Timeouts) -> Self {
+        #[cfg(target_arch = "wasm32")]
+        let watchdog = match tcp_stream {
+            TcpStream::Lunatic(_) => match crate::spawn_with!((), watchdog) {
+                Ok(watchdog) => {
+                    // If the watchdog panics, so does this process.
+                    watchdog.link();
+                    Some(watchdog)
+                }
+                Err(e) => {
+                    tracing::error!("failed to spawn watchdog: {}", e);
+                    None
+                }
+            },
+            TcpStream::Std(_) => None,
+        };
+        let mut timer = Self {
+            timeouts,
+            phase: Phase::Handling,
+            deadline: None,
+            #[cfg(target_arch = "wasm32")]
+            watchdog,
+        };
+        timer.start(Phase::Idle);
+        timer
+    }
+
+    #[inline]
+    pub(super) fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Starts `phase`, which must end before its timeout.
+    #[inline]
+    pub(super) fn start(&mut self, phase: Phase) {
+        let timeout = match phase {
+            Phase::Idle => Some(self.timeouts.idle),
+            Phase::Head => Some(self.timeouts.header_read),
+            Phase::Body => Some(self.timeouts.body_read),
+            Phase::Handling => None,
+        };
+        self.phase = phase;
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        #[cfg(target_arch = "wasm32")]
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.send(match timeout {
+                Some(timeout) => {
+                    Watch::Deadline(u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX))
+                }
+                None => Watch::Pause,
+            });
+        }
+    }
+
+    /// Bounds the next read of `tcp_stream` by the deadline of the phase.
+    /// Returns a `TimedOut` error if it has passed.
+    #[inline]
+    pub(super) fn before_read(&self, tcp_stream: &TcpStream) -> io::Result<()> {
+        let timeout = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => return Err(io::ErrorKind::TimedOut.into()),
+            },
+            None => None,
+        };
+        tcp_stream.set_read_timeout(timeout)
+    }
+}
+
+/// Returns `true` if `e` is a read which timed out. Depending on the
+/// platform, these fail with `WouldBlock` or `TimedOut`.
+#[inline]
+pub(super) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+impl std::fmt::Debug for Timer {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer")
+            .field("phase", &self.phase)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for Timer {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.send(Watch::Stop);
+        }
+    }
+}
+
+/// Entry point of a watchdog process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn watchdog(_context: (), mailbox: Mailbox<Watch>) {
+    let mut timeout = None;
+    loop {
+        let message = match timeout {
+            Some(timeout) => mailbox.receive_timeout(timeout),
+            None => mailbox.receive(),
+        };
+        timeout = match message {
+            Ok(Watch::Deadline(deadline)) => Some(deadline),
+            Ok(Watch::Pause) => None,
+            Ok(Watch::Stop) => return,
+            Err(ReceiveError::Timeout) => {
+                tracing::debug!("connection timed out");
+                panic!("connection timed out");
+            }
+            Err(e) => {
+                tracing::error!("receive error: {}", e);
+                timeout
+            }
+        };
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use super::{Phase, Timeouts, Timer};
+    use std::time::Duration;
+
+    #[test]
+    fn test_timer() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let tcp_stream = crate::net::TcpStream::from(tcp_stream);
+        let timeouts = Timeouts {
+            idle: Duration::ZERO,
+            ..Timeouts::default()
+        };
+        let mut timer = Timer::new(&tcp_stream, timeouts);
+        assert_eq!(timer.phase(), Phase::Idle);
+        let e = timer.before_read(&tcp_stream).unwrap_err();
+        assert!(super::is_timeout(&e));
+        timer.start(Phase::Head);
+        assert!(timer.before_read(&tcp_stream).is_ok());
+        timer.start(Phase::Handling);
+        assert!(timer.before_read(&tcp_stream).is_ok());
+    }
+}
@@ -1,31 +1,39 @@
 // FIXME: remove this attr
 #![allow(dead_code)]
 
-use lunatic::process;
-
 mod app;
+mod cli;
+mod config;
+mod crypto;
+mod csrf;
+mod diff;
+mod highlight;
+mod html;
 mod http;
+mod markdown;
+mod mime;
 mod net;
+mod postgres;
+mod ratelimit;
+mod session;
+mod store;
+mod templates;
 
-#[cfg(target_arch = "wasm32")]
-pub fn run() {
-    use crate::http::Handler;
-
-    crate::app::server(crate::app::App::handle);
-    loop {
-        process::sleep(u64::MAX);
-    }
+/// Runs the command line interface with `args`, without the name of the
+/// binary, returning the exit code, see `cli::main`.
+pub fn main(args: &[String]) -> i32 {
+    crate::cli::main(args)
 }
 
 /// Define a wrapper macro for `process::spawn` that initializes our
 /// logger when a process is spawned. Unlike normal Rust applications, the
-/// logger must be re-initialized for every process.
+/// logger must be re-initialized for every process, at the level of the
+/// process spawning it.
 #[macro_export]
 macro_rules! spawn {
     ( $function:expr ) => {
-        lunatic::process::spawn(|mailbox| {
-            #[cfg(feature = "logging")]
-            tracing_subscriber::fmt::init();
+        lunatic::process::spawn_with($crate::log_level(), |level, mailbox| {
+            $crate::init_logging(level);
             $function(mailbox)
         })
     };
@@ -33,14 +41,39 @@ macro_rules! spawn {
 
 /// Define a wrapper macro for `process::spawn_with` that initializes our
 /// logger when a process is spawned. Unlike normal Rust applications, the
-/// logger must be re-initialized for every process.
+/// logger must be re-initialized for every process, at the level of the
+/// process spawning it.
 #[macro_export]
 macro_rules! spawn_with {
     ( $context:expr, $function:expr ) => {
-        lunatic::process::spawn_with($context, |context, mailbox| {
-            #[cfg(feature = "logging")]
-            tracing_subscriber::fmt::init();
-            $function(context, mailbox)
-        })
+        lunatic::process::spawn_with(
+            ($context, $crate::log_level()),
+            |(context, level), mailbox| {
+                $crate::init_logging(level);
+                $function(context, mailbox)
+            },
+        )
     };
 }
+
+/// Returns the level of the logger of this process, e.g. `debug`, or `None`
+/// if it has none, for [`spawn_with`].
+#[inline]
+fn log_level() -> Option<String> {
+    tracing::dispatcher::has_been_set()
+        .then(|| tracing::level_filters::LevelFilter::current().to_string())
+}
+
+/// Initializes the logger of a new process at `level`, by default `info`.
+#[inline]
+fn init_logging(level: Option<String>) {
+    #[cfg(feature = "logging")]
+    {
+        let level = level.and_then(|level| level.parse().ok());
+        tracing_subscriber::fmt()
+            .with_max_level(level.unwrap_or(tracing::level_filters::LevelFilter::INFO))
+            .init();
+    }
+    #[cfg(not(feature = "logging"))]
+    let _ = level;
+}
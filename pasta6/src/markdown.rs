@@ -0,0 +1,173 @@
+//! Rendering of Markdown pastes and todos as HTML.
+//!
+//! Markdown is parsed as CommonMark with tables. The output is safe to embed
+//! in our pages without a separate sanitizer, because it's only ever
+//! produced by the renderer, which escapes all text:
+//!
+//! - raw HTML in the source is shown as text, rather than passed through,
+//! - links and images may only point to `http`, `https` and `mailto` URLs,
+//!   or relative ones, so that e.g. `javascript:` links can't run scripts.
+//!
+//! Fenced code blocks in a known language are highlighted like pastes.
+use crate::highlight::{self, Language};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag};
+
+/// Styles for tables, which are otherwise drawn without borders.
+pub(crate) const STYLESHEET: &str = "\
+.markdown table{border-collapse:collapse}\
+.markdown th,.markdown td{border:1px solid #d0d0d0;padding:2px 6px}";
+
+/// Schemes which links and images may use.
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Returns `true` if a paste should be rendered as Markdown: its language is
+/// `markdown`, or it has none and its title is a Markdown file name.
+#[inline]
+pub(crate) fn is_markdown(language: Option<&str>, title: Option<&str>) -> bool {
+    let is_markdown = |name: &str| {
+        name.eq_ignore_ascii_case("markdown") || name.eq_ignore_ascii_case("md")
+    };
+    match language {
+        Some(language) => is_markdown(language),
+        None => title
+            .and_then(|title| title.rsplit_once('.'))
+            .map_or(false, |(_, extension)| is_markdown(extension)),
+    }
+}
+
+/// Renders a Markdown document as HTML.
+#[inline]
+pub(crate) fn render(source: &str) -> String {
+    let mut code_block = None;
+    let events = sanitize(Parser::new_ext(source, Options::ENABLE_TABLES)).filter_map(|event| {
+        match (event, &mut code_block) {
+            (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), _) => {
+                // The info string is e.g. `rust` or `rust,ignore`.
+                let name = info.split([' ', ',']).next().unwrap_or_default();
+                match Language::from_name(name) {
+                    Some(language) => {
+                        code_block = Some((language, String::new()));
+                        None
+                    }
+                    None => Some(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))),
+                }
+            }
+            (Event::Text(text), Some((_, code))) => {
+                code.push_str(&text);
+                None
+            }
+            (Event::End(Tag::CodeBlock(_)), Some(_)) => {
+                let (language, code) = code_block.take().unwrap();
+                Some(Event::Html(CowStr::from(format!(
+                    "<pre><code class=\"language-{}\">{}</code></pre>\n",
+                    language.name(),
+                    highlight::highlight(language, &code)
+                ))))
+            }
+            (event, _) => Some(event),
+        }
+    });
+    let mut output = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut output, events);
+    output
+}
+
+/// Renders a line of Markdown as inline HTML, e.g. emphasis, code and links,
+/// for todos. Block elements such as headings and lists are left out, but
+/// not their text.
+#[inline]
+pub(crate) fn render_inline(source: &str) -> String {
+    let events = sanitize(Parser::new(source)).filter(|event| match event {
+        Event::Start(tag) | Event::End(tag) => matches!(
+            tag,
+            Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..)
+        ),
+        Event::Text(_) | Event::Code(_) | Event::SoftBreak | Event::HardBreak => true,
+        _ => false,
+    });
+    let mut output = String::with_capacity(source.len());
+    html::push_html(&mut output, events);
+    output
+}
+
+/// Turns raw HTML into text, and drops the URLs of links and images which
+/// aren't safe.
+#[inline]
+fn sanitize<'a>(parser: Parser<'a, 'a>) -> impl Iterator<Item = Event<'a>> {
+    parser.map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) if !is_safe_url(&url) => {
+            Event::Start(Tag::Link(kind, CowStr::Borrowed(""), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) if !is_safe_url(&url) => {
+            Event::Start(Tag::Image(kind, CowStr::Borrowed(""), title))
+        }
+        event => event,
+    })
+}
+
+/// URLs are safe if they're relative, or use one of [`SAFE_SCHEMES`].
+#[inline]
+fn is_safe_url(url: &str) -> bool {
+    match url.split_once(':') {
+        // A colon after a `/`, `?` or `#` is part of a relative URL.
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => SAFE_SCHEMES
+            .iter()
+            .any(|safe| safe.eq_ignore_ascii_case(scheme)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_markdown, render, render_inline};
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("# Notes\n\nSome *text* and <b onclick=\"x()\">HTML</b>.\n"),
+            "<h1>Notes</h1>\n<p>Some <em>text</em> and &lt;b onclick=&quot;x()&quot;&gt;HTML\
+             &lt;/b&gt;.</p>\n"
+        );
+        assert_eq!(
+            render("<script>alert(1)</script>\n"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;\n"
+        );
+        assert_eq!(
+            render("[a](javascript:alert(1)) [b](https://example.com) [c](/p/abc)"),
+            "<p><a href=\"\">a</a> <a href=\"https://example.com\">b</a> \
+             <a href=\"/p/abc\">c</a></p>\n"
+        );
+        assert_eq!(
+            render("![x](data:image/svg+xml,a)"),
+            "<p><img src=\"\" alt=\"x\" /></p>\n"
+        );
+        assert_eq!(
+            render("| a | b |\n|---|---|\n| 1 | 2 |\n"),
+            "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody>\n\
+             <tr><td>1</td><td>2</td></tr>\n</tbody></table>\n"
+        );
+        assert_eq!(
+            render("```rust\nfn main() {}\n```\n"),
+            "<pre><code class=\"language-rust\"><span class=\"hl-keyword\">fn</span> \
+             <span class=\"hl-function\">main</span>() {}\n</code></pre>\n"
+        );
+        // Unknown languages aren't highlighted, but still escaped.
+        assert_eq!(
+            render("```klingon\n<qapla'>\n```\n"),
+            "<pre><code class=\"language-klingon\">&lt;qapla'&gt;\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_inline() {
+        assert_eq!(
+            render_inline("# Buy **milk**, see [list](javascript:x) <i>now</i>"),
+            "Buy <strong>milk</strong>, see <a href=\"\">list</a> &lt;i&gt;now&lt;/i&gt;"
+        );
+        assert!(is_markdown(Some("Markdown"), None));
+        assert!(is_markdown(None, Some("README.md")));
+        assert!(!is_markdown(Some("rust"), Some("README.md")));
+        assert!(!is_markdown(None, Some("main.rs")));
+    }
+}
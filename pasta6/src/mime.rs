@@ -0,0 +1,112 @@
+//! Detection of the media type of uploaded files.
+//!
+//! The type is sniffed from the magic bytes at the start of the file, never
+//! taken from the client, so that an upload can't make itself e.g. HTML and
+//! run scripts on our origin. Types which browsers would render as documents
+//! aren't detected at all.
+
+/// Media type of files which aren't recognized.
+pub(crate) const OCTET_STREAM: &str = "application/octet-stream";
+/// Media type of files which are valid UTF-8 text.
+pub(crate) const TEXT: &str = "text/plain; charset=utf-8";
+
+/// Signatures as `(offset, magic bytes, media type)`, most specific first.
+/// Short signatures which plain text could start with, like the `BM` of
+/// bitmaps, are left out.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3\x03", "audio/mpeg"),
+    (0, b"ID3\x04", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Types which are safe to show in the browser rather than download.
+const INLINE_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "audio/ogg",
+    "audio/flac",
+    "audio/mpeg",
+    "video/mp4",
+    "video/webm",
+    TEXT,
+];
+
+/// Returns the media type of `data`, e.g. `image/png`.
+#[inline]
+pub(crate) fn sniff(data: &[u8]) -> &'static str {
+    let signature = SIGNATURES.iter().find(|(offset, magic, _)| {
+        data.get(*offset..offset + magic.len()) == Some(magic)
+    });
+    match signature {
+        // `RIFF....WEBP`, not any other RIFF file.
+        Some((_, _, "image/webp")) if !data.starts_with(b"RIFF") => OCTET_STREAM,
+        Some((_, _, media_type)) => media_type,
+        None if is_text(data) => TEXT,
+        None => OCTET_STREAM,
+    }
+}
+
+/// Returns `true` if files of `media_type` can be shown inline.
+#[inline]
+pub(crate) fn is_inline(media_type: &str) -> bool {
+    INLINE_TYPES.contains(&media_type)
+}
+
+/// Returns `true` if `media_type` is an image.
+#[inline]
+pub(crate) fn is_image(media_type: &str) -> bool {
+    media_type.starts_with("image/")
+}
+
+/// Text is valid UTF-8 without NUL bytes, which never occur in text but
+/// often in binary files.
+#[inline]
+fn is_text(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_inline, sniff, OCTET_STREAM, TEXT};
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), OCTET_STREAM);
+        assert_eq!(sniff(b"\x1f\x8b\x08\0"), "application/gzip");
+        let mut tar = vec![0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar), "application/x-tar");
+        assert_eq!(sniff("2022-01-01 INFO démarré\n".as_bytes()), TEXT);
+        assert_eq!(sniff(b""), TEXT);
+        assert_eq!(sniff(b"\xff\xfe"), OCTET_STREAM);
+        assert_eq!(sniff(b"text\0"), OCTET_STREAM);
+        // HTML is only ever text, which is shown as plain text.
+        assert_eq!(sniff(b"<html><script>alert(1)</script>"), TEXT);
+        assert!(is_inline("image/png"));
+        assert!(!is_inline("application/pdf"));
+        assert!(!is_inline(OCTET_STREAM));
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub(crate) enum TcpStream {
@@ -8,6 +9,22 @@ pub(crate) enum TcpStream {
 }
 
 impl TcpStream {
+    /// Opens a TCP connection to a remote host, using the lunatic networking
+    /// APIs when running under the lunatic runtime.
+    #[inline]
+    pub(crate) fn connect(host: &str, port: u16) -> Result<Self, io::Error> {
+        #[cfg(target_arch = "wasm32")]
+        {
+            Ok(TcpStream::Lunatic(lunatic::net::TcpStream::connect((
+                host, port,
+            ))?))
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Ok(TcpStream::Std(std::net::TcpStream::connect((host, port))?))
+        }
+    }
+
     #[inline]
     pub(crate) fn try_clone(&self) -> Result<Self, io::Error> {
         match self {
@@ -18,6 +35,20 @@ impl TcpStream {
     }
 }
 
+impl TcpStream {
+    /// Sets the timeout of reads, or removes it with `None`. Lunatic sockets
+    /// have no timeouts, so this does nothing for them, see
+    /// [`crate::http::timeout`].
+    #[inline]
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            #[cfg(target_arch = "wasm32")]
+            TcpStream::Lunatic(_tcp_stream) => Ok(()),
+            TcpStream::Std(tcp_stream) => tcp_stream.set_read_timeout(timeout),
+        }
+    }
+}
+
 impl From<std::net::TcpStream> for TcpStream {
     #[inline]
     fn from(tcp_stream: std::net::TcpStream) -> Self {
@@ -0,0 +1,234 @@
+//! Password authentication mechanisms.
+//!
+//! # RFCs
+//!
+//! - [RFC 5802 (Salted Challenge Response Authentication Mechanism (SCRAM))][rfc5802]
+//! - [RFC 7677 (SCRAM-SHA-256 and SCRAM-SHA-256-PLUS)][rfc7677]
+//!
+//! [rfc5802]: https://datatracker.ietf.org/doc/html/rfc5802 "Salted Challenge Response Authentication Mechanism (SCRAM) SASL and GSS-API Mechanisms"
+//! [rfc7677]: https://datatracker.ietf.org/doc/html/rfc7677 "SCRAM-SHA-256 and SCRAM-SHA-256-PLUS Simple Authentication and Security Layer (SASL) Mechanisms"
+use crate::postgres::Error;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::str;
+
+pub(super) const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+/// Length of the client nonce, in characters.
+const NONCE_LENGTH: usize = 24;
+
+/// State of a SCRAM-SHA-256 exchange without channel binding.
+pub(super) struct ScramSha256 {
+    password: String,
+    client_first_bare: String,
+    client_nonce: String,
+    state: ScramState,
+}
+
+enum ScramState {
+    Initial,
+    Continued { server_signature: [u8; 32] },
+    Finished,
+}
+
+/// Computes the response to an `AuthenticationMD5Password` challenge.
+#[inline]
+pub(super) fn md5_password(user: &str, password: &str, salt: [u8; 4]) -> String {
+    let mut md5 = Md5::new();
+    md5.update(password.as_bytes());
+    md5.update(user.as_bytes());
+    let inner = hex(&md5.finalize_reset());
+    md5.update(inner.as_bytes());
+    md5.update(salt);
+    format!("md5{}", hex(&md5.finalize()))
+}
+
+impl ScramSha256 {
+    #[inline]
+    pub(super) fn new(password: &str) -> Self {
+        let mut rng = rand::thread_rng();
+        // Printable ASCII excluding `,`, as required by the `nonce` syntax.
+        let client_nonce = (0..NONCE_LENGTH)
+            .map(|_| match rng.gen_range(0x21u8..0x7e) {
+                b',' => '~',
+                c => c as char,
+            })
+            .collect::<String>();
+        // PostgreSQL ignores the SCRAM user name in favour of the one sent
+        // in the startup message.
+        Self::with_nonce("", password, client_nonce)
+    }
+
+    #[inline]
+    fn with_nonce(user: &str, password: &str, client_nonce: String) -> Self {
+        Self {
+            password: password.to_string(),
+            client_first_bare: format!("n={},r={}", user, client_nonce),
+            client_nonce,
+            state: ScramState::Initial,
+        }
+    }
+
+    /// Returns the `client-first-message`.
+    #[inline]
+    pub(super) fn client_first(&self) -> Vec<u8> {
+        format!("n,,{}", self.client_first_bare).into_bytes()
+    }
+
+    /// Processes the `server-first-message` and returns the
+    /// `client-final-message`.
+    #[inline]
+    pub(super) fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>, Error> {
+        if !matches!(self.state, ScramState::Initial) {
+            return Err(Error::authentication("unexpected SCRAM message"));
+        }
+        let server_first = str::from_utf8(server_first)
+            .map_err(|_e| Error::authentication("invalid SCRAM message"))?;
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attribute in server_first.split(',') {
+            match attribute.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = base64::decode(value).ok(),
+                Some(("i", value)) => iterations = value.parse::<u32>().ok(),
+                _ => {}
+            }
+        }
+        let (nonce, salt, iterations) = match (nonce, salt, iterations) {
+            (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 => {
+                (nonce, salt, iterations)
+            }
+            _ => return Err(Error::authentication("invalid SCRAM message")),
+        };
+        if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len() {
+            return Err(Error::authentication("invalid SCRAM nonce"));
+        }
+
+        let salted_password = hi(self.password.as_bytes(), &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+        // `biws` is the base64 encoding of the `n,,` GS2 header.
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let mut client_proof = client_key;
+        client_proof
+            .iter_mut()
+            .zip(client_signature.iter())
+            .for_each(|(p, s)| *p ^= s);
+        let server_key = hmac(&salted_password, b"Server Key");
+        let server_signature = hmac(&server_key, auth_message.as_bytes());
+
+        self.state = ScramState::Continued { server_signature };
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            base64::encode(client_proof)
+        )
+        .into_bytes())
+    }
+
+    /// Verifies the `server-final-message`.
+    #[inline]
+    pub(super) fn finish(&mut self, server_final: &[u8]) -> Result<(), Error> {
+        let server_signature = match self.state {
+            ScramState::Continued { server_signature } => server_signature,
+            _ => return Err(Error::authentication("unexpected SCRAM message")),
+        };
+        let server_final = str::from_utf8(server_final)
+            .map_err(|_e| Error::authentication("invalid SCRAM message"))?;
+        let verifier = match server_final.split_once('=') {
+            Some(("v", verifier)) => base64::decode(verifier)
+                .map_err(|_e| Error::authentication("invalid SCRAM message"))?,
+            Some(("e", error)) => {
+                tracing::error!("SCRAM server error: {}", error);
+                return Err(Error::authentication("SCRAM authentication rejected"));
+            }
+            _ => return Err(Error::authentication("invalid SCRAM message")),
+        };
+        if verifier != server_signature {
+            return Err(Error::authentication("invalid SCRAM server signature"));
+        }
+        self.state = ScramState::Finished;
+        Ok(())
+    }
+}
+
+/// The `Hi` function from RFC 5802, which is PBKDF2 with HMAC-SHA-256 and an
+/// output length of a single block.
+#[inline]
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(password).expect("HMAC accepts any key length");
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut result = u;
+    for _ in 1..iterations {
+        u = hmac(password, &u);
+        result.iter_mut().zip(u.iter()).for_each(|(r, u)| *r ^= u);
+    }
+    result
+}
+
+#[inline]
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[inline]
+fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut string = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        string.push(DIGITS[(byte >> 4) as usize] as char);
+        string.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    string
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScramSha256;
+
+    #[test]
+    fn test_md5_password() {
+        // Computed with `SELECT 'md5' || md5(md5('password' || 'user') || 'salt')`.
+        assert_eq!(
+            super::md5_password("user", "password", *b"salt"),
+            "md58e998aaa66bd302e5592df3642c16f78"
+        );
+    }
+
+    #[test]
+    fn test_scram_sha_256() {
+        // Test vector from RFC 7677 section 3.
+        let mut scram = ScramSha256::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO".into());
+        assert_eq!(scram.client_first(), b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram
+            .client_final(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+            .unwrap();
+        assert_eq!(
+            client_final,
+            &b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="[..]
+        );
+        scram
+            .finish(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scram_bad_server_signature() {
+        let mut scram = ScramSha256::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO".into());
+        scram
+            .client_final(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+            .unwrap();
+        assert!(scram.finish(b"v=AAAA").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Encoding of frontend messages and decoding of backend messages.
+//!
+//! Every message other than the startup message consists of a one byte type
+//! tag, a big-endian `i32` length (which includes itself but not the tag) and
+//! the message body.
+use crate::postgres::{Column, DbError, Error};
+use std::io::{self, Read};
+use std::ops::Range;
+
+/// Protocol version 3.0, sent in the startup message.
+const PROTOCOL_VERSION: i32 = 196_608;
+/// Maximum size of a single backend message we're willing to buffer.
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+#[cfg_attr(test, derive(Debug))]
+pub(super) enum BackendMessage {
+    Authentication(Authentication),
+    BackendKeyData { process_id: i32, secret_key: i32 },
+    BindComplete,
+    CloseComplete,
+    CommandComplete { tag: String },
+    DataRow { values: Vec<u8>, ranges: Vec<Option<Range<usize>>> },
+    EmptyQueryResponse,
+    ErrorResponse(DbError),
+    NoData,
+    NoticeResponse(DbError),
+    ParameterDescription,
+    ParameterStatus { name: String, value: String },
+    ParseComplete,
+    PortalSuspended,
+    ReadyForQuery { status: u8 },
+    RowDescription { columns: Vec<Column> },
+}
+
+#[cfg_attr(test, derive(Debug))]
+pub(super) enum Authentication {
+    Ok,
+    CleartextPassword,
+    Md5Password { salt: [u8; 4] },
+    Sasl { mechanisms: Vec<String> },
+    SaslContinue { data: Vec<u8> },
+    SaslFinal { data: Vec<u8> },
+}
+
+/// Cursor over the body of a single backend message.
+struct Buffer<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+#[inline]
+pub(super) fn startup(buf: &mut Vec<u8>, parameters: &[(&str, &str)]) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    for (name, value) in parameters {
+        write_cstr(buf, name);
+        write_cstr(buf, value);
+    }
+    buf.push(0);
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn password(buf: &mut Vec<u8>, password: &str) {
+    let start = begin(buf, b'p');
+    write_cstr(buf, password);
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn sasl_initial_response(buf: &mut Vec<u8>, mechanism: &str, data: &[u8]) {
+    let start = begin(buf, b'p');
+    write_cstr(buf, mechanism);
+    buf.extend_from_slice(&(data.len() as i32).to_be_bytes());
+    buf.extend_from_slice(data);
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn sasl_response(buf: &mut Vec<u8>, data: &[u8]) {
+    let start = begin(buf, b'p');
+    buf.extend_from_slice(data);
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn query(buf: &mut Vec<u8>, sql: &str) {
+    let start = begin(buf, b'Q');
+    write_cstr(buf, sql);
+    finish_length(buf, start);
+}
+
+/// Writes a `Parse` message for the unnamed prepared statement, leaving the
+/// parameter types for the server to infer.
+#[inline]
+pub(super) fn parse(buf: &mut Vec<u8>, sql: &str) {
+    let start = begin(buf, b'P');
+    write_cstr(buf, "");
+    write_cstr(buf, sql);
+    buf.extend_from_slice(&0i16.to_be_bytes());
+    finish_length(buf, start);
+}
+
+/// Writes a `Bind` message binding the unnamed prepared statement to the
+/// unnamed portal. All parameters and results use the text format.
+#[inline]
+pub(super) fn bind(buf: &mut Vec<u8>, parameters: &[Option<Vec<u8>>]) {
+    let start = begin(buf, b'B');
+    write_cstr(buf, "");
+    write_cstr(buf, "");
+    buf.extend_from_slice(&0i16.to_be_bytes());
+    buf.extend_from_slice(&(parameters.len() as i16).to_be_bytes());
+    for parameter in parameters {
+        match parameter {
+            Some(value) => {
+                buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                buf.extend_from_slice(value);
+            }
+            None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+    buf.extend_from_slice(&0i16.to_be_bytes());
+    finish_length(buf, start);
+}
+
+/// Writes a `Describe` message for the unnamed portal.
+#[inline]
+pub(super) fn describe_portal(buf: &mut Vec<u8>) {
+    let start = begin(buf, b'D');
+    buf.push(b'P');
+    write_cstr(buf, "");
+    finish_length(buf, start);
+}
+
+/// Writes an `Execute` message for the unnamed portal without a row limit.
+#[inline]
+pub(super) fn execute(buf: &mut Vec<u8>) {
+    let start = begin(buf, b'E');
+    write_cstr(buf, "");
+    buf.extend_from_slice(&0i32.to_be_bytes());
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn sync(buf: &mut Vec<u8>) {
+    let start = begin(buf, b'S');
+    finish_length(buf, start);
+}
+
+#[inline]
+pub(super) fn terminate(buf: &mut Vec<u8>) {
+    let start = begin(buf, b'X');
+    finish_length(buf, start);
+}
+
+/// Reads a single backend message from `reader`, using `buf` as scratch
+/// space for the message body.
+#[inline]
+pub(super) fn read<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<BackendMessage, Error> {
+    let mut head = [0; 5];
+    reader.read_exact(&mut head)?;
+    let tag = head[0];
+    let length = i32::from_be_bytes([head[1], head[2], head[3], head[4]]);
+    let length = match usize::try_from(length) {
+        Ok(length) if (4..=MAX_MESSAGE_LENGTH).contains(&length) => length - 4,
+        _ => return Err(Error::protocol("invalid message length")),
+    };
+    buf.clear();
+    buf.resize(length, 0);
+    reader.read_exact(buf)?;
+    tracing::trace!("postgres received message: {}", tag as char);
+    parse_backend(tag, buf)
+}
+
+#[inline]
+fn parse_backend(tag: u8, body: &[u8]) -> Result<BackendMessage, Error> {
+    let mut buf = Buffer {
+        bytes: body,
+        position: 0,
+    };
+    let message = match tag {
+        b'R' => {
+            let authentication = match buf.i32()? {
+                0 => Authentication::Ok,
+                3 => Authentication::CleartextPassword,
+                5 => {
+                    let mut salt = [0; 4];
+                    salt.copy_from_slice(buf.take(4)?);
+                    Authentication::Md5Password { salt }
+                }
+                10 => {
+                    let mut mechanisms = vec![];
+                    loop {
+                        let mechanism = buf.cstr()?;
+                        if mechanism.is_empty() {
+                            break;
+                        }
+                        mechanisms.push(mechanism.to_string());
+                    }
+                    Authentication::Sasl { mechanisms }
+                }
+                11 => Authentication::SaslContinue {
+                    data: buf.rest().to_vec(),
+                },
+                12 => Authentication::SaslFinal {
+                    data: buf.rest().to_vec(),
+                },
+                code => {
+                    tracing::error!("unsupported authentication method: {}", code);
+                    return Err(Error::protocol("unsupported authentication method"));
+                }
+            };
+            BackendMessage::Authentication(authentication)
+        }
+        b'K' => BackendMessage::BackendKeyData {
+            process_id: buf.i32()?,
+            secret_key: buf.i32()?,
+        },
+        b'2' => BackendMessage::BindComplete,
+        b'3' => BackendMessage::CloseComplete,
+        b'C' => BackendMessage::CommandComplete {
+            tag: buf.cstr()?.to_string(),
+        },
+        b'D' => {
+            let count = buf.i16()?;
+            let mut ranges = Vec::with_capacity(count.max(0) as usize);
+            let mut values = Vec::with_capacity(buf.rest_len());
+            for _ in 0..count {
+                let length = buf.i32()?;
+                if length < 0 {
+                    ranges.push(None);
+                    continue;
+                }
+                let value = buf.take(length as usize)?;
+                let start = values.len();
+                values.extend_from_slice(value);
+                ranges.push(Some(start..values.len()));
+            }
+            BackendMessage::DataRow { values, ranges }
+        }
+        b'I' => BackendMessage::EmptyQueryResponse,
+        b'E' => BackendMessage::ErrorResponse(parse_fields(&mut buf)?),
+        b'n' => BackendMessage::NoData,
+        b'N' => BackendMessage::NoticeResponse(parse_fields(&mut buf)?),
+        b't' => BackendMessage::ParameterDescription,
+        b'S' => BackendMessage::ParameterStatus {
+            name: buf.cstr()?.to_string(),
+            value: buf.cstr()?.to_string(),
+        },
+        b'1' => BackendMessage::ParseComplete,
+        b's' => BackendMessage::PortalSuspended,
+        b'Z' => BackendMessage::ReadyForQuery { status: buf.u8()? },
+        b'T' => {
+            let count = buf.i16()?;
+            let mut columns = Vec::with_capacity(count.max(0) as usize);
+            for _ in 0..count {
+                let name = buf.cstr()?.to_string();
+                // Skip the table OID and attribute number.
+                buf.take(6)?;
+                let type_oid = buf.i32()? as u32;
+                // Skip the type size, type modifier and format code.
+                buf.take(8)?;
+                columns.push(Column { name, type_oid });
+            }
+            BackendMessage::RowDescription { columns }
+        }
+        tag => {
+            tracing::error!("unexpected message tag: {}", tag);
+            return Err(Error::protocol("unexpected message tag"));
+        }
+    };
+    Ok(message)
+}
+
+/// Parses the fields of an `ErrorResponse` or `NoticeResponse`.
+#[inline]
+fn parse_fields(buf: &mut Buffer) -> Result<DbError, Error> {
+    let mut error = DbError {
+        severity: String::new(),
+        code: String::new(),
+        message: String::new(),
+        detail: None,
+        constraint: None,
+    };
+    loop {
+        let field = buf.u8()?;
+        if field == 0 {
+            break;
+        }
+        let value = buf.cstr()?.to_string();
+        match field {
+            b'S' => error.severity = value,
+            b'C' => error.code = value,
+            b'M' => error.message = value,
+            b'D' => error.detail = Some(value),
+            b'n' => error.constraint = Some(value),
+            _ => {}
+        }
+    }
+    Ok(error)
+}
+
+/// Reserves space for the tag and length of a message, returning the offset
+/// of the length field.
+#[inline]
+fn begin(buf: &mut Vec<u8>, tag: u8) -> usize {
+    buf.push(tag);
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    start
+}
+
+#[inline]
+fn finish_length(buf: &mut [u8], start: usize) {
+    let length = (buf.len() - start) as i32;
+    buf[start..start + 4].copy_from_slice(&length.to_be_bytes());
+}
+
+#[inline]
+fn write_cstr(buf: &mut Vec<u8>, string: &str) {
+    buf.extend_from_slice(string.as_bytes());
+    buf.push(0);
+}
+
+impl<'a> Buffer<'a> {
+    #[inline]
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| Error::protocol("unexpected end of message"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    #[inline]
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    #[inline]
+    fn i16(&mut self) -> Result<i16, Error> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    #[inline]
+    fn i32(&mut self) -> Result<i32, Error> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    #[inline]
+    fn cstr(&mut self) -> Result<&'a str, Error> {
+        let rest = &self.bytes[self.position..];
+        let end = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| Error::protocol("unterminated string"))?;
+        let string = std::str::from_utf8(&rest[..end])
+            .map_err(|_e| Error::protocol("invalid utf-8 in string"))?;
+        self.position += end + 1;
+        Ok(string)
+    }
+
+    #[inline]
+    fn rest_len(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    #[inline]
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        rest
+    }
+}
+
+impl From<io::Error> for Error {
+    #[inline]
+    fn from(source: io::Error) -> Self {
+        Error::io(source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BackendMessage, Buffer};
+
+    #[test]
+    fn test_startup() {
+        let mut buf = vec![];
+        super::startup(&mut buf, &[("user", "test_user")]);
+        assert_eq!(&buf[..4], &(buf.len() as i32).to_be_bytes());
+        assert_eq!(&buf[4..8], &[0, 3, 0, 0]);
+        assert_eq!(&buf[8..], b"user\0test_user\0\0");
+    }
+
+    #[test]
+    fn test_bind_null() {
+        let mut buf = vec![];
+        super::bind(&mut buf, &[Some(b"1".to_vec()), None]);
+        assert_eq!(buf[0], b'B');
+        assert_eq!(
+            &buf[5..],
+            &[0, 0, 0, 0, 0, 2, 0, 0, 0, 1, b'1', 255, 255, 255, 255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_data_row() {
+        let body = [0, 2, 0, 0, 0, 2, b'4', b'2', 255, 255, 255, 255];
+        match super::parse_backend(b'D', &body).unwrap() {
+            BackendMessage::DataRow { values, ranges } => {
+                assert_eq!(values, b"42");
+                assert_eq!(ranges, vec![Some(0..2), None]);
+            }
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let mut buf = Buffer {
+            bytes: &[0, 0, 1],
+            position: 0,
+        };
+        assert!(buf.i32().is_err());
+        assert!(super::parse_backend(b'C', b"SELECT 1").is_err());
+    }
+}
@@ -0,0 +1,744 @@
+//! Connection pooling.
+//!
+//! Lunatic processes don't share memory, so the pool is a process which owns
+//! the idle connections. A [`Pool`] handle can be sent to any process;
+//! checking out a connection moves its socket into the calling process, and
+//! dropping the [`PooledClient`] moves it back.
+//!
+//! The pool opens at most `max_size` connections, on demand. When all of them
+//! are checked out, checkouts wait in FIFO order for up to
+//! `checkout_timeout`. Connections which have been idle for
+//! `health_check_interval` are checked with a trivial query and closed if
+//! they no longer work.
+//!
+//! The pool links to every process it lends a connection to. If a borrower
+//! dies with the connection, the socket dies with it, so the pool forgets
+//! the connection and opens a new one for the next waiter.
+#[cfg(target_arch = "wasm32")]
+use crate::postgres::{Client, Config};
+use crate::postgres::Error;
+#[cfg(target_arch = "wasm32")]
+use lunatic::{
+    process::{Process, Request},
+    Mailbox, Message, ReceiveError, Tag,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(target_arch = "wasm32")]
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PoolConfig {
+    /// Maximum number of open connections, idle or checked out.
+    pub(crate) max_size: usize,
+    /// How long a checkout waits for a connection before failing.
+    pub(crate) checkout_timeout: Duration,
+    /// How long a connection may be idle before it's health-checked.
+    pub(crate) health_check_interval: Duration,
+}
+
+/// A snapshot of the state of a pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PoolMetrics {
+    /// Number of open connections, idle or checked out.
+    pub(crate) size: usize,
+    pub(crate) idle: usize,
+    /// Number of checkouts waiting for a connection.
+    pub(crate) waiting: usize,
+    pub(crate) checkouts: u64,
+    pub(crate) timeouts: u64,
+    pub(crate) connects: u64,
+    pub(crate) connect_errors: u64,
+    /// Number of connections closed because they were broken.
+    pub(crate) closed: u64,
+}
+
+/// A handle to a pool process.
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Pool {
+    process: Process<Checkout>,
+}
+
+/// A connection checked out of a [`Pool`], which is returned to the pool
+/// when dropped.
+#[cfg(target_arch = "wasm32")]
+pub(crate) struct PooledClient {
+    pool: Pool,
+    loan: u64,
+    client: Option<Client>,
+}
+
+/// A connection in a form which can be moved to another process.
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone, Serialize, Deserialize)]
+struct SendableClient {
+    tcp_stream: lunatic::net::TcpStream,
+    parameters: Vec<(String, String)>,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[derive(Serialize, Deserialize)]
+enum PoolRequest {
+    Checkout,
+    Checkin(u64, SendableClient),
+    /// Reports that a checked out connection was closed.
+    Discard(u64),
+    Metrics,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[derive(Serialize, Deserialize)]
+enum PoolResponse {
+    /// A connection and the id of its loan, which identifies it on checkin.
+    Client(Result<(u64, SendableClient), String>),
+    Metrics(PoolMetrics),
+    Ok,
+}
+
+#[cfg(target_arch = "wasm32")]
+type Checkout = Request<PoolRequest, PoolResponse>;
+
+/// A process holding a connection, and the tag of the pool's link to it.
+#[cfg(target_arch = "wasm32")]
+type Borrower = (Tag, Process<PoolResponse>);
+
+/// Opens and checks the connections of a [`PoolState`].
+trait Manager {
+    type Connection;
+
+    fn connect(&mut self) -> Result<Self::Connection, Error>;
+
+    /// Returns `false` if the connection no longer works.
+    fn is_valid(&mut self, connection: &mut Self::Connection) -> bool;
+}
+
+/// The bookkeeping of a pool, independent of how connections and waiting
+/// checkouts are represented.
+struct PoolState<M: Manager, W> {
+    manager: M,
+    config: PoolConfig,
+    // Used as a stack, so that the most recently used connections are
+    // reused and the others become idle long enough to be health-checked.
+    idle: Vec<Idle<M::Connection>>,
+    waiters: VecDeque<Waiter<W>>,
+    size: usize,
+    metrics: PoolMetrics,
+}
+
+struct Idle<C> {
+    connection: C,
+    since: Instant,
+}
+
+struct Waiter<W> {
+    waiter: W,
+    deadline: Instant,
+}
+
+/// A checkout which is ready to be answered.
+type Ready<M, W> = (W, Result<<M as Manager>::Connection, Error>);
+
+/// The checked out connections, by loan id, with the processes holding
+/// them.
+struct Loans<B> {
+    next: u64,
+    loans: Vec<(u64, B)>,
+}
+
+impl Default for PoolConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            checkout_timeout: Duration::from_secs(5),
+            health_check_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl<B> Loans<B> {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            next: 0,
+            loans: vec![],
+        }
+    }
+
+    /// Records a connection lent to `borrower`, returning the loan id.
+    #[inline]
+    fn lend(&mut self, borrower: B) -> u64 {
+        self.next += 1;
+        self.loans.push((self.next, borrower));
+        self.next
+    }
+
+    /// Ends the loan with the given id, returning its borrower. Returns
+    /// `None` if there is no such loan.
+    #[inline]
+    fn end(&mut self, loan: u64) -> Option<B> {
+        let index = self.loans.iter().position(|(id, _)| *id == loan)?;
+        Some(self.loans.swap_remove(index).1)
+    }
+
+    /// Ends the first loan whose borrower matches `predicate`.
+    #[inline]
+    fn end_where(&mut self, predicate: impl Fn(&B) -> bool) -> Option<B> {
+        let index = self.loans.iter().position(|(_, borrower)| predicate(borrower))?;
+        Some(self.loans.swap_remove(index).1)
+    }
+
+    #[inline]
+    fn find(&self, predicate: impl Fn(&B) -> bool) -> Option<&B> {
+        self.loans
+            .iter()
+            .map(|(_, borrower)| borrower)
+            .find(|borrower| predicate(borrower))
+    }
+}
+
+impl<M: Manager, W> PoolState<M, W> {
+    #[inline]
+    fn new(manager: M, config: PoolConfig) -> Self {
+        Self {
+            manager,
+            config,
+            idle: vec![],
+            waiters: VecDeque::new(),
+            size: 0,
+            metrics: PoolMetrics::default(),
+        }
+    }
+
+    /// Checks out a connection for `waiter`, opening a new one if there is
+    /// room. Returns `None` if the waiter has to wait for a checkin.
+    #[inline]
+    fn checkout(&mut self, waiter: W, now: Instant) -> Option<Ready<M, W>> {
+        if let Some(idle) = self.idle.pop() {
+            self.metrics.checkouts += 1;
+            return Some((waiter, Ok(idle.connection)));
+        }
+        if self.size < self.config.max_size {
+            return Some((waiter, self.connect()));
+        }
+        self.waiters.push_back(Waiter {
+            waiter,
+            deadline: now + self.config.checkout_timeout,
+        });
+        None
+    }
+
+    /// Returns a connection to the pool, handing it straight to the first
+    /// waiter, if any.
+    #[inline]
+    fn checkin(&mut self, connection: M::Connection, now: Instant) -> Option<Ready<M, W>> {
+        match self.waiters.pop_front() {
+            Some(waiter) => {
+                self.metrics.checkouts += 1;
+                Some((waiter.waiter, Ok(connection)))
+            }
+            None => {
+                self.idle.push(Idle {
+                    connection,
+                    since: now,
+                });
+                None
+            }
+        }
+    }
+
+    /// Forgets a checked out connection which was closed, opening a new one
+    /// for the first waiter, if any.
+    #[inline]
+    fn discard(&mut self) -> Option<Ready<M, W>> {
+        self.size -= 1;
+        self.metrics.closed += 1;
+        let waiter = self.waiters.pop_front()?;
+        Some((waiter.waiter, self.connect()))
+    }
+
+    /// Removes and returns the waiters whose checkout timed out.
+    #[inline]
+    fn expire(&mut self, now: Instant) -> Vec<W> {
+        // All waiters have the same timeout, so they're ordered by deadline.
+        let expired = self
+            .waiters
+            .iter()
+            .take_while(|waiter| waiter.deadline <= now)
+            .count();
+        self.metrics.timeouts += expired as u64;
+        self.waiters
+            .drain(..expired)
+            .map(|waiter| waiter.waiter)
+            .collect()
+    }
+
+    /// Checks the connections which have been idle for longer than the
+    /// health check interval, closing the broken ones.
+    #[inline]
+    fn health_check(&mut self, now: Instant) {
+        let interval = self.config.health_check_interval;
+        let manager = &mut self.manager;
+        let before = self.idle.len();
+        self.idle.retain_mut(|idle| {
+            if now.duration_since(idle.since) < interval {
+                return true;
+            }
+            idle.since = now;
+            manager.is_valid(&mut idle.connection)
+        });
+        let closed = before - self.idle.len();
+        if closed > 0 {
+            tracing::warn!("closed {} broken idle connection(s)", closed);
+            self.size -= closed;
+            self.metrics.closed += closed as u64;
+        }
+    }
+
+    /// Returns the next time at which [`Self::expire`] or
+    /// [`Self::health_check`] has work to do.
+    #[inline]
+    fn next_deadline(&self) -> Option<Instant> {
+        let next_health_check = self
+            .idle
+            .iter()
+            .map(|idle| idle.since + self.config.health_check_interval)
+            .min();
+        let next_timeout = self.waiters.front().map(|waiter| waiter.deadline);
+        next_health_check.into_iter().chain(next_timeout).min()
+    }
+
+    #[inline]
+    fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            size: self.size,
+            idle: self.idle.len(),
+            waiting: self.waiters.len(),
+            ..self.metrics
+        }
+    }
+
+    #[inline]
+    fn connect(&mut self) -> Result<M::Connection, Error> {
+        match self.manager.connect() {
+            Ok(connection) => {
+                self.size += 1;
+                self.metrics.connects += 1;
+                self.metrics.checkouts += 1;
+                Ok(connection)
+            }
+            Err(e) => {
+                tracing::error!("pool failed to connect: {}", e);
+                self.metrics.connect_errors += 1;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+struct ClientManager {
+    config: Config,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Manager for ClientManager {
+    type Connection = Client;
+
+    #[inline]
+    fn connect(&mut self) -> Result<Client, Error> {
+        Client::connect(&self.config)
+    }
+
+    #[inline]
+    fn is_valid(&mut self, client: &mut Client) -> bool {
+        match client.batch_execute("SELECT 1") {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::debug!("pool health check failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Pool {
+    /// Spawns a pool process and checks that it can connect to the server.
+    #[inline]
+    pub(crate) fn spawn(config: Config, pool_config: PoolConfig) -> Result<Self, Error> {
+        tracing::info!("spawning pool process (max size {})", pool_config.max_size);
+        let process = crate::spawn_with!((config, pool_config), run).map_err(Error::pool)?;
+        let pool = Self { process };
+        // Report configuration errors at startup rather than on the first
+        // request. This also leaves a connection in the pool.
+        drop(pool.get()?);
+        Ok(pool)
+    }
+
+    /// Checks out a connection, waiting for one to be returned if all of
+    /// them are in use.
+    #[inline]
+    pub(crate) fn get(&self) -> Result<PooledClient, Error> {
+        match self.call(PoolRequest::Checkout)? {
+            PoolResponse::Client(Ok((loan, client))) => Ok(PooledClient {
+                pool: self.clone(),
+                loan,
+                client: Some(Client::from_sendable(client)),
+            }),
+            PoolResponse::Client(Err(message)) => Err(Error::pool(message)),
+            _ => Err(Error::pool("unexpected response from pool process")),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn metrics(&self) -> Result<PoolMetrics, Error> {
+        match self.call(PoolRequest::Metrics)? {
+            PoolResponse::Metrics(metrics) => Ok(metrics),
+            _ => Err(Error::pool("unexpected response from pool process")),
+        }
+    }
+
+    #[inline]
+    fn call(&self, request: PoolRequest) -> Result<PoolResponse, Error> {
+        self.process.request(request).map_err(Error::pool)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Borrow<Client> for PooledClient {
+    #[inline]
+    fn borrow(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl BorrowMut<Client> for PooledClient {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for PooledClient {
+    #[inline]
+    fn drop(&mut self) {
+        let mut client = self.client.take().unwrap();
+        // Don't leave an open transaction behind for the next user.
+        if client.is_in_transaction() && !client.is_closed() {
+            if let Err(e) = client.batch_execute("ROLLBACK") {
+                tracing::warn!("pool rollback failed: {}", e);
+            }
+        }
+        let request = if client.is_closed() {
+            PoolRequest::Discard(self.loan)
+        } else {
+            match client.into_sendable() {
+                Some(client) => PoolRequest::Checkin(self.loan, client),
+                None => PoolRequest::Discard(self.loan),
+            }
+        };
+        if let Err(e) = self.pool.call(request) {
+            tracing::error!("failed to return connection to pool: {}", e);
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Client {
+    /// Converts the client into a form which can be sent to another process.
+    /// Returns `None` if it has buffered data, which would be lost.
+    #[inline]
+    fn into_sendable(self) -> Option<SendableClient> {
+        if !self.reader.buffer().is_empty() || self.is_in_transaction() {
+            return None;
+        }
+        match self.reader.into_inner() {
+            crate::net::TcpStream::Lunatic(tcp_stream) => Some(SendableClient {
+                tcp_stream,
+                parameters: self.parameters,
+            }),
+            crate::net::TcpStream::Std(_) => None,
+        }
+    }
+
+    #[inline]
+    fn from_sendable(client: SendableClient) -> Self {
+        Self {
+            reader: std::io::BufReader::new(client.tcp_stream.into()),
+            write_buf: Vec::with_capacity(1024),
+            read_buf: Vec::with_capacity(1024),
+            parameters: client.parameters,
+            transaction_status: b'I',
+            closed: false,
+        }
+    }
+}
+
+/// Ends a loan, unlinking from the borrower unless it holds another
+/// connection. Returns `false` if there was no such loan.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn end_loan(loans: &mut Loans<Borrower>, loan: u64) -> bool {
+    match loans.end(loan) {
+        Some((_, process)) => {
+            if loans.find(|(_, borrower)| borrower.id() == process.id()).is_none() {
+                process.unlink();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Entry point of the pool process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn run(
+    (config, pool_config): (Config, PoolConfig),
+    mailbox: Mailbox<Checkout>,
+) {
+    // Borrowers which die are reported as signals, instead of killing the
+    // pool.
+    let mailbox = mailbox.catch_link_panic();
+    let mut state: PoolState<_, Checkout> = PoolState::new(ClientManager { config }, pool_config);
+    let mut loans: Loans<Borrower> = Loans::new();
+    loop {
+        let now = Instant::now();
+        let expired = state.expire(now);
+        if !expired.is_empty() {
+            tracing::warn!("pool checkout timed out: {:?}", state.metrics());
+        }
+        for waiter in expired {
+            waiter.reply(PoolResponse::Client(Err(
+                "timed out waiting for a connection".to_string(),
+            )));
+        }
+        state.health_check(now);
+
+        let request = match state.next_deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(now).as_millis();
+                // Round up, so that we don't wake up just before the deadline.
+                let timeout = u32::try_from(timeout + 1).unwrap_or(u32::MAX);
+                mailbox.receive_timeout(timeout)
+            }
+            None => mailbox.receive(),
+        };
+        let request = match request {
+            Message::Normal(Ok(request)) => request,
+            Message::Normal(Err(ReceiveError::Timeout)) => continue,
+            Message::Normal(Err(e)) => {
+                tracing::error!("receive error: {}", e);
+                continue;
+            }
+            Message::Signal(tag) => {
+                // The sockets of the borrower's connections died with it.
+                while loans.end_where(|(linked, _)| *linked == tag).is_some() {
+                    tracing::warn!("pool connection lost with the process holding it");
+                    if let Some(ready) = state.discard() {
+                        hand_out(&mut state, &mut loans, ready, Instant::now());
+                    }
+                }
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+        let ready = match request.data() {
+            PoolRequest::Checkout => state.checkout(request, now),
+            PoolRequest::Checkin(loan, client) => {
+                let client = Client::from_sendable(client.clone());
+                let lent = end_loan(&mut loans, *loan);
+                request.reply(PoolResponse::Ok);
+                if lent {
+                    state.checkin(client, now)
+                } else {
+                    // The connection was already written off, so it no
+                    // longer counts towards the size of the pool.
+                    tracing::warn!("closing connection returned on an unknown loan");
+                    None
+                }
+            }
+            PoolRequest::Discard(loan) => {
+                let lent = end_loan(&mut loans, *loan);
+                request.reply(PoolResponse::Ok);
+                if lent {
+                    state.discard()
+                } else {
+                    None
+                }
+            }
+            PoolRequest::Metrics => {
+                request.reply(PoolResponse::Metrics(state.metrics()));
+                None
+            }
+        };
+        if let Some(ready) = ready {
+            hand_out(&mut state, &mut loans, ready, now);
+        }
+    }
+}
+
+/// Answers a checkout, lending the connection to the waiting process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn hand_out(
+    state: &mut PoolState<ClientManager, Checkout>,
+    loans: &mut Loans<Borrower>,
+    ready: Ready<ClientManager, Checkout>,
+    now: Instant,
+) {
+    let mut next = Some(ready);
+    while let Some((waiter, result)) = next.take() {
+        match result.map(Client::into_sendable) {
+            Ok(Some(client)) => {
+                // Link before replying, so that a waiter which died while
+                // waiting is reported like any other borrower. A process
+                // holding several connections is linked to once.
+                let process = waiter.sender().clone();
+                let tag = match loans.find(|(_, borrower)| borrower.id() == process.id()) {
+                    Some((tag, _)) => *tag,
+                    None => process.link(),
+                };
+                let loan = loans.lend((tag, process));
+                waiter.reply(PoolResponse::Client(Ok((loan, client))));
+            }
+            Ok(None) => {
+                // The connection can't be moved, so close it and try again.
+                if let Some(ready) = state.discard() {
+                    hand_out(state, loans, ready, now);
+                }
+                next = state.checkout(waiter, now);
+            }
+            Err(e) => waiter.reply(PoolResponse::Client(Err(e.to_string()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Loans, Manager, PoolConfig, PoolState};
+    use crate::postgres::Error;
+    use std::time::{Duration, Instant};
+
+    /// Hands out numbered connections. Odd connections fail health checks.
+    struct TestManager {
+        next: u32,
+    }
+
+    impl Manager for TestManager {
+        type Connection = u32;
+
+        fn connect(&mut self) -> Result<u32, Error> {
+            self.next += 1;
+            Ok(self.next)
+        }
+
+        fn is_valid(&mut self, connection: &mut u32) -> bool {
+            *connection % 2 == 0
+        }
+    }
+
+    fn pool() -> PoolState<TestManager, &'static str> {
+        let config = PoolConfig {
+            max_size: 2,
+            checkout_timeout: Duration::from_secs(1),
+            health_check_interval: Duration::from_secs(10),
+        };
+        PoolState::new(TestManager { next: 0 }, config)
+    }
+
+    #[test]
+    fn test_checkout() {
+        let mut pool = pool();
+        let now = Instant::now();
+        let (waiter, first) = pool.checkout("a", now).unwrap();
+        assert_eq!((waiter, first.unwrap()), ("a", 1));
+        let (_, second) = pool.checkout("b", now).unwrap();
+        assert_eq!(second.unwrap(), 2);
+        // The pool is full, so the next checkouts wait.
+        assert!(pool.checkout("c", now).is_none());
+        assert!(pool.checkout("d", now).is_none());
+        assert_eq!(pool.metrics().waiting, 2);
+        // Checked in connections go to the waiters in order.
+        let (waiter, connection) = pool.checkin(2, now).unwrap();
+        assert_eq!((waiter, connection.unwrap()), ("c", 2));
+        // A discarded connection is replaced for the next waiter.
+        let (waiter, connection) = pool.discard().unwrap();
+        assert_eq!((waiter, connection.unwrap()), ("d", 3));
+        assert!(pool.checkin(3, now).is_none());
+        let (_, connection) = pool.checkout("e", now).unwrap();
+        assert_eq!(connection.unwrap(), 3);
+        let metrics = pool.metrics();
+        assert_eq!(metrics.size, 2);
+        assert_eq!(metrics.checkouts, 5);
+        assert_eq!(metrics.connects, 3);
+        assert_eq!(metrics.closed, 1);
+    }
+
+    #[test]
+    fn test_checkout_timeout() {
+        let mut pool = pool();
+        let now = Instant::now();
+        assert!(pool.checkout("a", now).unwrap().1.is_ok());
+        assert!(pool.checkout("b", now).unwrap().1.is_ok());
+        assert!(pool.checkout("c", now).is_none());
+        let later = now + Duration::from_millis(500);
+        assert!(pool.checkout("d", later).is_none());
+        assert_eq!(pool.next_deadline(), Some(now + Duration::from_secs(1)));
+        assert_eq!(pool.expire(later), Vec::<&str>::new());
+        assert_eq!(pool.expire(now + Duration::from_secs(1)), vec!["c"]);
+        assert_eq!(pool.expire(later + Duration::from_secs(1)), vec!["d"]);
+        assert_eq!(pool.metrics().timeouts, 2);
+        assert_eq!(pool.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_health_check() {
+        let mut pool = pool();
+        let now = Instant::now();
+        let (_, first) = pool.checkout("a", now).unwrap();
+        let (_, second) = pool.checkout("b", now).unwrap();
+        assert!(pool.checkin(first.unwrap(), now).is_none());
+        assert!(pool.checkin(second.unwrap(), now).is_none());
+        assert_eq!(pool.next_deadline(), Some(now + Duration::from_secs(10)));
+        // Connections aren't checked before the interval elapsed.
+        pool.health_check(now + Duration::from_secs(5));
+        assert_eq!(pool.metrics().idle, 2);
+        // Connection 1 is broken and gets closed, making room for a new one.
+        pool.health_check(now + Duration::from_secs(10));
+        let metrics = pool.metrics();
+        assert_eq!((metrics.size, metrics.idle, metrics.closed), (1, 1, 1));
+        let (_, connection) = pool.checkout("c", now).unwrap();
+        assert_eq!(connection.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_lost_connection() {
+        let mut pool = pool();
+        let mut loans = Loans::new();
+        let now = Instant::now();
+        let (waiter, first) = pool.checkout("a", now).unwrap();
+        let first_loan = loans.lend(waiter);
+        let (waiter, _) = pool.checkout("b", now).unwrap();
+        let second_loan = loans.lend(waiter);
+        assert!(pool.checkout("c", now).is_none());
+        // "b" dies with its connection, which is replaced for "c".
+        assert_eq!(loans.end_where(|borrower| *borrower == "b"), Some("b"));
+        let (waiter, connection) = pool.discard().unwrap();
+        assert_eq!((waiter, connection.unwrap()), ("c", 3));
+        loans.lend(waiter);
+        // The loan of "b" is gone, so the connection can't be returned.
+        assert_eq!(loans.end(second_loan), None);
+        assert_eq!(loans.end(first_loan), Some("a"));
+        assert!(pool.checkin(first.unwrap(), now).is_none());
+        assert_eq!(loans.find(|borrower| *borrower == "c"), Some(&"c"));
+        let metrics = pool.metrics();
+        assert_eq!((metrics.size, metrics.idle, metrics.closed), (2, 1, 1));
+    }
+}
@@ -0,0 +1,192 @@
+//! Conversions between Rust values and the PostgreSQL text format.
+use crate::postgres::Error;
+use std::str;
+
+pub(crate) type Oid = u32;
+
+pub(crate) const BOOL: Oid = 16;
+pub(crate) const BYTEA: Oid = 17;
+pub(crate) const INT8: Oid = 20;
+pub(crate) const INT2: Oid = 21;
+pub(crate) const INT4: Oid = 23;
+pub(crate) const TEXT: Oid = 25;
+pub(crate) const FLOAT4: Oid = 700;
+pub(crate) const FLOAT8: Oid = 701;
+pub(crate) const BPCHAR: Oid = 1042;
+pub(crate) const VARCHAR: Oid = 1043;
+pub(crate) const NAME: Oid = 19;
+
+/// A type which can be sent to the server as a query parameter.
+pub(crate) trait ToSql {
+    /// Returns the text representation of the value, or `None` for `NULL`.
+    fn to_sql(&self) -> Option<Vec<u8>>;
+}
+
+/// A type which can be decoded from a column of a result row.
+pub(crate) trait FromSql: Sized {
+    fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error>;
+}
+
+impl ToSql for bool {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        Some(if *self { b"t".to_vec() } else { b"f".to_vec() })
+    }
+}
+
+impl ToSql for str {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        Some(self.as_bytes().to_vec())
+    }
+}
+
+impl ToSql for String {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        self.as_str().to_sql()
+    }
+}
+
+impl ToSql for [u8] {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        // Use the `bytea` hex format.
+        let mut buf = Vec::with_capacity(2 + self.len() * 2);
+        buf.extend_from_slice(b"\\x");
+        for byte in self {
+            buf.extend_from_slice(format!("{:02x}", byte).as_bytes());
+        }
+        Some(buf)
+    }
+}
+
+impl ToSql for Vec<u8> {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        self.as_slice().to_sql()
+    }
+}
+
+impl<T: ToSql + ?Sized> ToSql for &T {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        (**self).to_sql()
+    }
+}
+
+impl<T: ToSql> ToSql for Option<T> {
+    #[inline]
+    fn to_sql(&self) -> Option<Vec<u8>> {
+        self.as_ref().and_then(ToSql::to_sql)
+    }
+}
+
+macro_rules! to_sql_display {
+    ( $( $ty:ty ),* ) => {
+        $(
+            impl ToSql for $ty {
+                #[inline]
+                fn to_sql(&self) -> Option<Vec<u8>> {
+                    Some(self.to_string().into_bytes())
+                }
+            }
+        )*
+    };
+}
+
+to_sql_display!(i16, i32, i64, f32, f64);
+
+impl<T: FromSql> FromSql for Option<T> {
+    #[inline]
+    fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error> {
+        match raw {
+            Some(raw) => T::from_sql(type_oid, Some(raw)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FromSql for bool {
+    #[inline]
+    fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error> {
+        match (type_oid, non_null(raw)?) {
+            (BOOL, b"t") => Ok(true),
+            (BOOL, b"f") => Ok(false),
+            (BOOL, _) => Err(Error::conversion("invalid boolean")),
+            _ => Err(Error::conversion("expected a boolean column")),
+        }
+    }
+}
+
+impl FromSql for String {
+    #[inline]
+    fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error> {
+        match type_oid {
+            TEXT | VARCHAR | BPCHAR | NAME => Ok(text(non_null(raw)?)?.to_string()),
+            _ => Err(Error::conversion("expected a text column")),
+        }
+    }
+}
+
+impl FromSql for Vec<u8> {
+    #[inline]
+    fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error> {
+        if type_oid != BYTEA {
+            return Err(Error::conversion("expected a bytea column"));
+        }
+        let hex = non_null(raw)?
+            .strip_prefix(b"\\x")
+            .ok_or_else(|| Error::conversion("unsupported bytea format"))?;
+        if hex.len() % 2 != 0 {
+            return Err(Error::conversion("invalid bytea"));
+        }
+        hex.chunks(2)
+            .map(|pair| {
+                text(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| Error::conversion("invalid bytea"))
+            })
+            .collect()
+    }
+}
+
+macro_rules! from_sql_parse {
+    ( $( $ty:ty => [$( $oid:expr ),*] ),* ) => {
+        $(
+            impl FromSql for $ty {
+                #[inline]
+                fn from_sql(type_oid: Oid, raw: Option<&[u8]>) -> Result<Self, Error> {
+                    if ![$( $oid ),*].contains(&type_oid) {
+                        return Err(Error::conversion(concat!(
+                            "column is not convertible to ",
+                            stringify!($ty)
+                        )));
+                    }
+                    text(non_null(raw)?)?
+                        .parse()
+                        .map_err(|_e| Error::conversion(concat!("invalid ", stringify!($ty))))
+                }
+            }
+        )*
+    };
+}
+
+from_sql_parse!(
+    i16 => [INT2],
+    i32 => [INT2, INT4],
+    i64 => [INT2, INT4, INT8],
+    f32 => [FLOAT4],
+    f64 => [FLOAT4, FLOAT8]
+);
+
+#[inline]
+fn non_null(raw: Option<&[u8]>) -> Result<&[u8], Error> {
+    raw.ok_or_else(|| Error::conversion("unexpected NULL"))
+}
+
+#[inline]
+fn text(raw: &[u8]) -> Result<&str, Error> {
+    str::from_utf8(raw).map_err(|_e| Error::conversion("invalid utf-8"))
+}
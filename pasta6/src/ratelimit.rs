@@ -0,0 +1,511 @@
+//! Rate limiting with token buckets.
+//!
+//! Every key, e.g. `unlock:<paste ID>`, has a bucket holding up to
+//! [`Quota::capacity`] tokens, refilled at a steady rate. Each attempt takes a
+//! token, and attempts are refused while the bucket is empty. Connection
+//! processes don't share memory, so on lunatic the buckets live in a process
+//! of their own.
+//!
+//! Besides the quotas of specific actions, such as unlocking a paste,
+//! [`RouteQuotas`] limits how often each client may request some routes,
+//! e.g. creating pastes.
+//!
+//! Only so many buckets are kept, and the least recently used ones are
+//! forgotten first. Clients can make up as many keys as they have addresses,
+//! so the buckets of credentials, e.g. `login:<name>`, are kept apart, where
+//! flooding the limiter with clients can't make them forgotten.
+#[cfg(target_arch = "wasm32")]
+use lunatic::{process::Process, Mailbox, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of buckets kept for clients, and for credentials, before the least
+/// recently used ones are forgotten.
+const MAX_BUCKETS: usize = 100_000;
+/// Prefixes of the keys of attempts at guessing a password.
+const CREDENTIAL_PREFIXES: &[&str] = &["login:", "unlock:"];
+/// Quotas of routes unless overridden, as `METHOD PATH=REQUESTS/PERIOD`.
+const DEFAULT_ROUTE_QUOTAS: &str = "POST /p=10/1m, POST /p/*=30/1m, POST /=30/1m, \
+    POST /register=5/1h, * /api/*=120/1m";
+
+/// How many attempts are allowed for a key.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Quota {
+    /// Attempts allowed in a burst.
+    pub(crate) capacity: u32,
+    /// Time it takes to regain one attempt.
+    pub(crate) refill: Duration,
+}
+
+/// The state of a bucket after an attempt, as told by the `ratelimit-*`
+/// headers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Status {
+    /// Attempts left in the bucket.
+    pub(crate) remaining: u32,
+    /// Time until the bucket is full again.
+    pub(crate) reset: Duration,
+    /// Time until the next attempt is allowed, if this one was refused.
+    pub(crate) retry_after: Option<Duration>,
+}
+
+/// Quotas for the requests of each client to some routes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RouteQuotas {
+    routes: Vec<RouteQuota>,
+}
+
+/// The quota of a route.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RouteQuota {
+    /// The method of the route, or `*` for any.
+    pub(crate) method: String,
+    /// The path of the route, or a prefix followed by `*`.
+    pub(crate) path: String,
+    pub(crate) quota: Quota,
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimitError {
+    kind: RateLimitErrorKind,
+}
+
+#[derive(Debug)]
+enum RateLimitErrorKind {
+    /// A route quota which couldn't be parsed.
+    InvalidRouteQuota(String),
+}
+
+/// A handle to the rate limiter, which can be sent to other processes.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum RateLimiter {
+    /// Allows every attempt.
+    Disabled,
+    /// Forwards all calls to a rate limiter process.
+    #[cfg(target_arch = "wasm32")]
+    Process(Process<Request<(String, Quota), Status>>),
+    /// Shares buckets between the threads of the test server.
+    #[cfg(all(test, not(target_arch = "wasm32")))]
+    #[serde(skip)]
+    Local(std::sync::Arc<std::sync::Mutex<Buckets>>),
+}
+
+impl RateLimiter {
+    /// Spawns a rate limiter process.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub(crate) fn spawn() -> Result<Self, lunatic::LunaticError> {
+        tracing::info!("spawning rate limiter process");
+        Ok(RateLimiter::Process(crate::spawn_with!(MAX_BUCKETS, run)?))
+    }
+
+    /// Takes a token from the bucket of `key`, or returns how long until one
+    /// is available. If the rate limiter can't be reached, the attempt is
+    /// allowed.
+    #[inline]
+    pub(crate) fn acquire(&self, key: &str, quota: Quota) -> Result<(), Duration> {
+        match self.check(key, quota).retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a token from the bucket of `key` if there is one, returning the
+    /// state of the bucket.
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+    #[inline]
+    pub(crate) fn check(&self, key: &str, quota: Quota) -> Status {
+        match self {
+            RateLimiter::Disabled => Status::unlimited(quota),
+            #[cfg(target_arch = "wasm32")]
+            RateLimiter::Process(process) => {
+                match process.request((key.to_string(), quota)) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        tracing::warn!("rate limiter error: {}", e);
+                        Status::unlimited(quota)
+                    }
+                }
+            }
+            #[cfg(all(test, not(target_arch = "wasm32")))]
+            RateLimiter::Local(buckets) => buckets.lock().unwrap().check(key, quota, now_ms()),
+        }
+    }
+}
+
+impl Status {
+    /// Returns the state of a full bucket, which is also given when the
+    /// attempt isn't rate limited after all.
+    #[inline]
+    fn unlimited(quota: Quota) -> Self {
+        Self {
+            remaining: quota.capacity,
+            reset: Duration::ZERO,
+            retry_after: None,
+        }
+    }
+}
+
+impl RouteQuotas {
+    /// Returns no quotas at all.
+    #[inline]
+    pub(crate) fn none() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Parses a comma-separated list of `METHOD PATH=REQUESTS/PERIOD`, e.g.
+    /// `POST /p=10/1m, * /api/*=120/1m`. An empty list turns them off.
+    #[inline]
+    pub(crate) fn parse(quotas: &str) -> Result<Self, RateLimitError> {
+        let routes = quotas
+            .split(',')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(|route| parse_route_quota(route).ok_or_else(|| RateLimitError::invalid(route)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { routes })
+    }
+
+    /// Returns the quota of the first route matching a request, if any.
+    #[inline]
+    pub(crate) fn find(&self, method: &str, path: &str) -> Option<&RouteQuota> {
+        self.routes.iter().find(|route| route.matches(method, path))
+    }
+}
+
+impl Default for RouteQuotas {
+    #[inline]
+    fn default() -> Self {
+        Self::parse(DEFAULT_ROUTE_QUOTAS).unwrap()
+    }
+}
+
+impl RouteQuota {
+    #[inline]
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let method_matches = self.method == "*" || self.method.eq_ignore_ascii_case(method);
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+        method_matches && path_matches
+    }
+
+    /// Returns the rate limiter key of the bucket of `client` for this
+    /// route.
+    #[inline]
+    pub(crate) fn key(&self, client: &str) -> String {
+        format!("route:{} {}:{}", self.method, self.path, client)
+    }
+}
+
+/// Returns the key of the client at `addr`, e.g. `ip:192.0.2.1`. IPv6
+/// clients are keyed by their /64 network, since a single host usually gets
+/// all of one.
+#[inline]
+pub(crate) fn client_key(addr: IpAddr) -> String {
+    match addr.to_canonical() {
+        IpAddr::V4(addr) => format!("ip:{}", addr),
+        IpAddr::V6(addr) => {
+            let network = Ipv6Addr::from(u128::from(addr) & !u128::from(u64::MAX));
+            format!("ip:{}/64", network)
+        }
+    }
+}
+
+/// Parses `METHOD PATH=REQUESTS/PERIOD`, where the period is a number of
+/// seconds, minutes or hours, e.g. `10s`, `1m` or `1h`.
+#[inline]
+fn parse_route_quota(route: &str) -> Option<RouteQuota> {
+    let (route, quota) = route.split_once('=')?;
+    let (method, path) = route.trim().split_once(' ')?;
+    let path = path.trim();
+    if !path.starts_with('/') {
+        return None;
+    }
+    let (capacity, period) = quota.trim().split_once('/')?;
+    let capacity: u32 = capacity.parse().ok().filter(|capacity| *capacity > 0)?;
+    let (count, unit) = period.split_at(period.len().checked_sub(1)?);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    let period = Duration::from_secs(count.parse::<u64>().ok()?.checked_mul(seconds)?);
+    Some(RouteQuota {
+        method: method.to_ascii_uppercase(),
+        path: path.to_string(),
+        quota: Quota {
+            capacity,
+            // The bucket refills over the whole period.
+            refill: period / capacity,
+        },
+    })
+}
+
+impl RateLimitError {
+    #[inline]
+    fn invalid(route: &str) -> Self {
+        Self {
+            kind: RateLimitErrorKind::InvalidRouteQuota(route.to_string()),
+        }
+    }
+}
+
+impl Display for RateLimitError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RateLimitErrorKind::InvalidRouteQuota(route) => write!(
+                f,
+                "invalid rate limit {:?}, expected e.g. `POST /p=10/1m`",
+                route
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// Entry point of the rate limiter process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn run(max_buckets: usize, mailbox: Mailbox<Request<(String, Quota), Status>>) {
+    let mut buckets = Buckets::new(max_buckets);
+    loop {
+        let request = match mailbox.receive() {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("receive error: {}", e);
+                continue;
+            }
+        };
+        let (key, quota) = request.data();
+        let status = buckets.check(key, *quota, now_ms());
+        request.reply(status);
+    }
+}
+
+#[inline]
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Token buckets by key.
+pub(crate) struct Buckets {
+    clients: Table,
+    credentials: Table,
+}
+
+/// A least-recently-used map of buckets.
+struct Table {
+    buckets: HashMap<String, (Bucket, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    max_buckets: usize,
+}
+
+struct Bucket {
+    tokens: u32,
+    /// Milliseconds since the Unix epoch at which `tokens` was last refilled.
+    refilled_at: u64,
+    /// Milliseconds since the Unix epoch at which the bucket will be full
+    /// again, after which it can be forgotten.
+    full_at: u64,
+}
+
+impl Buckets {
+    #[inline]
+    pub(crate) fn new(max_buckets: usize) -> Self {
+        Self {
+            clients: Table::new(max_buckets),
+            credentials: Table::new(max_buckets),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn acquire(&mut self, key: &str, quota: Quota, now: u64) -> Result<(), Duration> {
+        match self.check(key, quota, now).retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn check(&mut self, key: &str, quota: Quota, now: u64) -> Status {
+        let table = if CREDENTIAL_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+            &mut self.credentials
+        } else {
+            &mut self.clients
+        };
+        let refill = (quota.refill.as_millis() as u64).max(1);
+        let bucket = table.get_or_insert(key, || Bucket {
+            tokens: quota.capacity,
+            refilled_at: now,
+            full_at: now,
+        });
+        let refilled = now.saturating_sub(bucket.refilled_at) / refill;
+        if refilled > 0 {
+            bucket.tokens = (bucket.tokens as u64 + refilled).min(quota.capacity as u64) as u32;
+            bucket.refilled_at += refilled * refill;
+        }
+        if bucket.tokens == quota.capacity {
+            bucket.refilled_at = now;
+        }
+        let retry_after = if bucket.tokens == 0 {
+            let retry_after = (bucket.refilled_at + refill).saturating_sub(now);
+            Some(Duration::from_millis(retry_after))
+        } else {
+            bucket.tokens -= 1;
+            bucket.full_at = bucket.refilled_at + (quota.capacity - bucket.tokens) as u64 * refill;
+            None
+        };
+        Status {
+            remaining: bucket.tokens,
+            reset: Duration::from_millis(bucket.full_at.saturating_sub(now)),
+            retry_after,
+        }
+    }
+}
+
+impl Table {
+    #[inline]
+    fn new(max_buckets: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            max_buckets,
+        }
+    }
+
+    /// Returns the bucket of `key`, inserting the one made by `f` if there
+    /// is none, after forgetting the least recently used one if the table is
+    /// full.
+    #[inline]
+    fn get_or_insert(&mut self, key: &str, f: impl FnOnce() -> Bucket) -> &mut Bucket {
+        self.tick += 1;
+        if let Some((_, used)) = self.buckets.get(key) {
+            let key = self.order.remove(used).unwrap();
+            self.order.insert(self.tick, key);
+        } else {
+            if self.buckets.len() >= self.max_buckets {
+                if let Some((_, oldest)) = self.order.pop_first() {
+                    tracing::debug!("too many rate limited keys, forgetting {}", oldest);
+                    self.buckets.remove(&oldest);
+                }
+            }
+            self.order.insert(self.tick, key.to_string());
+            self.buckets.insert(key.to_string(), (f(), self.tick));
+        }
+        let (bucket, used) = self.buckets.get_mut(key).unwrap();
+        *used = self.tick;
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{client_key, Buckets, Quota, RouteQuotas, Status};
+    use std::time::Duration;
+
+    #[test]
+    fn test_buckets() {
+        let quota = Quota {
+            capacity: 2,
+            refill: Duration::from_secs(10),
+        };
+        let mut buckets = Buckets::new(2);
+        assert_eq!(buckets.acquire("a", quota, 0), Ok(()));
+        assert_eq!(buckets.acquire("a", quota, 1_000), Ok(()));
+        assert_eq!(
+            buckets.acquire("a", quota, 4_000),
+            Err(Duration::from_secs(6))
+        );
+        // Keys have their own buckets.
+        assert_eq!(buckets.acquire("b", quota, 4_000), Ok(()));
+        // One token is back after 10 seconds, and no more.
+        assert_eq!(buckets.acquire("a", quota, 10_000), Ok(()));
+        assert_eq!(
+            buckets.acquire("a", quota, 10_000),
+            Err(Duration::from_secs(10))
+        );
+        // Unused tokens don't accumulate past the capacity.
+        assert_eq!(buckets.acquire("a", quota, 100_000), Ok(()));
+        assert_eq!(buckets.acquire("a", quota, 100_000), Ok(()));
+        assert!(buckets.acquire("a", quota, 100_000).is_err());
+
+        // The least recently used bucket is forgotten to make room for new
+        // keys, so `b` starts over, but `a` is still empty.
+        assert_eq!(buckets.acquire("c", quota, 100_000), Ok(()));
+        assert_eq!(buckets.clients.buckets.len(), 2);
+        assert!(buckets.acquire("a", quota, 100_000).is_err());
+        assert_eq!(buckets.acquire("b", quota, 100_000), Ok(()));
+        assert_eq!(buckets.acquire("b", quota, 100_000), Ok(()));
+
+        // Clients can't make the buckets of credentials forgotten.
+        assert_eq!(buckets.acquire("unlock:a1b2c3", quota, 100_000), Ok(()));
+        assert_eq!(buckets.acquire("unlock:a1b2c3", quota, 100_000), Ok(()));
+        for client in 0..10 {
+            let key = client_key(format!("2001:db8:{}::1", client).parse().unwrap());
+            assert_eq!(buckets.acquire(&key, quota, 100_000), Ok(()));
+        }
+        assert!(buckets.acquire("unlock:a1b2c3", quota, 100_000).is_err());
+        assert_eq!(buckets.credentials.buckets.len(), 1);
+        assert_eq!(buckets.clients.order.len(), 2);
+    }
+
+    #[test]
+    fn test_client_key() {
+        let key = |addr: &str| client_key(addr.parse().unwrap());
+        assert_eq!(key("192.0.2.1"), "ip:192.0.2.1");
+        assert_eq!(key("::ffff:192.0.2.1"), "ip:192.0.2.1");
+        // Addresses of the same /64 network share a bucket.
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), "ip:2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2::ffff"), "ip:2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:3::1"), "ip:2001:db8:1:3::/64");
+    }
+
+    #[test]
+    fn test_route_quotas() {
+        let quotas = RouteQuotas::parse("post /p=10/1m, * /api/*=120/1h,").unwrap();
+        let route = quotas.find("POST", "/p").unwrap();
+        assert_eq!(route.quota.capacity, 10);
+        assert_eq!(route.quota.refill, Duration::from_secs(6));
+        assert_eq!(route.key("ip:127.0.0.1"), "route:POST /p:ip:127.0.0.1");
+        assert!(quotas.find("GET", "/p").is_none());
+        assert!(quotas.find("POST", "/p/abc").is_none());
+        let route = quotas.find("DELETE", "/api/todos/1").unwrap();
+        assert_eq!(route.quota.refill, Duration::from_secs(30));
+        assert!(RouteQuotas::default().find("POST", "/register").is_some());
+        assert_eq!(RouteQuotas::parse(" ").unwrap(), RouteQuotas::none());
+        for invalid in ["POST /p", "/p=1/1m", "POST p=1/1m", "POST /p=0/1m", "POST /p=1/1d"] {
+            assert!(RouteQuotas::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let mut buckets = Buckets::new(2);
+        let status = |retry_after| Status {
+            remaining: 0,
+            reset: Duration::from_secs(12),
+            retry_after,
+        };
+        assert_eq!(buckets.check("a", route.quota, 0).remaining, 119);
+        let quota = Quota {
+            capacity: 2,
+            refill: Duration::from_secs(6),
+        };
+        assert_eq!(buckets.check("b", quota, 0).reset, Duration::from_secs(6));
+        assert_eq!(buckets.check("b", quota, 0), status(None));
+        assert_eq!(
+            buckets.check("b", quota, 0),
+            status(Some(Duration::from_secs(6)))
+        );
+    }
+}
@@ -0,0 +1,547 @@
+//! Server-side sessions.
+//!
+//! A session is identified by a random ID in the signed `session` cookie,
+//! which is all the client ever sees of it. Connection processes don't share
+//! memory, so on lunatic the sessions live in a process of their own, which
+//! keeps each of them for [`SESSION_TTL`] after it was last used. When
+//! persistence is enabled, sessions are also written to the storage backend,
+//! so that they survive restarts.
+//!
+//! Sessions are keyed by the SHA-256 of their ID, in memory as in the store,
+//! so that e.g. a database dump can't be used to take them over.
+//!
+//! Handlers use the [`Session`] of a request, which is saved once the
+//! response is ready. Sessions nothing was stored in don't get a cookie.
+//! [`Session::rotate`] gives a session a new ID, which must be done when a
+//! user signs in, so that an ID planted before by an attacker doesn't get
+//! signed in too.
+use crate::http::{CookieKey, Request, SameSite, SetCookie};
+use crate::store::{Store, StoredSession};
+#[cfg(target_arch = "wasm32")]
+use crate::store::StoreHandle;
+#[cfg(target_arch = "wasm32")]
+use lunatic::{process::Process, Mailbox};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Name of the cookie holding the session ID.
+const SESSION_COOKIE: &str = "session";
+/// Time a session is kept without being used.
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Default number of sessions kept in memory before the least recently used
+/// ones are forgotten.
+pub(crate) const MAX_SESSIONS: usize = 100_000;
+/// Length of session IDs in random bytes.
+const SESSION_ID_LENGTH: usize = 32;
+
+/// The data of a session.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SessionData {
+    #[serde(default)]
+    values: BTreeMap<String, String>,
+    /// Messages for the next page shown, e.g. to confirm a form was sent.
+    #[serde(default)]
+    flash: Vec<String>,
+}
+
+/// A handle to the session store, which can be sent to other processes.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Sessions {
+    /// Keeps nothing, so every request starts a new session.
+    Disabled,
+    /// Forwards all calls to a session store process.
+    #[cfg(target_arch = "wasm32")]
+    Process(Process<lunatic::Request<SessionRequest, Option<SessionData>>>),
+    /// Shares sessions between the threads of the test server.
+    #[cfg(all(test, not(target_arch = "wasm32")))]
+    #[serde(skip)]
+    Local(std::sync::Arc<std::sync::Mutex<SessionTable<crate::store::MemoryStore>>>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum SessionRequest {
+    Load(String),
+    Save(String, SessionData),
+    Remove(String),
+}
+
+/// The session of a request.
+pub(crate) struct Session {
+    /// The ID of the session, unless it's new.
+    id: Option<String>,
+    data: SessionData,
+    modified: bool,
+    /// An ID given up by [`Session::rotate`], which must be removed.
+    stale_id: Option<String>,
+}
+
+impl Sessions {
+    /// Spawns a session store process keeping up to `max_sessions` in
+    /// memory, persisting sessions to `store` if given.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub(crate) fn spawn(
+        max_sessions: usize,
+        store: Option<StoreHandle>,
+    ) -> Result<Self, lunatic::LunaticError> {
+        tracing::info!("spawning session store process");
+        Ok(Sessions::Process(crate::spawn_with!(
+            (max_sessions, store),
+            run
+        )?))
+    }
+
+    /// Returns the data of a live session, extending its lifetime.
+    #[inline]
+    fn load(&self, id: &str) -> Option<SessionData> {
+        self.call(SessionRequest::Load(id.to_string()))
+    }
+
+    #[inline]
+    fn save(&self, id: &str, data: SessionData) {
+        self.call(SessionRequest::Save(id.to_string(), data));
+    }
+
+    #[inline]
+    fn remove(&self, id: &str) {
+        self.call(SessionRequest::Remove(id.to_string()));
+    }
+
+    /// Sends a request to the session store. If it can't be reached, sessions
+    /// are lost, but requests still succeed.
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+    #[inline]
+    fn call(&self, request: SessionRequest) -> Option<SessionData> {
+        match self {
+            Sessions::Disabled => None,
+            #[cfg(target_arch = "wasm32")]
+            Sessions::Process(process) => match process.request(request) {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::warn!("session store error: {}", e);
+                    None
+                }
+            },
+            #[cfg(all(test, not(target_arch = "wasm32")))]
+            Sessions::Local(table) => table
+                .lock()
+                .unwrap()
+                .handle(&request, crate::store::unix_time()),
+        }
+    }
+}
+
+/// Entry point of the session store process.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn run(
+    (max_sessions, store): (usize, Option<StoreHandle>),
+    mailbox: Mailbox<lunatic::Request<SessionRequest, Option<SessionData>>>,
+) {
+    let store = store.and_then(|store| match store.open() {
+        Ok(store) => Some(store),
+        Err(e) => {
+            tracing::error!("not persisting sessions: {}", e);
+            None
+        }
+    });
+    let mut table = SessionTable::new(max_sessions, store);
+    loop {
+        let request = match mailbox.receive() {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("receive error: {}", e);
+                continue;
+            }
+        };
+        let response = table.handle(request.data(), crate::store::unix_time());
+        request.reply(response);
+    }
+}
+
+impl Session {
+    /// Loads the session of the cookie sent with `request`, or starts a new
+    /// one.
+    #[inline]
+    pub(crate) fn load(sessions: &Sessions, key: &CookieKey, request: &Request) -> Self {
+        let loaded = request
+            .cookie(SESSION_COOKIE)
+            .and_then(|signed| key.verify(SESSION_COOKIE, signed))
+            .and_then(|id| Some((id.to_string(), sessions.load(id)?)));
+        let (id, data) = match loaded {
+            Some((id, data)) => (Some(id), data),
+            None => (None, SessionData::default()),
+        };
+        Self {
+            id,
+            data,
+            modified: false,
+            stale_id: None,
+        }
+    }
+
+    /// Returns the ID of the session, unless it's new or was just rotated.
+    #[inline]
+    pub(crate) fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.data.values.get(name).map(String::as_str)
+    }
+
+    #[inline]
+    pub(crate) fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.data.values.insert(name.into(), value.into());
+        self.modified = true;
+    }
+
+    #[inline]
+    pub(crate) fn remove(&mut self, name: &str) -> Option<String> {
+        let value = self.data.values.remove(name);
+        self.modified |= value.is_some();
+        value
+    }
+
+    /// Adds a message for the next page shown.
+    #[inline]
+    pub(crate) fn push_flash(&mut self, message: impl Into<String>) {
+        self.data.flash.push(message.into());
+        self.modified = true;
+    }
+
+    /// Removes and returns the messages for the current page.
+    #[inline]
+    pub(crate) fn take_flash(&mut self) -> Vec<String> {
+        self.modified |= !self.data.flash.is_empty();
+        std::mem::take(&mut self.data.flash)
+    }
+
+    /// Moves the session to a new ID, keeping its data.
+    #[inline]
+    pub(crate) fn rotate(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.stale_id = Some(id);
+        }
+        self.modified = true;
+    }
+
+    /// Ends the session, removing its data.
+    #[inline]
+    pub(crate) fn destroy(&mut self) {
+        self.rotate();
+        self.data = SessionData::default();
+    }
+
+    /// Saves the session, returning the cookie to send if its ID changed.
+    #[inline]
+    pub(crate) fn save(
+        self,
+        sessions: &Sessions,
+        key: &CookieKey,
+        secure: bool,
+    ) -> Option<SetCookie> {
+        if let Some(stale_id) = &self.stale_id {
+            sessions.remove(stale_id);
+        }
+        if !self.modified {
+            return None;
+        }
+        let is_empty = self.data.values.is_empty() && self.data.flash.is_empty();
+        match self.id {
+            Some(id) if is_empty => {
+                sessions.remove(&id);
+                Some(SetCookie::removal(SESSION_COOKIE).path("/"))
+            }
+            Some(id) => {
+                sessions.save(&id, self.data);
+                None
+            }
+            // The client may still have the cookie of a destroyed session.
+            None if is_empty => self
+                .stale_id
+                .map(|_| SetCookie::removal(SESSION_COOKIE).path("/")),
+            None => {
+                let id = generate_session_id(&mut rand::thread_rng());
+                sessions.save(&id, self.data);
+                let cookie = SetCookie::new(SESSION_COOKIE, key.sign(SESSION_COOKIE, &id))
+                    .path("/")
+                    .http_only(true)
+                    .same_site(SameSite::Lax)
+                    .secure(secure);
+                Some(cookie)
+            }
+        }
+    }
+}
+
+/// Sessions by the hash of their ID, optionally written through to a store.
+pub(crate) struct SessionTable<S: ?Sized = dyn Store> {
+    sessions: HashMap<String, Entry>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    max_sessions: usize,
+    store: Option<Box<S>>,
+}
+
+struct Entry {
+    data: SessionData,
+    /// Seconds since the Unix epoch.
+    expires_at: i64,
+    /// When the stored copy of the session expires, if it's stored.
+    stored_until: Option<i64>,
+    /// The tick the session was last used at.
+    used: u64,
+}
+
+impl<S: Store + ?Sized> SessionTable<S> {
+    #[inline]
+    pub(crate) fn new(max_sessions: usize, store: Option<Box<S>>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            max_sessions,
+            store,
+        }
+    }
+
+    #[inline]
+    fn handle(&mut self, request: &SessionRequest, now: i64) -> Option<SessionData> {
+        match request {
+            SessionRequest::Load(id) => return self.load(id, now),
+            SessionRequest::Save(id, data) => self.save(id, data.clone(), now),
+            SessionRequest::Remove(id) => self.remove(id),
+        }
+        None
+    }
+
+    #[inline]
+    fn load(&mut self, id: &str, now: i64) -> Option<SessionData> {
+        let key = hash_session_id(id);
+        if !self.sessions.contains_key(&key) {
+            let entry = self.load_stored(&key, now)?;
+            self.make_room();
+            self.insert(key.clone(), entry);
+        }
+        if self.sessions.get(&key)?.expires_at <= now {
+            self.forget(&key);
+            return None;
+        }
+        self.touch(&key);
+        let entry = self.sessions.get_mut(&key)?;
+        entry.expires_at = now + SESSION_TTL.as_secs() as i64;
+        // The stored copy only has to be refreshed when it's halfway to
+        // expiring, rather than on every request.
+        let refresh = entry
+            .stored_until
+            .map_or(false, |stored_until| stored_until - now < SESSION_TTL.as_secs() as i64 / 2);
+        if refresh {
+            persist(&mut self.store, &key, entry);
+        }
+        Some(entry.data.clone())
+    }
+
+    #[inline]
+    fn save(&mut self, id: &str, data: SessionData, now: i64) {
+        let key = hash_session_id(id);
+        if self.sessions.contains_key(&key) {
+            self.touch(&key);
+        } else {
+            self.make_room();
+            let entry = Entry {
+                data: SessionData::default(),
+                expires_at: now,
+                stored_until: None,
+                used: 0,
+            };
+            self.insert(key.clone(), entry);
+        }
+        if let Some(entry) = self.sessions.get_mut(&key) {
+            entry.data = data;
+            entry.expires_at = now + SESSION_TTL.as_secs() as i64;
+            persist(&mut self.store, &key, entry);
+        }
+    }
+
+    #[inline]
+    fn remove(&mut self, id: &str) {
+        let key = hash_session_id(id);
+        self.forget(&key);
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.delete_session(&key) {
+                tracing::error!("failed to delete session: {}", e);
+            }
+        }
+    }
+
+    /// Returns a live session from the store.
+    #[inline]
+    fn load_stored(&mut self, key: &str, now: i64) -> Option<Entry> {
+        let stored = match self.store.as_mut()?.get_session(key) {
+            Ok(stored) => stored?,
+            Err(e) => {
+                tracing::error!("failed to load session: {}", e);
+                return None;
+            }
+        };
+        if stored.expires_at <= now {
+            return None;
+        }
+        match serde_json::from_str(&stored.data) {
+            Ok(data) => Some(Entry {
+                data,
+                expires_at: stored.expires_at,
+                stored_until: Some(stored.expires_at),
+                used: 0,
+            }),
+            Err(e) => {
+                tracing::error!("invalid session data: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Keeps `entry` as the most recently used session.
+    #[inline]
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        self.forget(&key);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.sessions.insert(key, entry);
+    }
+
+    /// Marks the session at `key` as the most recently used.
+    #[inline]
+    fn touch(&mut self, key: &str) {
+        if let Some(entry) = self.sessions.get_mut(key) {
+            self.tick += 1;
+            let key = self.order.remove(&entry.used).unwrap();
+            self.order.insert(self.tick, key);
+            entry.used = self.tick;
+        }
+    }
+
+    #[inline]
+    fn forget(&mut self, key: &str) {
+        if let Some(entry) = self.sessions.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    /// Forgets the least recently used sessions until there is room for a
+    /// new one. Using a session extends it, so expired sessions are the
+    /// least recently used. Stored sessions can still be loaded again.
+    #[inline]
+    fn make_room(&mut self) {
+        while self.sessions.len() >= self.max_sessions {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    tracing::warn!("too many sessions, forgetting the least recently used");
+                    self.sessions.remove(&oldest);
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+/// Writes a session to the store, if sessions are persisted.
+#[inline]
+fn persist<S: Store + ?Sized>(store: &mut Option<Box<S>>, key: &str, entry: &mut Entry) {
+    let store = match store {
+        Some(store) => store,
+        None => return,
+    };
+    let stored = match serde_json::to_string(&entry.data) {
+        Ok(data) => StoredSession {
+            id: key.to_string(),
+            data,
+            expires_at: entry.expires_at,
+        },
+        Err(e) => {
+            tracing::error!("failed to serialize session: {}", e);
+            return;
+        }
+    };
+    match store.put_session(&stored) {
+        Ok(()) => entry.stored_until = Some(entry.expires_at),
+        Err(e) => tracing::error!("failed to store session: {}", e),
+    }
+}
+
+#[inline]
+fn generate_session_id<R: Rng>(rng: &mut R) -> String {
+    let mut id = [0; SESSION_ID_LENGTH];
+    rng.fill(&mut id);
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+/// Returns the key a session is kept under: the hex SHA-256 of its ID.
+#[inline]
+fn hash_session_id(id: &str) -> String {
+    Sha256::digest(id.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionData, SessionTable, SESSION_TTL};
+    use crate::store::{MemoryStore, SessionStore};
+
+    #[test]
+    fn test_session_table() {
+        let ttl = SESSION_TTL.as_secs() as i64;
+        let data = |value: &str| {
+            let mut data = SessionData::default();
+            data.values.insert("user".to_string(), value.to_string());
+            data
+        };
+        let mut table = SessionTable::new(2, Some(Box::new(MemoryStore::new())));
+        table.save("a", data("1"), 0);
+        assert_eq!(table.load("a", 10), Some(data("1")));
+        assert_eq!(table.load("b", 10), None);
+        // Sessions expire after not being used for the TTL.
+        assert_eq!(table.load("a", 9 + ttl), Some(data("1")));
+        assert_eq!(table.load("a", 8 + 2 * ttl), Some(data("1")));
+        assert_eq!(table.load("a", 8 + 3 * ttl), None);
+
+        // Only hashes of the IDs are stored.
+        let now = 10 * ttl;
+        table.save("b", data("2"), now);
+        table.save("c", data("3"), now + 1);
+        let store = table.store.as_mut().unwrap();
+        assert!(store.get_session("b").unwrap().is_none());
+        assert!(store.get_session(&super::hash_session_id("b")).unwrap().is_some());
+
+        // The least recently used session is forgotten to make room, but is
+        // loaded again from the store.
+        table.save("d", data("4"), now + 2);
+        assert_eq!(table.sessions.len(), 2);
+        assert!(!table.sessions.contains_key(&super::hash_session_id("b")));
+        assert_eq!(table.load("b", now + 3), Some(data("2")));
+        assert!(!table.sessions.contains_key(&super::hash_session_id("c")));
+        // Using `d` makes `b` the least recently used again.
+        assert_eq!(table.load("d", now + 3), Some(data("4")));
+        table.save("e", data("5"), now + 3);
+        assert!(!table.sessions.contains_key(&super::hash_session_id("b")));
+        assert!(table.sessions.contains_key(&super::hash_session_id("d")));
+        assert_eq!(table.order.len(), table.sessions.len());
+
+        // Sessions survive a restart if they're stored.
+        let mut table = SessionTable::new(2, table.store.take());
+        assert_eq!(table.load("c", now + 4), Some(data("3")));
+        table.remove("c");
+        let mut table = SessionTable::new(2, table.store.take());
+        assert_eq!(table.load("c", now + 5), None);
+        assert_eq!(table.load("d", now + 5), Some(data("4")));
+    }
+}
@@ -0,0 +1,210 @@
+//! Exporting and importing pastes.
+//!
+//! Pastes are exported as JSON lines, one paste per line with its revisions
+//! and, for uploaded files, the base64 of the file, so that they can be
+//! copied between stores, e.g. from a log file to PostgreSQL. Accounts,
+//! sessions and todos aren't exported, so imported pastes belong to nobody.
+use crate::store::{
+    blob_hash, is_valid_paste_id, unix_time, NewRevision, Paste, PasteStore, Revision,
+    StoreError,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// A line of an export.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedPaste {
+    paste: Paste,
+    /// Oldest first.
+    revisions: Vec<Revision>,
+    /// The base64 of the uploaded file, if the paste is one.
+    #[serde(default)]
+    file_data: Option<String>,
+}
+
+/// The outcome of [`import_pastes`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ImportSummary {
+    pub(crate) imported: u64,
+    /// Pastes whose ID was taken already, which are left alone.
+    pub(crate) skipped: u64,
+}
+
+/// Writes every paste which hasn't expired to `output`, returning how many
+/// were written.
+#[inline]
+pub(crate) fn export_pastes(
+    store: &mut dyn PasteStore,
+    output: &mut dyn Write,
+) -> Result<u64, StoreError> {
+    let now = unix_time();
+    let mut exported = 0;
+    for id in store.list_paste_ids()? {
+        let paste = match store.get_paste(&id)? {
+            Some(paste) if !paste.is_expired(now) => paste,
+            // Expired, or deleted since it was listed.
+            _ => continue,
+        };
+        let revisions = store.list_revisions(&id)?;
+        let file_data = match &paste.file {
+            Some(file) => match store.get_blob(&file.hash)? {
+                Some(data) => Some(base64::encode(data)),
+                None => return Err(StoreError::corrupt(format!("missing blob {}", file.hash))),
+            },
+            None => None,
+        };
+        let line = ExportedPaste {
+            paste,
+            revisions,
+            file_data,
+        };
+        serde_json::to_writer(&mut *output, &line).map_err(StoreError::corrupt)?;
+        output.write_all(b"\n")?;
+        exported += 1;
+    }
+    output.flush()?;
+    Ok(exported)
+}
+
+/// Reads pastes written by [`export_pastes`] from `input` into `store`.
+/// Revisions after the first are added anew, so they're dated by the import.
+#[inline]
+pub(crate) fn import_pastes(
+    store: &mut dyn PasteStore,
+    input: &mut dyn BufRead,
+) -> Result<ImportSummary, StoreError> {
+    let mut summary = ImportSummary::default();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |e: &dyn std::fmt::Display| {
+            StoreError::corrupt(format!("line {}: {}", number + 1, e))
+        };
+        let exported: ExportedPaste = serde_json::from_str(&line).map_err(|e| invalid(&e))?;
+        if import_paste(store, exported).map_err(|e| invalid(&e))? {
+            summary.imported += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Imports a paste, returning `false` if its ID is taken.
+#[inline]
+fn import_paste(store: &mut dyn PasteStore, exported: ExportedPaste) -> Result<bool, StoreError> {
+    let ExportedPaste {
+        mut paste,
+        revisions,
+        file_data,
+    } = exported;
+    if !is_valid_paste_id(&paste.id) {
+        return Err(StoreError::corrupt(format!("invalid paste ID {:?}", paste.id)));
+    }
+    if let Some(file) = &paste.file {
+        let data = file_data
+            .as_deref()
+            .and_then(|data| base64::decode(data).ok())
+            .filter(|data| blob_hash(data) == file.hash)
+            .ok_or_else(|| StoreError::corrupt(format!("invalid data for blob {}", file.hash)))?;
+        // Stored first, so that pastes never refer to a missing blob.
+        store.put_blob(&file.hash, &data)?;
+    }
+    paste.owner = None;
+    // The paste is inserted as of its first revision, then the others are
+    // added on top, which brings it up to date.
+    let mut revisions = revisions.into_iter();
+    if let Some(first) = revisions.next() {
+        paste.revision = first.revision;
+        paste.title = first.title;
+        paste.language = first.language;
+        paste.content = first.content;
+    }
+    if !store.insert_paste(&paste)? {
+        return Ok(false);
+    }
+    for revision in revisions {
+        let new_revision = NewRevision {
+            title: revision.title.as_deref(),
+            language: revision.language.as_deref(),
+            content: &revision.content,
+        };
+        store.add_revision(&paste.id, new_revision)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export_pastes, import_pastes, ImportSummary};
+    use crate::store::{MemoryStore, NewPaste, NewRevision, Paste, PasteFile, PasteStore};
+
+    #[test]
+    fn test_export_import() {
+        let mut store = MemoryStore::new();
+        let new_paste = |content| NewPaste {
+            title: Some("main.rs"),
+            language: Some("rust"),
+            content,
+            expires_in: None,
+            burn_after_reading: false,
+            parent: None,
+            protected: false,
+            file: None,
+            owner: Some(1),
+        };
+        let edited = store.create_paste(new_paste("fn main() {}\n")).unwrap();
+        let edit = NewRevision {
+            title: None,
+            language: Some("rust"),
+            content: "fn main() {}\n// edited\n",
+        };
+        store.add_revision(&edited.id, edit).unwrap();
+        let data = b"\x89PNG\r\n\x1a\n";
+        let hash = crate::store::blob_hash(data);
+        store.put_blob(&hash, data).unwrap();
+        let file = PasteFile {
+            name: "a.png".to_string(),
+            media_type: "image/png".to_string(),
+            size: data.len() as i64,
+            hash: hash.clone(),
+        };
+        let image = store
+            .create_paste(NewPaste {
+                file: Some(file),
+                ..new_paste("")
+            })
+            .unwrap();
+        // Expired, but not purged yet.
+        let expired = Paste {
+            id: "expired0".to_string(),
+            expires_at: Some(edited.created_at - 1),
+            ..edited.clone()
+        };
+        assert!(store.insert_paste(&expired).unwrap());
+
+        let mut export = Vec::new();
+        assert_eq!(export_pastes(&mut store, &mut export).unwrap(), 2);
+        let mut imported = MemoryStore::new();
+        let summary = import_pastes(&mut imported, &mut &export[..]).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 2, skipped: 0 });
+        for id in [&edited.id, &image.id] {
+            let mut paste = store.get_paste(id).unwrap().unwrap();
+            paste.owner = None;
+            assert_eq!(imported.get_paste(id).unwrap(), Some(paste));
+            let contents = |store: &mut MemoryStore| -> Vec<_> {
+                let revisions = store.list_revisions(id).unwrap();
+                revisions.into_iter().map(|r| (r.revision, r.title, r.content)).collect()
+            };
+            assert_eq!(contents(&mut imported), contents(&mut store));
+        }
+        assert_eq!(imported.get_blob(&hash).unwrap().as_deref(), Some(&data[..]));
+
+        // Importing again changes nothing.
+        let summary = import_pastes(&mut imported, &mut &export[..]).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 0, skipped: 2 });
+        assert!(import_pastes(&mut imported, &mut &b"{}\n"[..]).is_err());
+    }
+}
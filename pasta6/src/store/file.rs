@@ -0,0 +1,530 @@
+//! An append-only log file backend.
+//!
+//! Every change is appended to the log as a single line of JSON. When the
+//! store is opened, the log is replayed into a [`MemoryStore`], which then
+//! serves all reads.
+//!
+//! Sessions are logged whenever they're saved, which is at most every few
+//! minutes per active session, see [`crate::session`].
+//!
+//! Blobs aren't logged, but kept as files named by their hash in a directory
+//! next to the log: `pasta6.log.blobs/` for `pasta6.log`.
+use crate::store::{
+    Account, AccountStore, ApiToken, ApiTokenStore, MemoryStore, NewRevision, Paste, PasteStore,
+    Revision, SessionStore, StoreError, StoredSession, Todo, TodoStore, TokenScope,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) struct FileStore {
+    file: File,
+    memory: MemoryStore,
+    blob_dir: PathBuf,
+}
+
+/// A single record of the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    CreateTodo {
+        id: i64,
+        content: String,
+        #[serde(default)]
+        owner: Option<i64>,
+    },
+    DeleteTodo { id: i64 },
+    CreatePaste(Paste),
+    DeletePaste { id: String },
+    AddRevision { id: String, revision: Revision },
+    PutSession(StoredSession),
+    DeleteSession { id: String },
+    CreateAccount(Account),
+    CreateApiToken(ApiToken),
+    DeleteApiToken { account_id: i64, id: i64 },
+}
+
+impl FileStore {
+    #[inline]
+    pub(crate) fn open(path: &Path) -> Result<Self, StoreError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut memory = MemoryStore::new();
+        let mut entries = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = Vec::new();
+        // The length of the log up to the end of its last complete line.
+        let mut length = 0;
+        for number in 1.. {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            // A crash while appending can leave a truncated last line, which
+            // we cut off rather than refusing to start, so that the next
+            // entry starts on a line of its own.
+            if line.last() != Some(&b'\n') {
+                tracing::warn!("truncating partial log entry on line {}", number);
+                file.set_len(length)?;
+                break;
+            }
+            length += line.len() as u64;
+            let entry = match serde_json::from_slice(&line) {
+                Ok(entry) => entry,
+                Err(e) if e.is_eof() => {
+                    tracing::warn!("ignoring truncated log entry on line {}", number);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(StoreError::corrupt(format!("line {}: {}", number, e)));
+                }
+            };
+            apply(&mut memory, entry)?;
+            entries += 1;
+        }
+        tracing::debug!("replayed {} log entries from {}", entries, path.display());
+        let mut blob_dir = path.as_os_str().to_owned();
+        blob_dir.push(".blobs");
+        Ok(Self {
+            file,
+            memory,
+            blob_dir: blob_dir.into(),
+        })
+    }
+
+    /// Returns the path of a blob, or an error if `hash` isn't one, so that
+    /// it can't name a file outside of the blob directory.
+    #[inline]
+    fn blob_path(&self, hash: &str) -> Result<PathBuf, StoreError> {
+        if !crate::store::is_valid_blob_hash(hash) {
+            return Err(StoreError::corrupt(format!("invalid blob hash: {}", hash)));
+        }
+        Ok(self.blob_dir.join(hash))
+    }
+
+    /// Appends an entry to the log and applies it.
+    #[inline]
+    fn append(&mut self, entry: Entry) -> Result<(), StoreError> {
+        let mut line = serde_json::to_vec(&entry).map_err(StoreError::corrupt)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        apply(&mut self.memory, entry)
+    }
+}
+
+#[inline]
+fn apply(memory: &mut MemoryStore, entry: Entry) -> Result<(), StoreError> {
+    match entry {
+        Entry::CreateTodo { id, content, owner } => {
+            memory.insert_todo(Todo { id, content, owner })
+        }
+        Entry::DeleteTodo { id } => {
+            memory.delete_todo(id)?;
+        }
+        Entry::CreatePaste(paste) => {
+            memory.insert_paste(&paste)?;
+        }
+        Entry::DeletePaste { id } => {
+            memory.delete_paste(&id)?;
+        }
+        Entry::AddRevision { id, revision } => {
+            if !memory.push_revision(&id, revision) {
+                return Err(StoreError::corrupt(format!("revision of unknown paste {}", id)));
+            }
+        }
+        Entry::PutSession(session) => memory.put_session(&session)?,
+        Entry::DeleteSession { id } => {
+            memory.delete_session(&id)?;
+        }
+        Entry::CreateAccount(account) => memory.insert_account(account),
+        Entry::CreateApiToken(token) => memory.insert_api_token(token),
+        Entry::DeleteApiToken { account_id, id } => {
+            memory.delete_api_token(account_id, id)?;
+        }
+    }
+    Ok(())
+}
+
+impl TodoStore for FileStore {
+    #[inline]
+    fn list_todos(&mut self, owner: Option<i64>) -> Result<Vec<Todo>, StoreError> {
+        self.memory.list_todos(owner)
+    }
+
+    #[inline]
+    fn get_todo(&mut self, id: i64) -> Result<Option<Todo>, StoreError> {
+        self.memory.get_todo(id)
+    }
+
+    #[inline]
+    fn create_todo(&mut self, content: &str, owner: Option<i64>) -> Result<Todo, StoreError> {
+        let todo = Todo {
+            id: self.memory.next_todo_id(),
+            content: content.to_string(),
+            owner,
+        };
+        self.append(Entry::CreateTodo {
+            id: todo.id,
+            content: todo.content.clone(),
+            owner,
+        })?;
+        Ok(todo)
+    }
+
+    #[inline]
+    fn delete_todo(&mut self, id: i64) -> Result<bool, StoreError> {
+        if self.memory.get_todo(id)?.is_none() {
+            return Ok(false);
+        }
+        self.append(Entry::DeleteTodo { id })?;
+        Ok(true)
+    }
+}
+
+impl PasteStore for FileStore {
+    #[inline]
+    fn get_paste(&mut self, id: &str) -> Result<Option<Paste>, StoreError> {
+        self.memory.get_paste(id)
+    }
+
+    #[inline]
+    fn list_paste_ids(&mut self) -> Result<Vec<String>, StoreError> {
+        self.memory.list_paste_ids()
+    }
+
+    #[inline]
+    fn insert_paste(&mut self, paste: &Paste) -> Result<bool, StoreError> {
+        if self.memory.get_paste(&paste.id)?.is_some() {
+            return Ok(false);
+        }
+        self.append(Entry::CreatePaste(paste.clone()))?;
+        Ok(true)
+    }
+
+    #[inline]
+    fn delete_paste(&mut self, id: &str) -> Result<bool, StoreError> {
+        if self.memory.get_paste(id)?.is_none() {
+            return Ok(false);
+        }
+        self.append(Entry::DeletePaste { id: id.to_string() })?;
+        Ok(true)
+    }
+
+    #[inline]
+    fn purge_expired_pastes(&mut self, now: i64) -> Result<u64, StoreError> {
+        let expired = self.memory.expired_paste_ids(now);
+        for id in &expired {
+            self.append(Entry::DeletePaste { id: id.clone() })?;
+        }
+        Ok(expired.len() as u64)
+    }
+
+    #[inline]
+    fn put_blob(&mut self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        let path = self.blob_path(hash)?;
+        match File::options().write(true).open(&path) {
+            Ok(file) => {
+                file.set_modified(SystemTime::now())?;
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        fs::create_dir_all(&self.blob_dir)?;
+        // Write to a temporary file first, so that a crash can't leave a
+        // truncated blob behind.
+        let temporary = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        let mut file = File::create(&temporary)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+
+    #[inline]
+    fn get_blob(&mut self, hash: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match fs::read(self.blob_path(hash)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[inline]
+    fn purge_orphaned_blobs(&mut self, before: i64) -> Result<u64, StoreError> {
+        let entries = match fs::read_dir(&self.blob_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let referenced = self.memory.referenced_blobs();
+        let mut purged = 0;
+        for entry in entries {
+            let entry = entry?;
+            // Leftover temporary files are never referenced.
+            if referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+                continue;
+            }
+            let stored_at = entry
+                .metadata()?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |stored_at| stored_at.as_secs() as i64);
+            if stored_at <= before {
+                fs::remove_file(entry.path())?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    #[inline]
+    fn add_revision(
+        &mut self,
+        id: &str,
+        new_revision: NewRevision,
+    ) -> Result<Option<Revision>, StoreError> {
+        let latest = match self.memory.get_paste(id)? {
+            Some(paste) => paste.revision,
+            None => return Ok(None),
+        };
+        let revision = Revision::new(latest + 1, new_revision);
+        self.append(Entry::AddRevision {
+            id: id.to_string(),
+            revision: revision.clone(),
+        })?;
+        Ok(Some(revision))
+    }
+
+    #[inline]
+    fn list_revisions(&mut self, id: &str) -> Result<Vec<Revision>, StoreError> {
+        self.memory.list_revisions(id)
+    }
+
+    #[inline]
+    fn get_revision(&mut self, id: &str, revision: i32) -> Result<Option<Revision>, StoreError> {
+        self.memory.get_revision(id, revision)
+    }
+}
+
+impl SessionStore for FileStore {
+    #[inline]
+    fn get_session(&mut self, id: &str) -> Result<Option<StoredSession>, StoreError> {
+        self.memory.get_session(id)
+    }
+
+    #[inline]
+    fn put_session(&mut self, session: &StoredSession) -> Result<(), StoreError> {
+        self.append(Entry::PutSession(session.clone()))
+    }
+
+    #[inline]
+    fn delete_session(&mut self, id: &str) -> Result<bool, StoreError> {
+        if self.memory.get_session(id)?.is_none() {
+            return Ok(false);
+        }
+        self.append(Entry::DeleteSession { id: id.to_string() })?;
+        Ok(true)
+    }
+
+    #[inline]
+    fn purge_expired_sessions(&mut self, now: i64) -> Result<u64, StoreError> {
+        let expired = self.memory.expired_session_ids(now);
+        for id in &expired {
+            self.append(Entry::DeleteSession { id: id.clone() })?;
+        }
+        Ok(expired.len() as u64)
+    }
+}
+
+impl AccountStore for FileStore {
+    #[inline]
+    fn get_account(&mut self, id: i64) -> Result<Option<Account>, StoreError> {
+        self.memory.get_account(id)
+    }
+
+    #[inline]
+    fn get_account_by_name(&mut self, name: &str) -> Result<Option<Account>, StoreError> {
+        self.memory.get_account_by_name(name)
+    }
+
+    #[inline]
+    fn create_account(
+        &mut self,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<Account>, StoreError> {
+        if self.memory.get_account_by_name(name)?.is_some() {
+            return Ok(None);
+        }
+        let account = Account {
+            id: self.memory.next_account_id(),
+            name: name.to_string(),
+            password_hash: password_hash.to_string(),
+            created_at: crate::store::unix_time(),
+        };
+        self.append(Entry::CreateAccount(account.clone()))?;
+        Ok(Some(account))
+    }
+}
+
+impl ApiTokenStore for FileStore {
+    #[inline]
+    fn list_api_tokens(&mut self, account_id: i64) -> Result<Vec<ApiToken>, StoreError> {
+        self.memory.list_api_tokens(account_id)
+    }
+
+    #[inline]
+    fn get_api_token(&mut self, hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        self.memory.get_api_token(hash)
+    }
+
+    #[inline]
+    fn create_api_token(
+        &mut self,
+        account_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<ApiToken, StoreError> {
+        let token = ApiToken {
+            id: self.memory.next_api_token_id(),
+            account_id,
+            name: name.to_string(),
+            hash: hash.to_string(),
+            scope,
+            created_at: crate::store::unix_time(),
+        };
+        self.append(Entry::CreateApiToken(token.clone()))?;
+        Ok(token)
+    }
+
+    #[inline]
+    fn delete_api_token(&mut self, account_id: i64, id: i64) -> Result<bool, StoreError> {
+        let owned = self
+            .memory
+            .list_api_tokens(account_id)?
+            .iter()
+            .any(|token| token.id == id);
+        if !owned {
+            return Ok(false);
+        }
+        self.append(Entry::DeleteApiToken { account_id, id })?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FileStore;
+    use crate::store::{
+        AccountStore, ApiTokenStore, NewPaste, NewRevision, PasteStore, SessionStore,
+        StoredSession, TodoStore,
+    };
+    use std::io::Write;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        crate::store::test::test_todo_store(&mut FileStore::open(&path).unwrap());
+        // Simulate a crash halfway through appending an entry.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"op\":\"create_todo\",\"id\":3,\"cont")
+            .unwrap();
+        // The log is replayed when the store is reopened.
+        let mut store = FileStore::open(&path).unwrap();
+        let todos = store.list_todos(None).unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].content, "second");
+        assert_eq!(store.create_todo("third", None).unwrap().id, 3);
+        // The partial entry was cut off, so nothing is lost on the next start.
+        let mut store = FileStore::open(&path).unwrap();
+        let todos = store.list_todos(None).unwrap();
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[0].content, "second");
+        assert_eq!(todos[1].content, "third");
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        let mut store = FileStore::open(&path).unwrap();
+        crate::store::test::test_paste_store(&mut store);
+        let new_paste = || NewPaste {
+            title: None,
+            language: None,
+            content: "kept",
+            expires_in: None,
+            burn_after_reading: false,
+            parent: None,
+            protected: false,
+            file: None,
+            owner: None,
+        };
+        let kept = store.create_paste(new_paste()).unwrap();
+        let edit = NewRevision {
+            title: None,
+            language: None,
+            content: "edited",
+        };
+        store.add_revision(&kept.id, edit).unwrap().unwrap();
+        let kept = store.get_paste(&kept.id).unwrap().unwrap();
+        let deleted = store.create_paste(new_paste()).unwrap();
+        assert!(store.delete_paste(&deleted.id).unwrap());
+        // Creates, revisions and deletes are all replayed.
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get_paste(&kept.id).unwrap(), Some(kept.clone()));
+        assert_eq!(store.list_revisions(&kept.id).unwrap().len(), 2);
+        assert_eq!(store.get_paste(&deleted.id).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        let mut store = FileStore::open(&path).unwrap();
+        crate::store::test::test_session_store(&mut store);
+        let session = StoredSession {
+            id: crate::store::blob_hash(b"kept"),
+            data: "{}".to_string(),
+            expires_at: crate::store::unix_time() + 60,
+        };
+        store.put_session(&session).unwrap();
+        // Sessions survive a restart, but purged ones stay purged.
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get_session(&session.id).unwrap(), Some(session));
+        assert_eq!(
+            store
+                .get_session(&crate::store::blob_hash(b"session"))
+                .unwrap(),
+            None
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // Accounts and the owners of todos are replayed too.
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        let mut store = FileStore::open(&path).unwrap();
+        crate::store::test::test_account_store(&mut store);
+        let alice = store.get_account_by_name("alice").unwrap().unwrap();
+        let todos = store.list_todos(Some(alice.id)).unwrap();
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get_account(alice.id).unwrap(), Some(alice.clone()));
+        assert_eq!(store.list_todos(Some(alice.id)).unwrap(), todos);
+        assert_ne!(store.create_account("carol", "").unwrap().unwrap().id, alice.id);
+        std::fs::remove_file(&path).unwrap();
+
+        // Revoked API tokens stay revoked.
+        let path = std::env::temp_dir().join(format!("pasta6-{}.log", rand::random::<u64>()));
+        let mut store = FileStore::open(&path).unwrap();
+        crate::store::test::test_api_token_store(&mut store);
+        let alice = store.get_account_by_name("alice").unwrap().unwrap();
+        let tokens = store.list_api_tokens(alice.id).unwrap();
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.list_api_tokens(alice.id).unwrap(), tokens);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
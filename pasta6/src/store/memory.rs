@@ -0,0 +1,390 @@
+use crate::store::{
+    Account, AccountStore, ApiToken, ApiTokenStore, NewRevision, Paste, PasteStore, Revision,
+    SessionStore, StoreError, StoredSession, Todo, TodoStore, TokenScope,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A store which keeps everything in the memory of the current process.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    todos: BTreeMap<i64, Todo>,
+    next_todo_id: i64,
+    pastes: BTreeMap<String, Paste>,
+    /// Revisions of each paste, oldest first.
+    revisions: BTreeMap<String, Vec<Revision>>,
+    /// Blobs by hash, with the time they were last stored.
+    blobs: BTreeMap<String, (Vec<u8>, i64)>,
+    /// Sessions by the hash of their ID.
+    sessions: BTreeMap<String, StoredSession>,
+    accounts: BTreeMap<i64, Account>,
+    next_account_id: i64,
+    api_tokens: BTreeMap<i64, ApiToken>,
+    next_api_token_id: i64,
+}
+
+impl MemoryStore {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self {
+            todos: BTreeMap::new(),
+            next_todo_id: 1,
+            pastes: BTreeMap::new(),
+            revisions: BTreeMap::new(),
+            blobs: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            accounts: BTreeMap::new(),
+            next_account_id: 1,
+            api_tokens: BTreeMap::new(),
+            next_api_token_id: 1,
+        }
+    }
+
+    /// Inserts a todo with a known ID, used when replaying a log.
+    #[inline]
+    pub(super) fn insert_todo(&mut self, todo: Todo) {
+        self.next_todo_id = self.next_todo_id.max(todo.id + 1);
+        self.todos.insert(todo.id, todo);
+    }
+
+    #[inline]
+    pub(super) fn next_todo_id(&self) -> i64 {
+        self.next_todo_id
+    }
+
+    /// Inserts an account with a known ID, used when replaying a log.
+    #[inline]
+    pub(super) fn insert_account(&mut self, account: Account) {
+        self.next_account_id = self.next_account_id.max(account.id + 1);
+        self.accounts.insert(account.id, account);
+    }
+
+    #[inline]
+    pub(super) fn next_account_id(&self) -> i64 {
+        self.next_account_id
+    }
+
+    /// Inserts an API token with a known ID, used when replaying a log.
+    #[inline]
+    pub(super) fn insert_api_token(&mut self, token: ApiToken) {
+        self.next_api_token_id = self.next_api_token_id.max(token.id + 1);
+        self.api_tokens.insert(token.id, token);
+    }
+
+    #[inline]
+    pub(super) fn next_api_token_id(&self) -> i64 {
+        self.next_api_token_id
+    }
+
+    /// Makes `revision` the current revision of a paste, returning `false` if
+    /// the paste doesn't exist. Used when replaying a log.
+    #[inline]
+    pub(super) fn push_revision(&mut self, id: &str, revision: Revision) -> bool {
+        let paste = match self.pastes.get_mut(id) {
+            Some(paste) => paste,
+            None => return false,
+        };
+        paste.revision = revision.revision;
+        paste.title = revision.title.clone();
+        paste.language = revision.language.clone();
+        paste.content = revision.content.clone();
+        self.revisions
+            .entry(id.to_string())
+            .or_default()
+            .push(revision);
+        true
+    }
+
+    /// Returns the hashes of the blobs which pastes refer to.
+    #[inline]
+    pub(super) fn referenced_blobs(&self) -> BTreeSet<&str> {
+        self.pastes
+            .values()
+            .filter_map(|paste| paste.file.as_ref())
+            .map(|file| file.hash.as_str())
+            .collect()
+    }
+
+    #[inline]
+    pub(super) fn expired_paste_ids(&self, now: i64) -> Vec<String> {
+        self.pastes
+            .values()
+            .filter(|paste| paste.is_expired(now))
+            .map(|paste| paste.id.clone())
+            .collect()
+    }
+
+    #[inline]
+    pub(super) fn expired_session_ids(&self, now: i64) -> Vec<String> {
+        self.sessions
+            .values()
+            .filter(|session| session.expires_at <= now)
+            .map(|session| session.id.clone())
+            .collect()
+    }
+}
+
+impl TodoStore for MemoryStore {
+    #[inline]
+    fn list_todos(&mut self, owner: Option<i64>) -> Result<Vec<Todo>, StoreError> {
+        Ok(self
+            .todos
+            .values()
+            .filter(|todo| todo.owner == owner)
+            .cloned()
+            .collect())
+    }
+
+    #[inline]
+    fn get_todo(&mut self, id: i64) -> Result<Option<Todo>, StoreError> {
+        Ok(self.todos.get(&id).cloned())
+    }
+
+    #[inline]
+    fn create_todo(&mut self, content: &str, owner: Option<i64>) -> Result<Todo, StoreError> {
+        let todo = Todo {
+            id: self.next_todo_id,
+            content: content.to_string(),
+            owner,
+        };
+        self.insert_todo(todo.clone());
+        Ok(todo)
+    }
+
+    #[inline]
+    fn delete_todo(&mut self, id: i64) -> Result<bool, StoreError> {
+        Ok(self.todos.remove(&id).is_some())
+    }
+}
+
+impl PasteStore for MemoryStore {
+    #[inline]
+    fn get_paste(&mut self, id: &str) -> Result<Option<Paste>, StoreError> {
+        Ok(self.pastes.get(id).cloned())
+    }
+
+    #[inline]
+    fn list_paste_ids(&mut self) -> Result<Vec<String>, StoreError> {
+        Ok(self.pastes.keys().cloned().collect())
+    }
+
+    #[inline]
+    fn insert_paste(&mut self, paste: &Paste) -> Result<bool, StoreError> {
+        if self.pastes.contains_key(&paste.id) {
+            return Ok(false);
+        }
+        self.pastes.insert(paste.id.clone(), paste.clone());
+        let revision = Revision {
+            revision: paste.revision,
+            title: paste.title.clone(),
+            language: paste.language.clone(),
+            content: paste.content.clone(),
+            created_at: paste.created_at,
+        };
+        self.revisions.insert(paste.id.clone(), vec![revision]);
+        Ok(true)
+    }
+
+    #[inline]
+    fn delete_paste(&mut self, id: &str) -> Result<bool, StoreError> {
+        self.revisions.remove(id);
+        Ok(self.pastes.remove(id).is_some())
+    }
+
+    #[inline]
+    fn purge_expired_pastes(&mut self, now: i64) -> Result<u64, StoreError> {
+        let expired = self.expired_paste_ids(now);
+        for id in &expired {
+            self.delete_paste(id)?;
+        }
+        Ok(expired.len() as u64)
+    }
+
+    #[inline]
+    fn put_blob(&mut self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        let now = crate::store::unix_time();
+        self.blobs
+            .entry(hash.to_string())
+            .and_modify(|(_, stored_at)| *stored_at = now)
+            .or_insert_with(|| (data.to_vec(), now));
+        Ok(())
+    }
+
+    #[inline]
+    fn get_blob(&mut self, hash: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.blobs.get(hash).map(|(data, _)| data.clone()))
+    }
+
+    #[inline]
+    fn purge_orphaned_blobs(&mut self, before: i64) -> Result<u64, StoreError> {
+        let referenced = self.referenced_blobs();
+        let orphaned: Vec<String> = self
+            .blobs
+            .iter()
+            .filter(|(hash, (_, stored_at))| {
+                *stored_at <= before && !referenced.contains(hash.as_str())
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in &orphaned {
+            self.blobs.remove(hash);
+        }
+        Ok(orphaned.len() as u64)
+    }
+
+    #[inline]
+    fn add_revision(
+        &mut self,
+        id: &str,
+        new_revision: NewRevision,
+    ) -> Result<Option<Revision>, StoreError> {
+        let latest = match self.pastes.get(id) {
+            Some(paste) => paste.revision,
+            None => return Ok(None),
+        };
+        let revision = Revision::new(latest + 1, new_revision);
+        self.push_revision(id, revision.clone());
+        Ok(Some(revision))
+    }
+
+    #[inline]
+    fn list_revisions(&mut self, id: &str) -> Result<Vec<Revision>, StoreError> {
+        Ok(self.revisions.get(id).cloned().unwrap_or_default())
+    }
+
+    #[inline]
+    fn get_revision(&mut self, id: &str, revision: i32) -> Result<Option<Revision>, StoreError> {
+        Ok(self
+            .revisions
+            .get(id)
+            .and_then(|revisions| revisions.iter().find(|r| r.revision == revision))
+            .cloned())
+    }
+}
+
+impl SessionStore for MemoryStore {
+    #[inline]
+    fn get_session(&mut self, id: &str) -> Result<Option<StoredSession>, StoreError> {
+        Ok(self.sessions.get(id).cloned())
+    }
+
+    #[inline]
+    fn put_session(&mut self, session: &StoredSession) -> Result<(), StoreError> {
+        self.sessions.insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    #[inline]
+    fn delete_session(&mut self, id: &str) -> Result<bool, StoreError> {
+        Ok(self.sessions.remove(id).is_some())
+    }
+
+    #[inline]
+    fn purge_expired_sessions(&mut self, now: i64) -> Result<u64, StoreError> {
+        let expired = self.expired_session_ids(now);
+        for id in &expired {
+            self.sessions.remove(id);
+        }
+        Ok(expired.len() as u64)
+    }
+}
+
+impl AccountStore for MemoryStore {
+    #[inline]
+    fn get_account(&mut self, id: i64) -> Result<Option<Account>, StoreError> {
+        Ok(self.accounts.get(&id).cloned())
+    }
+
+    #[inline]
+    fn get_account_by_name(&mut self, name: &str) -> Result<Option<Account>, StoreError> {
+        Ok(self
+            .accounts
+            .values()
+            .find(|account| account.name == name)
+            .cloned())
+    }
+
+    #[inline]
+    fn create_account(
+        &mut self,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<Account>, StoreError> {
+        if self.get_account_by_name(name)?.is_some() {
+            return Ok(None);
+        }
+        let account = Account {
+            id: self.next_account_id,
+            name: name.to_string(),
+            password_hash: password_hash.to_string(),
+            created_at: crate::store::unix_time(),
+        };
+        self.insert_account(account.clone());
+        Ok(Some(account))
+    }
+}
+
+impl ApiTokenStore for MemoryStore {
+    #[inline]
+    fn list_api_tokens(&mut self, account_id: i64) -> Result<Vec<ApiToken>, StoreError> {
+        Ok(self
+            .api_tokens
+            .values()
+            .filter(|token| token.account_id == account_id)
+            .cloned()
+            .collect())
+    }
+
+    #[inline]
+    fn get_api_token(&mut self, hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        Ok(self
+            .api_tokens
+            .values()
+            .find(|token| token.hash == hash)
+            .cloned())
+    }
+
+    #[inline]
+    fn create_api_token(
+        &mut self,
+        account_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<ApiToken, StoreError> {
+        let token = ApiToken {
+            id: self.next_api_token_id,
+            account_id,
+            name: name.to_string(),
+            hash: hash.to_string(),
+            scope,
+            created_at: crate::store::unix_time(),
+        };
+        self.insert_api_token(token.clone());
+        Ok(token)
+    }
+
+    #[inline]
+    fn delete_api_token(&mut self, account_id: i64, id: i64) -> Result<bool, StoreError> {
+        match self.api_tokens.get(&id) {
+            Some(token) if token.account_id == account_id => {
+                self.api_tokens.remove(&id);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryStore;
+
+    #[test]
+    fn test_memory_store() {
+        crate::store::test::test_todo_store(&mut MemoryStore::new());
+        crate::store::test::test_paste_store(&mut MemoryStore::new());
+        crate::store::test::test_session_store(&mut MemoryStore::new());
+        crate::store::test::test_account_store(&mut MemoryStore::new());
+        crate::store::test::test_api_token_store(&mut MemoryStore::new());
+    }
+}
@@ -0,0 +1,229 @@
+//! Versioned schema migrations for the PostgreSQL backend.
+//!
+//! Migrations live in the `migrations/` directory and are embedded in the
+//! binary. Each one is applied in its own transaction, together with a row in
+//! `pasta.migration` recording its version, so a failed migration leaves the
+//! database unchanged. A transaction-scoped advisory lock serializes
+//! concurrent runners, e.g. several instances starting at once.
+//!
+//! Migrations must never be edited once released; add a new one instead.
+#[cfg(target_arch = "wasm32")]
+use lunatic::{
+    process::{self, Process},
+    Mailbox,
+};
+
+use crate::postgres::Client;
+use crate::store::{StoreConfig, StoreError};
+
+/// Key of the advisory lock held while migrating.
+const LOCK_KEY: i64 = 0x7061_7374_6136; // "pasta6"
+
+pub(crate) struct Migration {
+    version: i64,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/// All migrations, ordered by version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_todo",
+        sql: include_str!("../../migrations/0001_create_todo.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_paste",
+        sql: include_str!("../../migrations/0002_create_paste.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "paste_expiry",
+        sql: include_str!("../../migrations/0003_paste_expiry.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "paste_revisions",
+        sql: include_str!("../../migrations/0004_paste_revisions.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "paste_protection",
+        sql: include_str!("../../migrations/0005_paste_protection.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "paste_files",
+        sql: include_str!("../../migrations/0006_paste_files.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "sessions",
+        sql: include_str!("../../migrations/0007_sessions.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "accounts",
+        sql: include_str!("../../migrations/0008_accounts.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "api_tokens",
+        sql: include_str!("../../migrations/0009_api_tokens.sql"),
+    },
+];
+
+/// Applies all pending migrations, returning the versions which were
+/// applied. Backends other than PostgreSQL have no schema, so this is a no-op
+/// for them.
+#[inline]
+pub(crate) fn migrate(config: &StoreConfig) -> Result<Vec<i64>, StoreError> {
+    match config {
+        StoreConfig::Postgres(config) => {
+            let mut client = Client::connect(config)?;
+            let applied = apply(&mut client, MIGRATIONS)?;
+            client.close()?;
+            Ok(applied)
+        }
+        StoreConfig::Memory | StoreConfig::File { .. } => {
+            tracing::info!("store {} has no migrations", config);
+            Ok(vec![])
+        }
+    }
+}
+
+/// Runs [`migrate`] in a new process and returns whether it succeeded. Like
+/// the server, this must run in a lunatic process for logging to work.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+pub(crate) fn migrate_in_process(config: StoreConfig) -> bool {
+    fn migrate_((parent, config): (Process<bool>, StoreConfig), _mailbox: Mailbox<()>) {
+        let success = match migrate(&config) {
+            Ok(applied) => {
+                tracing::info!("applied {} migration(s)", applied.len());
+                true
+            }
+            Err(e) => {
+                tracing::error!("migration failed: {}", e);
+                false
+            }
+        };
+        parent.send(success);
+    }
+    let mailbox = unsafe { Mailbox::new() };
+    let this = process::this(&mailbox);
+    crate::spawn_with!((this, config), migrate_).unwrap();
+    mailbox.receive().unwrap()
+}
+
+#[inline]
+fn apply(client: &mut Client, migrations: &[Migration]) -> Result<Vec<i64>, StoreError> {
+    client.transaction(|client| {
+        client.query("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+        client.batch_execute(
+            "CREATE SCHEMA IF NOT EXISTS pasta; \
+             CREATE TABLE IF NOT EXISTS pasta.migration ( \
+               version BIGINT PRIMARY KEY, \
+               name TEXT NOT NULL, \
+               applied_at TIMESTAMPTZ NOT NULL DEFAULT now() \
+             )",
+        )?;
+        Ok::<_, StoreError>(())
+    })?;
+
+    let mut applied = vec![];
+    for migration in migrations {
+        let was_applied = client.transaction(|client| {
+            client.query("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+            // Checked under the lock, in case another runner got here first.
+            let row = client.query_opt(
+                "SELECT name FROM pasta.migration WHERE version = $1",
+                &[&migration.version],
+            )?;
+            if let Some(row) = row {
+                let name: String = row.get(0)?;
+                if name != migration.name {
+                    tracing::warn!(
+                        "migration {} was applied as {:?}, expected {:?}",
+                        migration.version,
+                        name,
+                        migration.name
+                    );
+                }
+                return Ok(false);
+            }
+            tracing::info!(
+                "applying migration {} ({})",
+                migration.version,
+                migration.name
+            );
+            client.batch_execute(migration.sql)?;
+            client.execute(
+                "INSERT INTO pasta.migration (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )?;
+            Ok::<_, StoreError>(true)
+        })?;
+        if was_applied {
+            applied.push(migration.version);
+        }
+    }
+
+    let latest = migrations.last().map_or(0, |m| m.version);
+    let row = client.query_one("SELECT max(version) FROM pasta.migration", &[])?;
+    if let Some(version) = row.get::<Option<i64>>(0)? {
+        if version > latest {
+            tracing::warn!(
+                "database is at migration {}, newer than this binary ({})",
+                version,
+                latest
+            );
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, Migration, MIGRATIONS};
+    use crate::postgres::Client;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+        assert!(MIGRATIONS.iter().all(|m| m.version > 0));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut client = Client::connect(&crate::postgres::test::config()).unwrap();
+        apply(&mut client, MIGRATIONS).unwrap();
+        // Everything is applied now, so a second run is a no-op.
+        assert_eq!(apply(&mut client, MIGRATIONS).unwrap(), Vec::<i64>::new());
+        let row = client
+            .query_one("SELECT count(*) FROM pasta.migration WHERE version = 1", &[])
+            .unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let mut client = Client::connect(&crate::postgres::test::config()).unwrap();
+        let migrations = [Migration {
+            version: i64::MAX,
+            name: "broken",
+            sql: "CREATE TABLE pasta.broken (); SELECT 1 / 0",
+        }];
+        assert!(apply(&mut client, &migrations).is_err());
+        let row = client
+            .query_one(
+                "SELECT to_regclass('pasta.broken') IS NULL, \
+                   NOT EXISTS (SELECT 1 FROM pasta.migration WHERE version = $1)",
+                &[&i64::MAX],
+            )
+            .unwrap();
+        assert!(row.get::<bool>(0).unwrap());
+        assert!(row.get::<bool>(1).unwrap());
+    }
+}
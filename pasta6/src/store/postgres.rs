@@ -0,0 +1,662 @@
+use crate::postgres::{Client, Config, Row};
+use crate::store::{
+    Account, AccountStore, ApiToken, ApiTokenStore, NewRevision, Paste, PasteFile, PasteStore,
+    Revision, RevisionRef, SessionStore, StoreError, StoredSession, Todo, TodoStore, TokenScope,
+};
+use std::borrow::BorrowMut;
+
+/// A store backed by the `pasta` schema of a PostgreSQL database.
+///
+/// The store either owns its connection or borrows one from a pool.
+pub(crate) struct PostgresStore<C = Client> {
+    client: C,
+}
+
+impl PostgresStore {
+    #[inline]
+    pub(crate) fn connect(config: &Config) -> Result<Self, StoreError> {
+        Ok(Self::new(Client::connect(config)?))
+    }
+}
+
+impl<C: BorrowMut<Client>> PostgresStore<C> {
+    #[inline]
+    pub(crate) fn new(client: C) -> Self {
+        Self { client }
+    }
+
+    #[inline]
+    fn client(&mut self) -> &mut Client {
+        self.client.borrow_mut()
+    }
+}
+
+impl<C: BorrowMut<Client>> TodoStore for PostgresStore<C> {
+    #[inline]
+    fn list_todos(&mut self, owner: Option<i64>) -> Result<Vec<Todo>, StoreError> {
+        self.client()
+            .query(
+                "SELECT id, content, owner_id FROM pasta.todo \
+                 WHERE owner_id IS NOT DISTINCT FROM $1 ORDER BY id",
+                &[&owner],
+            )?
+            .iter()
+            .map(todo_from_row)
+            .collect()
+    }
+
+    #[inline]
+    fn get_todo(&mut self, id: i64) -> Result<Option<Todo>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, content, owner_id FROM pasta.todo WHERE id = $1",
+                &[&id],
+            )?
+            .as_ref()
+            .map(todo_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn create_todo(&mut self, content: &str, owner: Option<i64>) -> Result<Todo, StoreError> {
+        let row = self.client().query_one(
+            "INSERT INTO pasta.todo (content, owner_id) VALUES ($1, $2) \
+             RETURNING id, content, owner_id",
+            &[&content, &owner],
+        )?;
+        todo_from_row(&row)
+    }
+
+    #[inline]
+    fn delete_todo(&mut self, id: i64) -> Result<bool, StoreError> {
+        let deleted = self
+            .client()
+            .execute("DELETE FROM pasta.todo WHERE id = $1", &[&id])?;
+        Ok(deleted > 0)
+    }
+}
+
+impl<C: BorrowMut<Client>> PasteStore for PostgresStore<C> {
+    #[inline]
+    fn get_paste(&mut self, id: &str) -> Result<Option<Paste>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, title, language, content, \
+                   extract(epoch FROM created_at)::BIGINT, \
+                   extract(epoch FROM expires_at)::BIGINT, \
+                   burn_after_reading, revision, parent_id, parent_revision, protected, \
+                   file_name, file_type, file_size, file_hash, owner_id \
+                 FROM pasta.paste WHERE id = $1",
+                &[&id],
+            )?
+            .as_ref()
+            .map(paste_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn list_paste_ids(&mut self) -> Result<Vec<String>, StoreError> {
+        self.client()
+            .query("SELECT id FROM pasta.paste ORDER BY id COLLATE \"C\"", &[])?
+            .iter()
+            .map(|row| Ok(row.get(0)?))
+            .collect()
+    }
+
+    #[inline]
+    fn insert_paste(&mut self, paste: &Paste) -> Result<bool, StoreError> {
+        let parent_id = paste.parent.as_ref().map(|parent| &parent.paste_id);
+        let parent_revision = paste.parent.as_ref().map(|parent| parent.revision);
+        let file = paste.file.as_ref();
+        // A single statement, so that the paste is never without its
+        // revision.
+        let inserted = self.client().execute(
+            "WITH paste AS ( \
+               INSERT INTO pasta.paste \
+                 (id, title, language, content, created_at, expires_at, burn_after_reading, \
+                  revision, parent_id, parent_revision, protected, \
+                  file_name, file_type, file_size, file_hash, owner_id) \
+               VALUES ($1, $2, $3, $4, to_timestamp($5), to_timestamp($6), $7, $8, $9, $10, \
+                 $11, $12, $13, $14, $15, $16) \
+               ON CONFLICT (id) DO NOTHING \
+               RETURNING id, revision, title, language, content, created_at \
+             ) \
+             INSERT INTO pasta.paste_revision \
+               (paste_id, revision, title, language, content, created_at) \
+             SELECT * FROM paste",
+            &[
+                &paste.id,
+                &paste.title,
+                &paste.language,
+                &paste.content,
+                &paste.created_at,
+                &paste.expires_at,
+                &paste.burn_after_reading,
+                &paste.revision,
+                &parent_id,
+                &parent_revision,
+                &paste.protected,
+                &file.map(|file| &file.name),
+                &file.map(|file| &file.media_type),
+                &file.map(|file| file.size),
+                &file.map(|file| &file.hash),
+                &paste.owner,
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    #[inline]
+    fn delete_paste(&mut self, id: &str) -> Result<bool, StoreError> {
+        let deleted = self
+            .client()
+            .execute("DELETE FROM pasta.paste WHERE id = $1", &[&id])?;
+        Ok(deleted > 0)
+    }
+
+    #[inline]
+    fn purge_expired_pastes(&mut self, now: i64) -> Result<u64, StoreError> {
+        Ok(self.client().execute(
+            "DELETE FROM pasta.paste WHERE expires_at <= to_timestamp($1)",
+            &[&now],
+        )?)
+    }
+
+    #[inline]
+    fn put_blob(&mut self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        self.client().execute(
+            "INSERT INTO pasta.blob (hash, data, stored_at) VALUES ($1, $2, now()) \
+             ON CONFLICT (hash) DO UPDATE SET stored_at = now()",
+            &[&hash, &data],
+        )?;
+        Ok(())
+    }
+
+    #[inline]
+    fn get_blob(&mut self, hash: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        self.client()
+            .query_opt("SELECT data FROM pasta.blob WHERE hash = $1", &[&hash])?
+            .map(|row| Ok(row.get(0)?))
+            .transpose()
+    }
+
+    #[inline]
+    fn purge_orphaned_blobs(&mut self, before: i64) -> Result<u64, StoreError> {
+        Ok(self.client().execute(
+            "DELETE FROM pasta.blob \
+             WHERE stored_at <= to_timestamp($1) \
+               AND NOT EXISTS (SELECT FROM pasta.paste WHERE file_hash = blob.hash)",
+            &[&before],
+        )?)
+    }
+
+    #[inline]
+    fn add_revision(
+        &mut self,
+        id: &str,
+        new_revision: NewRevision,
+    ) -> Result<Option<Revision>, StoreError> {
+        // Updating the paste locks its row, so concurrent revisions are
+        // numbered one after the other.
+        self.client()
+            .query_opt(
+                "WITH paste AS ( \
+                   UPDATE pasta.paste \
+                   SET revision = revision + 1, title = $2, language = $3, content = $4 \
+                   WHERE id = $1 \
+                   RETURNING id, revision, title, language, content, now() AS created_at \
+                 ) \
+                 INSERT INTO pasta.paste_revision \
+                   (paste_id, revision, title, language, content, created_at) \
+                 SELECT * FROM paste \
+                 RETURNING revision, title, language, content, \
+                   extract(epoch FROM created_at)::BIGINT",
+                &[
+                    &id,
+                    &new_revision.title,
+                    &new_revision.language,
+                    &new_revision.content,
+                ],
+            )?
+            .as_ref()
+            .map(revision_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn list_revisions(&mut self, id: &str) -> Result<Vec<Revision>, StoreError> {
+        self.client()
+            .query(
+                "SELECT revision, title, language, content, \
+                   extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.paste_revision WHERE paste_id = $1 ORDER BY revision",
+                &[&id],
+            )?
+            .iter()
+            .map(revision_from_row)
+            .collect()
+    }
+
+    #[inline]
+    fn get_revision(&mut self, id: &str, revision: i32) -> Result<Option<Revision>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT revision, title, language, content, \
+                   extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.paste_revision WHERE paste_id = $1 AND revision = $2",
+                &[&id, &revision],
+            )?
+            .as_ref()
+            .map(revision_from_row)
+            .transpose()
+    }
+}
+
+impl<C: BorrowMut<Client>> SessionStore for PostgresStore<C> {
+    #[inline]
+    fn get_session(&mut self, id: &str) -> Result<Option<StoredSession>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, data, extract(epoch FROM expires_at)::BIGINT \
+                 FROM pasta.session WHERE id = $1",
+                &[&id],
+            )?
+            .as_ref()
+            .map(session_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn put_session(&mut self, session: &StoredSession) -> Result<(), StoreError> {
+        self.client().execute(
+            "INSERT INTO pasta.session (id, data, expires_at) VALUES ($1, $2, to_timestamp($3)) \
+             ON CONFLICT (id) DO UPDATE SET data = $2, expires_at = to_timestamp($3)",
+            &[&session.id, &session.data, &session.expires_at],
+        )?;
+        Ok(())
+    }
+
+    #[inline]
+    fn delete_session(&mut self, id: &str) -> Result<bool, StoreError> {
+        let deleted = self
+            .client()
+            .execute("DELETE FROM pasta.session WHERE id = $1", &[&id])?;
+        Ok(deleted > 0)
+    }
+
+    #[inline]
+    fn purge_expired_sessions(&mut self, now: i64) -> Result<u64, StoreError> {
+        Ok(self.client().execute(
+            "DELETE FROM pasta.session WHERE expires_at <= to_timestamp($1)",
+            &[&now],
+        )?)
+    }
+}
+
+impl<C: BorrowMut<Client>> AccountStore for PostgresStore<C> {
+    #[inline]
+    fn get_account(&mut self, id: i64) -> Result<Option<Account>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, name, password_hash, extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.account WHERE id = $1",
+                &[&id],
+            )?
+            .as_ref()
+            .map(account_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn get_account_by_name(&mut self, name: &str) -> Result<Option<Account>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, name, password_hash, extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.account WHERE name = $1",
+                &[&name],
+            )?
+            .as_ref()
+            .map(account_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn create_account(
+        &mut self,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<Account>, StoreError> {
+        self.client()
+            .query_opt(
+                "INSERT INTO pasta.account (name, password_hash, created_at) \
+                 VALUES ($1, $2, now()) \
+                 ON CONFLICT (name) DO NOTHING \
+                 RETURNING id, name, password_hash, extract(epoch FROM created_at)::BIGINT",
+                &[&name, &password_hash],
+            )?
+            .as_ref()
+            .map(account_from_row)
+            .transpose()
+    }
+}
+
+impl<C: BorrowMut<Client>> ApiTokenStore for PostgresStore<C> {
+    #[inline]
+    fn list_api_tokens(&mut self, account_id: i64) -> Result<Vec<ApiToken>, StoreError> {
+        self.client()
+            .query(
+                "SELECT id, account_id, name, hash, scope, \
+                 extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.api_token WHERE account_id = $1 ORDER BY id",
+                &[&account_id],
+            )?
+            .iter()
+            .map(api_token_from_row)
+            .collect()
+    }
+
+    #[inline]
+    fn get_api_token(&mut self, hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        self.client()
+            .query_opt(
+                "SELECT id, account_id, name, hash, scope, \
+                 extract(epoch FROM created_at)::BIGINT \
+                 FROM pasta.api_token WHERE hash = $1",
+                &[&hash],
+            )?
+            .as_ref()
+            .map(api_token_from_row)
+            .transpose()
+    }
+
+    #[inline]
+    fn create_api_token(
+        &mut self,
+        account_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<ApiToken, StoreError> {
+        let row = self.client().query_one(
+            "INSERT INTO pasta.api_token (account_id, name, hash, scope, created_at) \
+             VALUES ($1, $2, $3, $4, now()) \
+             RETURNING id, account_id, name, hash, scope, \
+             extract(epoch FROM created_at)::BIGINT",
+            &[&account_id, &name, &hash, &scope.as_str()],
+        )?;
+        api_token_from_row(&row)
+    }
+
+    #[inline]
+    fn delete_api_token(&mut self, account_id: i64, id: i64) -> Result<bool, StoreError> {
+        let deleted = self.client().execute(
+            "DELETE FROM pasta.api_token WHERE id = $1 AND account_id = $2",
+            &[&id, &account_id],
+        )?;
+        Ok(deleted > 0)
+    }
+}
+
+/// Checks out a connection for every call, so that a connection is only
+/// held for the duration of a single query.
+#[cfg(target_arch = "wasm32")]
+impl TodoStore for crate::postgres::Pool {
+    #[inline]
+    fn list_todos(&mut self, owner: Option<i64>) -> Result<Vec<Todo>, StoreError> {
+        PostgresStore::new(self.get()?).list_todos(owner)
+    }
+
+    #[inline]
+    fn get_todo(&mut self, id: i64) -> Result<Option<Todo>, StoreError> {
+        PostgresStore::new(self.get()?).get_todo(id)
+    }
+
+    #[inline]
+    fn create_todo(&mut self, content: &str, owner: Option<i64>) -> Result<Todo, StoreError> {
+        PostgresStore::new(self.get()?).create_todo(content, owner)
+    }
+
+    #[inline]
+    fn delete_todo(&mut self, id: i64) -> Result<bool, StoreError> {
+        PostgresStore::new(self.get()?).delete_todo(id)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl PasteStore for crate::postgres::Pool {
+    #[inline]
+    fn get_paste(&mut self, id: &str) -> Result<Option<Paste>, StoreError> {
+        PostgresStore::new(self.get()?).get_paste(id)
+    }
+
+    #[inline]
+    fn list_paste_ids(&mut self) -> Result<Vec<String>, StoreError> {
+        PostgresStore::new(self.get()?).list_paste_ids()
+    }
+
+    #[inline]
+    fn insert_paste(&mut self, paste: &Paste) -> Result<bool, StoreError> {
+        PostgresStore::new(self.get()?).insert_paste(paste)
+    }
+
+    #[inline]
+    fn delete_paste(&mut self, id: &str) -> Result<bool, StoreError> {
+        PostgresStore::new(self.get()?).delete_paste(id)
+    }
+
+    #[inline]
+    fn purge_expired_pastes(&mut self, now: i64) -> Result<u64, StoreError> {
+        PostgresStore::new(self.get()?).purge_expired_pastes(now)
+    }
+
+    #[inline]
+    fn put_blob(&mut self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        PostgresStore::new(self.get()?).put_blob(hash, data)
+    }
+
+    #[inline]
+    fn get_blob(&mut self, hash: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        PostgresStore::new(self.get()?).get_blob(hash)
+    }
+
+    #[inline]
+    fn purge_orphaned_blobs(&mut self, before: i64) -> Result<u64, StoreError> {
+        PostgresStore::new(self.get()?).purge_orphaned_blobs(before)
+    }
+
+    #[inline]
+    fn add_revision(
+        &mut self,
+        id: &str,
+        new_revision: NewRevision,
+    ) -> Result<Option<Revision>, StoreError> {
+        PostgresStore::new(self.get()?).add_revision(id, new_revision)
+    }
+
+    #[inline]
+    fn list_revisions(&mut self, id: &str) -> Result<Vec<Revision>, StoreError> {
+        PostgresStore::new(self.get()?).list_revisions(id)
+    }
+
+    #[inline]
+    fn get_revision(&mut self, id: &str, revision: i32) -> Result<Option<Revision>, StoreError> {
+        PostgresStore::new(self.get()?).get_revision(id, revision)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl SessionStore for crate::postgres::Pool {
+    #[inline]
+    fn get_session(&mut self, id: &str) -> Result<Option<StoredSession>, StoreError> {
+        PostgresStore::new(self.get()?).get_session(id)
+    }
+
+    #[inline]
+    fn put_session(&mut self, session: &StoredSession) -> Result<(), StoreError> {
+        PostgresStore::new(self.get()?).put_session(session)
+    }
+
+    #[inline]
+    fn delete_session(&mut self, id: &str) -> Result<bool, StoreError> {
+        PostgresStore::new(self.get()?).delete_session(id)
+    }
+
+    #[inline]
+    fn purge_expired_sessions(&mut self, now: i64) -> Result<u64, StoreError> {
+        PostgresStore::new(self.get()?).purge_expired_sessions(now)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl AccountStore for crate::postgres::Pool {
+    #[inline]
+    fn get_account(&mut self, id: i64) -> Result<Option<Account>, StoreError> {
+        PostgresStore::new(self.get()?).get_account(id)
+    }
+
+    #[inline]
+    fn get_account_by_name(&mut self, name: &str) -> Result<Option<Account>, StoreError> {
+        PostgresStore::new(self.get()?).get_account_by_name(name)
+    }
+
+    #[inline]
+    fn create_account(
+        &mut self,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<Account>, StoreError> {
+        PostgresStore::new(self.get()?).create_account(name, password_hash)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl ApiTokenStore for crate::postgres::Pool {
+    #[inline]
+    fn list_api_tokens(&mut self, account_id: i64) -> Result<Vec<ApiToken>, StoreError> {
+        PostgresStore::new(self.get()?).list_api_tokens(account_id)
+    }
+
+    #[inline]
+    fn get_api_token(&mut self, hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        PostgresStore::new(self.get()?).get_api_token(hash)
+    }
+
+    #[inline]
+    fn create_api_token(
+        &mut self,
+        account_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<ApiToken, StoreError> {
+        PostgresStore::new(self.get()?).create_api_token(account_id, name, hash, scope)
+    }
+
+    #[inline]
+    fn delete_api_token(&mut self, account_id: i64, id: i64) -> Result<bool, StoreError> {
+        PostgresStore::new(self.get()?).delete_api_token(account_id, id)
+    }
+}
+
+#[inline]
+fn todo_from_row(row: &Row) -> Result<Todo, StoreError> {
+    Ok(Todo {
+        id: row.get(0)?,
+        content: row.get(1)?,
+        owner: row.get(2)?,
+    })
+}
+
+#[inline]
+fn paste_from_row(row: &Row) -> Result<Paste, StoreError> {
+    Ok(Paste {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        language: row.get(2)?,
+        content: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        burn_after_reading: row.get(6)?,
+        revision: row.get(7)?,
+        parent: match (row.get(8)?, row.get(9)?) {
+            (Some(paste_id), Some(revision)) => Some(RevisionRef { paste_id, revision }),
+            _ => None,
+        },
+        protected: row.get(10)?,
+        file: match (row.get(11)?, row.get(12)?, row.get(13)?, row.get(14)?) {
+            (Some(name), Some(media_type), Some(size), Some(hash)) => Some(PasteFile {
+                name,
+                media_type,
+                size,
+                hash,
+            }),
+            _ => None,
+        },
+        owner: row.get(15)?,
+    })
+}
+
+#[inline]
+fn revision_from_row(row: &Row) -> Result<Revision, StoreError> {
+    Ok(Revision {
+        revision: row.get(0)?,
+        title: row.get(1)?,
+        language: row.get(2)?,
+        content: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+#[inline]
+fn account_from_row(row: &Row) -> Result<Account, StoreError> {
+    Ok(Account {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        password_hash: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+#[inline]
+fn api_token_from_row(row: &Row) -> Result<ApiToken, StoreError> {
+    let scope: String = row.get(4)?;
+    Ok(ApiToken {
+        id: row.get(0)?,
+        account_id: row.get(1)?,
+        name: row.get(2)?,
+        hash: row.get(3)?,
+        scope: TokenScope::from_name(&scope)
+            .ok_or_else(|| StoreError::corrupt(format!("unknown token scope: {}", scope)))?,
+        created_at: row.get(5)?,
+    })
+}
+
+#[inline]
+fn session_from_row(row: &Row) -> Result<StoredSession, StoreError> {
+    Ok(StoredSession {
+        id: row.get(0)?,
+        data: row.get(1)?,
+        expires_at: row.get(2)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::PostgresStore;
+
+    #[test]
+    fn test_postgres_store() {
+        let mut store = PostgresStore::connect(&crate::postgres::test::config()).unwrap();
+        // Run inside a transaction which is never committed, so the test
+        // leaves the database untouched.
+        store.client.batch_execute("BEGIN; DELETE FROM pasta.todo").unwrap();
+        crate::store::test::test_todo_store(&mut store);
+        crate::store::test::test_paste_store(&mut store);
+        crate::store::test::test_session_store(&mut store);
+        store.client.batch_execute("DELETE FROM pasta.account").unwrap();
+        crate::store::test::test_account_store(&mut store);
+        store.client.batch_execute("DELETE FROM pasta.account").unwrap();
+        crate::store::test::test_api_token_store(&mut store);
+        store.client.batch_execute("ROLLBACK").unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+use crate::store::{
+    Account, AccountStore, ApiToken, ApiTokenStore, NewRevision, Paste, PasteStore, Revision,
+    SessionStore, Store, StoreConfig, StoreError, StoredSession, Todo, TodoStore, TokenScope,
+};
+use lunatic::process::Process;
+use lunatic::{Mailbox, Request};
+use serde::{Deserialize, Serialize};
+
+type StoreMailbox = Mailbox<Request<StoreRequest, Result<StoreResponse, StoreError>>>;
+
+/// A handle to a process which owns a store.
+///
+/// The handle can be sent to other processes and implements [`Store`] by
+/// forwarding every call to the store process, so that all connection
+/// processes see the same data.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct StoreProcess {
+    process: Process<Request<StoreRequest, Result<StoreResponse, StoreError>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoreRequest {
+    Ping,
+    ListTodos(Option<i64>),
+    GetTodo(i64),
+    CreateTodo(String, Option<i64>),
+    DeleteTodo(i64),
+    GetPaste(String),
+    ListPasteIds,
+    InsertPaste(Paste),
+    DeletePaste(String),
+    PurgeExpiredPastes(i64),
+    PutBlob { hash: String, data: Vec<u8> },
+    GetBlob(String),
+    PurgeOrphanedBlobs(i64),
+    AddRevision {
+        id: String,
+        title: Option<String>,
+        language: Option<String>,
+        content: String,
+    },
+    ListRevisions(String),
+    GetRevision(String, i32),
+    GetSession(String),
+    PutSession(StoredSession),
+    DeleteSession(String),
+    PurgeExpiredSessions(i64),
+    GetAccount(i64),
+    GetAccountByName(String),
+    CreateAccount { name: String, password_hash: String },
+    ListApiTokens(i64),
+    GetApiToken(String),
+    CreateApiToken {
+        account_id: i64,
+        name: String,
+        hash: String,
+        scope: TokenScope,
+    },
+    DeleteApiToken { account_id: i64, id: i64 },
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoreResponse {
+    Pong,
+    Todos(Vec<Todo>),
+    Todo(Option<Todo>),
+    Deleted(bool),
+    Paste(Option<Paste>),
+    PasteIds(Vec<String>),
+    Inserted(bool),
+    Purged(u64),
+    Stored,
+    Blob(Option<Vec<u8>>),
+    Revision(Option<Revision>),
+    Revisions(Vec<Revision>),
+    Session(Option<StoredSession>),
+    Account(Option<Account>),
+    ApiTokens(Vec<ApiToken>),
+    ApiToken(Option<ApiToken>),
+}
+
+impl StoreProcess {
+    /// Spawns a store process and waits for it to open the backend.
+    #[inline]
+    pub(crate) fn spawn(config: StoreConfig) -> Result<Self, StoreError> {
+        tracing::info!("spawning store process");
+        let process = crate::spawn_with!(config, run).map_err(StoreError::unavailable)?;
+        let store = Self { process };
+        // Make sure the backend opened successfully, so that configuration
+        // errors are reported at startup rather than on the first request.
+        store.call(StoreRequest::Ping)?;
+        Ok(store)
+    }
+
+    #[inline]
+    fn call(&self, request: StoreRequest) -> Result<StoreResponse, StoreError> {
+        self.process
+            .request(request)
+            .map_err(StoreError::unavailable)?
+    }
+}
+
+impl TodoStore for StoreProcess {
+    #[inline]
+    fn list_todos(&mut self, owner: Option<i64>) -> Result<Vec<Todo>, StoreError> {
+        match self.call(StoreRequest::ListTodos(owner))? {
+            StoreResponse::Todos(todos) => Ok(todos),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn get_todo(&mut self, id: i64) -> Result<Option<Todo>, StoreError> {
+        match self.call(StoreRequest::GetTodo(id))? {
+            StoreResponse::Todo(todo) => Ok(todo),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn create_todo(&mut self, content: &str, owner: Option<i64>) -> Result<Todo, StoreError> {
+        match self.call(StoreRequest::CreateTodo(content.to_string(), owner))? {
+            StoreResponse::Todo(Some(todo)) => Ok(todo),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn delete_todo(&mut self, id: i64) -> Result<bool, StoreError> {
+        match self.call(StoreRequest::DeleteTodo(id))? {
+            StoreResponse::Deleted(deleted) => Ok(deleted),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+impl PasteStore for StoreProcess {
+    #[inline]
+    fn get_paste(&mut self, id: &str) -> Result<Option<Paste>, StoreError> {
+        match self.call(StoreRequest::GetPaste(id.to_string()))? {
+            StoreResponse::Paste(paste) => Ok(paste),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn list_paste_ids(&mut self) -> Result<Vec<String>, StoreError> {
+        match self.call(StoreRequest::ListPasteIds)? {
+            StoreResponse::PasteIds(ids) => Ok(ids),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn insert_paste(&mut self, paste: &Paste) -> Result<bool, StoreError> {
+        match self.call(StoreRequest::InsertPaste(paste.clone()))? {
+            StoreResponse::Inserted(inserted) => Ok(inserted),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn delete_paste(&mut self, id: &str) -> Result<bool, StoreError> {
+        match self.call(StoreRequest::DeletePaste(id.to_string()))? {
+            StoreResponse::Deleted(deleted) => Ok(deleted),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn purge_expired_pastes(&mut self, now: i64) -> Result<u64, StoreError> {
+        match self.call(StoreRequest::PurgeExpiredPastes(now))? {
+            StoreResponse::Purged(purged) => Ok(purged),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn put_blob(&mut self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        let request = StoreRequest::PutBlob {
+            hash: hash.to_string(),
+            data: data.to_vec(),
+        };
+        match self.call(request)? {
+            StoreResponse::Stored => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn get_blob(&mut self, hash: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match self.call(StoreRequest::GetBlob(hash.to_string()))? {
+            StoreResponse::Blob(data) => Ok(data),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn purge_orphaned_blobs(&mut self, before: i64) -> Result<u64, StoreError> {
+        match self.call(StoreRequest::PurgeOrphanedBlobs(before))? {
+            StoreResponse::Purged(purged) => Ok(purged),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn add_revision(
+        &mut self,
+        id: &str,
+        new_revision: NewRevision,
+    ) -> Result<Option<Revision>, StoreError> {
+        let request = StoreRequest::AddRevision {
+            id: id.to_string(),
+            title: new_revision.title.map(str::to_string),
+            language: new_revision.language.map(str::to_string),
+            content: new_revision.content.to_string(),
+        };
+        match self.call(request)? {
+            StoreResponse::Revision(revision) => Ok(revision),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn list_revisions(&mut self, id: &str) -> Result<Vec<Revision>, StoreError> {
+        match self.call(StoreRequest::ListRevisions(id.to_string()))? {
+            StoreResponse::Revisions(revisions) => Ok(revisions),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn get_revision(&mut self, id: &str, revision: i32) -> Result<Option<Revision>, StoreError> {
+        match self.call(StoreRequest::GetRevision(id.to_string(), revision))? {
+            StoreResponse::Revision(revision) => Ok(revision),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+impl SessionStore for StoreProcess {
+    #[inline]
+    fn get_session(&mut self, id: &str) -> Result<Option<StoredSession>, StoreError> {
+        match self.call(StoreRequest::GetSession(id.to_string()))? {
+            StoreResponse::Session(session) => Ok(session),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn put_session(&mut self, session: &StoredSession) -> Result<(), StoreError> {
+        match self.call(StoreRequest::PutSession(session.clone()))? {
+            StoreResponse::Stored => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn delete_session(&mut self, id: &str) -> Result<bool, StoreError> {
+        match self.call(StoreRequest::DeleteSession(id.to_string()))? {
+            StoreResponse::Deleted(deleted) => Ok(deleted),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn purge_expired_sessions(&mut self, now: i64) -> Result<u64, StoreError> {
+        match self.call(StoreRequest::PurgeExpiredSessions(now))? {
+            StoreResponse::Purged(purged) => Ok(purged),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+impl AccountStore for StoreProcess {
+    #[inline]
+    fn get_account(&mut self, id: i64) -> Result<Option<Account>, StoreError> {
+        match self.call(StoreRequest::GetAccount(id))? {
+            StoreResponse::Account(account) => Ok(account),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn get_account_by_name(&mut self, name: &str) -> Result<Option<Account>, StoreError> {
+        match self.call(StoreRequest::GetAccountByName(name.to_string()))? {
+            StoreResponse::Account(account) => Ok(account),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn create_account(
+        &mut self,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<Account>, StoreError> {
+        let request = StoreRequest::CreateAccount {
+            name: name.to_string(),
+            password_hash: password_hash.to_string(),
+        };
+        match self.call(request)? {
+            StoreResponse::Account(account) => Ok(account),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+impl ApiTokenStore for StoreProcess {
+    #[inline]
+    fn list_api_tokens(&mut self, account_id: i64) -> Result<Vec<ApiToken>, StoreError> {
+        match self.call(StoreRequest::ListApiTokens(account_id))? {
+            StoreResponse::ApiTokens(tokens) => Ok(tokens),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn get_api_token(&mut self, hash: &str) -> Result<Option<ApiToken>, StoreError> {
+        match self.call(StoreRequest::GetApiToken(hash.to_string()))? {
+            StoreResponse::ApiToken(token) => Ok(token),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn create_api_token(
+        &mut self,
+        account_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<ApiToken, StoreError> {
+        let request = StoreRequest::CreateApiToken {
+            account_id,
+            name: name.to_string(),
+            hash: hash.to_string(),
+            scope,
+        };
+        match self.call(request)? {
+            StoreResponse::ApiToken(Some(token)) => Ok(token),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    #[inline]
+    fn delete_api_token(&mut self, account_id: i64, id: i64) -> Result<bool, StoreError> {
+        match self.call(StoreRequest::DeleteApiToken { account_id, id })? {
+            StoreResponse::Deleted(deleted) => Ok(deleted),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+/// Entry point of the store process.
+#[inline]
+fn run(config: StoreConfig, mailbox: StoreMailbox) {
+    let mut store = config.open();
+    if let Err(e) = &store {
+        tracing::error!("store error: {}", e);
+    }
+    loop {
+        let request = match mailbox.receive() {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("receive error: {}", e);
+                continue;
+            }
+        };
+        let response = match &mut store {
+            Ok(store) => handle(store.as_mut(), request.data()),
+            // Keep answering with the error so that callers don't block
+            // forever.
+            Err(e) => Err(StoreError::unavailable(&*e)),
+        };
+        request.reply(response);
+    }
+}
+
+#[inline]
+fn handle(store: &mut dyn Store, request: &StoreRequest) -> Result<StoreResponse, StoreError> {
+    Ok(match request {
+        StoreRequest::Ping => StoreResponse::Pong,
+        StoreRequest::ListTodos(owner) => StoreResponse::Todos(store.list_todos(*owner)?),
+        StoreRequest::GetTodo(id) => StoreResponse::Todo(store.get_todo(*id)?),
+        StoreRequest::CreateTodo(content, owner) => {
+            StoreResponse::Todo(Some(store.create_todo(content, *owner)?))
+        }
+        StoreRequest::DeleteTodo(id) => StoreResponse::Deleted(store.delete_todo(*id)?),
+        StoreRequest::GetPaste(id) => StoreResponse::Paste(store.get_paste(id)?),
+        StoreRequest::ListPasteIds => StoreResponse::PasteIds(store.list_paste_ids()?),
+        StoreRequest::InsertPaste(paste) => StoreResponse::Inserted(store.insert_paste(paste)?),
+        StoreRequest::DeletePaste(id) => StoreResponse::Deleted(store.delete_paste(id)?),
+        StoreRequest::PurgeExpiredPastes(now) => {
+            StoreResponse::Purged(store.purge_expired_pastes(*now)?)
+        }
+        StoreRequest::PutBlob { hash, data } => {
+            store.put_blob(hash, data)?;
+            StoreResponse::Stored
+        }
+        StoreRequest::GetBlob(hash) => StoreResponse::Blob(store.get_blob(hash)?),
+        StoreRequest::PurgeOrphanedBlobs(before) => {
+            StoreResponse::Purged(store.purge_orphaned_blobs(*before)?)
+        }
+        StoreRequest::AddRevision {
+            id,
+            title,
+            language,
+            content,
+        } => {
+            let new_revision = NewRevision {
+                title: title.as_deref(),
+                language: language.as_deref(),
+                content,
+            };
+            StoreResponse::Revision(store.add_revision(id, new_revision)?)
+        }
+        StoreRequest::ListRevisions(id) => StoreResponse::Revisions(store.list_revisions(id)?),
+        StoreRequest::GetRevision(id, revision) => {
+            StoreResponse::Revision(store.get_revision(id, *revision)?)
+        }
+        StoreRequest::GetSession(id) => StoreResponse::Session(store.get_session(id)?),
+        StoreRequest::PutSession(session) => {
+            store.put_session(session)?;
+            StoreResponse::Stored
+        }
+        StoreRequest::DeleteSession(id) => StoreResponse::Deleted(store.delete_session(id)?),
+        StoreRequest::PurgeExpiredSessions(now) => {
+            StoreResponse::Purged(store.purge_expired_sessions(*now)?)
+        }
+        StoreRequest::GetAccount(id) => StoreResponse::Account(store.get_account(*id)?),
+        StoreRequest::GetAccountByName(name) => {
+            StoreResponse::Account(store.get_account_by_name(name)?)
+        }
+        StoreRequest::CreateAccount {
+            name,
+            password_hash,
+        } => StoreResponse::Account(store.create_account(name, password_hash)?),
+        StoreRequest::ListApiTokens(account_id) => {
+            StoreResponse::ApiTokens(store.list_api_tokens(*account_id)?)
+        }
+        StoreRequest::GetApiToken(hash) => StoreResponse::ApiToken(store.get_api_token(hash)?),
+        StoreRequest::CreateApiToken {
+            account_id,
+            name,
+            hash,
+            scope,
+        } => StoreResponse::ApiToken(Some(store.create_api_token(
+            *account_id,
+            name,
+            hash,
+            *scope,
+        )?)),
+        StoreRequest::DeleteApiToken { account_id, id } => {
+            StoreResponse::Deleted(store.delete_api_token(*account_id, *id)?)
+        }
+    })
+}
+
+#[inline]
+fn unexpected_response() -> StoreError {
+    StoreError::unavailable("unexpected response from store process")
+}
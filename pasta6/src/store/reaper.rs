@@ -0,0 +1,66 @@
+//! A background process which purges expired pastes and sessions, and the
+//! blobs of uploaded files which are no longer part of any paste.
+//!
+//! Expired pastes and sessions can't be used even before they're purged, so
+//! the reaper only has to run often enough to keep them from piling up.
+use crate::store::{Store, StoreError, StoreHandle};
+use lunatic::{
+    process::{self, Process},
+    Mailbox,
+};
+use std::time::Duration;
+
+/// Time between two purges.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+/// Time a blob is kept without a paste, which is how long the paste has to
+/// be created after the blob is stored.
+const ORPHANED_BLOB_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Spawns the reaper process.
+#[inline]
+pub(crate) fn spawn_reaper(store: StoreHandle) -> Result<Process<()>, StoreError> {
+    tracing::info!("spawning reaper process");
+    crate::spawn_with!(store, run).map_err(StoreError::unavailable)
+}
+
+/// Entry point of the reaper process.
+#[inline]
+fn run(store_handle: StoreHandle, _mailbox: Mailbox<()>) {
+    let mut store: Option<Box<dyn Store>> = None;
+    loop {
+        let result = match store.take() {
+            Some(opened) => Ok(opened),
+            None => store_handle.open(),
+        }
+        .and_then(|mut opened| {
+            let now = crate::store::unix_time();
+            let purged = opened.purge_expired_pastes(now).and_then(|pastes| {
+                let before = now - ORPHANED_BLOB_LIFETIME.as_secs() as i64;
+                let blobs = opened.purge_orphaned_blobs(before)?;
+                Ok((pastes, blobs, opened.purge_expired_sessions(now)?))
+            });
+            // Keep the store open for the next purge.
+            store = Some(opened);
+            purged
+        });
+        match result {
+            Ok((pastes, blobs, sessions)) => {
+                if pastes > 0 {
+                    tracing::info!("purged {} expired paste(s)", pastes);
+                }
+                if blobs > 0 {
+                    tracing::info!("purged {} orphaned blob(s)", blobs);
+                }
+                if sessions > 0 {
+                    tracing::info!("purged {} expired session(s)", sessions);
+                }
+            }
+            Err(e) => {
+                tracing::error!("failed to purge: {}", e);
+                // Reopen the store on the next attempt in case it went away.
+                store = None;
+            }
+        }
+        process::sleep(REAP_INTERVAL.as_millis() as u64);
+    }
+}
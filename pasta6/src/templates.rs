@@ -0,0 +1,234 @@
+//! HTML pages, rendered from the templates in `templates/`.
+//!
+//! Templates are compiled into the binary, so a mistake such as a missing
+//! field is a build error. Pages extend `layout.html`, and share partials
+//! such as `expiry.html`. Everything interpolated is HTML-escaped, except
+//! values filtered with `safe`, which must be HTML we produced ourselves,
+//! e.g. highlighted code or rendered Markdown.
+use crate::diff::{Change, Hunk};
+use crate::http::Response;
+use crate::store::{Account, ApiToken, Paste, PasteFile, Revision, Todo};
+use askama::Template;
+
+/// The home page, listing the todos.
+#[derive(Template)]
+#[template(path = "index.html")]
+pub(crate) struct IndexPage<'a> {
+    /// The todos of the signed-in account, or the anonymous ones.
+    pub(crate) todos: &'a [Todo],
+    pub(crate) account: Option<&'a Account>,
+    /// Messages from the previous request, see [`crate::session::Session`].
+    pub(crate) flash: &'a [String],
+    /// The CSRF token of the session, for its forms, see [`crate::csrf`].
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The form to create an account.
+#[derive(Template)]
+#[template(path = "register.html")]
+pub(crate) struct RegisterPage<'a> {
+    /// The name submitted, if the form is shown again.
+    pub(crate) name: &'a str,
+    pub(crate) message: Option<&'a str>,
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The form to log in to an account.
+#[derive(Template)]
+#[template(path = "login.html")]
+pub(crate) struct LoginPage<'a> {
+    /// The name submitted, if the form is shown again.
+    pub(crate) name: &'a str,
+    pub(crate) message: Option<&'a str>,
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The API tokens of the signed-in account, and the form to create one.
+#[derive(Template)]
+#[template(path = "tokens.html")]
+pub(crate) struct TokensPage<'a> {
+    pub(crate) tokens: &'a [ApiToken],
+    /// A token which was just created, shown this once.
+    pub(crate) created: Option<&'a str>,
+    pub(crate) message: Option<&'a str>,
+    pub(crate) flash: &'a [String],
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The form to create a paste.
+#[derive(Template)]
+#[template(path = "new_paste.html")]
+pub(crate) struct NewPastePage<'a> {
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The link to a new burn-after-reading paste.
+#[derive(Template)]
+#[template(path = "paste_created.html")]
+pub(crate) struct PasteCreatedPage<'a> {
+    pub(crate) location: &'a str,
+}
+
+/// A revision of a paste.
+#[derive(Template)]
+#[template(path = "paste.html")]
+pub(crate) struct PastePage<'a> {
+    pub(crate) paste: &'a Paste,
+    pub(crate) title: &'a str,
+    pub(crate) language: Option<&'a str>,
+    pub(crate) stylesheet: String,
+    pub(crate) expires_in: Option<String>,
+    /// Number of the revision shown.
+    pub(crate) revision: i32,
+    /// Path of the revision as plain text.
+    pub(crate) raw: String,
+    pub(crate) content: PasteContent<'a>,
+    pub(crate) csrf_token: Option<&'a str>,
+    /// The nonce of the stylesheet, see [`crate::http::generate_nonce`].
+    pub(crate) nonce: &'a str,
+}
+
+/// The content of a paste, as shown on its page.
+pub(crate) enum PasteContent<'a> {
+    /// Markdown rendered as HTML.
+    Markdown(String),
+    /// Code highlighted as HTML, in a language.
+    Code(&'static str, String),
+    /// Text shown as is.
+    Text(&'a str),
+}
+
+/// A file paste, linking to the file.
+#[derive(Template)]
+#[template(path = "file.html")]
+pub(crate) struct FilePage<'a> {
+    pub(crate) paste: &'a Paste,
+    pub(crate) file: &'a PasteFile,
+    pub(crate) title: &'a str,
+    pub(crate) expires_in: Option<String>,
+    pub(crate) is_image: bool,
+}
+
+/// The form asking for the password of a protected paste.
+#[derive(Template)]
+#[template(path = "unlock.html")]
+pub(crate) struct UnlockPage<'a> {
+    pub(crate) paste: &'a Paste,
+    pub(crate) title: &'a str,
+    pub(crate) message: Option<&'a str>,
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The form to edit a paste.
+#[derive(Template)]
+#[template(path = "edit_paste.html")]
+pub(crate) struct EditPastePage<'a> {
+    pub(crate) paste: &'a Paste,
+    pub(crate) csrf_token: Option<&'a str>,
+}
+
+/// The revisions of a paste, latest first.
+#[derive(Template)]
+#[template(path = "history.html")]
+pub(crate) struct HistoryPage<'a> {
+    pub(crate) paste: &'a Paste,
+    /// Revisions with how long ago they were made.
+    pub(crate) revisions: Vec<(&'a Revision, String)>,
+}
+
+/// The changes between two revisions of a paste.
+#[derive(Template)]
+#[template(path = "diff.html")]
+pub(crate) struct DiffPage<'a> {
+    pub(crate) paste_id: &'a str,
+    pub(crate) old_path: &'a str,
+    pub(crate) old_revision: i32,
+    pub(crate) new_path: &'a str,
+    pub(crate) new_revision: i32,
+    pub(crate) diff_path: &'a str,
+    pub(crate) hunks: Vec<Hunk<'a, 'a>>,
+    /// The rows of each hunk, if shown side by side rather than unified.
+    pub(crate) side_by_side: Option<Vec<Vec<DiffRow<'a>>>>,
+}
+
+/// A row of a side-by-side diff.
+pub(crate) struct DiffRow<'a> {
+    pub(crate) left: Option<DiffCell<'a>>,
+    pub(crate) right: Option<DiffCell<'a>>,
+    /// Unchanged lines appear on both sides.
+    pub(crate) changed: bool,
+}
+
+/// A line of a side-by-side diff, without its line break.
+pub(crate) struct DiffCell<'a> {
+    pub(crate) number: usize,
+    pub(crate) line: &'a str,
+}
+
+/// Renders `page` as an HTML response.
+#[inline]
+pub(crate) fn render<'response>(code: u16, page: &impl Template) -> Response<'response> {
+    match page.render() {
+        Ok(html) => Response::html(code, html),
+        Err(e) => {
+            tracing::error!("template error: {}", e);
+            Response::from_static(500, "")
+        }
+    }
+}
+
+/// Filters available to templates, as `value|filter`.
+mod filters {
+    /// Renders a todo as inline Markdown, which is safe HTML.
+    #[inline]
+    pub(crate) fn inline_markdown(source: &str) -> askama::Result<String> {
+        Ok(crate::markdown::render_inline(source))
+    }
+
+    /// Removes the line break at the end of a line of a diff.
+    #[inline]
+    pub(crate) fn trim_newline<'a>(line: &&'a str) -> askama::Result<&'a str> {
+        Ok(line.strip_suffix('\n').unwrap_or(line))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IndexPage;
+    use crate::store::Todo;
+    use askama::Template;
+
+    #[test]
+    fn test_escaping() {
+        let todos = [Todo {
+            id: 1,
+            content: "<img src=x onerror=alert(1)> *now*".to_string(),
+            owner: None,
+        }];
+        let flash = ["<b>Added</b>".to_string()];
+        let html = IndexPage {
+            todos: &todos,
+            account: None,
+            flash: &flash,
+            csrf_token: Some("t\"ken"),
+        }
+        .render()
+        .unwrap();
+        assert!(
+            html.contains("<li>&lt;img src=x onerror=alert(1)&gt; <em>now</em>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<form method=\"post\" action=\"/\""), "{}", html);
+        assert!(
+            html.contains("<input type=\"hidden\" name=\"csrf_token\" value=\"t&quot;ken\">"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<p class=\"flash\">&lt;b&gt;Added&lt;/b&gt;</p>"),
+            "{}",
+            html
+        );
+    }
+}